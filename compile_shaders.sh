@@ -1,4 +0,0 @@
-/Users/sebastian/VulkanSDK/1.3.268.1/macOS/bin/glslc shaders/shader.vert -o shaders/vert.spv
-/Users/sebastian/VulkanSDK/1.3.268.1/macOS/bin/glslc shaders/shader.frag -o shaders/frag.spv
-/Users/sebastian/VulkanSDK/1.3.268.1/macOS/bin/glslc shaders/2d.vert -o shaders/2d_vert.spv
-
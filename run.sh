@@ -7,9 +7,8 @@
 #     exit 1
 # fi
 
-# TODO: Move environment variables to the code and remove this file
-
-export RUST_LOG=debug
+# Renderer settings and the log level are read from broth.toml. The
+# loader only reads these variables at startup, so they stay here.
 export VULKAN_SDK=/Users/sebastian/VulkanSDK/1.3.268.1/macOS
 # export VK_LOADER_LAYERS_ENABLE=*validation,*profiles
 
@@ -1,14 +1,53 @@
 #version 450
 
-layout(binding = 2) uniform sampler2D texSampler;
+layout(set = 0, binding = 0) uniform CameraBufferObject {
+    mat4 view;
+    mat4 proj;
+    mat4 correction;
+    uint outputEncoding;
+    float paperWhite;
+} camera;
+layout(set = 1, binding = 0) uniform texture2D texImage;
+layout(set = 1, binding = 1) uniform sampler texSampler;
 
 layout(location = 0) in vec3 fragColor;
 layout(location = 1) in vec2 fragTexCoord;
 
-
 layout(location = 0) out vec4 outColor;
 
+// `OutputEncoding` in swapchain.rs.
+const uint ENCODING_SRGB = 1;
+const uint ENCODING_PQ = 2;
+const uint ENCODING_EXTENDED_LINEAR = 3;
+
+vec3 encodeSrgb(vec3 color) {
+    vec3 c = clamp(color, 0.0, 1.0);
+    return mix(12.92 * c, 1.055 * pow(c, vec3(1.0 / 2.4)) - 0.055, step(0.0031308, c));
+}
+
+// Rec. 709 to Rec. 2020 primaries, then SMPTE ST 2084.
+vec3 encodePq(vec3 color) {
+    mat3 toRec2020 = mat3(
+        0.6274, 0.0691, 0.0164,
+        0.3293, 0.9195, 0.0880,
+        0.0433, 0.0114, 0.8956);
+    vec3 y = clamp(toRec2020 * color * camera.paperWhite / 10000.0, 0.0, 1.0);
+    vec3 p = pow(y, vec3(0.1593017578125));
+    return pow((0.8359375 + 18.8515625 * p) / (1.0 + 18.6875 * p), vec3(78.84375));
+}
+
+vec3 encodeOutput(vec3 color) {
+    if (camera.outputEncoding == ENCODING_SRGB) {
+        return encodeSrgb(color);
+    } else if (camera.outputEncoding == ENCODING_PQ) {
+        return encodePq(color);
+    } else if (camera.outputEncoding == ENCODING_EXTENDED_LINEAR) {
+        return color * camera.paperWhite / 80.0;
+    }
+    return color;
+}
+
 void main() {
-    outColor = vec4(fragColor * texture(texSampler, fragTexCoord).rgb, 1.0);
-    // outColor = vec4(fragTexCoord, 0.0, 1.0);
-}
\ No newline at end of file
+    vec3 color = fragColor * texture(sampler2D(texImage, texSampler), fragTexCoord).rgb;
+    outColor = vec4(encodeOutput(color), 1.0);
+}
@@ -4,10 +4,12 @@ layout(set = 0, binding = 0) uniform CameraBufferObject {
     mat4 view;
     mat4 proj;
     mat4 correction;
+    uint outputEncoding;
+    float paperWhite;
 } camera;
-layout(set = 0, binding = 1) uniform UniformBufferObject {
+layout(push_constant) uniform DrawConstants {
     mat4 model;
-} ubo;
+} draw;
 
 layout(location = 0) in vec3 inPosition;
 layout(location = 1) in vec3 inColor;
@@ -17,7 +19,7 @@ layout(location = 0) out vec3 fragColor;
 layout(location = 1) out vec2 fragTexCoord;
 
 void main() {
-    gl_Position = camera.correction * camera.proj * camera.view * ubo.model * vec4(inPosition, 1.0);
+    gl_Position = camera.correction * camera.proj * camera.view * draw.model * vec4(inPosition, 1.0);
     fragColor = inColor;
     fragTexCoord = inTexCoord;
 }
\ No newline at end of file
@@ -0,0 +1,845 @@
+use std::{cell::RefCell, collections::HashMap, ptr, rc::Rc};
+
+use vulkanalia::{
+    vk::{self, DeviceV1_0, ErrorCode, HasBuilder, InstanceV1_0},
+    Instance,
+};
+
+use crate::{
+    device::LogicalDevice,
+    memory::{get_memory_type_index, MemoryError},
+    VkErrorSource,
+};
+
+/// Size of the `vkDeviceMemory` blocks backing general allocations.
+const BLOCK_SIZE: vk::DeviceSize = 64 * 1024 * 1024;
+/// Size of the blocks backing short lived staging allocations.
+const LINEAR_BLOCK_SIZE: vk::DeviceSize = 32 * 1024 * 1024;
+/// Size of the blocks backing small allocations.
+const BUDDY_BLOCK_SIZE: vk::DeviceSize = 4 * 1024 * 1024;
+/// Allocations up to this size go to the buddy pools.
+const BUDDY_MAX_SIZE: vk::DeviceSize = 256 * 1024;
+const BUDDY_MIN_SIZE: vk::DeviceSize = 256;
+
+/// The sub-allocation strategy of a pool.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PoolKind {
+    /// Free list with coalescing, for long lived resources.
+    Block,
+    /// Bump allocator that resets once all its allocations are freed,
+    /// for staging buffers.
+    Linear,
+    /// Power of two buddy allocator, for small resources such as
+    /// uniform buffers.
+    Buddy,
+}
+
+impl PoolKind {
+    fn block_size(self) -> vk::DeviceSize {
+        match self {
+            PoolKind::Block => BLOCK_SIZE,
+            PoolKind::Linear => LINEAR_BLOCK_SIZE,
+            PoolKind::Buddy => BUDDY_BLOCK_SIZE,
+        }
+    }
+
+    /// `None` if the allocation is too big to share a block.
+    fn select(
+        size: vk::DeviceSize,
+        transient: bool,
+    ) -> Option<PoolKind> {
+        if transient && size <= LINEAR_BLOCK_SIZE / 2 {
+            Some(PoolKind::Linear)
+        } else if size <= BUDDY_MAX_SIZE {
+            Some(PoolKind::Buddy)
+        } else if size <= BLOCK_SIZE / 2 {
+            Some(PoolKind::Block)
+        } else {
+            None
+        }
+    }
+}
+
+/// Buffers and linearly tiled images are linear resources, optimally
+/// tiled images are not. Neighbouring resources of different kinds
+/// must not share a `bufferImageGranularity` page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResourceKind {
+    Linear,
+    Optimal,
+}
+
+/// Sub-allocates device memory from per memory type pools instead of
+/// calling `vkAllocateMemory` for every resource.
+#[derive(Debug)]
+pub struct Allocator {
+    device: Rc<LogicalDevice>,
+    physical_device: vk::PhysicalDevice,
+    memory_properties: vk::PhysicalDeviceMemoryProperties,
+    buffer_image_granularity: vk::DeviceSize,
+    pools: RefCell<HashMap<(u32, PoolKind), Vec<MemoryBlock>>>,
+    next_block_id: RefCell<u64>,
+}
+
+pub unsafe fn create_allocator(
+    instance: &Instance,
+    device: &Rc<LogicalDevice>,
+    physical_device: vk::PhysicalDevice,
+) -> Rc<Allocator> {
+    let properties =
+        instance.get_physical_device_properties(physical_device);
+
+    Rc::new(Allocator {
+        device: device.clone(),
+        physical_device,
+        memory_properties: instance
+            .get_physical_device_memory_properties(physical_device),
+        buffer_image_granularity: properties
+            .limits
+            .buffer_image_granularity,
+        pools: RefCell::new(HashMap::new()),
+        next_block_id: RefCell::new(0),
+    })
+}
+
+impl Allocator {
+    pub fn device(&self) -> &Rc<LogicalDevice> {
+        &self.device
+    }
+
+    /// Finds memory for a resource with the given `requirements`.
+    /// `transient` allocations are expected to be freed shortly,
+    /// like staging buffers.
+    pub unsafe fn allocate(
+        self: &Rc<Self>,
+        requirements: vk::MemoryRequirements,
+        properties: vk::MemoryPropertyFlags,
+        resource: ResourceKind,
+        transient: bool,
+    ) -> Result<Allocation> {
+        let memory_type_index = get_memory_type_index(
+            self.device.instance(),
+            self.physical_device,
+            properties,
+            requirements,
+        )?;
+
+        let Some(kind) =
+            PoolKind::select(requirements.size, transient)
+        else {
+            let (memory, mapped) = self.allocate_memory(
+                memory_type_index,
+                requirements.size,
+            )?;
+            return Ok(Allocation {
+                allocator: self.clone(),
+                memory,
+                offset: 0,
+                mapped,
+                source: AllocationSource::Dedicated,
+            });
+        };
+
+        let mut pools = self.pools.borrow_mut();
+        let blocks =
+            pools.entry((memory_type_index, kind)).or_default();
+
+        let found = blocks.iter_mut().find_map(|b| {
+            b.sub_allocator
+                .allocate(
+                    requirements.size,
+                    requirements.alignment,
+                    resource,
+                )
+                .map(|offset| (b.id, b.memory, b.mapped, offset))
+        });
+        let (block_id, memory, mapped, offset) = match found {
+            Some(found) => found,
+            None => {
+                let mut block =
+                    self.allocate_block(memory_type_index, kind)?;
+                let offset = block
+                    .sub_allocator
+                    .allocate(
+                        requirements.size,
+                        requirements.alignment,
+                        resource,
+                    )
+                    .ok_or(AllocatorError::OutOfBlockMemoryError)?;
+                let found =
+                    (block.id, block.memory, block.mapped, offset);
+                blocks.push(block);
+                found
+            }
+        };
+
+        Ok(Allocation {
+            allocator: self.clone(),
+            memory,
+            offset,
+            mapped: if mapped.is_null() {
+                mapped
+            } else {
+                mapped.add(offset as usize)
+            },
+            source: AllocationSource::Pool {
+                memory_type_index,
+                kind,
+                block_id,
+            },
+        })
+    }
+
+    unsafe fn allocate_block(
+        &self,
+        memory_type_index: u32,
+        kind: PoolKind,
+    ) -> Result<MemoryBlock> {
+        let size = kind.block_size();
+        let (memory, mapped) =
+            self.allocate_memory(memory_type_index, size)?;
+
+        let granularity = self.buffer_image_granularity;
+        let sub_allocator = match kind {
+            PoolKind::Block => SubAllocator::FreeList(FreeList::new(
+                size,
+                granularity,
+            )),
+            PoolKind::Linear => {
+                SubAllocator::Linear(Linear::new(size, granularity))
+            }
+            PoolKind::Buddy => {
+                SubAllocator::Buddy(Buddy::new(size, granularity))
+            }
+        };
+
+        let mut next_block_id = self.next_block_id.borrow_mut();
+        let id = *next_block_id;
+        *next_block_id += 1;
+
+        Ok(MemoryBlock {
+            id,
+            memory,
+            mapped,
+            sub_allocator,
+        })
+    }
+
+    unsafe fn allocate_memory(
+        &self,
+        memory_type_index: u32,
+        size: vk::DeviceSize,
+    ) -> Result<(vk::DeviceMemory, *mut u8)> {
+        let info = vk::MemoryAllocateInfo::builder()
+            .allocation_size(size)
+            .memory_type_index(memory_type_index);
+
+        let memory = self.device.allocate_memory(&info, None)?;
+
+        // Host visible memory stays mapped for its whole lifetime so
+        // resources sharing a block never map the same memory twice.
+        let property_flags = self.memory_properties.memory_types
+            [memory_type_index as usize]
+            .property_flags;
+        if !property_flags
+            .contains(vk::MemoryPropertyFlags::HOST_VISIBLE)
+        {
+            return Ok((memory, ptr::null_mut()));
+        }
+
+        match self.device.map_memory(
+            memory,
+            0,
+            vk::WHOLE_SIZE as u64,
+            vk::MemoryMapFlags::empty(),
+        ) {
+            Ok(mapped) => Ok((memory, mapped.cast())),
+            Err(e) => {
+                self.device.free_memory(memory, None);
+                Err(e.into())
+            }
+        }
+    }
+
+    unsafe fn free(&self, allocation: &Allocation) {
+        let AllocationSource::Pool {
+            memory_type_index,
+            kind,
+            block_id,
+        } = allocation.source
+        else {
+            self.device.free_memory(allocation.memory, None);
+            return;
+        };
+
+        let mut pools = self.pools.borrow_mut();
+        let Some(blocks) = pools.get_mut(&(memory_type_index, kind))
+        else {
+            return;
+        };
+        let Some(index) =
+            blocks.iter().position(|b| b.id == block_id)
+        else {
+            return;
+        };
+
+        blocks[index].sub_allocator.free(allocation.offset);
+
+        // Keep the last block of a pool around so a pool that is
+        // emptied and refilled every frame does not reallocate.
+        if blocks[index].sub_allocator.is_empty() && blocks.len() > 1
+        {
+            let block = blocks.swap_remove(index);
+            self.device.free_memory(block.memory, None);
+        }
+    }
+}
+
+impl Drop for Allocator {
+    fn drop(&mut self) {
+        for block in self.pools.get_mut().values().flatten() {
+            unsafe {
+                self.device.free_memory(block.memory, None);
+            }
+        }
+    }
+}
+
+/// A range of device memory, returned to its pool when dropped. The
+/// resource bound to it has to be destroyed first.
+#[derive(Debug)]
+pub struct Allocation {
+    allocator: Rc<Allocator>,
+    memory: vk::DeviceMemory,
+    offset: vk::DeviceSize,
+    mapped: *mut u8,
+    source: AllocationSource,
+}
+
+impl Allocation {
+    pub fn memory(&self) -> vk::DeviceMemory {
+        self.memory
+    }
+
+    pub fn offset(&self) -> vk::DeviceSize {
+        self.offset
+    }
+
+    /// Host pointer to the start of the allocation, only available
+    /// for host visible memory.
+    pub fn mapped(&self) -> Result<*mut u8> {
+        if self.mapped.is_null() {
+            Err(AllocatorError::NotHostVisibleError)
+        } else {
+            Ok(self.mapped)
+        }
+    }
+}
+
+impl Drop for Allocation {
+    fn drop(&mut self) {
+        unsafe {
+            self.allocator.free(self);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum AllocationSource {
+    Dedicated,
+    Pool {
+        memory_type_index: u32,
+        kind: PoolKind,
+        block_id: u64,
+    },
+}
+
+#[derive(Debug)]
+struct MemoryBlock {
+    id: u64,
+    memory: vk::DeviceMemory,
+    mapped: *mut u8,
+    sub_allocator: SubAllocator,
+}
+
+#[derive(Debug)]
+enum SubAllocator {
+    FreeList(FreeList),
+    Linear(Linear),
+    Buddy(Buddy),
+}
+
+impl SubAllocator {
+    fn allocate(
+        &mut self,
+        size: vk::DeviceSize,
+        alignment: vk::DeviceSize,
+        resource: ResourceKind,
+    ) -> Option<vk::DeviceSize> {
+        match self {
+            SubAllocator::FreeList(a) => {
+                a.allocate(size, alignment, resource)
+            }
+            SubAllocator::Linear(a) => {
+                a.allocate(size, alignment, resource)
+            }
+            SubAllocator::Buddy(a) => a.allocate(size, alignment),
+        }
+    }
+
+    fn free(&mut self, offset: vk::DeviceSize) {
+        match self {
+            SubAllocator::FreeList(a) => a.free(offset),
+            SubAllocator::Linear(a) => a.free(),
+            SubAllocator::Buddy(a) => a.free(offset),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        match self {
+            SubAllocator::FreeList(a) => a.is_empty(),
+            SubAllocator::Linear(a) => a.count == 0,
+            SubAllocator::Buddy(a) => a.is_empty(),
+        }
+    }
+}
+
+fn align_up(value: vk::DeviceSize, alignment: vk::DeviceSize) -> u64 {
+    value.div_ceil(alignment) * alignment
+}
+
+/// Whether the byte at `a` and the byte at `b` lie on the same
+/// `bufferImageGranularity` page.
+fn same_page(
+    a: vk::DeviceSize,
+    b: vk::DeviceSize,
+    granularity: vk::DeviceSize,
+) -> bool {
+    a / granularity == b / granularity
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Region {
+    offset: vk::DeviceSize,
+    size: vk::DeviceSize,
+    /// `None` for free regions.
+    resource: Option<ResourceKind>,
+}
+
+/// First fit over the regions of a block, sorted by offset. Adjacent
+/// free regions are always merged.
+#[derive(Debug)]
+struct FreeList {
+    regions: Vec<Region>,
+    granularity: vk::DeviceSize,
+}
+
+impl FreeList {
+    fn new(
+        size: vk::DeviceSize,
+        granularity: vk::DeviceSize,
+    ) -> Self {
+        Self {
+            regions: vec![Region {
+                offset: 0,
+                size,
+                resource: None,
+            }],
+            granularity,
+        }
+    }
+
+    fn allocate(
+        &mut self,
+        size: vk::DeviceSize,
+        alignment: vk::DeviceSize,
+        resource: ResourceKind,
+    ) -> Option<vk::DeviceSize> {
+        let conflicts = |r: &Region| {
+            r.resource.is_some_and(|other| other != resource)
+        };
+
+        for i in 0..self.regions.len() {
+            let region = self.regions[i];
+            if region.resource.is_some() {
+                continue;
+            }
+
+            let mut offset = align_up(region.offset, alignment);
+            if let Some(previous) =
+                i.checked_sub(1).map(|i| self.regions[i])
+            {
+                let previous_end =
+                    previous.offset + previous.size - 1;
+                if conflicts(&previous)
+                    && same_page(
+                        previous_end,
+                        offset,
+                        self.granularity,
+                    )
+                {
+                    offset = align_up(offset, self.granularity);
+                }
+            }
+
+            let end = offset + size;
+            if end > region.offset + region.size {
+                continue;
+            }
+            if let Some(next) = self.regions.get(i + 1) {
+                if conflicts(next)
+                    && same_page(
+                        end - 1,
+                        next.offset,
+                        self.granularity,
+                    )
+                {
+                    continue;
+                }
+            }
+
+            let mut split = vec![];
+            if offset > region.offset {
+                split.push(Region {
+                    offset: region.offset,
+                    size: offset - region.offset,
+                    resource: None,
+                });
+            }
+            split.push(Region {
+                offset,
+                size,
+                resource: Some(resource),
+            });
+            if end < region.offset + region.size {
+                split.push(Region {
+                    offset: end,
+                    size: region.offset + region.size - end,
+                    resource: None,
+                });
+            }
+            self.regions.splice(i..=i, split);
+
+            return Some(offset);
+        }
+
+        None
+    }
+
+    fn free(&mut self, offset: vk::DeviceSize) {
+        let Some(mut i) = self
+            .regions
+            .iter()
+            .position(|r| r.offset == offset && r.resource.is_some())
+        else {
+            return;
+        };
+        self.regions[i].resource = None;
+
+        if i + 1 < self.regions.len()
+            && self.regions[i + 1].resource.is_none()
+        {
+            self.regions[i].size += self.regions.remove(i + 1).size;
+        }
+        if i > 0 && self.regions[i - 1].resource.is_none() {
+            let region = self.regions.remove(i);
+            i -= 1;
+            self.regions[i].size += region.size;
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.regions.iter().all(|r| r.resource.is_none())
+    }
+}
+
+#[derive(Debug)]
+struct Linear {
+    size: vk::DeviceSize,
+    top: vk::DeviceSize,
+    count: usize,
+    last_resource: Option<ResourceKind>,
+    granularity: vk::DeviceSize,
+}
+
+impl Linear {
+    fn new(
+        size: vk::DeviceSize,
+        granularity: vk::DeviceSize,
+    ) -> Self {
+        Self {
+            size,
+            top: 0,
+            count: 0,
+            last_resource: None,
+            granularity,
+        }
+    }
+
+    fn allocate(
+        &mut self,
+        size: vk::DeviceSize,
+        alignment: vk::DeviceSize,
+        resource: ResourceKind,
+    ) -> Option<vk::DeviceSize> {
+        let mut offset = align_up(self.top, alignment);
+        if self.last_resource.is_some_and(|last| last != resource) {
+            offset = align_up(offset, self.granularity);
+        }
+        if offset + size > self.size {
+            return None;
+        }
+
+        self.top = offset + size;
+        self.count += 1;
+        self.last_resource = Some(resource);
+
+        Some(offset)
+    }
+
+    fn free(&mut self) {
+        self.count -= 1;
+        if self.count == 0 {
+            self.top = 0;
+            self.last_resource = None;
+        }
+    }
+}
+
+/// Nodes are aligned to their own size and never smaller than
+/// `bufferImageGranularity`, so two allocations never share a page.
+#[derive(Debug)]
+struct Buddy {
+    min_size: vk::DeviceSize,
+    /// Free node offsets by order, a node of order `n` is
+    /// `min_size << n` bytes.
+    free_nodes: Vec<Vec<vk::DeviceSize>>,
+    allocated: HashMap<vk::DeviceSize, usize>,
+}
+
+impl Buddy {
+    fn new(
+        size: vk::DeviceSize,
+        granularity: vk::DeviceSize,
+    ) -> Self {
+        let min_size =
+            BUDDY_MIN_SIZE.max(granularity).next_power_of_two();
+        let orders = (size / min_size).trailing_zeros() as usize + 1;
+        let mut free_nodes = vec![vec![]; orders];
+        free_nodes[orders - 1].push(0);
+
+        Self {
+            min_size,
+            free_nodes,
+            allocated: HashMap::new(),
+        }
+    }
+
+    fn allocate(
+        &mut self,
+        size: vk::DeviceSize,
+        alignment: vk::DeviceSize,
+    ) -> Option<vk::DeviceSize> {
+        let node_size = size
+            .max(alignment)
+            .max(self.min_size)
+            .next_power_of_two();
+        let order =
+            (node_size / self.min_size).trailing_zeros() as usize;
+
+        let available = (order..self.free_nodes.len())
+            .find(|o| !self.free_nodes[*o].is_empty())?;
+        let offset = self.free_nodes[available].pop()?;
+
+        // Split down to the requested order, keeping the upper halves.
+        for o in (order..available).rev() {
+            self.free_nodes[o].push(offset + (self.min_size << o));
+        }
+        self.allocated.insert(offset, order);
+
+        Some(offset)
+    }
+
+    fn free(&mut self, offset: vk::DeviceSize) {
+        let Some(mut order) = self.allocated.remove(&offset) else {
+            return;
+        };
+
+        let mut offset = offset;
+        while order + 1 < self.free_nodes.len() {
+            let buddy = offset ^ (self.min_size << order);
+            let Some(index) = self.free_nodes[order]
+                .iter()
+                .position(|o| *o == buddy)
+            else {
+                break;
+            };
+            self.free_nodes[order].swap_remove(index);
+            offset = offset.min(buddy);
+            order += 1;
+        }
+        self.free_nodes[order].push(offset);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.allocated.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum AllocatorError {
+    #[error(transparent)]
+    VkErrorCode(#[from] ErrorCode),
+    #[error(transparent)]
+    MemoryError(#[from] MemoryError),
+    #[error("Allocation does not fit into a new memory block.")]
+    OutOfBlockMemoryError,
+    #[error("Allocation is not host visible.")]
+    NotHostVisibleError,
+}
+type Result<T> = std::result::Result<T, AllocatorError>;
+
+impl VkErrorSource for AllocatorError {
+    fn vk_error_code(&self) -> Option<ErrorCode> {
+        match self {
+            Self::VkErrorCode(e) => Some(*e),
+            Self::MemoryError(_)
+            | Self::OutOfBlockMemoryError
+            | Self::NotHostVisibleError => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use ResourceKind::{Linear as L, Optimal as O};
+
+    #[test]
+    fn free_list_aligns_offsets() {
+        let mut list = FreeList::new(4096, 1);
+        assert_eq!(list.allocate(100, 1, L), Some(0));
+        assert_eq!(list.allocate(100, 256, L), Some(256));
+        assert_eq!(list.allocate(10, 64, L), Some(128));
+        assert_eq!(list.allocate(4096, 1, L), None);
+    }
+
+    #[test]
+    fn free_list_keeps_neighbours_of_other_kinds_apart() {
+        let mut list = FreeList::new(4096, 1024);
+        assert_eq!(list.allocate(256, 256, L), Some(0));
+        // The previous region is linear and on the same page.
+        assert_eq!(list.allocate(256, 256, O), Some(1024));
+        assert_eq!(list.allocate(256, 256, L), Some(256));
+        // The gap before the optimal region is on its own page.
+        assert_eq!(list.allocate(256, 256, L), Some(512));
+
+        let mut list = FreeList::new(4096, 1024);
+        assert_eq!(list.allocate(256, 256, O), Some(0));
+        assert_eq!(list.allocate(256, 256, O), Some(256));
+        list.free(0);
+        // The next region is optimal and on the same page.
+        assert_eq!(list.allocate(128, 128, L), Some(1024));
+        assert_eq!(list.allocate(128, 128, O), Some(0));
+    }
+
+    #[test]
+    fn free_list_coalesces_back_to_one_region() {
+        let mut list = FreeList::new(4096, 1);
+        let offsets: Vec<_> = (0..4)
+            .map(|_| list.allocate(1024, 1, L).unwrap())
+            .collect();
+        assert_eq!(offsets, [0, 1024, 2048, 3072]);
+        assert_eq!(list.allocate(1, 1, L), None);
+
+        for offset in [1024, 3072, 0] {
+            list.free(offset);
+            assert!(!list.is_empty());
+        }
+        let regions: Vec<_> = list
+            .regions
+            .iter()
+            .map(|r| (r.offset, r.size, r.resource))
+            .collect();
+        assert_eq!(
+            regions,
+            [
+                (0, 2048, None),
+                (2048, 1024, Some(L)),
+                (3072, 1024, None)
+            ]
+        );
+        list.free(2048);
+        assert!(list.is_empty());
+        assert_eq!(list.regions.len(), 1);
+        assert_eq!(list.allocate(4096, 1, L), Some(0));
+    }
+
+    #[test]
+    fn free_list_ignores_unknown_offsets() {
+        let mut list = FreeList::new(4096, 1);
+        assert_eq!(list.allocate(1024, 1, L), Some(0));
+        list.free(1024);
+        list.free(512);
+        assert!(!list.is_empty());
+        list.free(0);
+        list.free(0);
+        assert!(list.is_empty());
+        assert_eq!(list.regions.len(), 1);
+    }
+
+    #[test]
+    fn linear_bumps_to_a_new_page_between_kinds() {
+        let mut linear = Linear::new(4096, 1024);
+        assert_eq!(linear.allocate(100, 16, L), Some(0));
+        assert_eq!(linear.allocate(100, 16, L), Some(112));
+        assert_eq!(linear.allocate(100, 16, O), Some(1024));
+        assert_eq!(linear.allocate(100, 16, O), Some(1136));
+        assert_eq!(linear.allocate(100, 16, L), Some(2048));
+        assert_eq!(linear.allocate(2048, 1, L), None);
+    }
+
+    #[test]
+    fn linear_resets_once_everything_is_freed() {
+        let mut linear = Linear::new(4096, 1024);
+        assert_eq!(linear.allocate(2048, 1, L), Some(0));
+        assert_eq!(linear.allocate(1024, 1, O), Some(2048));
+        linear.free();
+        assert_eq!(linear.allocate(2048, 1, O), None);
+        linear.free();
+        assert_eq!(linear.count, 0);
+        assert_eq!(linear.allocate(4096, 1, O), Some(0));
+    }
+
+    #[test]
+    fn buddy_splits_and_merges() {
+        let mut buddy = Buddy::new(4096, 1);
+        assert_eq!(buddy.min_size, BUDDY_MIN_SIZE);
+        assert_eq!(buddy.allocate(100, 1), Some(0));
+        assert_eq!(buddy.allocate(300, 1), Some(512));
+        assert_eq!(buddy.allocate(1, 1024), Some(1024));
+        assert_eq!(buddy.allocate(1, 1), Some(256));
+        assert_eq!(buddy.allocate(4096, 1), None);
+
+        for offset in [512, 0, 1024] {
+            buddy.free(offset);
+            assert!(!buddy.is_empty());
+        }
+        buddy.free(256);
+        assert!(buddy.is_empty());
+        assert_eq!(buddy.free_nodes.last().unwrap(), &[0]);
+        assert!(buddy.free_nodes[..4].iter().all(Vec::is_empty));
+        assert_eq!(buddy.allocate(4096, 1), Some(0));
+    }
+
+    #[test]
+    fn buddy_nodes_cover_whole_pages() {
+        let mut buddy = Buddy::new(4096, 1024);
+        assert_eq!(buddy.allocate(1, 1), Some(0));
+        assert_eq!(buddy.allocate(1, 1), Some(1024));
+        assert_eq!(buddy.allocate(1025, 1), Some(2048));
+        assert_eq!(buddy.allocate(1, 1), None);
+        assert_eq!(buddy.allocate(8192, 1), None);
+    }
+}
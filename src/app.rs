@@ -1,64 +1,97 @@
 use std::fs::File;
-use std::mem::size_of;
+use std::rc::Rc;
+use std::sync::Arc;
 
+use crate::allocator::{create_allocator, Allocator};
 use crate::buffer::{
-    create_index_buffer, create_uniform_buffers, BufferError,
-    CameraObject, Mat3, Mat4, ModelObject,
+    create_uniform_buffers, Buffer, BufferError, CameraObject, Mat3,
+    Mat4,
 };
 use crate::color::{create_color_objects, ColorError};
 use crate::command::{
-    create_command_buffers, create_command_buffers_2d,
-    create_command_pool, CommandError,
+    create_command_pool, create_compute_command_pool,
+    create_frame_commands, record_command_buffer, CommandError,
+    FrameCommands,
 };
+use crate::compute::{
+    self, create_compute_semaphores, ComputeError, Handoff,
+};
+use crate::config::{ColorOutput, Config, Msaa};
 use crate::descriptor::{
     create_descriptor_pool, create_descriptor_set_layout,
-    create_descriptor_sets, create_descriptor_sets_2d,
-    DescriptorError,
+    create_descriptor_sets, create_material, DescriptorError,
+    CAMERA_SET, MATERIAL_SET,
 };
 use crate::device::{
-    create_logical_device, pick_physical_device, DeviceError,
+    create_logical_device, pick_physical_device,
+    report_physical_devices, DeviceError, DeviceReport,
+    LogicalDevice,
 };
 
+use crate::draw::{Draw, DrawId, DrawList, Material, Mesh, MeshData};
+use crate::frame_timing::{CpuTimings, FrameTimer, Phase};
+use crate::image::Image;
+use crate::offscreen::{
+    create_offscreen_target, save_offscreen_image, OffscreenError,
+};
+use crate::owned::Owned;
 use crate::pipeline::{
-    create_pipeline, create_pipeline_2d, PipelineError,
+    ComputePipeline, ComputePipelineBuilder, GraphicsPipelineBuilder,
+    Pipeline, PipelineError,
+};
+use crate::pipeline_cache::{
+    create_pipeline_cache, save_pipeline_cache, PipelineCacheError,
+    PIPELINE_CACHE_PATH,
+};
+use crate::profiler::{
+    create_gpu_profiler, DrawCounters, FrameStats, GpuProfiler,
+    ProfilerError, RollingAverage,
 };
+use crate::queue::{QueueError, QueueFamilyIndices};
+use crate::reflect::ShaderReflection;
 use crate::render_pass::{
-    create_depth_objects, create_render_pass, create_render_pass_2d,
+    create_depth_objects, create_render_pass, RenderPass,
     RenderPassError,
 };
+use crate::shader::{
+    compile_embedded_shader, compile_shader, ShaderError,
+    ShaderWatcher, SHADER_DIR,
+};
 use crate::swapchain::{
-    create_framebuffers, create_framebuffers_2d, create_swapchain,
-    create_swapchain_image_views, create_sync_objects,
-    SwapchainError,
+    create_framebuffers, create_surface, create_swapchain,
+    create_sync_objects, OutputFormat, Surface, Swapchain,
+    SwapchainError, SwapchainSupport,
 };
 use crate::texture::{
-    create_texture_image, create_texture_image_view,
-    create_texture_sampler, TextureError,
+    create_texture_image, create_texture_sampler, TextureData,
+    TextureError,
 };
-use crate::vertex::{
-    create_vertex_buffer_2d, SpaceDimension, Vertex2,
+use crate::upload::{
+    create_uploader, UploadError, UploadTicket, Uploader,
 };
+use crate::validation::{LogSink, MessageSink};
 use crate::{
-    instance::{create_instance, InstanceError},
-    validation::destroy_debug_utils_messenger_ext,
+    instance::{create_instance, InstanceError, VulkanInstance},
     vertex::{create_vertex_buffer, Vertex3, VertexError},
-    MAX_FRAMES_IN_FLIGHT,
+    VkErrorSource, DEFAULT_FRAMES_IN_FLIGHT,
 };
 // use cgmath::Angle::{cos, sin};
-use cgmath::{point3, vec2, vec3, Angle, Deg, Point3, Vector3};
+use cgmath::{
+    point3, vec2, vec3, Angle, Deg, InnerSpace, Point3, Vector3,
+};
 use std::{
-    collections::HashMap, io::BufReader,
-    ptr::copy_nonoverlapping as memcpy, time::Instant,
+    collections::{hash_map::Entry, HashMap},
+    io::BufReader,
+    path::Path,
+    time::{Duration, Instant},
 };
 use thiserror::Error;
 use vulkanalia::{
     loader::{LibloadingLoader, LIBRARY},
     vk::{
-        self, DeviceV1_0, Handle, HasBuilder, InstanceV1_0,
-        KhrSurfaceExtension, KhrSwapchainExtension,
+        self, DeviceV1_0, Handle, HasBuilder, KhrSwapchainExtension,
     },
-    window::create_surface,
-    Device, Entry, Instance,
+    Device, Entry as VkEntry, Instance,
 };
 use winit::window::Window;
 
@@ -90,6 +123,20 @@ pub enum AppError {
     VertexError(#[from] VertexError),
     #[error(transparent)]
     CommandError(#[from] CommandError),
+    #[error(transparent)]
+    OffscreenError(#[from] OffscreenError),
+    #[error(transparent)]
+    ShaderError(#[from] ShaderError),
+    #[error(transparent)]
+    UploadError(#[from] UploadError),
+    #[error(transparent)]
+    ComputeError(#[from] ComputeError),
+    #[error(transparent)]
+    QueueError(#[from] QueueError),
+    #[error(transparent)]
+    PipelineCacheError(#[from] PipelineCacheError),
+    #[error(transparent)]
+    ProfilerError(#[from] ProfilerError),
     #[error("Failed to open file with error: {0}.")]
     FileOpenError(String),
     #[error("{0:?}")]
@@ -99,354 +146,644 @@ pub enum AppError {
 }
 type Result<T> = std::result::Result<T, AppError>;
 
-#[derive(Clone, Debug)]
+impl VkErrorSource for AppError {
+    fn vk_error_code(&self) -> Option<vk::ErrorCode> {
+        match self {
+            Self::VkErrorCode(e) => Some(*e),
+            Self::InstanceError(e) => e.vk_error_code(),
+            Self::BufferError(e) => e.vk_error_code(),
+            Self::SwapchainError(e) => e.vk_error_code(),
+            Self::PipelineError(e) => e.vk_error_code(),
+            Self::DeviceError(e) => e.vk_error_code(),
+            Self::ColorError(e) => e.vk_error_code(),
+            Self::TextureError(e) => e.vk_error_code(),
+            Self::RenderPassError(e) => e.vk_error_code(),
+            Self::DescriptorError(e) => e.vk_error_code(),
+            Self::VertexError(e) => e.vk_error_code(),
+            Self::CommandError(e) => e.vk_error_code(),
+            Self::OffscreenError(e) => e.vk_error_code(),
+            Self::UploadError(e) => e.vk_error_code(),
+            Self::ComputeError(e) => e.vk_error_code(),
+            Self::QueueError(e) => e.vk_error_code(),
+            Self::PipelineCacheError(e) => e.vk_error_code(),
+            Self::ProfilerError(e) => e.vk_error_code(),
+            Self::LoadError(_)
+            | Self::ShaderError(_)
+            | Self::FileOpenError(_)
+            | Self::VkLibLoadingError(_)
+            | Self::VkEntryError(_) => None,
+        }
+    }
+}
+
+pub const DEFAULT_MODEL_PATH: &str = "resources/fish.obj";
+pub const DEFAULT_TEXTURE_PATH: &str = "resources/fish_albedo.png";
+const MESH_VERTEX_SHADER: &str = "shader.vert";
+const MESH_FRAGMENT_SHADER: &str = "shader.frag";
+/// Failed attempts to replace a lost device before `render` gives up.
+const MAX_DEVICE_RETRIES: u32 = 8;
+/// Wait before the second attempt, doubled after every failure.
+const DEVICE_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Every Vulkan object is released when the app is dropped. `data`
+/// goes first, then the surface, and the device and instance last
+/// since everything created from them holds an `Rc` of them.
+#[derive(Debug)]
 pub struct App {
-    pub _entry: Entry,
-    pub instance: Instance,
-    pub data: AppData,
-    pub device: Device,
-    pub frame: usize,
-    pub resized: bool,
-    pub start: Instant,
-    pub camera_direction: Vector3<f32>,
-    pub camera_alt_direction: Vector3<f32>,
-    pub camera_up_direction: Vector3<f32>,
-    pub camera_position: Point3<f32>,
+    data: AppData,
+    surface: Option<Surface>,
+    device: Rc<LogicalDevice>,
+    instance: Rc<VulkanInstance>,
+    /// Rebuilds pipelines when their shaders change, windowed only.
+    shader_watcher: Option<ShaderWatcher>,
+    frame: usize,
+    resized: bool,
+    start: Instant,
+    /// CPU time of the phases of the last frame and frame time
+    /// statistics.
+    frame_timer: FrameTimer,
+    camera_direction: Vector3<f32>,
+    camera_alt_direction: Vector3<f32>,
+    camera_up_direction: Vector3<f32>,
+    camera_position: Point3<f32>,
+    /// What the app was created with, kept up to date with the loaded
+    /// mesh and texture.
+    config: Config,
+    /// Incremented whenever a lost device is replaced. The draw list
+    /// is rebuilt on the new device, other GPU objects the caller
+    /// created from an older device are gone.
+    device_generation: u64,
+    /// Set while a lost device couldn't be replaced, the next frame
+    /// retries instead of rendering.
+    device_lost: bool,
+    /// When to try replacing the lost device again.
+    device_retry: DeviceRetry,
 }
 
-#[derive(Clone, Debug, Default)]
-pub struct AppData {
-    pub messenger: vk::DebugUtilsMessengerEXT,
-    pub physical_device: vk::PhysicalDevice,
-    pub msaa_samples: vk::SampleCountFlags,
-    pub graphics_queue: vk::Queue,
-    pub present_queue: vk::Queue,
-    pub surface: vk::SurfaceKHR,
-    pub swapchain: vk::SwapchainKHR,
-    pub swapchain_images: Vec<vk::Image>,
-    pub swapchain_format: vk::Format,
-    pub swapchain_extent: vk::Extent2D,
-    pub swapchain_image_views: Vec<vk::ImageView>,
-    pub command_pool: vk::CommandPool,
-    pub image_available_semaphores: Vec<vk::Semaphore>,
-    pub render_finished_semaphores: Vec<vk::Semaphore>,
-    pub in_flight_fences: Vec<vk::Fence>,
-    pub images_in_flight: Vec<vk::Fence>,
-    pub descriptor_pool: vk::DescriptorPool,
-    pub descriptor_sets: Vec<vk::DescriptorSet>,
-    pub mip_levels: u32,
-    pub color_image: vk::Image,
-    pub color_image_memory: vk::DeviceMemory,
-    pub color_image_view: vk::ImageView,
-    pub texture_image: vk::Image,
-    pub texture_image_memory: vk::DeviceMemory,
-    pub texture_image_view: vk::ImageView,
-    pub texture_sampler: vk::Sampler,
-    pub depth_image: vk::Image,
-    pub depth_image_memory: vk::DeviceMemory,
-    pub depth_image_view: vk::ImageView,
-    pub dimension: SpaceDimension,
-    pub framebuffers: Vec<vk::Framebuffer>,
-    pub vertices: Vec<Vertex3>,
-    pub vertices_2d: Vec<Vertex2>,
-    pub indices: Vec<u32>,
-    pub indices_2d: Vec<u32>,
-    pub render_pass: vk::RenderPass,
-    pub pipeline_layout: vk::PipelineLayout,
-    pub descriptor_set_layout: vk::DescriptorSetLayout,
-    pub pipeline: vk::Pipeline,
-    pub command_buffers: Vec<vk::CommandBuffer>,
-    pub vertex_buffer: vk::Buffer,
-    pub vertex_buffer_memory: vk::DeviceMemory,
-    pub index_buffer: vk::Buffer,
-    pub index_buffer_memory: vk::DeviceMemory,
-    pub camera_buffers: Vec<vk::Buffer>,
-    pub camera_buffers_memory: Vec<vk::DeviceMemory>,
-    pub model_buffers: Vec<vk::Buffer>,
-    pub model_buffers_memory: Vec<vk::DeviceMemory>,
+/// Backoff between attempts to replace a lost device, a driver reset
+/// can take seconds.
+#[derive(Debug, Clone, Copy, Default)]
+struct DeviceRetry {
+    failures: u32,
+    next: Option<Instant>,
 }
 
-impl App {
-    pub unsafe fn create(window: &Window) -> Result<Self> {
-        let loader = LibloadingLoader::new(LIBRARY).map_err(|e| {
-            AppError::VkLibLoadingError(e.to_string())
-        })?;
-        let entry = Entry::new(loader)?;
-        let mut data = AppData::default();
-        let instance =
-            create_instance(window, &entry, &mut data.messenger)?;
-        data.surface = create_surface(&instance, &window, &window)?;
+impl DeviceRetry {
+    fn is_due(&self, now: Instant) -> bool {
+        self.next.is_none_or(|next| now >= next)
+    }
+
+    /// Records an attempt that failed at `now`. Returns the delay
+    /// before the next one, or `None` after `MAX_DEVICE_RETRIES`.
+    fn failed(&mut self, now: Instant) -> Option<Duration> {
+        if self.failures >= MAX_DEVICE_RETRIES {
+            return None;
+        }
+        let delay = DEVICE_RETRY_DELAY * 2u32.pow(self.failures);
+        self.failures += 1;
+        self.next = Some(now + delay);
+        Some(delay)
+    }
+}
+
+// Fields are dropped in declaration order, so objects have to be
+// declared before the objects they were created from.
+#[derive(Debug)]
+struct AppData {
+    physical_device: vk::PhysicalDevice,
+    msaa_samples: vk::SampleCountFlags,
+    supported_msaa_samples: vk::SampleCountFlags,
+    /// Minimum fraction of samples shaded per pixel with MSAA, `None`
+    /// shades once per pixel.
+    sample_shading: Option<f32>,
+    /// Preferred when the swapchain is recreated, see
+    /// `get_swapchain_present_mode`.
+    present_mode: vk::PresentModeKHR,
+    /// Preferred swapchain outputs, see `get_swapchain_surface_format`.
+    outputs: Vec<ColorOutput>,
+    /// Nits of white in HDR outputs.
+    paper_white: f32,
+    graphics_queue: vk::Queue,
+    present_queue: vk::Queue,
+    compute_queue: vk::Queue,
+    queue_families: QueueFamilyIndices,
+    /// What the mesh shaders expect to be bound, the descriptor set
+    /// layout and pools are created from it.
+    shader_reflection: ShaderReflection,
+    /// Times the passes of every frame and the uploads.
+    profiler: GpuProfiler,
+    /// Of the last recorded frame.
+    draw_counters: DrawCounters,
+    frame_commands: Vec<FrameCommands>,
+    /// Recorded every frame, in order.
+    draws: DrawList,
+    /// The draw of `mesh` with `material`, spun around the z-axis.
+    scene_draw: DrawId,
+    /// Camera sets, one per frame in flight.
+    descriptor_sets: Vec<vk::DescriptorSet>,
+    descriptor_pool: Owned<vk::DescriptorPool>,
+    camera_buffers: Vec<Buffer>,
+    framebuffers: Vec<Owned<vk::Framebuffer>>,
+    pipeline: Rc<Pipeline>,
+    render_pass: RenderPass,
+    /// Resolved into the swapchain image, `None` without MSAA.
+    color_image: Option<Image>,
+    depth_image: Image,
+    swapchain: Swapchain,
+    mesh: Rc<Mesh>,
+    material: Rc<Material>,
+    /// Of the mesh pipeline's `CAMERA_SET`.
+    camera_set_layout: Owned<vk::DescriptorSetLayout>,
+    /// Of the mesh pipeline's `MATERIAL_SET`.
+    material_set_layout: Owned<vk::DescriptorSetLayout>,
+    /// Shared by every pipeline, saved to `PIPELINE_CACHE_PATH` when
+    /// the app is dropped.
+    pipeline_cache: Owned<vk::PipelineCache>,
+    image_available_semaphores: Vec<Owned<vk::Semaphore>>,
+    render_finished_semaphores: Vec<Owned<vk::Semaphore>>,
+    in_flight_fences: Vec<Owned<vk::Fence>>,
+    images_in_flight: Vec<vk::Fence>,
+    /// Signaled by `App::submit_compute`, one per frame in flight.
+    compute_semaphores: Vec<Owned<vk::Semaphore>>,
+    /// Acquired by the next frame if compute work was submitted for
+    /// it.
+    compute_handoffs: Option<Vec<Handoff>>,
+    compute_command_pool: Owned<vk::CommandPool>,
+    pending_mesh: Option<PendingMesh>,
+    pending_texture: Option<PendingTexture>,
+    uploader: Uploader,
+    command_pool: Owned<vk::CommandPool>,
+    allocator: Rc<Allocator>,
+}
+
+impl AppData {
+    /// The set layouts of the mesh pipeline, by set number.
+    fn mesh_set_layouts(&self) -> [vk::DescriptorSetLayout; 2] {
+        [
+            self.camera_set_layout.handle(),
+            self.material_set_layout.handle(),
+        ]
+    }
+}
+
+/// A mesh streamed in by `App::load_mesh`, it replaces `AppData::mesh`
+/// once its upload completes.
+#[derive(Debug)]
+struct PendingMesh {
+    ticket: UploadTicket,
+    mesh: Rc<Mesh>,
+}
+
+/// A texture streamed in by `App::load_texture`, it replaces
+/// `AppData::material` once its upload completes.
+#[derive(Debug)]
+struct PendingTexture {
+    ticket: UploadTicket,
+    material: Rc<Material>,
+}
+
+/// The instance, surface and device of a new app, created before
+/// anything else since they are what fails on a lost device.
+struct DeviceObjects {
+    surface: Option<Surface>,
+    device: Rc<LogicalDevice>,
+    instance: Rc<VulkanInstance>,
+    physical_device: vk::PhysicalDevice,
+    supported_msaa_samples: vk::SampleCountFlags,
+    graphics_queue: vk::Queue,
+    present_queue: vk::Queue,
+    transfer_queue: vk::Queue,
+    compute_queue: vk::Queue,
+}
+
+impl DeviceObjects {
+    /// Picks a device that can present to `window`, or any device
+    /// without one.
+    unsafe fn create(
+        window: Option<&Window>,
+        config: &Config,
+    ) -> Result<Self> {
+        let instance = Rc::new(create_instance(
+            window,
+            create_entry()?,
+            config.renderer.validation,
+            &config.renderer.validation_features,
+            config.renderer.validation_sink.into(),
+        )?);
+        let surface = window
+            .map(|window| create_surface(&instance, window))
+            .transpose()?;
+        let surface_handle = surface
+            .as_ref()
+            .map_or(vk::SurfaceKHR::null(), |s| s.handle());
+        let mut physical_device = vk::PhysicalDevice::null();
+        let mut supported_msaa_samples =
+            vk::SampleCountFlags::default();
         pick_physical_device(
             &instance,
-            data.surface,
-            &mut data.physical_device,
-            &mut data.msaa_samples,
+            surface_handle,
+            config.renderer.device.as_ref(),
+            &mut physical_device,
+            &mut supported_msaa_samples,
         )?;
+        let mut graphics_queue = vk::Queue::null();
+        let mut present_queue = vk::Queue::null();
+        let mut transfer_queue = vk::Queue::null();
+        let mut compute_queue = vk::Queue::null();
         let device = create_logical_device(
-            &entry,
             &instance,
-            data.surface,
-            data.physical_device,
-            &mut data.graphics_queue,
-            &mut data.present_queue,
+            surface_handle,
+            physical_device,
+            &mut graphics_queue,
+            &mut present_queue,
+            &mut transfer_queue,
+            &mut compute_queue,
         )?;
-        create_swapchain(
-            window,
-            &instance,
-            &device,
-            data.surface,
-            data.physical_device,
-            &mut data.swapchain,
-            &mut data.swapchain_images,
-            &mut data.swapchain_format,
-            &mut data.swapchain_extent,
+
+        Ok(Self {
+            surface,
+            device,
+            instance,
+            physical_device,
+            supported_msaa_samples,
+            graphics_queue,
+            present_queue,
+            transfer_queue,
+            compute_queue,
+        })
+    }
+}
+
+/// The CPU side of the scene's mesh and texture.
+struct Scene {
+    mesh: Rc<MeshData>,
+    texture: Rc<TextureData>,
+}
+
+impl Scene {
+    fn load(config: &Config) -> Result<Self> {
+        Ok(Self {
+            mesh: Rc::new(load_model(&config.scene.model)?),
+            texture: Rc::new(TextureData::load(
+                &config.scene.texture,
+            )?),
+        })
+    }
+}
+
+impl App {
+    pub unsafe fn create(
+        window: &Window,
+        config: &Config,
+    ) -> Result<Self> {
+        let _span = tracing::info_span!("create").entered();
+        let scene = Scene::load(config)?;
+        let objects = DeviceObjects::create(Some(window), config)?;
+        let shader_watcher =
+            ShaderWatcher::new(Path::new(SHADER_DIR))
+                .map_err(|e| {
+                    log::warn!("Shader hot reload disabled: {}", e)
+                })
+                .ok();
+
+        let mut app = Self::create_scene(
+            objects,
+            Some(window),
+            config,
+            &scene,
+            shader_watcher.as_ref().map(ShaderWatcher::dir),
         )?;
-        create_swapchain_image_views(
+        app.shader_watcher = shader_watcher;
+
+        Ok(app)
+    }
+
+    /// Creates an app without a window or surface that renders into
+    /// a single offscreen image of the configured window size, see
+    /// `render_offscreen` and `save_offscreen`.
+    pub unsafe fn create_headless(config: &Config) -> Result<Self> {
+        let _span = tracing::info_span!("create_headless").entered();
+        let scene = Scene::load(config)?;
+        let objects = DeviceObjects::create(None, config)?;
+
+        Self::create_scene(objects, None, config, &scene, None)
+    }
+
+    /// Checks every physical device against `window`'s surface, or
+    /// for headless rendering without one.
+    pub unsafe fn list_devices(
+        window: Option<&Window>,
+        validation: bool,
+    ) -> Result<Vec<DeviceReport>> {
+        let instance = Rc::new(create_instance(
+            window,
+            create_entry()?,
+            validation,
+            &[],
+            Arc::new(LogSink),
+        )?);
+        let surface = window
+            .map(|window| create_surface(&instance, window))
+            .transpose()?;
+        let surface_handle = surface
+            .as_ref()
+            .map_or(vk::SurfaceKHR::null(), |s| s.handle());
+
+        Ok(report_physical_devices(&instance, surface_handle)?)
+    }
+
+    /// Creates the swapchain of `window`, or an offscreen target
+    /// without one, and everything drawing `scene` into it. Shaders
+    /// are compiled from `shader_dir`, or from the embedded sources
+    /// without one.
+    unsafe fn create_scene(
+        objects: DeviceObjects,
+        window: Option<&Window>,
+        config: &Config,
+        scene: &Scene,
+        shader_dir: Option<&Path>,
+    ) -> Result<Self> {
+        let DeviceObjects {
+            surface,
+            device,
+            instance,
+            physical_device,
+            supported_msaa_samples,
+            graphics_queue,
+            present_queue,
+            transfer_queue,
+            compute_queue,
+        } = objects;
+        let surface_handle = surface
+            .as_ref()
+            .map_or(vk::SurfaceKHR::null(), |s| s.handle());
+        let allocator =
+            create_allocator(&instance, &device, physical_device);
+        let (swapchain, final_layout) = match window {
+            Some(window) => (
+                create_swapchain(
+                    window,
+                    &instance,
+                    &device,
+                    surface_handle,
+                    physical_device,
+                    &config.renderer.output,
+                    config.renderer.present_mode.into(),
+                    vk::SwapchainKHR::null(),
+                )?,
+                vk::ImageLayout::PRESENT_SRC_KHR,
+            ),
+            None => (
+                create_offscreen_target(
+                    &allocator,
+                    config.window.width,
+                    config.window.height,
+                )?,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            ),
+        };
+        let msaa_samples =
+            config.renderer.msaa.sample_count(supported_msaa_samples);
+        let sample_shading = supported_sample_shading(
             &device,
-            &data.swapchain_images,
-            data.swapchain_format,
-            &mut data.swapchain_image_views,
-        )?;
+            config.renderer.sample_shading(),
+        );
 
-        create_render_pass(
+        let render_pass = create_render_pass(
             &instance,
             &device,
-            data.physical_device,
-            data.swapchain_format,
-            data.msaa_samples,
-            &mut data.render_pass,
-        )?;
-        // create_render_pass_2d(
-        //     &instance,
-        //     &device,
-        //     data.swapchain_format,
-        //     data.msaa_samples,
-        //     &mut data.render_pass,
-        // )?;
-
-        create_descriptor_set_layout(
-            &device,
-            &mut data.descriptor_set_layout,
-            2,
+            physical_device,
+            swapchain.format(),
+            msaa_samples,
+            final_layout,
         )?;
 
-        create_pipeline(
-            &device,
-            &mut data.pipeline,
-            &mut data.pipeline_layout,
-            data.descriptor_set_layout,
-            data.render_pass,
-            data.swapchain_extent,
-            data.msaa_samples,
-        )?;
-        // create_pipeline_2d(
-        //     &device,
-        //     &mut data.pipeline,
-        //     &mut data.pipeline_layout,
-        //     data.descriptor_set_layout,
-        //     data.render_pass,
-        //     data.swapchain_extent,
-        //     data.msaa_samples,
-        // )?;
-
-        create_command_pool(
+        let pipeline_cache = create_pipeline_cache(
             &instance,
             &device,
-            data.surface,
-            data.physical_device,
-            &mut data.command_pool,
+            physical_device,
+            Path::new(PIPELINE_CACHE_PATH),
         )?;
-        create_color_objects(
-            &instance,
+        let shaders = MeshShaders::compile(shader_dir)?;
+        let camera_set_layout = create_descriptor_set_layout(
             &device,
-            &mut data.color_image,
-            &mut data.color_image_memory,
-            &mut data.color_image_view,
-            data.physical_device,
-            data.swapchain_extent,
-            data.swapchain_format,
-            data.msaa_samples,
+            &shaders.reflection,
+            CAMERA_SET,
         )?;
-        create_depth_objects(
-            &instance,
+        let material_set_layout = create_descriptor_set_layout(
             &device,
-            data.physical_device,
-            data.swapchain_extent,
-            data.msaa_samples,
-            &mut data.depth_image,
-            &mut data.depth_image_memory,
-            &mut data.depth_image_view,
+            &shaders.reflection,
+            MATERIAL_SET,
         )?;
 
-        create_framebuffers(
+        let pipeline = Rc::new(create_mesh_pipeline(
             &device,
-            &data.swapchain_image_views,
-            data.color_image_view,
-            data.depth_image_view,
-            data.swapchain_extent,
-            data.render_pass,
-            &mut data.framebuffers,
-        )?;
-        // create_framebuffers_2d(
-        //     &device,
-        //     &data.swapchain_image_views,
-        //     data.color_image_view,
-        //     data.swapchain_extent,
-        //     data.render_pass,
-        //     &mut data.framebuffers,
-        // )?;
-
-        create_texture_image(
+            &shaders,
+            &[
+                camera_set_layout.handle(),
+                material_set_layout.handle(),
+            ],
+            render_pass.handle(),
+            msaa_samples,
+            sample_shading,
+            pipeline_cache.handle(),
+        )?);
+
+        let queue_families = QueueFamilyIndices::get(
             &instance,
-            &device,
-            data.physical_device,
-            data.command_pool,
-            data.graphics_queue,
-            &mut data.mip_levels,
-            &mut data.texture_image,
-            &mut data.texture_image_memory,
+            surface_handle,
+            physical_device,
         )?;
-        create_texture_image_view(
-            &device,
-            &data.texture_image,
-            &data.mip_levels,
-            &mut data.texture_image_view,
-        )?;
-        create_texture_sampler(
+        let command_pool = create_command_pool(
+            &instance,
             &device,
-            &data.mip_levels,
-            &mut data.texture_sampler,
+            surface_handle,
+            physical_device,
         )?;
-
-        load_model(&mut data.vertices, &mut data.indices)?;
-        // create_vertices_2d(&mut data.vertices_2d, &mut data.indices)?;
-
-        create_vertex_buffer(
+        let compute_command_pool = create_compute_command_pool(
             &instance,
             &device,
-            data.physical_device,
-            data.graphics_queue,
-            data.command_pool,
-            &data.vertices,
-            &mut data.vertex_buffer,
-            &mut data.vertex_buffer_memory,
-        )?;
-        // create_vertex_buffer_2d(
-        //     &instance,
-        //     &device,
-        //     data.physical_device,
-        //     data.graphics_queue,
-        //     data.command_pool,
-        //     &data.vertices_2d,
-        //     &mut data.vertex_buffer,
-        //     &mut data.vertex_buffer_memory,
-        // )?;
-        create_index_buffer(
+            surface_handle,
+            physical_device,
+        )?;
+        let color_image = create_color_objects(
+            &allocator,
+            swapchain.extent(),
+            swapchain.format(),
+            msaa_samples,
+        )?;
+        let depth_image = create_depth_objects(
             &instance,
+            &allocator,
+            physical_device,
+            swapchain.extent(),
+            msaa_samples,
+        )?;
+
+        let framebuffers = create_framebuffers(
             &device,
-            data.graphics_queue,
-            data.physical_device,
-            &data.indices,
-            &mut data.index_buffer,
-            &mut data.index_buffer_memory,
-            data.command_pool,
+            swapchain.image_views(),
+            color_image.as_ref().map(|i| i.view()),
+            depth_image.view(),
+            swapchain.extent(),
+            render_pass.handle(),
         )?;
 
-        create_uniform_buffers(
+        let mut uploader = create_uploader(
+            &instance,
+            &allocator,
+            surface_handle,
+            physical_device,
+            graphics_queue,
+            transfer_queue,
+        )?;
+        let material = Rc::new(upload_material(
             &instance,
             &device,
-            &data.swapchain_images,
-            data.physical_device,
-            &mut data.camera_buffers,
-            &mut data.camera_buffers_memory,
-            &mut data.model_buffers,
-            &mut data.model_buffers_memory,
+            &mut uploader,
+            physical_device,
+            material_set_layout.handle(),
+            &shaders.reflection,
+            scene.texture.clone(),
+        )?);
+        let mesh =
+            Rc::new(upload_mesh(&mut uploader, scene.mesh.clone())?);
+        // The scene is drawn from the first frame on.
+        uploader.wait_all()?;
+
+        let mut camera_buffers = vec![];
+        create_uniform_buffers(
+            &allocator,
+            DEFAULT_FRAMES_IN_FLIGHT,
+            &mut camera_buffers,
         )?;
-        create_descriptor_pool(
+        let descriptor_pool = create_descriptor_pool(
             &device,
-            data.swapchain_images.len() as u32,
-            2,
-            &mut data.descriptor_pool,
+            DEFAULT_FRAMES_IN_FLIGHT as u32,
+            &shaders.reflection,
+            CAMERA_SET,
         )?;
 
+        let mut descriptor_sets = vec![];
         create_descriptor_sets(
             &device,
-            data.swapchain_images.len(),
-            data.descriptor_pool,
-            data.descriptor_set_layout,
-            &data.camera_buffers,
-            &data.model_buffers,
-            data.texture_image_view,
-            data.texture_sampler,
-            &mut data.descriptor_sets,
+            DEFAULT_FRAMES_IN_FLIGHT,
+            descriptor_pool.handle(),
+            camera_set_layout.handle(),
+            &shaders.reflection,
+            &camera_buffers,
+            &mut descriptor_sets,
         )?;
-        // create_descriptor_sets_2d(
-        //     &device,
-        //     data.swapchain_images.len(),
-        //     data.descriptor_pool,
-        //     data.descriptor_set_layout,
-        //     &data.uniform_buffers,
-        //     data.texture_image_view,
-        //     data.texture_sampler,
-        //     &mut data.descriptor_sets,
-        // )?;
-
-        create_command_buffers(
+
+        let mut draws = DrawList::new();
+        let scene_draw = draws.add(
+            Draw::new(pipeline.clone(), mesh.clone())
+                .material(material.clone()),
+        );
+        let frame_commands = create_frame_commands(
+            &instance,
             &device,
-            data.command_pool,
-            &data.framebuffers,
-            data.render_pass,
-            data.pipeline,
-            data.pipeline_layout,
-            data.vertex_buffer,
-            data.index_buffer,
-            &data.indices,
-            data.swapchain_extent,
-            &data.descriptor_sets,
-            &mut data.command_buffers,
-        )?;
-        // create_command_buffers_2d(
-        //     &device,
-        //     data.command_pool,
-        //     &data.framebuffers,
-        //     data.render_pass,
-        //     data.pipeline,
-        //     data.pipeline_layout,
-        //     data.vertex_buffer,
-        //     data.index_buffer,
-        //     &data.indices,
-        //     data.swapchain_extent,
-        //     &data.descriptor_sets,
-        //     &mut data.command_buffers,
-        // )?;
+            surface_handle,
+            physical_device,
+            DEFAULT_FRAMES_IN_FLIGHT,
+        )?;
 
+        let mut image_available_semaphores = vec![];
+        let mut render_finished_semaphores = vec![];
+        let mut in_flight_fences = vec![];
+        let mut images_in_flight = vec![];
         create_sync_objects(
             &device,
-            &data.swapchain_images,
-            &mut data.image_available_semaphores,
-            &mut data.render_finished_semaphores,
-            &mut data.in_flight_fences,
-            &mut data.images_in_flight,
+            swapchain.images(),
+            DEFAULT_FRAMES_IN_FLIGHT,
+            &mut image_available_semaphores,
+            &mut render_finished_semaphores,
+            &mut in_flight_fences,
+            &mut images_in_flight,
         )?;
+        let compute_semaphores = create_compute_semaphores(
+            &device,
+            DEFAULT_FRAMES_IN_FLIGHT,
+        )?;
+        let profiler = create_gpu_profiler(
+            &instance,
+            &device,
+            physical_device,
+            queue_families.graphics,
+            DEFAULT_FRAMES_IN_FLIGHT,
+            config.renderer.pipeline_statistics,
+        )?;
+
+        let data = AppData {
+            physical_device,
+            msaa_samples,
+            supported_msaa_samples,
+            sample_shading,
+            present_mode: config.renderer.present_mode.into(),
+            outputs: config.renderer.output.clone(),
+            paper_white: config.renderer.paper_white,
+            graphics_queue,
+            present_queue,
+            compute_queue,
+            queue_families,
+            shader_reflection: shaders.reflection,
+            profiler,
+            draw_counters: DrawCounters::default(),
+            frame_commands,
+            draws,
+            scene_draw,
+            descriptor_sets,
+            descriptor_pool,
+            camera_buffers,
+            framebuffers,
+            pipeline,
+            render_pass,
+            color_image,
+            depth_image,
+            swapchain,
+            mesh,
+            material,
+            camera_set_layout,
+            material_set_layout,
+            pipeline_cache,
+            image_available_semaphores,
+            render_finished_semaphores,
+            in_flight_fences,
+            images_in_flight,
+            compute_semaphores,
+            compute_handoffs: None,
+            compute_command_pool,
+            pending_mesh: None,
+            pending_texture: None,
+            uploader,
+            command_pool,
+            allocator,
+        };
+
         Ok(Self {
-            _entry: entry,
-            instance,
             data,
+            surface,
             device,
+            instance,
+            shader_watcher: None,
             frame: 0,
             resized: false,
             start: Instant::now(),
+            frame_timer: FrameTimer::default(),
             camera_direction: vec3(1.0, 0.0, 0.0),
             camera_alt_direction: vec3(0.0, 1.0, 0.0),
             camera_up_direction: vec3(0.0, 0.0, 1.0),
             camera_position: point3(1.0, 1.0, 1.0),
+            config: config.clone(),
+            device_generation: 0,
+            device_lost: false,
+            device_retry: DeviceRetry::default(),
         })
     }
 
-    unsafe fn update_uniform_buffer(
-        &self,
-        image_index: usize,
-    ) -> Result<()> {
+    /// Writes this frame's uniforms and spins the scene draw, the
+    /// frame's fence has to be signaled.
+    unsafe fn update_uniform_buffer(&mut self) -> Result<()> {
         let time = self.start.elapsed().as_secs_f32();
 
         let model = Mat4::from_axis_angle(
@@ -466,44 +803,30 @@ impl App {
             0.0, 0.0, 0.5, 0.0, //
             0.0, 0.0, 0.5, 1.0,
         );
+        let extent = self.data.swapchain.extent();
         let proj = cgmath::perspective(
             Deg(45.0),
-            self.data.swapchain_extent.width as f32
-                / self.data.swapchain_extent.height as f32,
+            extent.width as f32 / extent.height as f32,
             0.1,
             20.0,
         );
 
+        let output = self.data.swapchain.output();
         let camera_obj = CameraObject {
             view,
             proj,
             correction,
+            output_encoding: output.encoding as u32,
+            paper_white: self.data.paper_white,
+            _padding: [0; 2],
         };
 
-        let model_obj = ModelObject { model };
-
-        let camera_memory = self.device.map_memory(
-            self.data.camera_buffers_memory[image_index],
-            0,
-            size_of::<CameraObject>() as u64,
-            vk::MemoryMapFlags::empty(),
-        )?;
-        let model_memory = self.device.map_memory(
-            self.data.model_buffers_memory[image_index],
-            0,
-            size_of::<ModelObject>() as u64,
-            vk::MemoryMapFlags::empty(),
-        )?;
-
-        memcpy(&camera_obj, camera_memory.cast(), 1);
-        memcpy(&model_obj, model_memory.cast(), 1);
-
-        self.device.unmap_memory(
-            self.data.camera_buffers_memory[image_index],
-        );
-        self.device.unmap_memory(
-            self.data.model_buffers_memory[image_index],
-        );
+        self.data.camera_buffers[self.frame].write(&[camera_obj])?;
+        if let Some(draw) =
+            self.data.draws.get_mut(self.data.scene_draw)
+        {
+            draw.transform = model;
+        }
 
         Ok(())
     }
@@ -512,171 +835,414 @@ impl App {
         &mut self,
         window: &Window,
     ) -> Result<()> {
+        let _span =
+            tracing::info_span!("recreate_swapchain").entered();
         log::debug!("Recreating swapchain.");
         self.device.device_wait_idle()?;
-        self.destroy_swapchain();
+        let surface = self
+            .surface
+            .as_ref()
+            .map_or(vk::SurfaceKHR::null(), |s| s.handle());
         let instance = &self.instance;
         let device = &self.device;
         let data = &mut self.data;
-        create_swapchain(
+        let old_format = data.swapchain.format();
+        // Assigning drops the replaced object, nothing is in use
+        // after waiting for the device.
+        data.swapchain = create_swapchain(
             window,
-            &instance,
-            &device,
-            data.surface,
+            instance,
+            device,
+            surface,
             data.physical_device,
-            &mut data.swapchain,
-            &mut data.swapchain_images,
-            &mut data.swapchain_format,
-            &mut data.swapchain_extent,
-        )?;
-        create_swapchain_image_views(
-            &device,
-            &data.swapchain_images,
-            data.swapchain_format,
-            &mut data.swapchain_image_views,
+            &data.outputs,
+            data.present_mode,
+            data.swapchain.handle(),
         )?;
-        create_render_pass(
-            &instance,
-            &device,
-            data.physical_device,
-            data.swapchain_format,
-            data.msaa_samples,
-            &mut data.render_pass,
-        )?;
-        // create_render_pass_2d(
-        //     &instance,
-        //     &device,
-        //     data.swapchain_format,
-        //     data.msaa_samples,
-        //     &mut data.render_pass,
-        // )?;
-
-        create_pipeline(
-            &device,
-            &mut data.pipeline,
-            &mut data.pipeline_layout,
-            data.descriptor_set_layout,
-            data.render_pass,
-            data.swapchain_extent,
-            data.msaa_samples,
-        )?;
-        // create_pipeline_2d(
-        //     &device,
-        //     &mut data.pipeline,
-        //     &mut data.pipeline_layout,
-        //     data.descriptor_set_layout,
-        //     data.render_pass,
-        //     data.swapchain_extent,
-        //     data.msaa_samples,
-        // )?;
-
-        create_color_objects(
-            &instance,
-            &device,
-            &mut data.color_image,
-            &mut data.color_image_memory,
-            &mut data.color_image_view,
-            data.physical_device,
-            data.swapchain_extent,
-            data.swapchain_format,
-            data.msaa_samples,
+
+        // Viewport and scissor are dynamic, the render pass and
+        // pipeline only depend on the format.
+        let format_changed = data.swapchain.format() != old_format;
+        self.recreate_render_targets(
+            self.data.msaa_samples,
+            format_changed,
         )?;
-        create_depth_objects(
-            &instance,
-            &device,
-            data.physical_device,
-            data.swapchain_extent,
-            data.msaa_samples,
-            &mut data.depth_image,
-            &mut data.depth_image_memory,
-            &mut data.depth_image_view,
+
+        self.data.images_in_flight =
+            vec![
+                vk::Fence::null();
+                self.data.swapchain.images().len()
+            ];
+
+        Ok(())
+    }
+
+    /// Replaces a lost surface, the swapchain using it goes first.
+    unsafe fn recreate_surface(
+        &mut self,
+        window: &Window,
+    ) -> Result<()> {
+        self.device.device_wait_idle()?;
+        self.data.framebuffers.clear();
+        self.data.swapchain.destroy();
+        self.surface = None;
+        self.surface = Some(create_surface(&self.instance, window)?);
+        self.recreate_swapchain(window)
+    }
+
+    /// Replaces the lost device once `device_retry` is due. A failure
+    /// is logged and leaves the app in place for the next call, until
+    /// the retries run out.
+    unsafe fn retry_device(
+        &mut self,
+        window: Option<&Window>,
+    ) -> Result<()> {
+        if !self.device_retry.is_due(Instant::now()) {
+            return Ok(());
+        }
+        match self.recreate_device(window) {
+            Err(e) if self.device_lost => {
+                match self.device_retry.failed(Instant::now()) {
+                    Some(delay) => {
+                        log::error!(
+                            "Failed to replace the lost device, retrying in {:?}: {}",
+                            delay,
+                            e
+                        );
+                        Ok(())
+                    }
+                    None => Err(e),
+                }
+            }
+            result => result,
+        }
+    }
+
+    /// Replaces the app after a device loss with one rebuilt from the
+    /// CPU side of the scene's mesh and texture and of the draws,
+    /// keeping the camera, clock and settings. If no device can be
+    /// created the old app stays in place with `device_lost` set.
+    unsafe fn recreate_device(
+        &mut self,
+        window: Option<&Window>,
+    ) -> Result<()> {
+        let _span = tracing::info_span!("recreate_device").entered();
+        self.device_lost = true;
+        let mut config = self.config.clone();
+        config.renderer.msaa = Msaa::Samples(self.data.msaa_samples);
+        config.renderer.sample_shading =
+            self.data.sample_shading.unwrap_or(0.0);
+        config.renderer.output = self.data.outputs.clone();
+        config.renderer.paper_white = self.data.paper_white;
+        let scene = Scene {
+            mesh: match self.data.mesh.source() {
+                Some(mesh) => mesh.clone(),
+                None => Rc::new(load_model(&config.scene.model)?),
+            },
+            texture: self.data.material.source().clone(),
+        };
+
+        let objects = DeviceObjects::create(window, &config)?;
+        // A window can only have one swapchain, the old one goes once
+        // there is a device for its replacement. If creating the rest
+        // fails the old app stays with `device_lost` set and without
+        // a swapchain, which the next try doesn't need. Everything
+        // else, including the old surface, is destroyed with the old
+        // app, which is allowed on a lost device.
+        if window.is_some() {
+            self.data.framebuffers.clear();
+            self.data.swapchain.destroy();
+        }
+        let mut app = Self::create_scene(
+            objects,
+            window,
+            &config,
+            &scene,
+            self.shader_dir(),
         )?;
+        app.restore_draws(&self.data)?;
+        if app.frames_in_flight() != self.frames_in_flight() {
+            app.set_frames_in_flight(self.frames_in_flight())?;
+        }
+        if app.data.present_mode != self.data.present_mode {
+            app.set_present_mode(self.data.present_mode);
+        }
+        app.shader_watcher = self.shader_watcher.take();
+        app.start = self.start;
+        app.frame_timer = self.frame_timer.clone();
+        app.camera_direction = self.camera_direction;
+        app.camera_alt_direction = self.camera_alt_direction;
+        app.camera_up_direction = self.camera_up_direction;
+        app.camera_position = self.camera_position;
+        app.config = self.config.clone();
+        app.instance.set_sink(self.instance.sink());
+        app.device_generation = self.device_generation + 1;
+        *self = app;
 
-        create_framebuffers(
-            &device,
-            &data.swapchain_image_views,
-            data.color_image_view,
-            data.depth_image_view,
-            data.swapchain_extent,
-            data.render_pass,
-            &mut data.framebuffers,
-        )?;
-        // create_framebuffers_2d(
-        //     &device,
-        //     &data.swapchain_image_views,
-        //     data.color_image_view,
-        //     data.swapchain_extent,
-        //     data.render_pass,
-        //     &mut data.framebuffers,
-        // )?;
+        Ok(())
+    }
 
-        create_uniform_buffers(
-            &instance,
-            &device,
-            &data.swapchain_images,
+    /// Rebuilds the draws of `old`, the data of an app on a lost
+    /// device, keeping their IDs. Meshes and materials are uploaded
+    /// again from their sources, the scene's are replaced by this
+    /// app's. Draws with a pipeline other than the mesh pipeline or a
+    /// mesh without a source are dropped.
+    unsafe fn restore_draws(&mut self, old: &AppData) -> Result<()> {
+        let data = &mut self.data;
+        let mut meshes = HashMap::from([(
+            Rc::as_ptr(&old.mesh),
+            data.mesh.clone(),
+        )]);
+        let mut materials = HashMap::from([(
+            Rc::as_ptr(&old.material),
+            data.material.clone(),
+        )]);
+        for (_, draw) in old.draws.iter() {
+            if !Rc::ptr_eq(&draw.pipeline, &old.pipeline) {
+                continue;
+            }
+            if let (Entry::Vacant(entry), Some(source)) = (
+                meshes.entry(Rc::as_ptr(&draw.mesh)),
+                draw.mesh.source(),
+            ) {
+                let mesh =
+                    upload_mesh(&mut data.uploader, source.clone())?;
+                entry.insert(Rc::new(mesh));
+            }
+            let Some(material) = &draw.material else {
+                continue;
+            };
+            if let Entry::Vacant(entry) =
+                materials.entry(Rc::as_ptr(material))
+            {
+                entry.insert(Rc::new(upload_material(
+                    &self.instance,
+                    &self.device,
+                    &mut data.uploader,
+                    data.physical_device,
+                    data.material_set_layout.handle(),
+                    &data.shader_reflection,
+                    material.source().clone(),
+                )?));
+            }
+        }
+        data.uploader.wait_all()?;
+
+        let pipeline = &data.pipeline;
+        data.draws = old.draws.filter_map(|draw| {
+            let mesh = meshes
+                .get(&Rc::as_ptr(&draw.mesh))
+                .filter(|_| Rc::ptr_eq(&draw.pipeline, &old.pipeline));
+            let Some(mesh) = mesh else {
+                log::warn!(
+                    "A draw can't be rebuilt on the new device, dropping it."
+                );
+                return None;
+            };
+            Some(Draw {
+                pipeline: pipeline.clone(),
+                mesh: mesh.clone(),
+                material: draw.material.as_ref().map(|material| {
+                    materials[&Rc::as_ptr(material)].clone()
+                }),
+                transform: draw.transform,
+                visible: draw.visible,
+            })
+        });
+        data.scene_draw = old.scene_draw;
+
+        Ok(())
+    }
+
+    /// Recreates the color and depth targets and the framebuffers for
+    /// `msaa_samples`, and with `render_pass` the render pass and mesh
+    /// pipeline too. Everything is built before it replaces anything,
+    /// so the app is unchanged if this fails. The device has to be
+    /// idle.
+    unsafe fn recreate_render_targets(
+        &mut self,
+        msaa_samples: vk::SampleCountFlags,
+        render_pass: bool,
+    ) -> Result<()> {
+        let instance = &self.instance;
+        let device = &self.device;
+        let data = &mut self.data;
+        let shader_dir =
+            self.shader_watcher.as_ref().map(ShaderWatcher::dir);
+        let pass = if render_pass {
+            let final_layout = if self.surface.is_some() {
+                vk::ImageLayout::PRESENT_SRC_KHR
+            } else {
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL
+            };
+            let render_pass = create_render_pass(
+                instance,
+                device,
+                data.physical_device,
+                data.swapchain.format(),
+                msaa_samples,
+                final_layout,
+            )?;
+            let pipeline = create_mesh_pipeline(
+                device,
+                &MeshShaders::compile(shader_dir)?,
+                &data.mesh_set_layouts(),
+                render_pass.handle(),
+                msaa_samples,
+                data.sample_shading,
+                data.pipeline_cache.handle(),
+            )?;
+            Some((render_pass, Rc::new(pipeline)))
+        } else {
+            None
+        };
+        let render_pass_handle = pass
+            .as_ref()
+            .map_or(data.render_pass.handle(), |(r, _)| r.handle());
+
+        let color_image = create_color_objects(
+            &data.allocator,
+            data.swapchain.extent(),
+            data.swapchain.format(),
+            msaa_samples,
+        )?;
+        let depth_image = create_depth_objects(
+            instance,
+            &data.allocator,
             data.physical_device,
-            &mut data.camera_buffers,
-            &mut data.camera_buffers_memory,
-            &mut data.model_buffers,
-            &mut data.model_buffers_memory,
+            data.swapchain.extent(),
+            msaa_samples,
         )?;
-        create_descriptor_pool(
-            &device,
-            data.swapchain_images.len() as u32,
-            2,
-            &mut data.descriptor_pool,
+        let framebuffers = create_framebuffers(
+            device,
+            data.swapchain.image_views(),
+            color_image.as_ref().map(|i| i.view()),
+            depth_image.view(),
+            data.swapchain.extent(),
+            render_pass_handle,
         )?;
 
-        create_descriptor_sets(
-            &device,
-            data.swapchain_images.len(),
-            data.descriptor_pool,
-            data.descriptor_set_layout,
-            &data.camera_buffers,
-            &data.model_buffers,
-            data.texture_image_view,
-            data.texture_sampler,
-            &mut data.descriptor_sets,
-        )?;
-        // create_descriptor_sets_2d(
-        //     &device,
-        //     data.swapchain_images.len(),
-        //     data.descriptor_pool,
-        //     data.descriptor_set_layout,
-        //     &data.uniform_buffers,
-        //     data.texture_image_view,
-        //     data.texture_sampler,
-        //     &mut data.descriptor_sets,
-        // )?;
-        create_command_buffers(
-            &device,
-            data.command_pool,
-            &data.framebuffers,
-            data.render_pass,
-            data.pipeline,
-            data.pipeline_layout,
-            data.vertex_buffer,
-            data.index_buffer,
-            &data.indices,
-            data.swapchain_extent,
-            &data.descriptor_sets,
-            &mut data.command_buffers,
-        )?;
+        data.framebuffers = framebuffers;
+        data.color_image = color_image;
+        data.depth_image = depth_image;
+        if let Some((render_pass, pipeline)) = pass {
+            data.draws.replace_pipeline(&data.pipeline, &pipeline);
+            data.pipeline = pipeline;
+            data.render_pass = render_pass;
+        }
+        data.msaa_samples = msaa_samples;
+
+        Ok(())
+    }
+
+    /// Samples per pixel of the color and depth targets.
+    pub fn msaa_samples(&self) -> vk::SampleCountFlags {
+        self.data.msaa_samples
+    }
+
+    /// Changes the sample count to the largest supported one up to
+    /// `msaa` and rebuilds the targets, render pass and mesh
+    /// pipeline. Pipelines in the draw list other than
+    /// `mesh_pipeline` have to be rebuilt for the new sample count by
+    /// the caller.
+    pub unsafe fn set_msaa(&mut self, msaa: Msaa) -> Result<()> {
+        let msaa_samples =
+            msaa.sample_count(self.data.supported_msaa_samples);
+        if msaa_samples == self.data.msaa_samples {
+            return Ok(());
+        }
+        log::info!("Using msaa x{}", msaa_samples.bits());
+        self.device.device_wait_idle()?;
+        self.recreate_render_targets(msaa_samples, true)
+    }
+
+    /// Enables sample shading with MSAA, shading at least
+    /// `min_sample_shading` (0 to 1) of the samples of each pixel.
+    pub unsafe fn set_sample_shading(
+        &mut self,
+        min_sample_shading: Option<f32>,
+    ) -> Result<()> {
+        let sample_shading = supported_sample_shading(
+            &self.device,
+            min_sample_shading,
+        );
+        let pipeline = Rc::new(create_mesh_pipeline(
+            &self.device,
+            &MeshShaders::compile(self.shader_dir())?,
+            &self.data.mesh_set_layouts(),
+            self.data.render_pass.handle(),
+            self.data.msaa_samples,
+            sample_shading,
+            self.data.pipeline_cache.handle(),
+        )?);
+        self.device.device_wait_idle()?;
+        self.data
+            .draws
+            .replace_pipeline(&self.data.pipeline, &pipeline);
+        self.data.pipeline = pipeline;
+        self.data.sample_shading = sample_shading;
 
         Ok(())
     }
 
+    /// Renders a frame. A lost surface is recreated and a lost device
+    /// replaced along with everything created from it, see
+    /// `device_generation`. A failed replacement is logged and
+    /// retried by later calls with a growing delay, the error is only
+    /// returned once `MAX_DEVICE_RETRIES` attempts failed. Other
+    /// errors are returned. Panics on a validation error if the sink
+    /// asks for it.
     pub unsafe fn render(&mut self, window: &Window) -> Result<()> {
+        let _span = tracing::info_span!("render").entered();
+        self.frame_timer.begin_frame();
+        let result = if self.device_lost {
+            self.retry_device(Some(window))
+        } else {
+            match self.render_frame(window) {
+                Err(e)
+                    if e.vk_error_code()
+                        == Some(vk::ErrorCode::SURFACE_LOST_KHR) =>
+                {
+                    log::warn!("Surface lost, recreating it.");
+                    self.recreate_surface(window)
+                }
+                Err(e)
+                    if e.vk_error_code()
+                        == Some(vk::ErrorCode::DEVICE_LOST) =>
+                {
+                    log::error!(
+                        "Device lost, rebuilding the renderer."
+                    );
+                    self.retry_device(Some(window))
+                }
+                result => result,
+            }
+        };
+        self.instance.check_validation();
+
+        result
+    }
+
+    unsafe fn render_frame(&mut self, window: &Window) -> Result<()> {
+        self.reload_shaders()?;
+        self.apply_uploads()?;
+        let mut timings = CpuTimings::default();
+
+        let in_flight_fence =
+            self.data.in_flight_fences[self.frame].handle();
+        let phase = Phase::begin(tracing::info_span!("fence_wait"));
         self.device.wait_for_fences(
-            &[self.data.in_flight_fences[self.frame]],
+            &[in_flight_fence],
             true,
             u64::MAX,
         )?;
+        timings.fence_wait = phase.end();
 
+        let phase = Phase::begin(tracing::info_span!("acquire"));
         let image_index = match self.device.acquire_next_image_khr(
-            self.data.swapchain,
+            self.data.swapchain.handle(),
             u64::MAX,
-            self.data.image_available_semaphores[self.frame],
+            self.data.image_available_semaphores[self.frame].handle(),
             vk::Fence::null(),
         ) {
             Ok((i, _)) => i as usize,
@@ -685,56 +1251,74 @@ impl App {
             }
             Err(e) => return Err(e.into()),
         };
+        timings.acquire = phase.end();
 
-        if !self.data.images_in_flight[image_index as usize].is_null()
-        {
+        if !self.data.images_in_flight[image_index].is_null() {
+            let phase =
+                Phase::begin(tracing::info_span!("image_fence_wait"));
             self.device.wait_for_fences(
-                &[self.data.images_in_flight[image_index as usize]],
+                &[self.data.images_in_flight[image_index]],
                 true,
                 u64::MAX,
             )?;
+            timings.fence_wait += phase.end();
         }
 
-        self.data.images_in_flight[image_index as usize] =
-            self.data.in_flight_fences[self.frame];
-
-        self.update_uniform_buffer(image_index)?;
-
-        let wait_semaphores =
-            &[self.data.image_available_semaphores[self.frame]];
-        let wait_stages =
-            &[vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT];
-        let command_buffers =
-            &[self.data.command_buffers[image_index as usize]];
+        self.data.images_in_flight[image_index] = in_flight_fence;
+
+        let phase =
+            Phase::begin(tracing::info_span!("uniform_update"));
+        self.update_uniform_buffer()?;
+        timings.uniform_update = phase.end();
+        let phase = Phase::begin(tracing::info_span!("record"));
+        let command_buffer = self.record_frame(image_index)?;
+        timings.record = phase.end();
+
+        let mut wait_semaphores =
+            vec![self.data.image_available_semaphores[self.frame]
+                .handle()];
+        let mut wait_stages =
+            vec![vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT];
+        if self.data.compute_handoffs.take().is_some() {
+            wait_semaphores.push(
+                self.data.compute_semaphores[self.frame].handle(),
+            );
+            wait_stages.push(vk::PipelineStageFlags::ALL_COMMANDS);
+        }
+        let command_buffers = &[command_buffer];
         let signal_semaphores =
-            &[self.data.render_finished_semaphores[self.frame]];
+            &[self.data.render_finished_semaphores[self.frame]
+                .handle()];
         let submit_info = vk::SubmitInfo::builder()
-            .wait_semaphores(wait_semaphores)
-            .wait_dst_stage_mask(wait_stages)
+            .wait_semaphores(&wait_semaphores)
+            .wait_dst_stage_mask(&wait_stages)
             .command_buffers(command_buffers)
             .signal_semaphores(signal_semaphores);
 
-        self.device.reset_fences(&[
-            self.data.in_flight_fences[self.frame]
-        ])?;
+        let phase = Phase::begin(tracing::info_span!("submit"));
+        self.device.reset_fences(&[in_flight_fence])?;
 
         self.device.queue_submit(
             self.data.graphics_queue,
             &[submit_info],
-            self.data.in_flight_fences[self.frame],
+            in_flight_fence,
         )?;
+        timings.submit = phase.end();
 
-        let swapchains = &[self.data.swapchain];
+        let swapchains = &[self.data.swapchain.handle()];
         let image_indices = &[image_index as u32];
         let present_info = vk::PresentInfoKHR::builder()
             .wait_semaphores(signal_semaphores)
             .swapchains(swapchains)
             .image_indices(image_indices);
 
+        let phase = Phase::begin(tracing::info_span!("present"));
         let result = self.device.queue_present_khr(
             self.data.present_queue,
             &present_info,
         );
+        timings.present = phase.end();
+        self.frame_timer.end_frame(timings);
 
         let changed = result == Ok(vk::SuccessCode::SUBOPTIMAL_KHR)
             || result == Err(vk::ErrorCode::OUT_OF_DATE_KHR);
@@ -746,112 +1330,601 @@ impl App {
             return Err(e.into());
         }
 
-        self.device.queue_wait_idle(self.data.present_queue)?;
-        self.frame = (self.frame + 1) % MAX_FRAMES_IN_FLIGHT;
+        self.frame = (self.frame + 1) % self.frames_in_flight();
 
         Ok(())
     }
 
-    pub unsafe fn destroy(&mut self) {
-        log::debug!("Destroying application.");
-        self.device.device_wait_idle().unwrap();
+    /// Renders a frame into the offscreen image of a headless app,
+    /// replacing the device if it is lost like `render`. Panics on a
+    /// validation error if the sink asks for it.
+    pub unsafe fn render_offscreen(&mut self) -> Result<()> {
+        let _span = tracing::info_span!("render_offscreen").entered();
+        self.frame_timer.begin_frame();
+        let result = if self.device_lost {
+            self.retry_device(None)
+        } else {
+            match self.render_offscreen_frame() {
+                Err(e)
+                    if e.vk_error_code()
+                        == Some(vk::ErrorCode::DEVICE_LOST) =>
+                {
+                    log::error!(
+                        "Device lost, rebuilding the renderer."
+                    );
+                    self.retry_device(None)
+                }
+                result => result,
+            }
+        };
+        self.instance.check_validation();
 
-        self.destroy_swapchain();
+        result
+    }
 
-        self.data
-            .in_flight_fences
-            .iter()
-            .for_each(|f| self.device.destroy_fence(*f, None));
-        self.data
-            .render_finished_semaphores
-            .iter()
-            .for_each(|s| self.device.destroy_semaphore(*s, None));
-        self.data
-            .image_available_semaphores
-            .iter()
-            .for_each(|s| self.device.destroy_semaphore(*s, None));
+    unsafe fn render_offscreen_frame(&mut self) -> Result<()> {
+        self.apply_uploads()?;
+        let mut timings = CpuTimings::default();
+
+        let in_flight_fence =
+            self.data.in_flight_fences[self.frame].handle();
+        let phase = Phase::begin(tracing::info_span!("fence_wait"));
+        self.device.wait_for_fences(
+            &[in_flight_fence],
+            true,
+            u64::MAX,
+        )?;
+        timings.fence_wait = phase.end();
+
+        let phase =
+            Phase::begin(tracing::info_span!("uniform_update"));
+        self.update_uniform_buffer()?;
+        timings.uniform_update = phase.end();
+        let phase = Phase::begin(tracing::info_span!("record"));
+        let command_buffer = self.record_frame(0)?;
+        timings.record = phase.end();
+
+        let mut wait_semaphores = vec![];
+        if self.data.compute_handoffs.take().is_some() {
+            wait_semaphores.push(
+                self.data.compute_semaphores[self.frame].handle(),
+            );
+        }
+        let wait_stages = vec![
+            vk::PipelineStageFlags::ALL_COMMANDS;
+            wait_semaphores.len()
+        ];
+        let command_buffers = &[command_buffer];
+        let submit_info = vk::SubmitInfo::builder()
+            .wait_semaphores(&wait_semaphores)
+            .wait_dst_stage_mask(&wait_stages)
+            .command_buffers(command_buffers);
 
-        self.device.free_memory(self.data.index_buffer_memory, None);
-        self.device.destroy_buffer(self.data.index_buffer, None);
+        let phase = Phase::begin(tracing::info_span!("submit"));
+        self.device.reset_fences(&[in_flight_fence])?;
+        self.device.queue_submit(
+            self.data.graphics_queue,
+            &[submit_info],
+            in_flight_fence,
+        )?;
+        timings.submit = phase.end();
+        // Offscreen frames are finished before returning.
+        let phase = Phase::begin(tracing::info_span!("fence_wait"));
+        self.device.wait_for_fences(
+            &[in_flight_fence],
+            true,
+            u64::MAX,
+        )?;
+        timings.fence_wait += phase.end();
+        self.frame_timer.end_frame(timings);
 
-        self.device
-            .free_memory(self.data.vertex_buffer_memory, None);
-        self.device.destroy_buffer(self.data.vertex_buffer, None);
+        self.frame = (self.frame + 1) % self.frames_in_flight();
 
-        self.device.destroy_descriptor_set_layout(
-            self.data.descriptor_set_layout,
-            None,
-        );
+        Ok(())
+    }
 
-        self.device.destroy_sampler(self.data.texture_sampler, None);
-        self.device
-            .destroy_image_view(self.data.texture_image_view, None);
-        self.device
-            .free_memory(self.data.texture_image_memory, None);
-        self.device.destroy_image(self.data.texture_image, None);
+    /// Writes the last frame rendered by `render_offscreen` to `path`
+    /// as a PNG.
+    pub unsafe fn save_offscreen(&self, path: &Path) -> Result<()> {
+        save_offscreen_image(
+            &self.data.allocator,
+            self.data.command_pool.handle(),
+            self.data.graphics_queue,
+            self.data.swapchain.images()[0],
+            self.data.swapchain.extent(),
+            path,
+        )?;
 
-        self.device
-            .destroy_command_pool(self.data.command_pool, None);
+        Ok(())
+    }
 
-        self.device.destroy_device(None);
-        self.instance.destroy_surface_khr(self.data.surface, None);
+    /// Uploads a mesh that can be drawn by adding a `Draw` to
+    /// `draws_mut`, e.g. with `mesh_pipeline`. Blocks until the
+    /// upload finished, see `load_mesh` for streaming.
+    pub unsafe fn create_mesh(
+        &mut self,
+        vertices: &[Vertex3],
+        indices: &[u32],
+    ) -> Result<Rc<Mesh>> {
+        let source = Rc::new(MeshData {
+            vertices: vertices.to_vec(),
+            indices: indices.to_vec(),
+        });
+        let uploader = &mut self.data.uploader;
+        let mesh = upload_mesh(uploader, source)?;
+        let ticket = uploader.flush()?;
+        uploader.wait(ticket)?;
+
+        Ok(Rc::new(mesh))
+    }
 
-        destroy_debug_utils_messenger_ext(
+    /// Loads the PNG file at `path` into a material that can be set
+    /// on a `Draw` with `mesh_pipeline`. Blocks until the upload
+    /// finished, see `load_texture` for streaming.
+    pub unsafe fn create_material(
+        &mut self,
+        path: &Path,
+    ) -> Result<Rc<Material>> {
+        let texture = Rc::new(TextureData::load(path)?);
+        let data = &mut self.data;
+        let material = upload_material(
             &self.instance,
-            self.data.messenger,
-        );
+            &self.device,
+            &mut data.uploader,
+            data.physical_device,
+            data.material_set_layout.handle(),
+            &data.shader_reflection,
+            texture,
+        )?;
+        let ticket = data.uploader.flush()?;
+        data.uploader.wait(ticket)?;
 
-        self.instance.destroy_instance(None);
+        Ok(Rc::new(material))
     }
 
-    unsafe fn destroy_swapchain(&mut self) {
-        self.device.free_command_buffers(
-            self.data.command_pool,
-            &self.data.command_buffers,
-        );
-        self.data
-            .camera_buffers_memory
-            .iter()
-            .for_each(|m| self.device.free_memory(*m, None));
-        self.data
-            .camera_buffers
-            .iter()
-            .for_each(|b| self.device.destroy_buffer(*b, None));
-        self.data
-            .model_buffers_memory
-            .iter()
-            .for_each(|m| self.device.free_memory(*m, None));
-        self.data
-            .model_buffers
-            .iter()
-            .for_each(|b| self.device.destroy_buffer(*b, None));
-
-        self.device.destroy_pipeline(self.data.pipeline, None);
-        self.device
-            .destroy_pipeline_layout(self.data.pipeline_layout, None);
-        self.device.destroy_render_pass(self.data.render_pass, None);
-        self.device
-            .destroy_descriptor_pool(self.data.descriptor_pool, None);
-        self.device
-            .destroy_image_view(self.data.depth_image_view, None);
-        self.device.free_memory(self.data.depth_image_memory, None);
-        self.device.destroy_image(self.data.depth_image, None);
-
-        self.device
-            .destroy_image_view(self.data.color_image_view, None);
-        self.device.free_memory(self.data.color_image_memory, None);
-        self.device.destroy_image(self.data.color_image, None);
+    /// Starts streaming in the OBJ file at `path`, the rendered mesh
+    /// is replaced once the upload completes while frames keep being
+    /// drawn with the old one.
+    pub unsafe fn load_mesh(
+        &mut self,
+        path: &Path,
+    ) -> Result<UploadTicket> {
+        let _span = tracing::info_span!("load_mesh").entered();
+        let source = Rc::new(load_model(path)?);
+        self.config.scene.model = path.to_path_buf();
+
+        let uploader = &mut self.data.uploader;
+        let mesh = upload_mesh(uploader, source)?;
+        let ticket = uploader.flush()?;
+        // A superseded upload may still be writing its buffers.
+        if let Some(pending) = self.data.pending_mesh.take() {
+            uploader.wait(pending.ticket)?;
+        }
+        self.data.pending_mesh = Some(PendingMesh {
+            ticket,
+            mesh: Rc::new(mesh),
+        });
 
-        self.data
-            .framebuffers
-            .iter()
-            .for_each(|f| self.device.destroy_framebuffer(*f, None));
+        Ok(ticket)
+    }
+
+    /// Starts streaming in the PNG file at `path`, the mesh texture
+    /// is replaced once the upload completes.
+    pub unsafe fn load_texture(
+        &mut self,
+        path: &Path,
+    ) -> Result<UploadTicket> {
+        let _span = tracing::info_span!("load_texture").entered();
+        let texture = Rc::new(TextureData::load(path)?);
+        let data = &mut self.data;
+        let material = upload_material(
+            &self.instance,
+            &self.device,
+            &mut data.uploader,
+            data.physical_device,
+            data.material_set_layout.handle(),
+            &data.shader_reflection,
+            texture,
+        )?;
+        self.config.scene.texture = path.to_path_buf();
+        let ticket = data.uploader.flush()?;
+        if let Some(pending) = data.pending_texture.take() {
+            data.uploader.wait(pending.ticket)?;
+        }
+        data.pending_texture = Some(PendingTexture {
+            ticket,
+            material: Rc::new(material),
+        });
+
+        Ok(ticket)
+    }
+
+    /// Blocks until every streamed upload finished and replaced the
+    /// resource it was loaded for.
+    pub unsafe fn finish_uploads(&mut self) -> Result<()> {
+        self.data.uploader.wait_all()?;
+        self.apply_uploads()
+    }
+
+    /// Swaps in the meshes and textures whose uploads completed.
+    unsafe fn apply_uploads(&mut self) -> Result<()> {
+        let _span = tracing::info_span!("apply_uploads").entered();
+        let data = &mut self.data;
+        data.uploader.poll()?;
+        for duration in data.uploader.take_timings() {
+            data.profiler.record("Upload", duration);
+        }
+
+        let uploader = &data.uploader;
+        if let Some(pending) = data
+            .pending_mesh
+            .take_if(|p| uploader.is_complete(p.ticket))
+        {
+            // The frames in flight hold on to the old mesh.
+            data.draws.replace_mesh(&data.mesh, &pending.mesh);
+            data.mesh = pending.mesh;
+        }
+
+        if let Some(pending) = data
+            .pending_texture
+            .take_if(|p| uploader.is_complete(p.ticket))
+        {
+            // The frames in flight hold on to the old material.
+            data.draws
+                .replace_material(&data.material, &pending.material);
+            data.material = pending.material;
+        }
+
+        Ok(())
+    }
+
+    /// Rolling averages of the GPU time of each pass and of the
+    /// uploads, empty if timestamps aren't supported.
+    pub fn gpu_timings(&self) -> &[RollingAverage] {
+        self.data.profiler.averages()
+    }
+
+    /// What the last frames drew, see `FrameStats`.
+    pub fn stats(&self) -> FrameStats {
+        FrameStats {
+            draws: self.data.draw_counters,
+            pipeline: self.data.profiler.statistics(),
+        }
+    }
+
+    /// Turns the pipeline statistics query of the main pass on or
+    /// off, it stays off if the device doesn't support it.
+    pub fn set_pipeline_statistics(&mut self, enabled: bool) {
+        self.data.profiler.set_statistics(enabled);
+        self.config.renderer.pipeline_statistics = enabled;
+    }
+
+    /// CPU time of the phases of the last frame and frame time
+    /// statistics.
+    pub fn frame_timer(&self) -> &FrameTimer {
+        &self.frame_timer
+    }
+
+    /// What the app was created with, kept up to date with the loaded
+    /// mesh and texture and the settings changed since.
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Incremented whenever a lost device is replaced. The draw list
+    /// is rebuilt on the new device, other GPU objects the caller
+    /// created from an older device are gone.
+    pub fn device_generation(&self) -> u64 {
+        self.device_generation
+    }
+
+    /// The current device, to build pipelines and record compute
+    /// work with.
+    pub fn device_context(&self) -> DeviceContext<'_> {
+        DeviceContext { app: self }
+    }
+
+    /// Routes validation messages to `sink` from now on.
+    pub fn set_validation_sink(&self, sink: Arc<dyn MessageSink>) {
+        self.instance.set_sink(sink);
+    }
+
+    /// Recorded every frame, in order.
+    pub fn draws(&self) -> &DrawList {
+        &self.data.draws
+    }
+
+    pub fn draws_mut(&mut self) -> &mut DrawList {
+        &mut self.data.draws
+    }
+
+    /// The pipeline the scene is drawn with, for `Draw`s of meshes
+    /// and materials created by the app.
+    pub fn mesh_pipeline(&self) -> &Rc<Pipeline> {
+        &self.data.pipeline
+    }
+
+    /// The sample counts the device supports for the color and depth
+    /// targets, `set_msaa` picks one of them.
+    pub fn supported_msaa_samples(&self) -> vk::SampleCountFlags {
+        self.data.supported_msaa_samples
+    }
+
+    /// Number of frames the CPU may record ahead of the GPU.
+    pub fn frames_in_flight(&self) -> usize {
+        self.data.in_flight_fences.len()
+    }
+
+    /// Changes the number of frames in flight, at least one. Every
+    /// per frame resource is recreated.
+    pub unsafe fn set_frames_in_flight(
+        &mut self,
+        count: usize,
+    ) -> Result<()> {
+        let count = count.max(1);
+        self.device.device_wait_idle()?;
+
+        let surface = self
+            .surface
+            .as_ref()
+            .map_or(vk::SurfaceKHR::null(), |s| s.handle());
+        let data = &mut self.data;
+        data.frame_commands = create_frame_commands(
+            &self.instance,
+            &self.device,
+            surface,
+            data.physical_device,
+            count,
+        )?;
+        create_uniform_buffers(
+            &data.allocator,
+            count,
+            &mut data.camera_buffers,
+        )?;
+        create_sync_objects(
+            &self.device,
+            data.swapchain.images(),
+            count,
+            &mut data.image_available_semaphores,
+            &mut data.render_finished_semaphores,
+            &mut data.in_flight_fences,
+            &mut data.images_in_flight,
+        )?;
+        data.compute_semaphores =
+            create_compute_semaphores(&self.device, count)?;
+        data.profiler.set_frames_in_flight(count)?;
+        data.compute_handoffs = None;
+        self.frame = 0;
+
+        self.recreate_descriptor_sets()
+    }
+
+    /// Rewrites the per frame camera sets from a new pool.
+    unsafe fn recreate_descriptor_sets(&mut self) -> Result<()> {
+        let data = &mut self.data;
+        let frames_in_flight = data.camera_buffers.len();
+        data.descriptor_pool = create_descriptor_pool(
+            &self.device,
+            frames_in_flight as u32,
+            &data.shader_reflection,
+            CAMERA_SET,
+        )?;
+        create_descriptor_sets(
+            &self.device,
+            frames_in_flight,
+            data.descriptor_pool.handle(),
+            data.camera_set_layout.handle(),
+            &data.shader_reflection,
+            &data.camera_buffers,
+            &mut data.descriptor_sets,
+        )?;
+
+        Ok(())
+    }
+
+    /// Submits a compute command buffer, allocated from
+    /// `DeviceContext::compute_command_pool`, whose results the next
+    /// frame reads. It has to record `DeviceContext::release_compute`
+    /// for `handoffs` after its dispatches, the next frame waits for
+    /// it and acquires them. Resources read by frames still in
+    /// flight must not be written, e.g. keep one output buffer per
+    /// frame in flight. Only one submission is allowed per frame,
+    /// record all compute work into `command_buffer`.
+    pub unsafe fn submit_compute(
+        &mut self,
+        command_buffer: vk::CommandBuffer,
+        handoffs: &[Handoff],
+    ) -> Result<()> {
+        // The frame waits on the semaphore once, it can't be signaled
+        // again before that.
+        if self.data.compute_handoffs.is_some() {
+            return Err(ComputeError::AlreadySubmittedError.into());
+        }
+        // The semaphore's last wait was submitted with this frame's
+        // previous submission.
+        self.device.wait_for_fences(
+            &[self.data.in_flight_fences[self.frame].handle()],
+            true,
+            u64::MAX,
+        )?;
 
+        let command_buffers = &[command_buffer];
+        let signal_semaphores =
+            &[self.data.compute_semaphores[self.frame].handle()];
+        let submit_info = vk::SubmitInfo::builder()
+            .command_buffers(command_buffers)
+            .signal_semaphores(signal_semaphores);
+        self.device.queue_submit(
+            self.data.compute_queue,
+            &[submit_info],
+            vk::Fence::null(),
+        )?;
+        self.data.compute_handoffs = Some(handoffs.to_vec());
+
+        Ok(())
+    }
+
+    /// Places the camera at `position` looking along `direction`,
+    /// keeping the z-axis up.
+    pub fn set_camera(
+        &mut self,
+        position: Point3<f32>,
+        direction: Vector3<f32>,
+    ) {
+        let direction = direction.normalize();
+        let alt_direction = vec3(0.0, 0.0, 1.0).cross(direction);
+        if alt_direction.magnitude2() > f32::EPSILON {
+            self.camera_alt_direction = alt_direction.normalize();
+            self.camera_up_direction =
+                direction.cross(self.camera_alt_direction);
+        }
+        self.camera_direction = direction;
+        self.camera_position = position;
+    }
+
+    /// Marks the swapchain for recreation before the next frame,
+    /// call when the window size changes.
+    pub fn resize(&mut self) {
+        self.resized = true;
+    }
+
+    /// The format and color space presented in, which is the first
+    /// supported of the configured outputs or a fallback.
+    pub fn output_format(&self) -> OutputFormat {
+        self.data.swapchain.output()
+    }
+
+    /// Recreates the swapchain with the first supported of `outputs`
+    /// before the next frame.
+    pub fn set_outputs(&mut self, outputs: &[ColorOutput]) {
+        self.data.outputs = outputs.to_vec();
+        self.resized = true;
+    }
+
+    /// The present mode of the current swapchain.
+    pub fn present_mode(&self) -> vk::PresentModeKHR {
+        self.data.swapchain.present_mode()
+    }
+
+    /// Present modes the surface supports, empty when headless.
+    pub unsafe fn supported_present_modes(
+        &self,
+    ) -> Result<Vec<vk::PresentModeKHR>> {
+        let Some(surface) = &self.surface else {
+            return Ok(vec![]);
+        };
+        Ok(SwapchainSupport::get(
+            &self.instance,
+            surface.handle(),
+            self.data.physical_device,
+        )?
+        .present_modes)
+    }
+
+    /// Recreates the swapchain with `present_mode` before the next
+    /// frame, or with FIFO if the surface doesn't support it.
+    pub fn set_present_mode(
+        &mut self,
+        present_mode: vk::PresentModeKHR,
+    ) {
+        self.data.present_mode = present_mode;
+        self.resized = true;
+    }
+
+    /// The directory shaders are compiled from when hot reload
+    /// watches it, the embedded sources are used otherwise.
+    fn shader_dir(&self) -> Option<&Path> {
+        self.shader_watcher.as_ref().map(ShaderWatcher::dir)
+    }
+
+    /// Rebuilds the pipelines whose shaders changed on disk. A shader
+    /// that fails to compile is reported and the old pipeline kept.
+    pub unsafe fn reload_shaders(&mut self) -> Result<()> {
+        let Some(watcher) = &self.shader_watcher else {
+            return Ok(());
+        };
+        let changed = watcher.changed();
+        if !changed.contains(MESH_VERTEX_SHADER)
+            && !changed.contains(MESH_FRAGMENT_SHADER)
+        {
+            return Ok(());
+        }
+
+        log::info!("Reloading mesh shaders.");
+        let shaders = match MeshShaders::compile(Some(watcher.dir()))
+        {
+            Ok(shaders) => shaders,
+            Err(AppError::ShaderError(e)) => {
+                log::error!("{}", e);
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        // The descriptor sets are allocated against the current
+        // layout, only the pipeline is rebuilt here.
+        if shaders.reflection.bindings
+            != self.data.shader_reflection.bindings
+        {
+            log::error!(
+                "Descriptor bindings of the mesh shaders changed, restart to apply."
+            );
+            return Ok(());
+        }
+        let pipeline = match create_mesh_pipeline(
+            &self.device,
+            &shaders,
+            &self.data.mesh_set_layouts(),
+            self.data.render_pass.handle(),
+            self.data.msaa_samples,
+            self.data.sample_shading,
+            self.data.pipeline_cache.handle(),
+        ) {
+            Ok(pipeline) => pipeline,
+            Err(AppError::PipelineError(
+                PipelineError::ReflectError(e),
+            )) => {
+                log::error!("{}", e);
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        self.device.device_wait_idle()?;
+        let pipeline = Rc::new(pipeline);
         self.data
-            .swapchain_image_views
-            .iter()
-            .for_each(|v| self.device.destroy_image_view(*v, None));
-        self.device.destroy_swapchain_khr(self.data.swapchain, None);
+            .draws
+            .replace_pipeline(&self.data.pipeline, &pipeline);
+        self.data.pipeline = pipeline;
+
+        Ok(())
+    }
+
+    /// Records the draw list for this frame into the frame's command
+    /// buffer, the frame's fence has to be signaled.
+    unsafe fn record_frame(
+        &mut self,
+        image_index: usize,
+    ) -> Result<vk::CommandBuffer> {
+        let data = &mut self.data;
+        let frame_commands = &mut data.frame_commands[self.frame];
+        let command_buffer = frame_commands.reset(&self.device)?;
+        data.profiler.begin_frame(self.frame)?;
+        data.draw_counters = record_command_buffer(
+            &self.device,
+            &mut data.profiler,
+            command_buffer,
+            data.framebuffers[image_index].handle(),
+            data.render_pass.handle(),
+            data.swapchain.extent(),
+            data.camera_set_layout.handle(),
+            data.descriptor_sets[self.frame],
+            data.material_set_layout.handle(),
+            &data.draws,
+            data.compute_handoffs.as_deref().unwrap_or_default(),
+            &data.queue_families,
+        )?;
+        frame_commands.retain(&data.draws);
+
+        Ok(command_buffer)
     }
 
     pub fn rotate_camera(
@@ -904,18 +1977,229 @@ impl App {
     }
 }
 
-fn load_model(
-    vertices: &mut Vec<Vertex3>,
-    indices: &mut Vec<u32>,
-) -> Result<()> {
-    let mut reader = BufReader::new(
-        File::open("resources/fish.obj").map_err(|e| {
+/// The app's current device and the objects pipelines are built
+/// against, for work the app doesn't do itself. It borrows the app,
+/// so it can't be kept across `App::render`, which replaces a lost
+/// device. Everything created through it belongs to `generation` and
+/// has to be recreated once `App::device_generation` differs.
+#[derive(Debug, Clone, Copy)]
+pub struct DeviceContext<'a> {
+    app: &'a App,
+}
+
+impl<'a> DeviceContext<'a> {
+    /// The `App::device_generation` this context belongs to.
+    pub fn generation(&self) -> u64 {
+        self.app.device_generation
+    }
+
+    /// To create buffers and descriptor sets and record commands
+    /// with.
+    pub fn device(&self) -> &'a Device {
+        &self.app.device
+    }
+
+    /// The layouts of the camera and material sets, bound for every
+    /// draw whose pipeline was built with them, by set number.
+    pub fn mesh_set_layouts(&self) -> [vk::DescriptorSetLayout; 2] {
+        self.app.data.mesh_set_layouts()
+    }
+
+    /// Builds `builder` for the render pass draws are recorded in,
+    /// with the current MSAA sample count and the shared pipeline
+    /// cache. It has to be rebuilt after `App::set_msaa`.
+    pub unsafe fn build_pipeline(
+        &self,
+        builder: GraphicsPipelineBuilder,
+    ) -> Result<Pipeline> {
+        let data = &self.app.data;
+        Ok(builder
+            .samples(data.msaa_samples)
+            .cache(data.pipeline_cache.handle())
+            .build(&self.app.device, data.render_pass.handle())?)
+    }
+
+    /// Builds `builder` with the shared pipeline cache.
+    pub unsafe fn build_compute_pipeline(
+        &self,
+        builder: ComputePipelineBuilder,
+    ) -> Result<ComputePipeline> {
+        Ok(builder
+            .cache(self.app.data.pipeline_cache.handle())
+            .build(&self.app.device)?)
+    }
+
+    /// The pool compute command buffers are allocated from, see
+    /// `App::submit_compute`.
+    pub fn compute_command_pool(&self) -> vk::CommandPool {
+        self.app.data.compute_command_pool.handle()
+    }
+
+    /// Records the barriers handing `handoffs` to the graphics
+    /// pipeline into a compute command buffer, after the dispatches
+    /// writing them.
+    pub unsafe fn release_compute(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        handoffs: &[Handoff],
+    ) {
+        compute::release(
+            self.device(),
+            command_buffer,
+            handoffs,
+            &self.app.data.queue_families,
+        );
+    }
+}
+
+impl Drop for App {
+    fn drop(&mut self) {
+        log::debug!("Destroying application.");
+        // A lost device's cache isn't worth keeping.
+        if let Err(e) = unsafe { self.device.device_wait_idle() } {
+            log::error!("Failed to wait for device idle: {}", e);
+            return;
+        }
+        if let Err(e) = unsafe {
+            save_pipeline_cache(
+                &self.device,
+                self.data.pipeline_cache.handle(),
+                Path::new(PIPELINE_CACHE_PATH),
+            )
+        } {
+            log::error!("{}", e);
+        }
+    }
+}
+
+unsafe fn create_entry() -> Result<VkEntry> {
+    let loader = LibloadingLoader::new(LIBRARY)
+        .map_err(|e| AppError::VkLibLoadingError(e.to_string()))?;
+    Ok(VkEntry::new(loader)?)
+}
+
+/// The compiled mesh shaders and the resources they expect.
+struct MeshShaders {
+    vert: Vec<u8>,
+    frag: Vec<u8>,
+    reflection: ShaderReflection,
+}
+
+impl MeshShaders {
+    /// Compiles the sources in `dir`, or the embedded ones without
+    /// it.
+    fn compile(dir: Option<&Path>) -> Result<Self> {
+        let compile = |name| match dir {
+            Some(dir) => compile_shader(&dir.join(name)),
+            None => compile_embedded_shader(name),
+        };
+        let vert = compile(MESH_VERTEX_SHADER)?;
+        let frag = compile(MESH_FRAGMENT_SHADER)?;
+        let reflection =
+            ShaderReflection::from_stages(&[&vert, &frag])
+                .map_err(ShaderError::from)?;
+
+        Ok(Self {
+            vert,
+            frag,
+            reflection,
+        })
+    }
+}
+
+/// Records the upload of a mesh, it can be drawn once the next flush
+/// of `uploader` completes.
+unsafe fn upload_mesh(
+    uploader: &mut Uploader,
+    source: Rc<MeshData>,
+) -> Result<Mesh> {
+    let vertex_buffer =
+        create_vertex_buffer(uploader, &source.vertices)?;
+    let index_buffer = uploader.upload_buffer(
+        &source.indices,
+        vk::BufferUsageFlags::INDEX_BUFFER,
+        "Index buffer",
+    )?;
+    let index_count = source.indices.len() as u32;
+
+    Ok(Mesh::new(vertex_buffer, index_buffer, index_count)
+        .with_source(source))
+}
+
+/// Records the upload of `texture` and creates a material of it, it
+/// can be drawn once the next flush of `uploader` completes.
+unsafe fn upload_material(
+    instance: &Instance,
+    device: &Rc<LogicalDevice>,
+    uploader: &mut Uploader,
+    physical_device: vk::PhysicalDevice,
+    set_layout: vk::DescriptorSetLayout,
+    reflection: &ShaderReflection,
+    texture: Rc<TextureData>,
+) -> Result<Material> {
+    let image = create_texture_image(
+        instance,
+        uploader,
+        physical_device,
+        &texture,
+    )?;
+    let sampler = create_texture_sampler(device, image.mip_levels())?;
+
+    Ok(create_material(
+        device, set_layout, reflection, image, sampler, texture,
+    )?)
+}
+
+/// `sample_shading` if the device enabled sample shading, else `None`.
+fn supported_sample_shading(
+    device: &LogicalDevice,
+    sample_shading: Option<f32>,
+) -> Option<f32> {
+    if sample_shading.is_some() && !device.sample_rate_shading() {
+        log::warn!("Sample shading is not supported, disabling it.");
+        return None;
+    }
+    sample_shading
+}
+
+unsafe fn create_mesh_pipeline(
+    device: &Rc<LogicalDevice>,
+    shaders: &MeshShaders,
+    set_layouts: &[vk::DescriptorSetLayout],
+    render_pass: vk::RenderPass,
+    msaa_samples: vk::SampleCountFlags,
+    sample_shading: Option<f32>,
+    pipeline_cache: vk::PipelineCache,
+) -> Result<Pipeline> {
+    let mut builder = GraphicsPipelineBuilder::new()
+        .name("Mesh pipeline")
+        .stage(vk::ShaderStageFlags::VERTEX, &shaders.vert)
+        .stage(vk::ShaderStageFlags::FRAGMENT, &shaders.frag)
+        .vertex_layout::<Vertex3>()
+        .samples(msaa_samples)
+        .cache(pipeline_cache);
+    for layout in set_layouts {
+        builder = builder.set_layout(*layout);
+    }
+    if let Some(min_sample_shading) = sample_shading
+        .filter(|_| msaa_samples != vk::SampleCountFlags::_1)
+    {
+        builder = builder.min_sample_shading(min_sample_shading);
+    }
+    Ok(builder.build(device, render_pass)?)
+}
+
+fn load_model(path: &Path) -> Result<MeshData> {
+    let _span =
+        tracing::info_span!("load_model", path = %path.display())
+            .entered();
+    let mut reader =
+        BufReader::new(File::open(path).map_err(|e| {
             AppError::FileOpenError(format!(
                 "Failed to open object with error: {}",
                 e
             ))
-        })?,
-    );
+        })?);
 
     let (models, _) = tobj::load_obj_buf(
         &mut reader,
@@ -925,13 +2209,15 @@ fn load_model(
         },
         |_| Ok(Default::default()),
     )?;
+    let mut vertices = vec![];
+    let mut indices = vec![];
     let mut unique_vertices = HashMap::new();
     for model in &models {
         for i in 0..model.mesh.indices.len() {
             let vert_index = model.mesh.indices[i] as usize;
             let tex_index = model.mesh.texcoord_indices[i] as usize;
-            let pos_offset = (3 * vert_index) as usize;
-            let tex_coord_offset = (2 * tex_index) as usize;
+            let pos_offset = 3 * vert_index;
+            let tex_coord_offset = 2 * tex_index;
             let vertex = Vertex3 {
                 pos: vec3(
                     model.mesh.positions[pos_offset],
@@ -955,35 +2241,47 @@ fn load_model(
             }
         }
     }
-    Ok(())
+    Ok(MeshData { vertices, indices })
 }
 
-fn create_vertices_2d(
-    vertices_2d: &mut Vec<Vertex2>,
-    indices_2d: &mut Vec<u32>,
-) -> Result<()> {
-    *vertices_2d = vec![
-        Vertex2 {
-            pos: vec2(-0.5, -0.5),
-            color: vec3(1.0, 0.0, 0.0),
-            tex_coord: vec2(0.0, 0.0),
-        },
-        Vertex2 {
-            pos: vec2(0.5, -0.5),
-            color: vec3(0.0, 1.0, 0.0),
-            tex_coord: vec2(1.0, 0.0),
-        },
-        Vertex2 {
-            pos: vec2(0.5, 0.5),
-            color: vec3(0.0, 0.0, 1.0),
-            tex_coord: vec2(1.0, 1.0),
-        },
-        Vertex2 {
-            pos: vec2(-0.5, 0.5),
-            color: vec3(1.0, 1.0, 1.0),
-            tex_coord: vec2(0.0, 1.0),
-        },
-    ];
-    *indices_2d = vec![0, 2, 1, 3, 2, 0];
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{allocator::AllocatorError, image::ImageError};
+
+    #[test]
+    fn nested_vk_errors_are_classified() {
+        let lost = vk::ErrorCode::DEVICE_LOST;
+        let error = AppError::from(RenderPassError::from(
+            ImageError::from(AllocatorError::from(lost)),
+        ));
+        assert_eq!(error.vk_error_code(), Some(lost));
+
+        let error = AppError::from(DescriptorError::from(lost));
+        assert_eq!(error.vk_error_code(), Some(lost));
+
+        let error = AppError::from(
+            TextureError::UnsupportedTextureError("cube".into()),
+        );
+        assert_eq!(error.vk_error_code(), None);
+    }
+
+    #[test]
+    fn device_retries_back_off_then_give_up() {
+        let start = Instant::now();
+        let mut retry = DeviceRetry::default();
+        assert!(retry.is_due(start));
+
+        let mut now = start;
+        for i in 0..MAX_DEVICE_RETRIES {
+            let delay = retry.failed(now).unwrap();
+            assert_eq!(delay, DEVICE_RETRY_DELAY * 2u32.pow(i));
+            // A failed attempt leaves the next call waiting, not
+            // failing, until the delay has passed.
+            assert!(!retry.is_due(now));
+            now += delay;
+            assert!(retry.is_due(now));
+        }
+        assert_eq!(retry.failed(now), None);
+    }
 }
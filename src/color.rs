@@ -1,28 +1,27 @@
-use vulkanalia::{
-    vk::{self, ErrorCode},
-    Device, Instance,
-};
+use std::rc::Rc;
+
+use vulkanalia::vk::{self, ErrorCode};
 
 use crate::{
-    image::{create_image, ImageError},
-    image_view::{create_image_view, ImageViewError},
+    allocator::Allocator,
+    image::{create_image, Image, ImageError},
+    image_view::ImageViewError,
+    VkErrorSource,
 };
 
+/// The multisampled color target, `None` with one sample since the
+/// swapchain image is rendered to directly.
 pub unsafe fn create_color_objects(
-    instance: &Instance,
-    device: &Device,
-    color_image: &mut vk::Image,
-    color_image_memory: &mut vk::DeviceMemory,
-    color_image_view: &mut vk::ImageView,
-    physical_device: vk::PhysicalDevice,
+    allocator: &Rc<Allocator>,
     swapchain_extent: vk::Extent2D,
     swapchain_format: vk::Format,
     msaa_samples: vk::SampleCountFlags,
-) -> Result<()> {
-    (*color_image, *color_image_memory) = create_image(
-        instance,
-        device,
-        physical_device,
+) -> Result<Option<Image>> {
+    if msaa_samples == vk::SampleCountFlags::_1 {
+        return Ok(None);
+    }
+    let mut color_image = create_image(
+        allocator,
         swapchain_extent.width,
         swapchain_extent.height,
         1,
@@ -32,17 +31,12 @@ pub unsafe fn create_color_objects(
         vk::ImageUsageFlags::COLOR_ATTACHMENT
             | vk::ImageUsageFlags::TRANSIENT_ATTACHMENT,
         vk::MemoryPropertyFlags::DEVICE_LOCAL,
+        "MSAA color target",
     )?;
 
-    *color_image_view = create_image_view(
-        device,
-        *color_image,
-        swapchain_format,
-        vk::ImageAspectFlags::COLOR,
-        1,
-    )?;
+    color_image.create_view(vk::ImageAspectFlags::COLOR)?;
 
-    Ok(())
+    Ok(Some(color_image))
 }
 
 #[derive(Debug, Clone, thiserror::Error)]
@@ -55,3 +49,13 @@ pub enum ColorError {
     ImageViewError(#[from] ImageViewError),
 }
 type Result<T> = std::result::Result<T, ColorError>;
+
+impl VkErrorSource for ColorError {
+    fn vk_error_code(&self) -> Option<ErrorCode> {
+        match self {
+            Self::VkErrorCode(e) => Some(*e),
+            Self::ImageError(e) => e.vk_error_code(),
+            Self::ImageViewError(e) => e.vk_error_code(),
+        }
+    }
+}
@@ -1,219 +1,347 @@
+use std::{mem::size_of, rc::Rc, slice};
+
 use vulkanalia::{
     vk::{self, DeviceV1_0, ErrorCode, Handle, HasBuilder},
     Device, Instance,
 };
 
-use crate::queue::{QueueError, QueueFamilyIndices};
+use crate::{
+    buffer::ModelObject,
+    compute::{acquire, Handoff},
+    debug_utils::Label,
+    device::LogicalDevice,
+    draw::{Draw, DrawList},
+    owned::Owned,
+    profiler::{DrawCounters, GpuProfiler},
+    queue::{QueueError, QueueFamilyIndices},
+    VkErrorSource,
+};
 
 pub unsafe fn create_command_pool(
     instance: &Instance,
-    device: &Device,
+    device: &Rc<LogicalDevice>,
     surface: vk::SurfaceKHR,
     physical_device: vk::PhysicalDevice,
-    command_pool: &mut vk::CommandPool,
-) -> Result<()> {
+) -> Result<Owned<vk::CommandPool>> {
     let indices =
         QueueFamilyIndices::get(instance, surface, physical_device)?;
     let info = vk::CommandPoolCreateInfo::builder()
         .flags(vk::CommandPoolCreateFlags::empty()) // * Optional.
         .queue_family_index(indices.graphics);
 
-    *command_pool = device.create_command_pool(&info, None)?;
+    let command_pool = device.create_command_pool(&info, None)?;
 
-    Ok(())
+    Ok(Owned::new(device, command_pool).named("Command pool"))
 }
 
-pub unsafe fn create_command_buffers(
-    device: &Device,
-    command_pool: vk::CommandPool,
-    framebuffers: &[vk::Framebuffer],
-    render_pass: vk::RenderPass,
-    pipeline: vk::Pipeline,
-    pipeline_layout: vk::PipelineLayout,
-    vertex_buffer: vk::Buffer,
-    index_buffer: vk::Buffer,
-    indices: &[u32],
-    swapchain_extent: vk::Extent2D,
-    descriptor_sets: &[vk::DescriptorSet],
-    command_buffers: &mut Vec<vk::CommandBuffer>,
-) -> Result<()> {
-    // Allocate
+/// A pool for command buffers submitted to the compute queue.
+pub unsafe fn create_compute_command_pool(
+    instance: &Instance,
+    device: &Rc<LogicalDevice>,
+    surface: vk::SurfaceKHR,
+    physical_device: vk::PhysicalDevice,
+) -> Result<Owned<vk::CommandPool>> {
+    let indices =
+        QueueFamilyIndices::get(instance, surface, physical_device)?;
+    let info = vk::CommandPoolCreateInfo::builder()
+        .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER)
+        .queue_family_index(indices.compute);
 
-    let allocate_info = vk::CommandBufferAllocateInfo::builder()
-        .command_pool(command_pool)
-        .level(vk::CommandBufferLevel::PRIMARY)
-        .command_buffer_count(framebuffers.len() as u32);
-
-    *command_buffers =
-        device.allocate_command_buffers(&allocate_info)?;
-
-    // Commands
-    for (i, command_buffer) in command_buffers.iter().enumerate() {
-        let info = vk::CommandBufferBeginInfo::builder();
-
-        device.begin_command_buffer(*command_buffer, &info)?;
-
-        let render_area = vk::Rect2D::builder()
-            .offset(vk::Offset2D::default())
-            .extent(swapchain_extent);
-
-        let color_clear_value = vk::ClearValue {
-            color: vk::ClearColorValue {
-                float32: [0.0, 0.0, 0.0, 1.0],
-            },
-        };
-
-        let depth_clear_value = vk::ClearValue {
-            depth_stencil: vk::ClearDepthStencilValue {
-                depth: 1.0,
-                stencil: 0,
-            },
-        };
-
-        let clear_values = &[color_clear_value, depth_clear_value];
-        let info = vk::RenderPassBeginInfo::builder()
-            .render_pass(render_pass)
-            .framebuffer(framebuffers[i])
-            .render_area(render_area)
-            .clear_values(clear_values);
-
-        device.cmd_begin_render_pass(
-            *command_buffer,
-            &info,
-            vk::SubpassContents::INLINE,
-        );
-        device.cmd_bind_pipeline(
-            *command_buffer,
-            vk::PipelineBindPoint::GRAPHICS,
-            pipeline,
-        );
-        device.cmd_bind_vertex_buffers(
-            *command_buffer,
-            0,
-            &[vertex_buffer],
-            &[0],
-        );
-        device.cmd_bind_index_buffer(
-            *command_buffer,
-            index_buffer,
-            0,
-            vk::IndexType::UINT32,
-        );
-        device.cmd_bind_descriptor_sets(
-            *command_buffer,
-            vk::PipelineBindPoint::GRAPHICS,
-            pipeline_layout,
-            0,
-            &[descriptor_sets[i]],
-            &[],
-        );
-        device.cmd_draw_indexed(
-            *command_buffer,
-            indices.len() as u32,
-            1,
-            0,
-            0,
-            0,
-        );
-        device.cmd_end_render_pass(*command_buffer);
+    let command_pool = device.create_command_pool(&info, None)?;
+
+    Ok(
+        Owned::new(device, command_pool)
+            .named("Compute command pool"),
+    )
+}
+
+/// A command pool and buffer for one frame in flight. The pool is
+/// reset as a whole before the frame is recorded again, the draws
+/// recorded into it are kept alive until then.
+#[derive(Debug)]
+pub struct FrameCommands {
+    pool: Owned<vk::CommandPool>,
+    buffer: vk::CommandBuffer,
+    in_use: Vec<Draw>,
+}
 
-        device.end_command_buffer(*command_buffer)?;
+impl FrameCommands {
+    /// Resets the pool and returns the frame's command buffer, the
+    /// previous submission of it has to be complete.
+    pub unsafe fn reset(
+        &mut self,
+        device: &Device,
+    ) -> Result<vk::CommandBuffer> {
+        device.reset_command_pool(
+            self.pool.handle(),
+            vk::CommandPoolResetFlags::empty(),
+        )?;
+        self.in_use.clear();
+        Ok(self.buffer)
+    }
+
+    /// Holds on to the visible draws of `draws` until the next
+    /// `reset`, so removing a draw can't free what the GPU reads.
+    pub fn retain(&mut self, draws: &DrawList) {
+        self.in_use.extend(
+            draws
+                .iter()
+                .filter(|(_, d)| d.visible)
+                .map(|(_, d)| d.clone()),
+        );
     }
-    log::debug!("!!!\n");
-    Ok(())
 }
 
-pub unsafe fn create_command_buffers_2d(
-    device: &Device,
-    command_pool: vk::CommandPool,
-    framebuffers: &[vk::Framebuffer],
+pub unsafe fn create_frame_commands(
+    instance: &Instance,
+    device: &Rc<LogicalDevice>,
+    surface: vk::SurfaceKHR,
+    physical_device: vk::PhysicalDevice,
+    count: usize,
+) -> Result<Vec<FrameCommands>> {
+    let indices =
+        QueueFamilyIndices::get(instance, surface, physical_device)?;
+    let info = vk::CommandPoolCreateInfo::builder()
+        .flags(vk::CommandPoolCreateFlags::TRANSIENT)
+        .queue_family_index(indices.graphics);
+
+    (0..count)
+        .map(|i| {
+            let pool = Owned::new(
+                device,
+                device.create_command_pool(&info, None)?,
+            )
+            .named(&format!("Frame command pool {}", i));
+            let allocate_info =
+                vk::CommandBufferAllocateInfo::builder()
+                    .command_pool(pool.handle())
+                    .level(vk::CommandBufferLevel::PRIMARY)
+                    .command_buffer_count(1);
+            let buffer =
+                device.allocate_command_buffers(&allocate_info)?[0];
+
+            Ok(FrameCommands {
+                pool,
+                buffer,
+                in_use: vec![],
+            })
+        })
+        .collect()
+}
+
+/// Records one render pass over `framebuffer` drawing every visible
+/// draw in order. `camera_set` is bound to pipelines whose set 0 has
+/// `camera_set_layout`, and a draw's material to those whose set 1
+/// has `material_set_layout`, draws without one are skipped there.
+/// Pipelines, sets and buffers are only rebound when they change
+/// between draws, the transform is pushed for every draw. `handoffs`
+/// from compute are acquired first. Both are timed by `profiler`,
+/// whose frame has to be begun, which also counts what the render
+/// pass processes if enabled. Returns what was recorded.
+pub unsafe fn record_command_buffer(
+    device: &LogicalDevice,
+    profiler: &mut GpuProfiler,
+    command_buffer: vk::CommandBuffer,
+    framebuffer: vk::Framebuffer,
     render_pass: vk::RenderPass,
-    pipeline: vk::Pipeline,
-    pipeline_layout: vk::PipelineLayout,
-    vertex_buffer: vk::Buffer,
-    index_buffer: vk::Buffer,
-    indices: &[u32],
-    swapchain_extent: vk::Extent2D,
-    descriptor_sets: &[vk::DescriptorSet],
-    command_buffers: &mut Vec<vk::CommandBuffer>,
-) -> Result<()> {
-    // Allocate
+    extent: vk::Extent2D,
+    camera_set_layout: vk::DescriptorSetLayout,
+    camera_set: vk::DescriptorSet,
+    material_set_layout: vk::DescriptorSetLayout,
+    draws: &DrawList,
+    handoffs: &[Handoff],
+    families: &QueueFamilyIndices,
+) -> Result<DrawCounters> {
+    let info = vk::CommandBufferBeginInfo::builder()
+        .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
 
-    let allocate_info = vk::CommandBufferAllocateInfo::builder()
-        .command_pool(command_pool)
-        .level(vk::CommandBufferLevel::PRIMARY)
-        .command_buffer_count(framebuffers.len() as u32);
-
-    *command_buffers =
-        device.allocate_command_buffers(&allocate_info)?;
-
-    // Commands
-    for (i, command_buffer) in command_buffers.iter().enumerate() {
-        let info = vk::CommandBufferBeginInfo::builder();
-
-        device.begin_command_buffer(*command_buffer, &info)?;
-
-        let render_area = vk::Rect2D::builder()
-            .offset(vk::Offset2D::default())
-            .extent(swapchain_extent);
-
-        let color_clear_value = vk::ClearValue {
-            color: vk::ClearColorValue {
-                float32: [0.0, 0.0, 0.0, 1.0],
-            },
-        };
-
-        let clear_values = &[color_clear_value];
-        let info = vk::RenderPassBeginInfo::builder()
-            .render_pass(render_pass)
-            .framebuffer(framebuffers[i])
-            .render_area(render_area)
-            .clear_values(clear_values);
-
-        device.cmd_begin_render_pass(
-            *command_buffer,
-            &info,
-            vk::SubpassContents::INLINE,
-        );
-        device.cmd_bind_pipeline(
-            *command_buffer,
-            vk::PipelineBindPoint::GRAPHICS,
-            pipeline,
-        );
-        device.cmd_bind_vertex_buffers(
-            *command_buffer,
-            0,
-            &[vertex_buffer],
-            &[0],
-        );
-        device.cmd_bind_index_buffer(
-            *command_buffer,
-            index_buffer,
-            0,
-            vk::IndexType::UINT32,
-        );
-        device.cmd_bind_descriptor_sets(
-            *command_buffer,
-            vk::PipelineBindPoint::GRAPHICS,
-            pipeline_layout,
-            0,
-            &[descriptor_sets[i]],
-            &[],
-        );
+    device.begin_command_buffer(command_buffer, &info)?;
+    profiler.reset(command_buffer);
+    if !handoffs.is_empty() {
+        let _label =
+            Label::begin(device, command_buffer, "Acquire compute");
+        let scope = profiler.begin(command_buffer, "Acquire compute");
+        acquire(device, command_buffer, handoffs, families);
+        profiler.end(command_buffer, scope);
+    }
+
+    let render_area = vk::Rect2D::builder()
+        .offset(vk::Offset2D::default())
+        .extent(extent);
+
+    let color_clear_value = vk::ClearValue {
+        color: vk::ClearColorValue {
+            float32: [0.0, 0.0, 0.0, 1.0],
+        },
+    };
+
+    let depth_clear_value = vk::ClearValue {
+        depth_stencil: vk::ClearDepthStencilValue {
+            depth: 1.0,
+            stencil: 0,
+        },
+    };
+
+    // Index 1 is the depth clear. The resolve attachment is
+    // DONT_CARE on load and needs no clear value.
+    let clear_values = &[color_clear_value, depth_clear_value];
+    let info = vk::RenderPassBeginInfo::builder()
+        .render_pass(render_pass)
+        .framebuffer(framebuffer)
+        .render_area(render_area)
+        .clear_values(clear_values);
+
+    let label = Label::begin(device, command_buffer, "Main pass");
+    let scope = profiler.begin(command_buffer, "Main pass");
+    profiler.begin_statistics(command_buffer);
+    device.cmd_begin_render_pass(
+        command_buffer,
+        &info,
+        vk::SubpassContents::INLINE,
+    );
+    set_viewport(device, command_buffer, extent);
+
+    let mut bound_pipeline = vk::Pipeline::null();
+    let mut bound_layout = vk::PipelineLayout::null();
+    let mut bound_material = None;
+    let mut bound_mesh = None;
+    let mut counters = DrawCounters::default();
+    for (_, draw) in draws.recorded(material_set_layout) {
+        let pipeline = &draw.pipeline;
+        if pipeline.handle() != bound_pipeline {
+            bound_pipeline = pipeline.handle();
+            device.cmd_bind_pipeline(
+                command_buffer,
+                vk::PipelineBindPoint::GRAPHICS,
+                bound_pipeline,
+            );
+            counters.pipeline_binds += 1;
+        }
+        if pipeline.layout() != bound_layout {
+            bound_layout = pipeline.layout();
+            bound_material = None;
+            if pipeline.set_layouts().first()
+                == Some(&camera_set_layout)
+            {
+                device.cmd_bind_descriptor_sets(
+                    command_buffer,
+                    vk::PipelineBindPoint::GRAPHICS,
+                    bound_layout,
+                    0,
+                    &[camera_set],
+                    &[],
+                );
+                counters.descriptor_set_binds += 1;
+            }
+        }
+        if let Some(material) = draw.material.as_ref().filter(|_| {
+            pipeline.set_layouts().get(1)
+                == Some(&material_set_layout)
+        }) {
+            if bound_material != Some(Rc::as_ptr(material)) {
+                bound_material = Some(Rc::as_ptr(material));
+                device.cmd_bind_descriptor_sets(
+                    command_buffer,
+                    vk::PipelineBindPoint::GRAPHICS,
+                    bound_layout,
+                    1,
+                    &[material.descriptor_set()],
+                    &[],
+                );
+                counters.descriptor_set_binds += 1;
+            }
+        }
+        if let Some(stages) =
+            transform_stages(pipeline.push_constant_ranges())
+        {
+            let constants = ModelObject {
+                model: draw.transform,
+            };
+            device.cmd_push_constants(
+                command_buffer,
+                bound_layout,
+                stages,
+                0,
+                slice::from_raw_parts(
+                    (&constants as *const ModelObject).cast(),
+                    size_of::<ModelObject>(),
+                ),
+            );
+        }
+        if bound_mesh != Some(Rc::as_ptr(&draw.mesh)) {
+            bound_mesh = Some(Rc::as_ptr(&draw.mesh));
+            device.cmd_bind_vertex_buffers(
+                command_buffer,
+                0,
+                &[draw.mesh.vertex_buffer().handle()],
+                &[0],
+            );
+            device.cmd_bind_index_buffer(
+                command_buffer,
+                draw.mesh.index_buffer().handle(),
+                0,
+                vk::IndexType::UINT32,
+            );
+        }
         device.cmd_draw_indexed(
-            *command_buffer,
-            indices.len() as u32,
+            command_buffer,
+            draw.mesh.index_count(),
             1,
             0,
             0,
             0,
         );
-        device.cmd_end_render_pass(*command_buffer);
+        counters.draw_calls += 1;
+        counters.triangles += draw.mesh.index_count() as u64 / 3;
+    }
+
+    device.cmd_end_render_pass(command_buffer);
+    profiler.end_statistics(command_buffer);
+    profiler.end(command_buffer, scope);
+    drop(label);
+    device.end_command_buffer(command_buffer)?;
 
-        device.end_command_buffer(*command_buffer)?;
+    Ok(counters)
+}
+
+/// The stages to push a draw's transform to, `None` unless every
+/// range of those stages covers a whole `ModelObject` at offset 0.
+fn transform_stages(
+    ranges: &[vk::PushConstantRange],
+) -> Option<vk::ShaderStageFlags> {
+    let size = size_of::<ModelObject>() as u32;
+    // Every range overlapping the pushed bytes has to be pushed to.
+    let overlapping = ranges.iter().filter(|r| r.offset < size);
+    let mut stages = vk::ShaderStageFlags::empty();
+    for range in overlapping {
+        if range.offset != 0 || range.size < size {
+            return None;
+        }
+        stages |= range.stage_flags;
     }
+    (!stages.is_empty()).then_some(stages)
+}
 
-    Ok(())
+/// Covers the whole of `extent`, the pipelines use dynamic viewport
+/// and scissor state.
+unsafe fn set_viewport(
+    device: &Device,
+    command_buffer: vk::CommandBuffer,
+    extent: vk::Extent2D,
+) {
+    let viewport = vk::Viewport::builder()
+        .x(0.0)
+        .y(0.0)
+        .width(extent.width as f32)
+        .height(extent.height as f32)
+        .min_depth(0.0)
+        .max_depth(1.0);
+    let scissor = vk::Rect2D::builder()
+        .offset(vk::Offset2D { x: 0, y: 0 })
+        .extent(extent);
+
+    device.cmd_set_viewport(command_buffer, 0, &[viewport]);
+    device.cmd_set_scissor(command_buffer, 0, &[scissor]);
 }
 
 pub unsafe fn begin_single_time_commands(
@@ -267,3 +395,66 @@ pub enum CommandError {
     QueueError(#[from] QueueError),
 }
 type Result<T> = std::result::Result<T, CommandError>;
+
+impl VkErrorSource for CommandError {
+    fn vk_error_code(&self) -> Option<ErrorCode> {
+        match self {
+            Self::VkErrorCode(e) => Some(*e),
+            Self::QueueError(e) => e.vk_error_code(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(
+        stage_flags: vk::ShaderStageFlags,
+        offset: u32,
+        size: u32,
+    ) -> vk::PushConstantRange {
+        vk::PushConstantRange {
+            stage_flags,
+            offset,
+            size,
+        }
+    }
+
+    #[test]
+    fn transforms_are_pushed_to_every_covering_stage() {
+        let (vertex, fragment) = (
+            vk::ShaderStageFlags::VERTEX,
+            vk::ShaderStageFlags::FRAGMENT,
+        );
+        assert_eq!(transform_stages(&[]), None);
+        assert_eq!(
+            transform_stages(&[range(vertex, 0, 64)]),
+            Some(vertex)
+        );
+        assert_eq!(
+            transform_stages(&[
+                range(vertex, 0, 64),
+                range(fragment, 0, 80),
+            ]),
+            Some(vertex | fragment)
+        );
+        // Constants after the transform don't take it.
+        assert_eq!(
+            transform_stages(&[
+                range(vertex, 0, 64),
+                range(fragment, 64, 16),
+            ]),
+            Some(vertex)
+        );
+        // Too small for a `mat4`, or only partly covering it.
+        assert_eq!(transform_stages(&[range(vertex, 0, 16)]), None);
+        assert_eq!(
+            transform_stages(&[
+                range(vertex, 0, 64),
+                range(fragment, 16, 16),
+            ]),
+            None
+        );
+    }
+}
@@ -0,0 +1,243 @@
+use std::rc::Rc;
+
+use vulkanalia::{
+    vk::{self, DeviceV1_0, ErrorCode, HasBuilder},
+    Device,
+};
+
+use crate::{
+    device::LogicalDevice, owned::Owned, queue::QueueFamilyIndices,
+    VkErrorSource,
+};
+
+/// How the graphics pipeline reads the result of a compute shader.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ComputeOutput {
+    /// E.g. skinned vertices or particles.
+    VertexBuffer,
+    IndexBuffer,
+    /// Draw arguments, e.g. written by culling.
+    IndirectBuffer,
+    /// A storage buffer read by the vertex or fragment stage.
+    ShaderRead,
+}
+
+impl ComputeOutput {
+    fn stage_access(
+        self,
+    ) -> (vk::PipelineStageFlags, vk::AccessFlags) {
+        match self {
+            ComputeOutput::VertexBuffer => (
+                vk::PipelineStageFlags::VERTEX_INPUT,
+                vk::AccessFlags::VERTEX_ATTRIBUTE_READ,
+            ),
+            ComputeOutput::IndexBuffer => (
+                vk::PipelineStageFlags::VERTEX_INPUT,
+                vk::AccessFlags::INDEX_READ,
+            ),
+            ComputeOutput::IndirectBuffer => (
+                vk::PipelineStageFlags::DRAW_INDIRECT,
+                vk::AccessFlags::INDIRECT_COMMAND_READ,
+            ),
+            ComputeOutput::ShaderRead => (
+                vk::PipelineStageFlags::VERTEX_SHADER
+                    | vk::PipelineStageFlags::FRAGMENT_SHADER,
+                vk::AccessFlags::SHADER_READ,
+            ),
+        }
+    }
+}
+
+/// A resource written by a compute shader and handed to the graphics
+/// pipeline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Handoff {
+    Buffer(vk::Buffer, ComputeOutput),
+    /// A color storage image written in `GENERAL` layout and sampled
+    /// by the fragment stage in `SHADER_READ_ONLY_OPTIMAL`.
+    Image(vk::Image),
+}
+
+impl Handoff {
+    unsafe fn record(
+        &self,
+        device: &Device,
+        command_buffer: vk::CommandBuffer,
+        src: (vk::PipelineStageFlags, vk::AccessFlags),
+        dst: (vk::PipelineStageFlags, vk::AccessFlags),
+        families: (u32, u32),
+    ) {
+        let (src_family, dst_family) = if families.0 == families.1 {
+            (vk::QUEUE_FAMILY_IGNORED, vk::QUEUE_FAMILY_IGNORED)
+        } else {
+            families
+        };
+        match *self {
+            Handoff::Buffer(buffer, _) => {
+                let barrier = vk::BufferMemoryBarrier::builder()
+                    .buffer(buffer)
+                    .offset(0)
+                    .size(vk::WHOLE_SIZE as vk::DeviceSize)
+                    .src_queue_family_index(src_family)
+                    .dst_queue_family_index(dst_family)
+                    .src_access_mask(src.1)
+                    .dst_access_mask(dst.1);
+                device.cmd_pipeline_barrier(
+                    command_buffer,
+                    src.0,
+                    dst.0,
+                    vk::DependencyFlags::empty(),
+                    &[] as &[vk::MemoryBarrier],
+                    &[barrier],
+                    &[] as &[vk::ImageMemoryBarrier],
+                );
+            }
+            Handoff::Image(image) => {
+                let subresource =
+                    vk::ImageSubresourceRange::builder()
+                        .aspect_mask(vk::ImageAspectFlags::COLOR)
+                        .base_mip_level(0)
+                        .level_count(vk::REMAINING_MIP_LEVELS)
+                        .base_array_layer(0)
+                        .layer_count(vk::REMAINING_ARRAY_LAYERS);
+                let barrier = vk::ImageMemoryBarrier::builder()
+                    .image(image)
+                    .old_layout(vk::ImageLayout::GENERAL)
+                    .new_layout(
+                        vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                    )
+                    .src_queue_family_index(src_family)
+                    .dst_queue_family_index(dst_family)
+                    .subresource_range(subresource)
+                    .src_access_mask(src.1)
+                    .dst_access_mask(dst.1);
+                device.cmd_pipeline_barrier(
+                    command_buffer,
+                    src.0,
+                    dst.0,
+                    vk::DependencyFlags::empty(),
+                    &[] as &[vk::MemoryBarrier],
+                    &[] as &[vk::BufferMemoryBarrier],
+                    &[barrier],
+                );
+            }
+        }
+    }
+
+    fn dst(&self) -> (vk::PipelineStageFlags, vk::AccessFlags) {
+        match self {
+            Handoff::Buffer(_, output) => output.stage_access(),
+            Handoff::Image(_) => (
+                vk::PipelineStageFlags::FRAGMENT_SHADER,
+                vk::AccessFlags::SHADER_READ,
+            ),
+        }
+    }
+}
+
+/// Records the barriers handing `handoffs` to the graphics pipeline
+/// into a compute command buffer, after the dispatches writing them.
+/// With a dedicated compute family this releases ownership and
+/// `acquire` has to be recorded on the graphics queue.
+pub unsafe fn release(
+    device: &Device,
+    command_buffer: vk::CommandBuffer,
+    handoffs: &[Handoff],
+    families: &QueueFamilyIndices,
+) {
+    let src = (
+        vk::PipelineStageFlags::COMPUTE_SHADER,
+        vk::AccessFlags::SHADER_WRITE,
+    );
+    for handoff in handoffs {
+        let dst = if families.has_dedicated_compute() {
+            (
+                vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                vk::AccessFlags::empty(),
+            )
+        } else {
+            handoff.dst()
+        };
+        handoff.record(
+            device,
+            command_buffer,
+            src,
+            dst,
+            (families.compute, families.graphics),
+        );
+    }
+}
+
+/// Records the acquiring half of `release` into a graphics command
+/// buffer, outside of a render pass. Does nothing unless compute has
+/// its own queue family.
+pub unsafe fn acquire(
+    device: &Device,
+    command_buffer: vk::CommandBuffer,
+    handoffs: &[Handoff],
+    families: &QueueFamilyIndices,
+) {
+    if !families.has_dedicated_compute() {
+        return;
+    }
+    let src = (
+        vk::PipelineStageFlags::TOP_OF_PIPE,
+        vk::AccessFlags::empty(),
+    );
+    for handoff in handoffs {
+        handoff.record(
+            device,
+            command_buffer,
+            src,
+            handoff.dst(),
+            (families.compute, families.graphics),
+        );
+    }
+}
+
+/// Workgroups needed to cover `invocations` with `workgroup_size`.
+pub fn group_count(
+    invocations: [u32; 3],
+    workgroup_size: [u32; 3],
+) -> [u32; 3] {
+    [0, 1, 2]
+        .map(|i| invocations[i].div_ceil(workgroup_size[i].max(1)))
+}
+
+/// Semaphores the graphics submission of a frame in flight waits on
+/// when compute work was submitted for it, one per frame.
+pub unsafe fn create_compute_semaphores(
+    device: &Rc<LogicalDevice>,
+    frames_in_flight: usize,
+) -> Result<Vec<Owned<vk::Semaphore>>> {
+    (0..frames_in_flight)
+        .map(|i| {
+            Ok(Owned::new(
+                device,
+                device.create_semaphore(
+                    &vk::SemaphoreCreateInfo::builder(),
+                    None,
+                )?,
+            )
+            .named(&format!("Compute semaphore {}", i)))
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum ComputeError {
+    #[error(transparent)]
+    VkErrorCode(#[from] ErrorCode),
+    #[error("Compute work was already submitted for this frame.")]
+    AlreadySubmittedError,
+}
+type Result<T> = std::result::Result<T, ComputeError>;
+
+impl VkErrorSource for ComputeError {
+    fn vk_error_code(&self) -> Option<ErrorCode> {
+        match self {
+            Self::VkErrorCode(e) => Some(*e),
+            Self::AlreadySubmittedError => None,
+        }
+    }
+}
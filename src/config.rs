@@ -0,0 +1,752 @@
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use serde::Deserialize;
+use toml::{Table, Value};
+use vulkanalia::vk;
+
+use crate::device::DeviceSelector;
+
+/// Loaded from the working directory if it exists and no other file
+/// is given.
+pub const CONFIG_PATH: &str = "broth.toml";
+/// Overrides `renderer.device`.
+pub const DEVICE_ENV: &str = "BROTH_DEVICE";
+/// Overrides `renderer.validation`, e.g. `BROTH_VALIDATION=false`.
+pub const VALIDATION_ENV: &str = "BROTH_VALIDATION";
+
+/// Renderer settings, read from a TOML file. Every key is optional
+/// and defaults to the value below.
+///
+/// ```toml
+/// log = "info"
+///
+/// [window]
+/// width = 1024
+/// height = 768
+/// title = "Broth"
+///
+/// [renderer]
+/// msaa = "max"            # or 1, 2, 4, 8, 16, 32, 64
+/// present_mode = "mailbox" # immediate, mailbox, fifo, fifo_relaxed
+/// validation = true       # defaults to on in debug builds only
+/// validation_features = [] # synchronization, best_practices, gpu_assisted
+/// validation_sink = "log" # or "panic" on the first error
+/// output = ["srgb"]       # e.g. ["hdr10", "extended_srgb", "srgb"]
+/// paper_white = 203.0     # nits of white in HDR output
+/// sample_shading = 0.0    # 0 to 1, shaded fraction of MSAA samples
+/// device = "nvidia"       # name substring, "#index" or UUID
+/// pipeline_statistics = false  # counts GPU work, see App::stats
+///
+/// [scene]
+/// model = "resources/fish.obj"
+/// texture = "resources/fish_albedo.png"
+/// ```
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// `env_logger` filter, `RUST_LOG` takes precedence.
+    pub log: String,
+    pub window: WindowConfig,
+    pub renderer: RendererConfig,
+    pub scene: SceneConfig,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WindowConfig {
+    pub width: u32,
+    pub height: u32,
+    pub title: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RendererConfig {
+    pub msaa: Msaa,
+    /// Used if the surface supports it, FIFO otherwise.
+    pub present_mode: PresentMode,
+    /// Enables the Khronos validation layer.
+    pub validation: bool,
+    /// Extra validation layer checks, these slow rendering down.
+    pub validation_features: Vec<ValidationFeature>,
+    pub validation_sink: ValidationSink,
+    /// Swapchain outputs in order of preference, see
+    /// `get_swapchain_surface_format`.
+    pub output: Vec<ColorOutput>,
+    /// Luminance of SDR white in HDR outputs, in nits.
+    pub paper_white: f32,
+    /// Minimum fraction of MSAA samples shaded per pixel, 0 disables
+    /// sample shading. Ignored on devices without sample shading.
+    pub sample_shading: f32,
+    /// Pins the physical device, the best ranked one is used
+    /// otherwise.
+    pub device: Option<DeviceSelector>,
+    /// Counts what the main pass processes on the GPU, see
+    /// `App::stats`.
+    pub pipeline_statistics: bool,
+}
+
+impl RendererConfig {
+    pub fn sample_shading(&self) -> Option<f32> {
+        (self.sample_shading > 0.0).then_some(self.sample_shading)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SceneConfig {
+    pub model: PathBuf,
+    pub texture: PathBuf,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            log: "info".into(),
+            window: WindowConfig::default(),
+            renderer: RendererConfig::default(),
+            scene: SceneConfig::default(),
+        }
+    }
+}
+
+impl Default for WindowConfig {
+    fn default() -> Self {
+        Self {
+            width: 1024,
+            height: 768,
+            title: "Broth".into(),
+        }
+    }
+}
+
+impl Default for RendererConfig {
+    fn default() -> Self {
+        Self {
+            msaa: Msaa::Max,
+            present_mode: PresentMode::Mailbox,
+            validation: cfg!(debug_assertions),
+            validation_features: Vec::new(),
+            validation_sink: ValidationSink::Log,
+            output: vec![ColorOutput::Srgb],
+            paper_white: 203.0,
+            sample_shading: 0.0,
+            device: None,
+            pipeline_statistics: false,
+        }
+    }
+}
+
+impl Default for SceneConfig {
+    fn default() -> Self {
+        Self {
+            model: PathBuf::from(crate::app::DEFAULT_MODEL_PATH),
+            texture: PathBuf::from(crate::app::DEFAULT_TEXTURE_PATH),
+        }
+    }
+}
+
+impl Config {
+    /// Reads the file at `path`, or `CONFIG_PATH` if it exists, and
+    /// applies `DEVICE_ENV`, `VALIDATION_ENV` and then `overrides` of
+    /// the form
+    /// `renderer.msaa=4` on top. Values that aren't valid TOML are
+    /// taken as strings, so paths don't need quotes.
+    pub fn load(
+        path: Option<&Path>,
+        overrides: &[String],
+    ) -> Result<Self> {
+        let open_error = |path: &Path, e: io::Error| {
+            ConfigError::FileOpenError(
+                path.display().to_string(),
+                e.to_string(),
+            )
+        };
+        let (path, source) = match path {
+            Some(path) => (
+                path.display().to_string(),
+                fs::read_to_string(path)
+                    .map_err(|e| open_error(path, e))?,
+            ),
+            // Only a missing default file falls back to the defaults.
+            None => (
+                CONFIG_PATH.to_string(),
+                match fs::read_to_string(CONFIG_PATH) {
+                    Ok(source) => source,
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                        String::new()
+                    }
+                    Err(e) => {
+                        return Err(open_error(
+                            Path::new(CONFIG_PATH),
+                            e,
+                        ))
+                    }
+                },
+            ),
+        };
+
+        Self::parse(&path, &source, &EnvOverrides::read(), overrides)
+    }
+
+    /// `load` for the contents of a file, `path` names it in errors.
+    fn parse(
+        path: &str,
+        source: &str,
+        env: &EnvOverrides,
+        overrides: &[String],
+    ) -> Result<Self> {
+        let mut table = source.parse::<Table>().map_err(|e| {
+            ConfigError::ParseError(format!(
+                "{}: {}",
+                path,
+                e.to_string().trim_end()
+            ))
+        })?;
+        if let Some(device) = env.device.clone() {
+            table
+                .entry("renderer")
+                .or_insert_with(|| Value::Table(Table::new()))
+                .as_table_mut()
+                .ok_or_else(|| {
+                    ConfigError::ParseError(format!(
+                        "{}: `renderer` is not a table",
+                        path
+                    ))
+                })?
+                .insert("device".into(), Value::String(device));
+        }
+        if let Some(validation) = &env.validation {
+            apply_override(
+                &mut table,
+                &format!("renderer.validation={}", validation),
+            )?;
+        }
+        for o in overrides {
+            apply_override(&mut table, o)?;
+        }
+
+        let config = Config::deserialize(table).map_err(|e| {
+            ConfigError::ParseError(format!(
+                "{}: {}",
+                path,
+                e.to_string().trim_end()
+            ))
+        })?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// Checks values the types don't rule out, `load` already does.
+    pub fn validate(&self) -> Result<()> {
+        let invalid =
+            |m: String| Err(ConfigError::InvalidValueError(m));
+        let window = &self.window;
+        if window.width == 0 || window.height == 0 {
+            return invalid(format!(
+                "window size {}x{} has to be at least 1x1",
+                window.width, window.height
+            ));
+        }
+        let paper_white = self.renderer.paper_white;
+        if !paper_white.is_finite() || paper_white <= 0.0 {
+            return invalid(format!(
+                "renderer.paper_white {} has to be positive",
+                paper_white
+            ));
+        }
+        if !(0.0..=1.0).contains(&self.renderer.sample_shading) {
+            return invalid(format!(
+                "renderer.sample_shading {} has to be between 0 and 1",
+                self.renderer.sample_shading
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+impl SceneConfig {
+    /// Checks that the scene files exist. Not part of
+    /// `Config::validate`, as only rendering loads the scene.
+    pub fn validate(&self) -> Result<()> {
+        for (key, path) in [
+            ("scene.model", &self.model),
+            ("scene.texture", &self.texture),
+        ] {
+            if !path.is_file() {
+                return Err(ConfigError::InvalidValueError(format!(
+                    "{} `{}` is not a file",
+                    key,
+                    path.display()
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The environment variables `Config::load` applies.
+#[derive(Debug, Default)]
+struct EnvOverrides {
+    device: Option<String>,
+    validation: Option<String>,
+}
+
+impl EnvOverrides {
+    fn read() -> Self {
+        Self {
+            device: env::var(DEVICE_ENV).ok(),
+            validation: env::var(VALIDATION_ENV).ok(),
+        }
+    }
+}
+
+/// Sets the dotted `key` of `key=value` in `table`.
+fn apply_override(table: &mut Table, assignment: &str) -> Result<()> {
+    let error = || ConfigError::OverrideError(assignment.to_string());
+    let (key, value) =
+        assignment.split_once('=').ok_or_else(error)?;
+    let value = format!("v = {}", value.trim())
+        .parse::<Table>()
+        .ok()
+        .and_then(|mut t| t.remove("v"))
+        .unwrap_or_else(|| Value::String(value.trim().to_string()));
+
+    let mut keys = key.trim().split('.').peekable();
+    let mut table = table;
+    while let Some(key) = keys.next() {
+        if key.is_empty() {
+            return Err(error());
+        }
+        if keys.peek().is_none() {
+            table.insert(key.to_string(), value);
+            break;
+        }
+        table = table
+            .entry(key)
+            .or_insert_with(|| Value::Table(Table::new()))
+            .as_table_mut()
+            .ok_or_else(error)?;
+    }
+
+    Ok(())
+}
+
+/// Sample count of the color and depth attachments.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "Value")]
+pub enum Msaa {
+    /// The highest count the device supports.
+    Max,
+    Samples(vk::SampleCountFlags),
+}
+
+impl Msaa {
+    /// The largest count in `supported`, the counts the device's
+    /// color and depth attachments both support as reported by
+    /// `pick_physical_device`, that doesn't exceed this one.
+    pub fn sample_count(
+        self,
+        supported: vk::SampleCountFlags,
+    ) -> vk::SampleCountFlags {
+        let limit = match self {
+            Msaa::Max => u32::MAX,
+            Msaa::Samples(samples) => samples.bits(),
+        };
+        let samples = (0..7)
+            .rev()
+            .map(|i| vk::SampleCountFlags::from_bits_truncate(1 << i))
+            .find(|c| c.bits() <= limit && supported.contains(*c))
+            .unwrap_or(vk::SampleCountFlags::_1);
+        if samples.bits() != limit && self != Msaa::Max {
+            log::warn!(
+                "MSAA x{} is not supported, using x{}.",
+                limit,
+                samples.bits()
+            );
+        }
+        samples
+    }
+}
+
+impl TryFrom<Value> for Msaa {
+    type Error = String;
+
+    fn try_from(value: Value) -> std::result::Result<Self, String> {
+        match value {
+            Value::String(s) if s == "max" => Ok(Msaa::Max),
+            Value::Integer(n @ (1 | 2 | 4 | 8 | 16 | 32 | 64)) => {
+                Ok(Msaa::Samples(vk::SampleCountFlags::from_bits_truncate(
+                    n as u32,
+                )))
+            }
+            v => Err(format!(
+                "invalid msaa {}, expected \"max\" or one of 1, 2, 4, 8, 16, 32, 64",
+                v
+            )),
+        }
+    }
+}
+
+impl FromStr for Msaa {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        Msaa::try_from(match s.parse::<i64>() {
+            Ok(n) => Value::Integer(n),
+            Err(_) => Value::String(s.to_string()),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PresentMode {
+    Immediate,
+    Mailbox,
+    Fifo,
+    FifoRelaxed,
+}
+
+/// A swapchain color space and the formats it is used with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ColorOutput {
+    /// 8 bit sRGB, supported everywhere.
+    Srgb,
+    /// 10 bit Rec. 2020 with the PQ transfer function.
+    Hdr10,
+    /// 16 bit float scRGB, linear and unbounded.
+    ExtendedSrgb,
+}
+
+/// Checks `VkValidationFeaturesEXT` adds to the validation layer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ValidationFeature {
+    /// Missing barriers and other hazards between commands.
+    Synchronization,
+    /// Valid but slow API usage.
+    BestPractices,
+    /// Out of bounds accesses in shaders.
+    GpuAssisted,
+}
+
+/// Where validation messages go, see `validation::MessageSink` to
+/// collect them instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ValidationSink {
+    Log,
+    Panic,
+}
+
+impl FromStr for ValidationFeature {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        ValidationFeature::deserialize(Value::String(s.to_string()))
+            .map_err(|e| e.to_string())
+    }
+}
+
+impl FromStr for PresentMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        PresentMode::deserialize(Value::String(s.to_string()))
+            .map_err(|e| e.to_string())
+    }
+}
+
+impl From<PresentMode> for vk::PresentModeKHR {
+    fn from(mode: PresentMode) -> Self {
+        match mode {
+            PresentMode::Immediate => vk::PresentModeKHR::IMMEDIATE,
+            PresentMode::Mailbox => vk::PresentModeKHR::MAILBOX,
+            PresentMode::Fifo => vk::PresentModeKHR::FIFO,
+            PresentMode::FifoRelaxed => {
+                vk::PresentModeKHR::FIFO_RELAXED
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum ConfigError {
+    #[error("Failed to open config {0} with error: {1}")]
+    FileOpenError(String, String),
+    #[error("Invalid config {0}")]
+    ParseError(String),
+    #[error("Invalid override `{0}`, expected `key=value`.")]
+    OverrideError(String),
+    #[error("Invalid config value: {0}.")]
+    InvalidValueError(String),
+}
+type Result<T> = std::result::Result<T, ConfigError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(source: &str, overrides: &[&str]) -> Result<Config> {
+        let overrides = overrides
+            .iter()
+            .map(|o| o.to_string())
+            .collect::<Vec<_>>();
+        Config::parse(
+            "test.toml",
+            source,
+            &EnvOverrides::default(),
+            &overrides,
+        )
+    }
+
+    #[test]
+    fn empty_source_is_the_default() {
+        assert_eq!(parse("", &[]).unwrap(), Config::default());
+    }
+
+    #[test]
+    fn overrides_are_coerced_to_their_type() {
+        let config = parse(
+            "",
+            &[
+                "renderer.msaa=4",
+                "renderer.validation=false",
+                "renderer.paper_white = 100.0",
+                "renderer.output=[\"hdr10\", \"srgb\"]",
+                "window.title=\"Two words\"",
+                "scene.texture=resources/texture.png",
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            config.renderer.msaa,
+            Msaa::Samples(vk::SampleCountFlags::_4)
+        );
+        assert!(!config.renderer.validation);
+        assert_eq!(config.renderer.paper_white, 100.0);
+        assert_eq!(
+            config.renderer.output,
+            [ColorOutput::Hdr10, ColorOutput::Srgb]
+        );
+        assert_eq!(config.window.title, "Two words");
+        assert_eq!(
+            config.scene.texture,
+            Path::new("resources/texture.png")
+        );
+    }
+
+    #[test]
+    fn overrides_replace_file_values() {
+        let config = parse(
+            "[renderer]\nmsaa = 2\npresent_mode = \"fifo\"\n",
+            &["renderer.msaa=max"],
+        )
+        .unwrap();
+        assert_eq!(config.renderer.msaa, Msaa::Max);
+        assert_eq!(config.renderer.present_mode, PresentMode::Fifo);
+    }
+
+    #[test]
+    fn environment_goes_between_file_and_overrides() {
+        let env = EnvOverrides {
+            device: Some("#0".into()),
+            validation: Some("false".into()),
+        };
+        let parse = |source: &str, overrides: &[&str]| {
+            let overrides: Vec<_> =
+                overrides.iter().map(|o| o.to_string()).collect();
+            Config::parse("test.toml", source, &env, &overrides)
+                .unwrap()
+        };
+
+        let config = parse(
+            "[renderer]\nvalidation = true\ndevice = \"nvidia\"\n",
+            &[],
+        );
+        assert!(!config.renderer.validation);
+        assert_eq!(
+            config.renderer.device,
+            Some(DeviceSelector::Index(0))
+        );
+
+        let config = parse(
+            "",
+            &["renderer.validation=true", "renderer.device=amd"],
+        );
+        assert!(config.renderer.validation);
+        assert_eq!(
+            config.renderer.device,
+            Some("amd".parse::<DeviceSelector>().unwrap())
+        );
+    }
+
+    #[test]
+    fn malformed_overrides_are_rejected() {
+        for o in ["renderer.msaa", "renderer..msaa=4", "=4"] {
+            assert!(
+                matches!(
+                    parse("", &[o]),
+                    Err(ConfigError::OverrideError(_))
+                ),
+                "{}",
+                o
+            );
+        }
+    }
+
+    #[test]
+    fn overrides_below_values_are_rejected() {
+        assert!(matches!(
+            parse("log = \"info\"\n", &["log.level=debug"]),
+            Err(ConfigError::OverrideError(_))
+        ));
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        for (source, overrides) in [
+            ("[renderer]\nsamples = 4\n", &[][..]),
+            ("[windw]\nwidth = 800\n", &[]),
+            ("", &["renderer.msaa_samples=4"]),
+        ] {
+            assert!(
+                matches!(
+                    parse(source, overrides),
+                    Err(ConfigError::ParseError(_))
+                ),
+                "{} {:?}",
+                source,
+                overrides
+            );
+        }
+    }
+
+    #[test]
+    fn wrong_types_are_rejected() {
+        assert!(parse("", &["window.width=wide"]).is_err());
+        assert!(parse("", &["renderer.present_mode=vsync"]).is_err());
+        assert!(
+            parse("[renderer]\nvalidation = \"yes\"\n", &[]).is_err()
+        );
+    }
+
+    #[test]
+    fn msaa_accepts_max_and_powers_of_two() {
+        assert_eq!(Msaa::try_from(Value::from("max")), Ok(Msaa::Max));
+        for n in [1, 2, 4, 8, 16, 32, 64] {
+            assert_eq!(
+                Msaa::try_from(Value::Integer(n)),
+                Ok(Msaa::Samples(
+                    vk::SampleCountFlags::from_bits_truncate(
+                        n as u32
+                    )
+                ))
+            );
+        }
+        for v in [
+            Value::Integer(0),
+            Value::Integer(3),
+            Value::Integer(128),
+            Value::from("high"),
+            Value::Float(4.0),
+        ] {
+            assert!(Msaa::try_from(v.clone()).is_err(), "{}", v);
+        }
+        assert_eq!(
+            "8".parse::<Msaa>(),
+            Ok(Msaa::Samples(vk::SampleCountFlags::_8))
+        );
+    }
+
+    #[test]
+    fn msaa_picks_supported_counts_only() {
+        // Lavapipe supports 1x and 4x.
+        let supported =
+            vk::SampleCountFlags::_1 | vk::SampleCountFlags::_4;
+        let samples = |n| {
+            Msaa::Samples(vk::SampleCountFlags::from_bits_truncate(n))
+        };
+        assert_eq!(
+            Msaa::Max.sample_count(supported),
+            vk::SampleCountFlags::_4
+        );
+        assert_eq!(
+            samples(2).sample_count(supported),
+            vk::SampleCountFlags::_1
+        );
+        assert_eq!(
+            samples(4).sample_count(supported),
+            vk::SampleCountFlags::_4
+        );
+        assert_eq!(
+            samples(16).sample_count(supported),
+            vk::SampleCountFlags::_4
+        );
+        assert_eq!(
+            Msaa::Max.sample_count(vk::SampleCountFlags::empty()),
+            vk::SampleCountFlags::_1
+        );
+    }
+
+    #[test]
+    fn out_of_range_values_are_invalid() {
+        for o in [
+            "window.width=0",
+            "renderer.paper_white=0.0",
+            "renderer.paper_white=nan",
+            "renderer.sample_shading=1.5",
+            "renderer.sample_shading=-0.1",
+        ] {
+            assert!(
+                matches!(
+                    parse("", &[o]),
+                    Err(ConfigError::InvalidValueError(_))
+                ),
+                "{}",
+                o
+            );
+        }
+    }
+
+    #[test]
+    fn missing_scene_files_only_fail_scene_validation() {
+        for o in [
+            "scene.model=resources/missing.obj",
+            "scene.texture=resources",
+        ] {
+            // E.g. `--list-devices` never loads the scene.
+            let config = parse("", &[o]).unwrap();
+            assert!(
+                matches!(
+                    config.scene.validate(),
+                    Err(ConfigError::InvalidValueError(_))
+                ),
+                "{}",
+                o
+            );
+        }
+        Config::default().scene.validate().unwrap();
+    }
+
+    #[test]
+    fn missing_files_are_open_errors() {
+        assert!(matches!(
+            Config::load(Some(Path::new("missing.toml")), &[]),
+            Err(ConfigError::FileOpenError(..))
+        ));
+        assert!(matches!(
+            Config::load(Some(Path::new("resources")), &[]),
+            Err(ConfigError::FileOpenError(..))
+        ));
+    }
+}
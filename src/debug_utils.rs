@@ -0,0 +1,78 @@
+use std::ffi::CString;
+
+use vulkanalia::vk::{
+    self, DeviceV1_0, ExtDebugUtilsExtension, Handle, HasBuilder,
+};
+
+use crate::device::LogicalDevice;
+
+/// Names `handle` in validation messages and captures. Does nothing
+/// without validation, which loads `VK_EXT_debug_utils`.
+pub unsafe fn set_name<H: Handle<Repr = u64>>(
+    device: &LogicalDevice,
+    handle: H,
+    name: &str,
+) {
+    let instance = device.instance();
+    if !instance.validation() || handle.is_null() {
+        return;
+    }
+    let Ok(name) = CString::new(name) else {
+        return;
+    };
+    let info = vk::DebugUtilsObjectNameInfoEXT::builder()
+        .object_type(H::TYPE)
+        .object_handle(handle.as_raw())
+        .object_name(name.as_bytes_with_nul());
+    if let Err(e) = instance
+        .set_debug_utils_object_name_ext(device.handle(), &info)
+    {
+        log::warn!("Failed to name {:?}: {}", handle, e);
+    }
+}
+
+/// Labels the commands recorded into a command buffer until it is
+/// dropped, labels nest. Does nothing without validation.
+#[must_use]
+pub struct Label<'a> {
+    device: &'a LogicalDevice,
+    command_buffer: vk::CommandBuffer,
+    enabled: bool,
+}
+
+impl<'a> Label<'a> {
+    pub unsafe fn begin(
+        device: &'a LogicalDevice,
+        command_buffer: vk::CommandBuffer,
+        name: &str,
+    ) -> Self {
+        let enabled = device.instance().validation();
+        if enabled {
+            let name = CString::new(name).unwrap_or_default();
+            let info = vk::DebugUtilsLabelEXT::builder()
+                .label_name(name.as_bytes_with_nul());
+            device.instance().cmd_begin_debug_utils_label_ext(
+                command_buffer,
+                &info,
+            );
+        }
+
+        Self {
+            device,
+            command_buffer,
+            enabled,
+        }
+    }
+}
+
+impl Drop for Label<'_> {
+    fn drop(&mut self) {
+        if self.enabled {
+            unsafe {
+                self.device.instance().cmd_end_debug_utils_label_ext(
+                    self.command_buffer,
+                );
+            }
+        }
+    }
+}
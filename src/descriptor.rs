@@ -1,211 +1,509 @@
-use std::mem::size_of;
+use std::{mem::size_of, rc::Rc};
 
 use vulkanalia::{
     vk::{self, DeviceV1_0, ErrorCode, HasBuilder},
     Device,
 };
 
-use crate::buffer::{CameraObject, ModelObject};
+use crate::{
+    buffer::{Buffer, CameraObject},
+    device::LogicalDevice,
+    draw::Material,
+    image::Image,
+    owned::Owned,
+    reflect::{DescriptorBinding, ReflectError, ShaderReflection},
+    texture::TextureData,
+    VkErrorSource,
+};
 
+/// Creates the layout of descriptor set `set` as the reflected
+/// shader stages expect it.
 pub unsafe fn create_descriptor_set_layout(
-    device: &Device,
-    descriptor_set_layout: &mut vk::DescriptorSetLayout,
-    uniform_buffer_count: u32,
-) -> Result<()> {
-    let mut bindings = vec![];
-
-    for i in 0..uniform_buffer_count {
-        let ubo_binding = vk::DescriptorSetLayoutBinding::builder()
-            .binding(i)
-            .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
-            .descriptor_count(1)
-            .stage_flags(vk::ShaderStageFlags::VERTEX);
-
-        bindings.push(ubo_binding);
-    }
-
-    let sampler_binding = vk::DescriptorSetLayoutBinding::builder()
-        .binding(2)
-        .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
-        .descriptor_count(1)
-        .stage_flags(vk::ShaderStageFlags::FRAGMENT);
-
-    bindings.push(sampler_binding);
+    device: &Rc<LogicalDevice>,
+    reflection: &ShaderReflection,
+    set: u32,
+) -> Result<Owned<vk::DescriptorSetLayout>> {
+    let bindings = reflection
+        .set_bindings(set)
+        .map(|b| {
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(b.binding)
+                .descriptor_type(b.descriptor_type)
+                .descriptor_count(b.count)
+                .stage_flags(b.stages)
+                .build()
+        })
+        .collect::<Vec<_>>();
 
     let info = vk::DescriptorSetLayoutCreateInfo::builder()
         .bindings(&bindings);
 
-    *descriptor_set_layout =
+    let descriptor_set_layout =
         device.create_descriptor_set_layout(&info, None)?;
 
-    Ok(())
+    Ok(Owned::new(device, descriptor_set_layout)
+        .named(&format!("Descriptor set layout {}", set)))
 }
 
 pub unsafe fn create_descriptor_pool(
-    device: &Device,
-    swapchain_images_len: u32,
-    uniform_buffer_count: u32,
-    descriptor_pool: &mut vk::DescriptorPool,
-) -> Result<()> {
-    let mut pool_sizes = vec![];
-
-    for _ in 0..uniform_buffer_count {
-        let ubo_size = vk::DescriptorPoolSize::builder()
-            .type_(vk::DescriptorType::UNIFORM_BUFFER)
-            .descriptor_count(swapchain_images_len);
-
-        pool_sizes.push(ubo_size);
-    }
-    let sampler_size = vk::DescriptorPoolSize::builder()
-        .type_(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
-        .descriptor_count(swapchain_images_len);
-
-    pool_sizes.push(sampler_size);
+    device: &Rc<LogicalDevice>,
+    frames_in_flight: u32,
+    reflection: &ShaderReflection,
+    set: u32,
+) -> Result<Owned<vk::DescriptorPool>> {
+    let pool_sizes = reflection.pool_sizes(set, frames_in_flight);
     let info = vk::DescriptorPoolCreateInfo::builder()
         .pool_sizes(&pool_sizes)
-        .max_sets(swapchain_images_len);
+        .max_sets(frames_in_flight);
 
-    *descriptor_pool = device.create_descriptor_pool(&info, None)?;
+    let descriptor_pool =
+        device.create_descriptor_pool(&info, None)?;
 
-    Ok(())
+    Ok(Owned::new(device, descriptor_pool)
+        .named(&format!("Descriptor pool {}", set)))
 }
 
-pub unsafe fn create_descriptor_sets(
+/// Allocates `count` sets with `descriptor_set_layout`, e.g. for
+/// compute pipelines whose descriptors are written individually.
+pub unsafe fn allocate_descriptor_sets(
     device: &Device,
-    swapchain_images_len: usize,
     descriptor_pool: vk::DescriptorPool,
     descriptor_set_layout: vk::DescriptorSetLayout,
-    camera_buffers: &[vk::Buffer],
-    model_buffers: &[vk::Buffer],
-    texture_image_view: vk::ImageView,
-    texture_sampler: vk::Sampler,
-    descriptor_sets: &mut Vec<vk::DescriptorSet>,
-) -> Result<()> {
-    // Allocate
-
-    let layouts = vec![descriptor_set_layout; swapchain_images_len];
-
+    count: usize,
+) -> Result<Vec<vk::DescriptorSet>> {
+    let layouts = vec![descriptor_set_layout; count];
     let info = vk::DescriptorSetAllocateInfo::builder()
         .descriptor_pool(descriptor_pool)
         .set_layouts(&layouts);
 
-    *descriptor_sets = device.allocate_descriptor_sets(&info)?;
+    Ok(device.allocate_descriptor_sets(&info)?)
+}
+
+/// Set of the mesh shaders written once per frame in flight.
+pub const CAMERA_SET: u32 = 0;
+/// Set of the mesh shaders written once per `Material`.
+pub const MATERIAL_SET: u32 = 1;
+
+/// What the mesh descriptor sets are written with. Each is bound to
+/// the reflected binding of its variable name, whose type and block
+/// size have to match.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MeshDescriptor {
+    Camera,
+    Texture,
+    Sampler,
+}
 
-    // Update
+impl MeshDescriptor {
+    /// The variable name in the mesh shaders.
+    fn name(self) -> &'static str {
+        match self {
+            MeshDescriptor::Camera => "camera",
+            MeshDescriptor::Texture => "texImage",
+            MeshDescriptor::Sampler => "texSampler",
+        }
+    }
 
-    for i in 0..swapchain_images_len {
-        let info = vk::DescriptorBufferInfo::builder()
-            .buffer(camera_buffers[i])
-            .offset(0)
-            .range(size_of::<CameraObject>() as u64);
+    fn set(self) -> u32 {
+        match self {
+            MeshDescriptor::Camera => CAMERA_SET,
+            MeshDescriptor::Texture | MeshDescriptor::Sampler => {
+                MATERIAL_SET
+            }
+        }
+    }
 
-        let buffer_info = &[info];
-        let camera_write = vk::WriteDescriptorSet::builder()
-            .dst_set(descriptor_sets[i])
-            .dst_binding(0)
-            .dst_array_element(0)
-            .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
-            .buffer_info(buffer_info);
-
-        let info = vk::DescriptorBufferInfo::builder()
-            .buffer(model_buffers[i])
-            .offset(0)
-            .range(size_of::<ModelObject>() as u64);
-
-        let buffer_info = &[info];
-        let model_write = vk::WriteDescriptorSet::builder()
-            .dst_set(descriptor_sets[i])
-            .dst_binding(1)
-            .dst_array_element(0)
-            .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
-            .buffer_info(buffer_info);
-
-        let info = vk::DescriptorImageInfo::builder()
-            .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
-            .image_view(texture_image_view)
-            .sampler(texture_sampler);
-
-        let image_info = &[info];
-        let sampler_write = vk::WriteDescriptorSet::builder()
-            .dst_set(descriptor_sets[i])
-            .dst_binding(2)
-            .dst_array_element(0)
-            .descriptor_type(
-                vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
-            )
-            .image_info(image_info);
+    fn descriptor_type(self) -> vk::DescriptorType {
+        match self {
+            MeshDescriptor::Camera => {
+                vk::DescriptorType::UNIFORM_BUFFER
+            }
+            MeshDescriptor::Texture => {
+                vk::DescriptorType::SAMPLED_IMAGE
+            }
+            MeshDescriptor::Sampler => vk::DescriptorType::SAMPLER,
+        }
+    }
 
-        device.update_descriptor_sets(
-            &[camera_write, model_write, sampler_write],
-            &[] as &[vk::CopyDescriptorSet],
-        );
+    /// Block size of the buffers.
+    fn size(self) -> Option<u64> {
+        match self {
+            MeshDescriptor::Camera => {
+                Some(size_of::<CameraObject>() as u64)
+            }
+            MeshDescriptor::Texture | MeshDescriptor::Sampler => None,
+        }
     }
+}
 
-    Ok(())
+const MESH_DESCRIPTORS: [MeshDescriptor; 3] = [
+    MeshDescriptor::Camera,
+    MeshDescriptor::Texture,
+    MeshDescriptor::Sampler,
+];
+
+/// Pairs every binding of `set` with the mesh descriptor of its
+/// name, every binding and descriptor of the set has to be used
+/// exactly once.
+fn match_mesh_descriptors(
+    reflection: &ShaderReflection,
+    set: u32,
+) -> Result<Vec<(&DescriptorBinding, MeshDescriptor)>> {
+    let mut unused = MESH_DESCRIPTORS
+        .into_iter()
+        .filter(|d| d.set() == set)
+        .collect::<Vec<_>>();
+    let mut matched = vec![];
+    for b in reflection.set_bindings(set) {
+        let mismatch = |message: String| {
+            DescriptorError::from(
+                ReflectError::DescriptorMismatchError(format!(
+                    "set {} binding {} {}",
+                    set, b.binding, message
+                )),
+            )
+        };
+        let Some(name) = &b.name else {
+            return Err(mismatch(
+                "has no name, the shaders need debug names".into(),
+            ));
+        };
+        let Some(i) = unused.iter().position(|d| d.name() == name)
+        else {
+            return Err(mismatch(format!(
+                "is `{}`, nothing is left to bind to it",
+                name
+            )));
+        };
+        let descriptor = unused.remove(i);
+        if b.descriptor_type != descriptor.descriptor_type() {
+            return Err(mismatch(format!(
+                "is `{}` of type {:?}, expected {:?}",
+                name,
+                b.descriptor_type,
+                descriptor.descriptor_type()
+            )));
+        }
+        if b.size != descriptor.size() {
+            return Err(mismatch(format!(
+                "is `{}` of {} bytes, expected {}",
+                name,
+                b.size.unwrap_or_default(),
+                descriptor.size().unwrap_or_default()
+            )));
+        }
+        if b.count != 1 {
+            return Err(mismatch(format!(
+                "is an array of {}, expected a single {:?}",
+                b.count, b.descriptor_type
+            )));
+        }
+        matched.push((b, descriptor));
+    }
+    if let Some(descriptor) = unused.first() {
+        return Err(ReflectError::DescriptorMismatchError(format!(
+            "set {} has no {:?} binding `{}` for the {:?}",
+            set,
+            descriptor.descriptor_type(),
+            descriptor.name(),
+            descriptor
+        ))
+        .into());
+    }
+
+    Ok(matched)
 }
 
-pub unsafe fn create_descriptor_sets_2d(
+/// Allocates and writes a camera set per frame in flight for the
+/// mesh shaders, their bindings of `CAMERA_SET` are taken from
+/// `reflection`.
+pub unsafe fn create_descriptor_sets(
     device: &Device,
-    swapchain_images_len: usize,
+    frames_in_flight: usize,
     descriptor_pool: vk::DescriptorPool,
     descriptor_set_layout: vk::DescriptorSetLayout,
-    uniform_buffers: &[vk::Buffer],
-    texture_image_view: vk::ImageView,
-    texture_sampler: vk::Sampler,
+    reflection: &ShaderReflection,
+    camera_buffers: &[Buffer],
     descriptor_sets: &mut Vec<vk::DescriptorSet>,
 ) -> Result<()> {
-    // Allocate
-
-    let layouts = vec![descriptor_set_layout; swapchain_images_len];
+    let bindings = match_mesh_descriptors(reflection, CAMERA_SET)?;
+    *descriptor_sets = allocate_descriptor_sets(
+        device,
+        descriptor_pool,
+        descriptor_set_layout,
+        frames_in_flight,
+    )?;
+
+    for (i, set) in descriptor_sets.iter().enumerate() {
+        for (binding, _) in &bindings {
+            let buffer_info = [vk::DescriptorBufferInfo::builder()
+                .buffer(camera_buffers[i].handle())
+                .offset(0)
+                .range(size_of::<CameraObject>() as u64)];
+            let write = vk::WriteDescriptorSet::builder()
+                .dst_set(*set)
+                .dst_binding(binding.binding)
+                .dst_array_element(0)
+                .descriptor_type(binding.descriptor_type)
+                .buffer_info(&buffer_info);
+
+            device.update_descriptor_sets(
+                &[write],
+                &[] as &[vk::CopyDescriptorSet],
+            );
+        }
+    }
 
-    let info = vk::DescriptorSetAllocateInfo::builder()
-        .descriptor_pool(descriptor_pool)
-        .set_layouts(&layouts);
+    Ok(())
+}
 
-    *descriptor_sets = device.allocate_descriptor_sets(&info)?;
-
-    // Update
-
-    for i in 0..swapchain_images_len {
-        // let info = vk::DescriptorBufferInfo::builder()
-        //     .buffer(data.uniform_buffers[i])
-        //     .offset(0)
-        //     .range(size_of::<UniformBufferObject>() as u64);
-
-        // let buffer_info = &[info];
-        // let ubo_write = vk::WriteDescriptorSet::builder()
-        //     .dst_set(data.descriptor_sets[i])
-        //     .dst_binding(0)
-        //     .dst_array_element(0)
-        //     .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
-        //     .buffer_info(buffer_info);
-        let info = vk::DescriptorImageInfo::builder()
-            .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
-            .image_view(texture_image_view)
-            .sampler(texture_sampler);
-
-        let image_info = &[info];
-        let sampler_write = vk::WriteDescriptorSet::builder()
-            .dst_set(descriptor_sets[i])
-            .dst_binding(1)
+/// Creates a material from `image` and `sampler`, uploaded from
+/// `source`, with its own pool. Its bindings of `MATERIAL_SET` are
+/// taken from `reflection`.
+pub unsafe fn create_material(
+    device: &Rc<LogicalDevice>,
+    descriptor_set_layout: vk::DescriptorSetLayout,
+    reflection: &ShaderReflection,
+    image: Image,
+    sampler: Owned<vk::Sampler>,
+    source: Rc<TextureData>,
+) -> Result<Material> {
+    let bindings = match_mesh_descriptors(reflection, MATERIAL_SET)?;
+    let descriptor_pool =
+        create_descriptor_pool(device, 1, reflection, MATERIAL_SET)?;
+    let descriptor_set = allocate_descriptor_sets(
+        device,
+        descriptor_pool.handle(),
+        descriptor_set_layout,
+        1,
+    )?[0];
+
+    for (binding, descriptor) in &bindings {
+        let image_info = [match descriptor {
+            MeshDescriptor::Sampler => {
+                vk::DescriptorImageInfo::builder()
+                    .sampler(sampler.handle())
+                    .build()
+            }
+            _ => vk::DescriptorImageInfo::builder()
+                .image_layout(
+                    vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                )
+                .image_view(image.view())
+                .build(),
+        }];
+        let write = vk::WriteDescriptorSet::builder()
+            .dst_set(descriptor_set)
+            .dst_binding(binding.binding)
             .dst_array_element(0)
-            .descriptor_type(
-                vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
-            )
-            .image_info(image_info);
+            .descriptor_type(binding.descriptor_type)
+            .image_info(&image_info);
 
         device.update_descriptor_sets(
-            &[sampler_write],
+            &[write],
             &[] as &[vk::CopyDescriptorSet],
         );
     }
 
-    Ok(())
+    Ok(Material::new(
+        descriptor_set,
+        descriptor_pool,
+        image,
+        sampler,
+        source,
+    ))
 }
 
 #[derive(Debug, Clone, thiserror::Error)]
 pub enum DescriptorError {
     #[error(transparent)]
     VkErrorCode(#[from] ErrorCode),
+    #[error(transparent)]
+    ReflectError(#[from] ReflectError),
 }
 type Result<T> = std::result::Result<T, DescriptorError>;
+
+impl VkErrorSource for DescriptorError {
+    fn vk_error_code(&self) -> Option<ErrorCode> {
+        match self {
+            Self::VkErrorCode(e) => Some(*e),
+            Self::ReflectError(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shader::compile_embedded_shader;
+
+    fn binding(
+        set: u32,
+        binding: u32,
+        descriptor_type: vk::DescriptorType,
+        name: &str,
+    ) -> DescriptorBinding {
+        DescriptorBinding {
+            name: Some(name.to_string()),
+            set,
+            binding,
+            descriptor_type,
+            count: 1,
+            stages: vk::ShaderStageFlags::FRAGMENT,
+            size: None,
+        }
+    }
+
+    fn uniform(
+        binding: u32,
+        name: &str,
+        size: usize,
+    ) -> DescriptorBinding {
+        DescriptorBinding {
+            size: Some(size as u64),
+            ..self::binding(
+                CAMERA_SET,
+                binding,
+                vk::DescriptorType::UNIFORM_BUFFER,
+                name,
+            )
+        }
+    }
+
+    fn camera(binding: u32) -> DescriptorBinding {
+        uniform(binding, "camera", size_of::<CameraObject>())
+    }
+
+    fn texture(binding: u32) -> DescriptorBinding {
+        self::binding(
+            MATERIAL_SET,
+            binding,
+            vk::DescriptorType::SAMPLED_IMAGE,
+            "texImage",
+        )
+    }
+
+    fn sampler(binding: u32) -> DescriptorBinding {
+        self::binding(
+            MATERIAL_SET,
+            binding,
+            vk::DescriptorType::SAMPLER,
+            "texSampler",
+        )
+    }
+
+    fn matched(
+        reflection: &ShaderReflection,
+        set: u32,
+    ) -> Vec<(u32, MeshDescriptor)> {
+        match_mesh_descriptors(reflection, set)
+            .unwrap()
+            .into_iter()
+            .map(|(b, d)| (b.binding, d))
+            .collect()
+    }
+
+    #[test]
+    fn mesh_shaders_match_mesh_descriptors() {
+        let vert = compile_embedded_shader("shader.vert").unwrap();
+        let frag = compile_embedded_shader("shader.frag").unwrap();
+        let reflection =
+            ShaderReflection::from_stages(&[&vert, &frag]).unwrap();
+
+        assert_eq!(
+            matched(&reflection, CAMERA_SET),
+            [(0, MeshDescriptor::Camera)]
+        );
+        assert_eq!(
+            matched(&reflection, MATERIAL_SET),
+            [
+                (0, MeshDescriptor::Texture),
+                (1, MeshDescriptor::Sampler)
+            ]
+        );
+    }
+
+    #[test]
+    fn bindings_follow_the_shader_not_fixed_numbers() {
+        let reflection = ShaderReflection {
+            bindings: vec![camera(5), sampler(4), texture(6)],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            matched(&reflection, CAMERA_SET),
+            [(5, MeshDescriptor::Camera)]
+        );
+        assert_eq!(
+            matched(&reflection, MATERIAL_SET),
+            [
+                (4, MeshDescriptor::Sampler),
+                (6, MeshDescriptor::Texture)
+            ]
+        );
+    }
+
+    #[test]
+    fn uniform_buffers_match_by_name() {
+        // A block of the right size but another name isn't the camera.
+        let size = size_of::<CameraObject>();
+        let mut reflection = ShaderReflection {
+            bindings: vec![uniform(0, "lights", size)],
+            ..Default::default()
+        };
+        assert!(matches!(
+            match_mesh_descriptors(&reflection, CAMERA_SET),
+            Err(DescriptorError::ReflectError(
+                ReflectError::DescriptorMismatchError(_)
+            ))
+        ));
+
+        reflection.bindings[0] = camera(0);
+        assert!(
+            match_mesh_descriptors(&reflection, CAMERA_SET).is_ok()
+        );
+
+        // The size only checks the named block.
+        reflection.bindings[0] = uniform(0, "camera", 128);
+        assert!(
+            match_mesh_descriptors(&reflection, CAMERA_SET).is_err()
+        );
+        reflection.bindings[0] = camera(0);
+        reflection.bindings[0].name = None;
+        assert!(
+            match_mesh_descriptors(&reflection, CAMERA_SET).is_err()
+        );
+    }
+
+    #[test]
+    fn mismatched_bindings_are_rejected() {
+        let mut reflection = ShaderReflection {
+            bindings: vec![
+                binding(
+                    MATERIAL_SET,
+                    0,
+                    vk::DescriptorType::STORAGE_IMAGE,
+                    "texImage",
+                ),
+                sampler(1),
+            ],
+            ..Default::default()
+        };
+        assert!(matches!(
+            match_mesh_descriptors(&reflection, MATERIAL_SET),
+            Err(DescriptorError::ReflectError(
+                ReflectError::DescriptorMismatchError(_)
+            ))
+        ));
+
+        // The texture has nowhere to go.
+        reflection.bindings.remove(0);
+        assert!(match_mesh_descriptors(&reflection, MATERIAL_SET)
+            .is_err());
+        // Nor has a camera in the material set.
+        reflection.bindings.push(camera(2));
+        reflection.bindings[1].set = MATERIAL_SET;
+        assert!(match_mesh_descriptors(&reflection, MATERIAL_SET)
+            .is_err());
+    }
+}
@@ -1,31 +1,91 @@
-use std::collections::HashSet;
+use std::{
+    collections::HashSet, convert::Infallible, ops::Deref, rc::Rc,
+    str::FromStr,
+};
+
+use serde::Deserialize;
 
 use vulkanalia::{
-    vk::{self, DeviceV1_0, ErrorCode, HasBuilder, InstanceV1_0},
-    Device, Entry, Instance,
+    vk::{
+        self, DeviceV1_0, ErrorCode, Handle, HasBuilder,
+        InstanceV1_0, InstanceV1_1,
+    },
+    Device, Instance, Version,
 };
 
 use crate::{
+    config::Msaa,
+    instance::VulkanInstance,
     queue::{QueueError, QueueFamilyIndices},
     swapchain::{SwapchainError, SwapchainSupport},
     validation::{validated_layers, ValidationError},
-    DEVICE_EXTENSIONS, IS_MACOS, PORTABILITY_MACOS_VERSION,
+    VkErrorSource, DEVICE_EXTENSIONS, IS_MACOS,
+    PORTABILITY_MACOS_VERSION,
 };
 
+/// Owns the logical device and destroys it when dropped. Device
+/// objects hold an `Rc` of it, so the device outlives everything
+/// created from it regardless of the order the owners are dropped in.
+#[derive(Debug)]
+pub struct LogicalDevice {
+    device: Device,
+    instance: Rc<VulkanInstance>,
+    pipeline_statistics: bool,
+    sample_rate_shading: bool,
+}
+
+impl LogicalDevice {
+    pub(crate) fn instance(&self) -> &Rc<VulkanInstance> {
+        &self.instance
+    }
+
+    /// Whether pipeline statistics queries were enabled, they are
+    /// whenever the device supports them.
+    pub fn pipeline_statistics(&self) -> bool {
+        self.pipeline_statistics
+    }
+
+    /// Whether sample shading was enabled, it is whenever the device
+    /// supports it.
+    pub fn sample_rate_shading(&self) -> bool {
+        self.sample_rate_shading
+    }
+}
+
+impl Deref for LogicalDevice {
+    type Target = Device;
+
+    fn deref(&self) -> &Self::Target {
+        &self.device
+    }
+}
+
+impl Drop for LogicalDevice {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.destroy_device(None);
+        }
+    }
+}
+
 pub unsafe fn create_logical_device(
-    entry: &Entry,
-    instance: &Instance,
+    instance: &Rc<VulkanInstance>,
     surface: vk::SurfaceKHR,
     physical_device: vk::PhysicalDevice,
     graphics_queue: &mut vk::Queue,
     present_queue: &mut vk::Queue,
-) -> Result<Device> {
+    transfer_queue: &mut vk::Queue,
+    compute_queue: &mut vk::Queue,
+) -> Result<Rc<LogicalDevice>> {
+    let entry = instance.entry();
     let indices =
         QueueFamilyIndices::get(instance, surface, physical_device)?;
 
     let mut unique_indices = HashSet::new();
     unique_indices.insert(indices.graphics);
     unique_indices.insert(indices.present);
+    unique_indices.insert(indices.transfer);
+    unique_indices.insert(indices.compute);
 
     let queue_priorities = &[1.0];
     let queue_infos = unique_indices
@@ -37,8 +97,8 @@ pub unsafe fn create_logical_device(
         })
         .collect::<Vec<_>>();
 
-    let layers = validated_layers(entry)?;
-    let mut extensions = DEVICE_EXTENSIONS
+    let layers = validated_layers(entry, instance.validation())?;
+    let mut extensions = required_device_extensions(surface)
         .iter()
         .map(|n| n.as_ptr())
         .collect::<Vec<_>>();
@@ -48,9 +108,16 @@ pub unsafe fn create_logical_device(
             .push(vk::KHR_PORTABILITY_SUBSET_EXTENSION.name.as_ptr());
     }
 
+    let supported =
+        instance.get_physical_device_features(physical_device);
+    let pipeline_statistics =
+        supported.pipeline_statistics_query == vk::TRUE;
+    let sample_rate_shading =
+        supported.sample_rate_shading == vk::TRUE;
     let features = vk::PhysicalDeviceFeatures::builder()
         .sampler_anisotropy(true)
-        .sample_rate_shading(true);
+        .sample_rate_shading(sample_rate_shading)
+        .pipeline_statistics_query(pipeline_statistics);
     let info = vk::DeviceCreateInfo::builder()
         .queue_create_infos(&queue_infos)
         .enabled_layer_names(&layers)
@@ -61,8 +128,15 @@ pub unsafe fn create_logical_device(
         instance.create_device(physical_device, &info, None)?;
     *graphics_queue = device.get_device_queue(indices.graphics, 0);
     *present_queue = device.get_device_queue(indices.present, 0);
+    *transfer_queue = device.get_device_queue(indices.transfer, 0);
+    *compute_queue = device.get_device_queue(indices.compute, 0);
 
-    Ok(device)
+    Ok(Rc::new(LogicalDevice {
+        device,
+        instance: instance.clone(),
+        pipeline_statistics,
+        sample_rate_shading,
+    }))
 }
 
 pub unsafe fn check_physical_device(
@@ -71,123 +145,370 @@ pub unsafe fn check_physical_device(
     physical_device: vk::PhysicalDevice,
 ) -> Result<()> {
     QueueFamilyIndices::get(instance, surface, physical_device)?;
-    check_physical_device_extensions(instance, physical_device)?;
+    check_physical_device_extensions(
+        instance,
+        physical_device,
+        required_device_extensions(surface),
+    )?;
 
-    let support =
-        SwapchainSupport::get(instance, surface, physical_device)?;
-    if support.formats.is_empty() || support.present_modes.is_empty()
-    {
-        return Err(DeviceError::SwapchainSupportError);
+    if !surface.is_null() {
+        let support = SwapchainSupport::get(
+            instance,
+            surface,
+            physical_device,
+        )?;
+        if support.formats.is_empty()
+            || support.present_modes.is_empty()
+        {
+            return Err(DeviceError::SwapchainSupportError);
+        }
     }
     let features =
         instance.get_physical_device_features(physical_device);
     if features.sampler_anisotropy != vk::TRUE {
         return Err(DeviceError::FeatureError(
-            "No sampler anisotropy.".into(),
+            "sampler anisotropy".into(),
         ));
     }
 
     Ok(())
 }
 
+/// A physical device, how it ranks and why it can't be used, if it
+/// can't.
+#[derive(Debug, Clone)]
+pub struct DeviceReport {
+    pub physical_device: vk::PhysicalDevice,
+    /// Position in `enumerate_physical_devices`.
+    pub index: usize,
+    pub name: String,
+    /// `None` for Vulkan 1.0 devices.
+    pub uuid: Option<[u8; vk::UUID_SIZE]>,
+    pub device_type: vk::PhysicalDeviceType,
+    pub api_version: Version,
+    pub score: DeviceScore,
+    pub suitability: Result<()>,
+}
+
+impl DeviceReport {
+    /// The UUID as `xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx`.
+    pub fn uuid_string(&self) -> Option<String> {
+        let hex = self
+            .uuid?
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+        Some(format!(
+            "{}-{}-{}-{}-{}",
+            &hex[..8],
+            &hex[8..12],
+            &hex[12..16],
+            &hex[16..20],
+            &hex[20..]
+        ))
+    }
+}
+
+/// Ranks devices, fields compare in order so any discrete GPU wins
+/// over any integrated one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DeviceScore {
+    /// Discrete, integrated, virtual, CPU, other.
+    pub type_rank: u8,
+    /// Device local memory in bytes.
+    pub memory: u64,
+    pub max_msaa_samples: u32,
+    /// Number of supported optional features.
+    pub features: u32,
+}
+
+unsafe fn score_physical_device(
+    instance: &Instance,
+    physical_device: vk::PhysicalDevice,
+    properties: &vk::PhysicalDeviceProperties,
+) -> DeviceScore {
+    let type_rank = match properties.device_type {
+        vk::PhysicalDeviceType::DISCRETE_GPU => 4,
+        vk::PhysicalDeviceType::INTEGRATED_GPU => 3,
+        vk::PhysicalDeviceType::VIRTUAL_GPU => 2,
+        vk::PhysicalDeviceType::CPU => 1,
+        _ => 0,
+    };
+    let memory_properties = instance
+        .get_physical_device_memory_properties(physical_device);
+    let memory = memory_properties.memory_heaps
+        [..memory_properties.memory_heap_count as usize]
+        .iter()
+        .filter(|h| {
+            h.flags.contains(vk::MemoryHeapFlags::DEVICE_LOCAL)
+        })
+        .map(|h| h.size)
+        .sum();
+    let features =
+        instance.get_physical_device_features(physical_device);
+    let features = [
+        features.fill_mode_non_solid,
+        features.geometry_shader,
+        features.multi_draw_indirect,
+        features.pipeline_statistics_query,
+        features.sample_rate_shading,
+    ]
+    .iter()
+    .filter(|f| **f == vk::TRUE)
+    .count() as u32;
+
+    DeviceScore {
+        type_rank,
+        memory,
+        max_msaa_samples: Msaa::Max
+            .sample_count(get_supported_msaa_samples(
+                instance,
+                physical_device,
+            ))
+            .bits(),
+        features,
+    }
+}
+
+/// Reads the device UUID, which needs Vulkan 1.1.
+unsafe fn get_device_uuid(
+    instance: &Instance,
+    physical_device: vk::PhysicalDevice,
+    properties: &vk::PhysicalDeviceProperties,
+) -> Option<[u8; vk::UUID_SIZE]> {
+    if Version::from(properties.api_version) < Version::new(1, 1, 0) {
+        return None;
+    }
+    let mut id_properties = vk::PhysicalDeviceIDProperties::builder();
+    let mut properties2 = vk::PhysicalDeviceProperties2::builder()
+        .push_next(&mut id_properties);
+    instance.get_physical_device_properties2(
+        physical_device,
+        &mut properties2,
+    );
+    Some(id_properties.device_uuid.0)
+}
+
+/// Runs `check_physical_device` on every physical device, suitable
+/// devices first and best first.
+pub unsafe fn report_physical_devices(
+    instance: &Instance,
+    surface: vk::SurfaceKHR,
+) -> Result<Vec<DeviceReport>> {
+    let mut reports = instance
+        .enumerate_physical_devices()?
+        .into_iter()
+        .enumerate()
+        .map(|(index, physical_device)| {
+            let properties = instance
+                .get_physical_device_properties(physical_device);
+            DeviceReport {
+                physical_device,
+                index,
+                name: properties.device_name.to_string(),
+                uuid: get_device_uuid(
+                    instance,
+                    physical_device,
+                    &properties,
+                ),
+                device_type: properties.device_type,
+                api_version: Version::from(properties.api_version),
+                score: score_physical_device(
+                    instance,
+                    physical_device,
+                    &properties,
+                ),
+                suitability: check_physical_device(
+                    instance,
+                    surface,
+                    physical_device,
+                ),
+            }
+        })
+        .collect::<Vec<_>>();
+    reports.sort_by(|a, b| {
+        (b.suitability.is_ok(), b.score)
+            .cmp(&(a.suitability.is_ok(), a.score))
+    });
+
+    Ok(reports)
+}
+
+/// Pins the physical device instead of taking the best ranked one.
+/// Parsed from `#1` for an index, a UUID or else a name, so numeric
+/// names like `3090` aren't taken for an index.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(from = "RawDeviceSelector")]
+pub enum DeviceSelector {
+    /// Position in `enumerate_physical_devices`, as listed by
+    /// `--list-devices`.
+    Index(usize),
+    Uuid([u8; vk::UUID_SIZE]),
+    /// Case insensitive substring of the device name.
+    Name(String),
+}
+
+impl DeviceSelector {
+    pub fn matches(&self, report: &DeviceReport) -> bool {
+        match self {
+            DeviceSelector::Index(index) => report.index == *index,
+            DeviceSelector::Uuid(uuid) => report.uuid == Some(*uuid),
+            DeviceSelector::Name(name) => report
+                .name
+                .to_lowercase()
+                .contains(&name.to_lowercase()),
+        }
+    }
+}
+
+impl From<String> for DeviceSelector {
+    fn from(s: String) -> Self {
+        if let Some(Ok(index)) = s.strip_prefix('#').map(str::parse) {
+            return DeviceSelector::Index(index);
+        }
+        let hex = s.replace('-', "");
+        // Checked first, slicing a name with multibyte characters at
+        // byte offsets would panic.
+        if hex.len() == 2 * vk::UUID_SIZE
+            && hex.bytes().all(|b| b.is_ascii_hexdigit())
+        {
+            let mut uuid = [0; vk::UUID_SIZE];
+            for (i, byte) in uuid.iter_mut().enumerate() {
+                *byte =
+                    u8::from_str_radix(&hex[2 * i..2 * i + 2], 16)
+                        .unwrap();
+            }
+            return DeviceSelector::Uuid(uuid);
+        }
+        DeviceSelector::Name(s)
+    }
+}
+
+/// `device = 1` or `device = "#1"` in a config.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawDeviceSelector {
+    Index(usize),
+    Text(String),
+}
+
+impl From<RawDeviceSelector> for DeviceSelector {
+    fn from(raw: RawDeviceSelector) -> Self {
+        match raw {
+            RawDeviceSelector::Index(index) => {
+                DeviceSelector::Index(index)
+            }
+            RawDeviceSelector::Text(s) => s.into(),
+        }
+    }
+}
+
+impl FromStr for DeviceSelector {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Infallible> {
+        Ok(s.to_string().into())
+    }
+}
+
+/// Headless rendering (null surface) does not present, so it does
+/// not require the swapchain extension.
+pub fn required_device_extensions(
+    surface: vk::SurfaceKHR,
+) -> &'static [vk::ExtensionName] {
+    if surface.is_null() {
+        &[]
+    } else {
+        DEVICE_EXTENSIONS
+    }
+}
+
 pub unsafe fn check_physical_device_extensions(
     instance: &Instance,
     physical_device: vk::PhysicalDevice,
+    required_extensions: &[vk::ExtensionName],
 ) -> Result<()> {
     let extensions = instance
         .enumerate_device_extension_properties(physical_device, None)?
         .iter()
         .map(|e| e.extension_name)
         .collect::<HashSet<_>>();
-    if DEVICE_EXTENSIONS.iter().all(|e| extensions.contains(e)) {
+    if required_extensions.iter().all(|e| extensions.contains(e)) {
         Ok(())
     } else {
         Err(DeviceError::MissingExtensions)
     }
 }
 
+/// Picks the best ranked suitable device, or the one matching
+/// `selector`, see `report_physical_devices`.
 pub unsafe fn pick_physical_device(
     instance: &Instance,
     surface: vk::SurfaceKHR,
+    selector: Option<&DeviceSelector>,
     physical_device: &mut vk::PhysicalDevice,
     msaa_samples: &mut vk::SampleCountFlags,
 ) -> Result<()> {
-    for physical_device_t in instance.enumerate_physical_devices()? {
-        let properties = instance
-            .get_physical_device_properties(physical_device_t);
-
-        match check_physical_device(
-            instance,
-            surface,
-            physical_device_t,
-        ) {
-            Err(e) => {
-                log::warn!(
-                    "Skipping physical device (`{}`): {}",
-                    properties.device_name,
-                    e
-                );
+    let reports = report_physical_devices(instance, surface)?;
+    log::info!("Physical devices, best first:");
+    for report in &reports {
+        log::info!(
+            "  #{} `{}` ({:?}, {} MiB, msaa x{}): {}",
+            report.index,
+            report.name,
+            report.device_type,
+            report.score.memory / (1024 * 1024),
+            report.score.max_msaa_samples,
+            match &report.suitability {
+                Ok(()) => "suitable".to_string(),
+                Err(e) => format!("unsuitable, {}", e),
             }
-            Ok(()) => {
-                log::info!(
-                    "Selected physical device (`{}`).",
-                    properties.device_name
-                );
-                *physical_device = physical_device_t;
-                *msaa_samples =
-                    get_max_msaa_samples(instance, *physical_device);
-                log::info!(
-                    "Using msaa x{}",
-                    match *msaa_samples {
-                        vk::SampleCountFlags::_1 => 1,
-                        vk::SampleCountFlags::_2 => 2,
-                        vk::SampleCountFlags::_4 => 4,
-                        vk::SampleCountFlags::_8 => 8,
-                        vk::SampleCountFlags::_16 => 16,
-                        vk::SampleCountFlags::_32 => 32,
-                        vk::SampleCountFlags::_64 => 64,
-                        _ => 1,
-                    }
-                );
-                return Ok(());
+        );
+    }
+
+    let report = match selector {
+        Some(selector) => {
+            let report = reports
+                .iter()
+                .find(|r| selector.matches(r))
+                .ok_or_else(|| {
+                    DeviceError::SelectionError(format!(
+                        "no physical device matches {:?}",
+                        selector
+                    ))
+                })?;
+            if let Err(e) = &report.suitability {
+                return Err(DeviceError::SelectionError(format!(
+                    "`{}` is not suitable: {}",
+                    report.name, e
+                )));
             }
+            report
         }
-        // * let features = instance.get_physical_device_features(physical_device);
-    }
+        None => reports
+            .iter()
+            .find(|r| r.suitability.is_ok())
+            .ok_or(DeviceError::NoSuitableDevice)?,
+    };
 
-    // ************************
-    // * Example of gpu tests *
-    // ************************
-    // * if properties.device_type != vk::PhysicalDeviceType::DISCRETE_GPU {
-    // *    return Err(RootError::SuitabilityError("Only discrete GPUs are supported."));
-    // * }
-    // * if features.geometry_shader != vk::TRUE {
-    // *     return Err(RootError::SuitabilityError("Missing geometry shader support."));
-    // * }
+    log::info!("Selected physical device (`{}`).", report.name);
+    *physical_device = report.physical_device;
+    *msaa_samples =
+        get_supported_msaa_samples(instance, *physical_device);
+    log::info!("Supports msaa {:?}", msaa_samples);
 
     Ok(())
 }
 
-unsafe fn get_max_msaa_samples(
+/// The sample counts both color and depth attachments support.
+unsafe fn get_supported_msaa_samples(
     instance: &Instance,
     physical_device: vk::PhysicalDevice,
 ) -> vk::SampleCountFlags {
     let properties =
         instance.get_physical_device_properties(physical_device);
-    let counts = properties.limits.framebuffer_color_sample_counts
-        & properties.limits.framebuffer_depth_sample_counts;
-    [
-        vk::SampleCountFlags::_64,
-        vk::SampleCountFlags::_32,
-        vk::SampleCountFlags::_16,
-        vk::SampleCountFlags::_8,
-        vk::SampleCountFlags::_4,
-        vk::SampleCountFlags::_2,
-    ]
-    .iter()
-    .cloned()
-    .find(|c| counts.contains(*c))
-    .unwrap_or(vk::SampleCountFlags::_1)
+    properties.limits.framebuffer_color_sample_counts
+        & properties.limits.framebuffer_depth_sample_counts
 }
 
 #[derive(Debug, Clone, thiserror::Error)]
@@ -204,7 +525,80 @@ pub enum DeviceError {
     MissingExtensions,
     #[error("Insufficient swapchain support.")]
     SwapchainSupportError,
-    #[error("Insufficient swapchain support.")]
+    #[error("Missing device feature: {0}")]
     FeatureError(String),
+    #[error("No suitable physical device.")]
+    NoSuitableDevice,
+    #[error("Failed to select physical device, {0}.")]
+    SelectionError(String),
 }
 type Result<T> = std::result::Result<T, DeviceError>;
+
+impl VkErrorSource for DeviceError {
+    fn vk_error_code(&self) -> Option<ErrorCode> {
+        match self {
+            Self::VkErrorCode(e) => Some(*e),
+            Self::ValidationError(e) => e.vk_error_code(),
+            Self::QueueError(e) => e.vk_error_code(),
+            Self::SwapchainError(e) => e.vk_error_code(),
+            Self::MissingExtensions
+            | Self::SwapchainSupportError
+            | Self::FeatureError(_)
+            | Self::NoSuitableDevice
+            | Self::SelectionError(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> DeviceSelector {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn indices_need_a_hash() {
+        assert_eq!(parse("#1"), DeviceSelector::Index(1));
+        assert_eq!(parse("1"), DeviceSelector::Name("1".into()));
+        assert_eq!(
+            parse("3090"),
+            DeviceSelector::Name("3090".into())
+        );
+        assert_eq!(
+            parse("#gpu"),
+            DeviceSelector::Name("#gpu".into())
+        );
+
+        let config: DeviceSelector =
+            toml::Value::Integer(2).try_into().unwrap();
+        assert_eq!(config, DeviceSelector::Index(2));
+    }
+
+    #[test]
+    fn uuids_are_parsed_with_or_without_dashes() {
+        let uuid = "00112233-4455-6677-8899-aabbccddeeff";
+        let expected = DeviceSelector::Uuid([
+            0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88,
+            0x99, 0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff,
+        ]);
+        assert_eq!(parse(uuid), expected);
+        assert_eq!(parse(&uuid.replace('-', "")), expected);
+        assert_eq!(
+            parse("00112233-4455-6677-8899-aabbccddeefg"),
+            DeviceSelector::Name(
+                "00112233-4455-6677-8899-aabbccddeefg".into()
+            )
+        );
+    }
+
+    #[test]
+    fn non_ascii_names_are_names() {
+        // 32 bytes without dashes, like a UUID.
+        let name = "Radeon™ Pro W7900 Dual Slot 48";
+        assert_eq!(name.len(), 2 * vk::UUID_SIZE);
+        assert_eq!(parse(name), DeviceSelector::Name(name.into()));
+        assert_eq!(parse("#２"), DeviceSelector::Name("#２".into()));
+    }
+}
@@ -0,0 +1,479 @@
+use std::rc::Rc;
+
+use cgmath::SquareMatrix;
+use vulkanalia::vk;
+
+use crate::{
+    buffer::{Buffer, Mat4},
+    image::Image,
+    owned::Owned,
+    pipeline::Pipeline,
+    texture::TextureData,
+    vertex::Vertex3,
+};
+
+/// The vertices and indices of a mesh, kept to upload it again
+/// after a device loss.
+#[derive(Debug, Clone, Default)]
+pub struct MeshData {
+    pub vertices: Vec<Vertex3>,
+    pub indices: Vec<u32>,
+}
+
+/// Vertex and index buffers drawn together, shared between draws.
+#[derive(Debug)]
+pub struct Mesh {
+    vertex_buffer: Buffer,
+    index_buffer: Buffer,
+    index_count: u32,
+    source: Option<Rc<MeshData>>,
+}
+
+impl Mesh {
+    /// `index_buffer` holds `index_count` `u32` indices into
+    /// `vertex_buffer`.
+    pub(crate) fn new(
+        vertex_buffer: Buffer,
+        index_buffer: Buffer,
+        index_count: u32,
+    ) -> Self {
+        Self {
+            vertex_buffer,
+            index_buffer,
+            index_count,
+            source: None,
+        }
+    }
+
+    /// Keeps what the buffers were uploaded from, meshes without it
+    /// aren't rebuilt after a device loss.
+    pub(crate) fn with_source(
+        mut self,
+        source: Rc<MeshData>,
+    ) -> Self {
+        self.source = Some(source);
+        self
+    }
+
+    pub(crate) fn source(&self) -> Option<&Rc<MeshData>> {
+        self.source.as_ref()
+    }
+
+    pub(crate) fn vertex_buffer(&self) -> &Buffer {
+        &self.vertex_buffer
+    }
+
+    pub(crate) fn index_buffer(&self) -> &Buffer {
+        &self.index_buffer
+    }
+
+    pub fn index_count(&self) -> u32 {
+        self.index_count
+    }
+}
+
+/// A texture and its sampler written to a descriptor set for the
+/// material set of the mesh pipeline, shared between draws.
+#[derive(Debug)]
+pub struct Material {
+    descriptor_set: vk::DescriptorSet,
+    _descriptor_pool: Owned<vk::DescriptorPool>,
+    _image: Image,
+    sampler: Owned<vk::Sampler>,
+    source: Rc<TextureData>,
+}
+
+impl Material {
+    /// `descriptor_set` is allocated from `descriptor_pool` and
+    /// refers to `image`, uploaded from `source`, and `sampler`.
+    pub(crate) fn new(
+        descriptor_set: vk::DescriptorSet,
+        descriptor_pool: Owned<vk::DescriptorPool>,
+        image: Image,
+        sampler: Owned<vk::Sampler>,
+        source: Rc<TextureData>,
+    ) -> Self {
+        Self {
+            descriptor_set,
+            _descriptor_pool: descriptor_pool,
+            _image: image,
+            sampler,
+            source,
+        }
+    }
+
+    pub(crate) fn source(&self) -> &Rc<TextureData> {
+        &self.source
+    }
+
+    pub fn descriptor_set(&self) -> vk::DescriptorSet {
+        self.descriptor_set
+    }
+
+    pub fn sampler(&self) -> vk::Sampler {
+        self.sampler.handle()
+    }
+}
+
+/// A mesh drawn with a pipeline. The per frame camera set and the
+/// material are bound where the pipeline layout has their set
+/// layouts, and `transform` is pushed as a `mat4` at offset 0 if it
+/// has push constants there.
+#[derive(Debug, Clone)]
+pub struct Draw {
+    pub pipeline: Rc<Pipeline>,
+    pub mesh: Rc<Mesh>,
+    pub material: Option<Rc<Material>>,
+    /// Model to world space.
+    pub transform: Mat4,
+    pub visible: bool,
+}
+
+impl Draw {
+    pub fn new(pipeline: Rc<Pipeline>, mesh: Rc<Mesh>) -> Self {
+        Self {
+            pipeline,
+            mesh,
+            material: None,
+            transform: Mat4::identity(),
+            visible: true,
+        }
+    }
+
+    pub fn material(mut self, material: Rc<Material>) -> Self {
+        self.material = Some(material);
+        self
+    }
+
+    pub fn transform(mut self, transform: Mat4) -> Self {
+        self.transform = transform;
+        self
+    }
+}
+
+impl DrawState for Draw {
+    fn visible(&self) -> bool {
+        self.visible
+    }
+
+    fn set_layouts(&self) -> &[vk::DescriptorSetLayout] {
+        self.pipeline.set_layouts()
+    }
+
+    fn has_material(&self) -> bool {
+        self.material.is_some()
+    }
+}
+
+/// What recording needs to know about a draw to decide whether it
+/// can be drawn, so `DrawList::recorded` can be tested without a
+/// device.
+pub trait DrawState {
+    fn visible(&self) -> bool;
+    /// The descriptor set layouts of the draw's pipeline.
+    fn set_layouts(&self) -> &[vk::DescriptorSetLayout];
+    fn has_material(&self) -> bool;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DrawId(u64);
+
+/// The draws recorded every frame, in order. Only generic over `D`
+/// so the ID and order bookkeeping can be tested without a device.
+#[derive(Debug)]
+pub struct DrawList<D = Draw> {
+    next_id: u64,
+    draws: Vec<(DrawId, D)>,
+}
+
+impl<D> Default for DrawList<D> {
+    fn default() -> Self {
+        Self {
+            next_id: 0,
+            draws: vec![],
+        }
+    }
+}
+
+impl<D> DrawList<D> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends `draw`, so it is drawn after every other draw. IDs are
+    /// never reused, so the ID of a removed draw stays invalid.
+    pub fn add(&mut self, draw: D) -> DrawId {
+        let id = DrawId(self.next_id);
+        self.next_id += 1;
+        self.draws.push((id, draw));
+        id
+    }
+
+    pub fn remove(&mut self, id: DrawId) -> Option<D> {
+        let index = self.index_of(id)?;
+        Some(self.draws.remove(index).1)
+    }
+
+    pub fn get(&self, id: DrawId) -> Option<&D> {
+        self.draws.iter().find(|(i, _)| *i == id).map(|(_, d)| d)
+    }
+
+    pub fn get_mut(&mut self, id: DrawId) -> Option<&mut D> {
+        self.draws
+            .iter_mut()
+            .find(|(i, _)| *i == id)
+            .map(|(_, d)| d)
+    }
+
+    /// Moves a draw to `index` in the draw order, clamped to the end
+    /// of the list.
+    pub fn move_to(&mut self, id: DrawId, index: usize) {
+        if let Some(from) = self.index_of(id) {
+            let draw = self.draws.remove(from);
+            let index = index.min(self.draws.len());
+            self.draws.insert(index, draw);
+        }
+    }
+
+    pub fn index_of(&self, id: DrawId) -> Option<usize> {
+        self.draws.iter().position(|(i, _)| *i == id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.draws.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.draws.is_empty()
+    }
+
+    /// Every draw in draw order, hidden ones included.
+    pub fn iter(&self) -> impl Iterator<Item = (DrawId, &D)> {
+        self.draws.iter().map(|(i, d)| (*i, d))
+    }
+
+    pub fn iter_mut(
+        &mut self,
+    ) -> impl Iterator<Item = (DrawId, &mut D)> {
+        self.draws.iter_mut().map(|(i, d)| (*i, d))
+    }
+
+    /// Converts every draw with `f`, keeping IDs and order, e.g. to
+    /// rebuild the list on a new device. Draws `f` returns `None` for
+    /// are left out and their IDs stay invalid.
+    pub fn filter_map<E>(
+        &self,
+        mut f: impl FnMut(&D) -> Option<E>,
+    ) -> DrawList<E> {
+        DrawList {
+            next_id: self.next_id,
+            draws: self
+                .draws
+                .iter()
+                .filter_map(|(i, d)| Some((*i, f(d)?)))
+                .collect(),
+        }
+    }
+}
+
+impl<D: DrawState> DrawList<D> {
+    /// The visible draws in draw order that can be recorded. Draws
+    /// without a material whose pipeline's set 1 has
+    /// `material_set_layout` would leave it unbound, they are left
+    /// out with a warning.
+    pub(crate) fn recorded(
+        &self,
+        material_set_layout: vk::DescriptorSetLayout,
+    ) -> impl Iterator<Item = (DrawId, &D)> {
+        self.iter().filter(move |(id, d)| {
+            if !d.visible() {
+                return false;
+            }
+            let missing_material = !d.has_material()
+                && d.set_layouts().get(1) == Some(&material_set_layout);
+            if missing_material {
+                log::warn!(
+                    "Skipping draw {:?}, its pipeline needs a material.",
+                    id
+                );
+            }
+            !missing_material
+        })
+    }
+}
+
+impl DrawList {
+    pub fn set_visible(&mut self, id: DrawId, visible: bool) {
+        if let Some(draw) = self.get_mut(id) {
+            draw.visible = visible;
+        }
+    }
+
+    /// Points every draw using `old` at `new`, e.g. after the
+    /// pipeline was rebuilt.
+    pub fn replace_pipeline(
+        &mut self,
+        old: &Rc<Pipeline>,
+        new: &Rc<Pipeline>,
+    ) {
+        for (_, draw) in &mut self.draws {
+            if Rc::ptr_eq(&draw.pipeline, old) {
+                draw.pipeline = new.clone();
+            }
+        }
+    }
+
+    pub fn replace_mesh(&mut self, old: &Rc<Mesh>, new: &Rc<Mesh>) {
+        for (_, draw) in &mut self.draws {
+            if Rc::ptr_eq(&draw.mesh, old) {
+                draw.mesh = new.clone();
+            }
+        }
+    }
+
+    pub fn replace_material(
+        &mut self,
+        old: &Rc<Material>,
+        new: &Rc<Material>,
+    ) {
+        for (_, draw) in &mut self.draws {
+            if draw
+                .material
+                .as_ref()
+                .is_some_and(|m| Rc::ptr_eq(m, old))
+            {
+                draw.material = Some(new.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use vulkanalia::vk::Handle;
+
+    use super::*;
+
+    fn order(list: &DrawList<char>) -> String {
+        list.iter().map(|(_, d)| *d).collect()
+    }
+
+    #[test]
+    fn removed_ids_stay_invalid() {
+        let mut list = DrawList::new();
+        let a = list.add('a');
+        let b = list.add('b');
+        assert_ne!(a, b);
+
+        assert_eq!(list.remove(a), Some('a'));
+        assert_eq!(list.remove(a), None);
+        assert_eq!(list.get(a), None);
+        assert_eq!(list.index_of(a), None);
+
+        let c = list.add('c');
+        assert_ne!(c, a);
+        assert_eq!(list.get(a), None);
+        list.move_to(a, 0);
+        assert_eq!(order(&list), "bc");
+        assert_eq!(list.index_of(b), Some(0));
+        assert_eq!(list.index_of(c), Some(1));
+    }
+
+    #[test]
+    fn added_draws_go_last() {
+        let mut list = DrawList::new();
+        assert!(list.is_empty());
+        let ids: Vec<_> =
+            "abc".chars().map(|d| list.add(d)).collect();
+        assert_eq!(order(&list), "abc");
+        assert_eq!(list.len(), 3);
+        let iter_ids: Vec<_> = list.iter().map(|(i, _)| i).collect();
+        assert_eq!(iter_ids, ids);
+    }
+
+    #[test]
+    fn move_to_reorders_and_clamps() {
+        let mut list = DrawList::new();
+        let ids: Vec<_> =
+            "abcd".chars().map(|d| list.add(d)).collect();
+
+        list.move_to(ids[3], 0);
+        assert_eq!(order(&list), "dabc");
+        list.move_to(ids[3], 2);
+        assert_eq!(order(&list), "abdc");
+        list.move_to(ids[0], 100);
+        assert_eq!(order(&list), "bdca");
+        list.move_to(ids[2], 2);
+        assert_eq!(order(&list), "bdca");
+        assert_eq!(list.index_of(ids[0]), Some(3));
+
+        list.remove(ids[3]);
+        assert_eq!(order(&list), "bca");
+        *list.get_mut(ids[1]).unwrap() = 'B';
+        assert_eq!(order(&list), "Bca");
+    }
+
+    struct TestDraw {
+        visible: bool,
+        set_layouts: Vec<vk::DescriptorSetLayout>,
+        material: bool,
+    }
+
+    impl DrawState for TestDraw {
+        fn visible(&self) -> bool {
+            self.visible
+        }
+
+        fn set_layouts(&self) -> &[vk::DescriptorSetLayout] {
+            &self.set_layouts
+        }
+
+        fn has_material(&self) -> bool {
+            self.material
+        }
+    }
+
+    #[test]
+    fn draws_missing_their_material_are_not_recorded() {
+        let camera = vk::DescriptorSetLayout::from_raw(1);
+        let material = vk::DescriptorSetLayout::from_raw(2);
+        let other = vk::DescriptorSetLayout::from_raw(3);
+        let draw = |visible, set_layouts: &[_], material| TestDraw {
+            visible,
+            set_layouts: set_layouts.to_vec(),
+            material,
+        };
+        let mut list = DrawList::new();
+        let textured =
+            list.add(draw(true, &[camera, material], true));
+        list.add(draw(true, &[camera, material], false));
+        list.add(draw(false, &[camera, material], true));
+        let unlit = list.add(draw(true, &[camera], false));
+        let custom = list.add(draw(true, &[camera, other], false));
+
+        let recorded: Vec<_> =
+            list.recorded(material).map(|(i, _)| i).collect();
+        assert_eq!(recorded, [textured, unlit, custom]);
+    }
+
+    #[test]
+    fn filter_map_keeps_ids() {
+        let mut list = DrawList::new();
+        let ids: Vec<_> =
+            "abcd".chars().map(|d| list.add(d)).collect();
+        list.move_to(ids[3], 0);
+
+        let mut mapped = list.filter_map(|d| {
+            (*d != 'b').then(|| d.to_ascii_uppercase())
+        });
+        assert_eq!(order(&mapped), "DAC");
+        assert_eq!(mapped.get(ids[0]), Some(&'A'));
+        assert_eq!(mapped.get(ids[1]), None);
+        assert_eq!(mapped.index_of(ids[3]), Some(0));
+
+        // New IDs don't collide with the old ones.
+        let e = mapped.add('E');
+        assert!(!ids.contains(&e));
+        assert_eq!(order(&mapped), "DACE");
+    }
+}
@@ -0,0 +1,160 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use tracing::{span::EnteredSpan, Span};
+
+/// Frames the frame time statistics are taken over.
+pub const FRAME_WINDOW: usize = 240;
+
+/// Where the CPU spent the last frame.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CpuTimings {
+    /// Waiting for the fences of the frame and of its swapchain
+    /// image, i.e. for the GPU.
+    pub fence_wait: Duration,
+    /// Waiting for the next swapchain image, zero headless.
+    pub acquire: Duration,
+    pub uniform_update: Duration,
+    /// Recording the frame's command buffer.
+    pub record: Duration,
+    pub submit: Duration,
+    /// Zero headless.
+    pub present: Duration,
+}
+
+/// Times a part of a frame, which is traced as `span` meanwhile.
+pub struct Phase {
+    _span: EnteredSpan,
+    start: Instant,
+}
+
+impl Phase {
+    pub fn begin(span: Span) -> Self {
+        Self {
+            _span: span.entered(),
+            start: Instant::now(),
+        }
+    }
+
+    pub fn end(self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+/// Frame times over the last `FRAME_WINDOW` frames and the CPU
+/// timings of the last one. A frame's time is the time since the
+/// previous frame began.
+#[derive(Debug, Clone, Default)]
+pub struct FrameTimer {
+    last_begin: Option<Instant>,
+    frame_times: VecDeque<Duration>,
+    sum: Duration,
+    cpu: CpuTimings,
+}
+
+impl FrameTimer {
+    pub fn begin_frame(&mut self) {
+        let now = Instant::now();
+        if let Some(last) = self.last_begin.replace(now) {
+            if self.frame_times.len() == FRAME_WINDOW {
+                self.sum -= self.frame_times.pop_front().unwrap();
+            }
+            let frame_time = now - last;
+            self.frame_times.push_back(frame_time);
+            self.sum += frame_time;
+        }
+    }
+
+    pub fn end_frame(&mut self, cpu: CpuTimings) {
+        self.cpu = cpu;
+    }
+
+    pub fn cpu_timings(&self) -> CpuTimings {
+        self.cpu
+    }
+
+    /// The average frame time, zero before the second frame.
+    pub fn frame_time(&self) -> Duration {
+        self.sum / self.frame_times.len().max(1) as u32
+    }
+
+    pub fn fps(&self) -> f64 {
+        let seconds = self.frame_time().as_secs_f64();
+        if seconds > 0.0 {
+            1.0 / seconds
+        } else {
+            0.0
+        }
+    }
+
+    /// The longest frame time of the fastest `percentile` (0 to 100)
+    /// percent of frames, e.g. 99 for where the slowest 1% start.
+    pub fn percentile(&self, percentile: f64) -> Duration {
+        let mut sorted: Vec<_> =
+            self.frame_times.iter().copied().collect();
+        sorted.sort_unstable();
+        let rank = (percentile.clamp(0.0, 100.0) / 100.0
+            * sorted.len() as f64)
+            .ceil() as usize;
+        sorted
+            .get(rank.saturating_sub(1))
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timer(millis: &[u64]) -> FrameTimer {
+        let frame_times: VecDeque<_> = millis
+            .iter()
+            .copied()
+            .map(Duration::from_millis)
+            .collect();
+        FrameTimer {
+            sum: frame_times.iter().sum(),
+            frame_times,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn empty_history_is_zero() {
+        let timer = FrameTimer::default();
+        for p in [0.0, 50.0, 99.0, 100.0] {
+            assert_eq!(timer.percentile(p), Duration::ZERO);
+        }
+        assert_eq!(timer.frame_time(), Duration::ZERO);
+        assert_eq!(timer.fps(), 0.0);
+    }
+
+    #[test]
+    fn percentiles_use_the_nearest_rank() {
+        let timer = timer(&[40, 10, 30, 20, 50, 60, 70, 80, 90, 100]);
+        let ms = |p| timer.percentile(p).as_millis();
+        assert_eq!(ms(0.0), 10);
+        assert_eq!(ms(10.0), 10);
+        assert_eq!(ms(11.0), 20);
+        assert_eq!(ms(50.0), 50);
+        assert_eq!(ms(99.0), 100);
+        assert_eq!(ms(100.0), 100);
+        assert_eq!(ms(-5.0), 10);
+        assert_eq!(ms(150.0), 100);
+        assert_eq!(timer.frame_time(), Duration::from_millis(55));
+    }
+
+    #[test]
+    fn single_frame_is_every_percentile() {
+        let timer = timer(&[16]);
+        for p in [0.0, 50.0, 100.0] {
+            assert_eq!(
+                timer.percentile(p),
+                Duration::from_millis(16)
+            );
+        }
+    }
+}
@@ -1,20 +1,90 @@
+use std::rc::Rc;
+
 use vulkanalia::{
-    vk::{self, DeviceV1_0, ErrorCode, HasBuilder, InstanceV1_0},
+    vk::{
+        self, DeviceV1_0, ErrorCode, Handle, HasBuilder, InstanceV1_0,
+    },
     Device, Instance,
 };
 
 use crate::{
-    command::{
-        begin_single_time_commands, end_single_time_commands,
-        CommandError,
+    allocator::{
+        Allocation, Allocator, AllocatorError, ResourceKind,
     },
-    memory::{get_memory_type_index, MemoryError},
+    debug_utils::set_name,
+    device::LogicalDevice,
+    image_view::{create_image_view, ImageViewError},
+    VkErrorSource,
 };
 
+/// An image bound to memory from the `Allocator` and, once
+/// `create_view` has been called, a view of all its mip levels. All
+/// of them are released when dropped.
+#[derive(Debug)]
+pub struct Image {
+    device: Rc<LogicalDevice>,
+    image: vk::Image,
+    allocation: Allocation,
+    view: vk::ImageView,
+    format: vk::Format,
+    mip_levels: u32,
+    name: String,
+}
+
+impl Image {
+    pub fn handle(&self) -> vk::Image {
+        self.image
+    }
+
+    pub fn view(&self) -> vk::ImageView {
+        self.view
+    }
+
+    pub fn format(&self) -> vk::Format {
+        self.format
+    }
+
+    pub fn mip_levels(&self) -> u32 {
+        self.mip_levels
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub unsafe fn create_view(
+        &mut self,
+        aspects: vk::ImageAspectFlags,
+    ) -> Result<()> {
+        self.device.destroy_image_view(self.view, None);
+        self.view = create_image_view(
+            &self.device,
+            self.image,
+            self.format,
+            aspects,
+            self.mip_levels,
+        )?;
+        set_name(
+            &self.device,
+            self.view,
+            &format!("{} view", self.name),
+        );
+
+        Ok(())
+    }
+}
+
+impl Drop for Image {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.destroy_image_view(self.view, None);
+            self.device.destroy_image(self.image, None);
+        }
+    }
+}
+
 pub unsafe fn create_image(
-    instance: &Instance,
-    device: &Device,
-    physical_device: vk::PhysicalDevice,
+    allocator: &Rc<Allocator>,
     width: u32,
     height: u32,
     mip_levels: u32,
@@ -23,7 +93,9 @@ pub unsafe fn create_image(
     tiling: vk::ImageTiling,
     usage: vk::ImageUsageFlags,
     properties: vk::MemoryPropertyFlags,
-) -> Result<(vk::Image, vk::DeviceMemory)> {
+    name: &str,
+) -> Result<Image> {
+    let device = allocator.device();
     let info = vk::ImageCreateInfo::builder()
         .image_type(vk::ImageType::_2D)
         .extent(vk::Extent3D {
@@ -41,36 +113,53 @@ pub unsafe fn create_image(
         .samples(samples);
 
     let image = device.create_image(&info, None)?;
-
+    set_name(device, image, name);
     let requirements = device.get_image_memory_requirements(image);
 
-    let info = vk::MemoryAllocateInfo::builder()
-        .allocation_size(requirements.size)
-        .memory_type_index(get_memory_type_index(
-            instance,
-            physical_device,
-            properties,
-            requirements,
-        )?);
+    let resource = if tiling == vk::ImageTiling::LINEAR {
+        ResourceKind::Linear
+    } else {
+        ResourceKind::Optimal
+    };
+    let allocation = match allocator.allocate(
+        requirements,
+        properties,
+        resource,
+        false,
+    ) {
+        Ok(allocation) => allocation,
+        Err(e) => {
+            device.destroy_image(image, None);
+            return Err(e.into());
+        }
+    };
 
-    let image_memory = device.allocate_memory(&info, None)?;
+    // Released by `Drop` if any of the following steps fail.
+    let image = Image {
+        device: device.clone(),
+        image,
+        allocation,
+        view: vk::ImageView::null(),
+        format,
+        mip_levels,
+        name: name.to_string(),
+    };
 
-    device.bind_image_memory(image, image_memory, 0)?;
+    device.bind_image_memory(
+        image.image,
+        image.allocation.memory(),
+        image.allocation.offset(),
+    )?;
 
-    Ok((image, image_memory))
+    Ok(image)
 }
 
-pub unsafe fn generate_mipmaps(
+/// Fails if `format` can't be linearly filtered when blitting, which
+/// `generate_mipmaps` requires.
+pub unsafe fn check_mipmap_support(
     instance: &Instance,
-    device: &Device,
     physical_device: vk::PhysicalDevice,
-    command_pool: vk::CommandPool,
-    graphics_queue: vk::Queue,
-    image: vk::Image,
     format: vk::Format,
-    width: u32,
-    height: u32,
-    mip_levels: u32,
 ) -> Result<()> {
     if !instance
         .get_physical_device_format_properties(
@@ -83,9 +172,20 @@ pub unsafe fn generate_mipmaps(
         return Err(ImageError::MipMapError);
     }
 
-    let command_buffer =
-        begin_single_time_commands(device, command_pool)?;
+    Ok(())
+}
 
+/// Records blits from every mip level to the next, starting with all
+/// levels in `TRANSFER_DST_OPTIMAL` and leaving them in
+/// `SHADER_READ_ONLY_OPTIMAL`. Needs a graphics queue.
+pub unsafe fn generate_mipmaps(
+    device: &Device,
+    command_buffer: vk::CommandBuffer,
+    image: vk::Image,
+    width: u32,
+    height: u32,
+    mip_levels: u32,
+) {
     let subresource = vk::ImageSubresourceRange::builder()
         .aspect_mask(vk::ImageAspectFlags::COLOR)
         .base_array_layer(0)
@@ -205,29 +305,18 @@ pub unsafe fn generate_mipmaps(
         &[] as &[vk::BufferMemoryBarrier],
         &[barrier],
     );
-
-    end_single_time_commands(
-        device,
-        graphics_queue,
-        command_pool,
-        command_buffer,
-    )?;
-
-    Ok(())
 }
 
+/// Records a copy of `buffer` to mip level 0 of `image`, which has to
+/// be in `TRANSFER_DST_OPTIMAL`.
 pub unsafe fn copy_buffer_to_image(
     device: &Device,
-    command_pool: vk::CommandPool,
-    graphics_queue: vk::Queue,
+    command_buffer: vk::CommandBuffer,
     buffer: vk::Buffer,
     image: vk::Image,
     width: u32,
     height: u32,
-) -> Result<()> {
-    let command_buffer =
-        begin_single_time_commands(device, command_pool)?;
-
+) {
     let subresource = vk::ImageSubresourceLayers::builder()
         .aspect_mask(vk::ImageAspectFlags::COLOR)
         .mip_level(0)
@@ -253,20 +342,11 @@ pub unsafe fn copy_buffer_to_image(
         vk::ImageLayout::TRANSFER_DST_OPTIMAL,
         &[region],
     );
-    end_single_time_commands(
-        device,
-        graphics_queue,
-        command_pool,
-        command_buffer,
-    )?;
-
-    Ok(())
 }
 
 pub unsafe fn transition_image_layout(
     device: &Device,
-    command_pool: vk::CommandPool,
-    graphics_queue: vk::Queue,
+    command_buffer: vk::CommandBuffer,
     image: vk::Image,
     format: vk::Format,
     old_layout: vk::ImageLayout,
@@ -311,8 +391,6 @@ pub unsafe fn transition_image_layout(
             return Err(ImageError::UnsupportedImageError);
         }
     };
-    let command_buffer =
-        begin_single_time_commands(device, command_pool)?;
 
     let aspect_mask = if new_layout
         == vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL
@@ -356,13 +434,6 @@ pub unsafe fn transition_image_layout(
         &[barrier],
     );
 
-    end_single_time_commands(
-        device,
-        graphics_queue,
-        command_pool,
-        command_buffer,
-    )?;
-
     Ok(())
 }
 
@@ -371,9 +442,9 @@ pub enum ImageError {
     #[error(transparent)]
     VkErrorCode(#[from] ErrorCode),
     #[error(transparent)]
-    MemoryError(#[from] MemoryError),
+    AllocatorError(#[from] AllocatorError),
     #[error(transparent)]
-    CommandError(#[from] CommandError),
+    ImageViewError(#[from] ImageViewError),
 
     #[error("Unsupported image format.")]
     UnsupportedImageError,
@@ -383,3 +454,14 @@ pub enum ImageError {
     MipMapError,
 }
 type Result<T> = std::result::Result<T, ImageError>;
+
+impl VkErrorSource for ImageError {
+    fn vk_error_code(&self) -> Option<ErrorCode> {
+        match self {
+            Self::VkErrorCode(e) => Some(*e),
+            Self::AllocatorError(e) => e.vk_error_code(),
+            Self::ImageViewError(e) => e.vk_error_code(),
+            Self::UnsupportedImageError | Self::MipMapError => None,
+        }
+    }
+}
@@ -3,6 +3,8 @@ use vulkanalia::{
     Device,
 };
 
+use crate::VkErrorSource;
+
 pub unsafe fn create_image_view(
     device: &Device,
     image: vk::Image,
@@ -32,3 +34,11 @@ pub enum ImageViewError {
     VkErrorCode(#[from] ErrorCode),
 }
 type Result<T> = std::result::Result<T, ImageViewError>;
+
+impl VkErrorSource for ImageViewError {
+    fn vk_error_code(&self) -> Option<ErrorCode> {
+        match self {
+            Self::VkErrorCode(e) => Some(*e),
+        }
+    }
+}
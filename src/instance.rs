@@ -1,30 +1,119 @@
-use log::{error, info};
+use std::{ops::Deref, sync::Arc};
+
+use log::info;
 use thiserror::Error;
-use vulkanalia::vk::{self, ErrorCode, HasBuilder};
+use vulkanalia::vk::{
+    self, EntryV1_0, ErrorCode, HasBuilder, InstanceV1_0,
+};
 use vulkanalia::{Entry, Instance};
 use winit::window::Window;
 
+use crate::config::ValidationFeature;
 use crate::validation::{
-    validated_extensions, validated_info, validated_instance,
-    validated_layers, ValidationError,
+    debug_messenger_info, destroy_debug_utils_messenger_ext,
+    validated_extensions, validated_features, validated_info,
+    validated_instance, validated_layers, MessageSink, SinkSlot,
+    ValidationError,
 };
+use crate::VkErrorSource;
 use crate::PORTABILITY_MACOS_VERSION;
 
+/// Owns the Vulkan instance together with its debug messenger and the
+/// loaded entry, destroying them when dropped. Every device object
+/// keeps the instance alive through the `LogicalDevice`.
+#[derive(Debug)]
+pub struct VulkanInstance {
+    instance: Instance,
+    messenger: vk::DebugUtilsMessengerEXT,
+    entry: Entry,
+    validation: bool,
+    // Boxed, the messenger points to it. Dropped after the instance.
+    sink: Box<SinkSlot>,
+}
+
+impl VulkanInstance {
+    pub fn entry(&self) -> &Entry {
+        &self.entry
+    }
+
+    /// Whether the validation layer is enabled, devices enable it too.
+    pub fn validation(&self) -> bool {
+        self.validation
+    }
+
+    /// Where validation messages go, `LogSink` unless configured
+    /// otherwise.
+    pub fn sink(&self) -> Arc<dyn MessageSink> {
+        self.sink.get()
+    }
+
+    /// Sends validation messages to `sink` from now on, e.g. a
+    /// `CollectingSink` to check for errors.
+    pub fn set_sink(&self, sink: Arc<dyn MessageSink>) {
+        self.sink.set(sink);
+    }
+
+    /// Panics if a sink that panics on errors received one, see
+    /// `SinkSlot::check`.
+    pub fn check_validation(&self) {
+        self.sink.check();
+    }
+}
+
+impl Deref for VulkanInstance {
+    type Target = Instance;
+
+    fn deref(&self) -> &Self::Target {
+        &self.instance
+    }
+}
+
+impl Drop for VulkanInstance {
+    fn drop(&mut self) {
+        unsafe {
+            destroy_debug_utils_messenger_ext(
+                &self.instance,
+                self.messenger,
+            );
+            self.instance.destroy_instance(None);
+        }
+    }
+}
+
 pub unsafe fn create_instance(
-    window: &Window,
-    entry: &Entry,
-    messenger: &mut vk::DebugUtilsMessengerEXT,
-) -> Result<Instance> {
+    window: Option<&Window>,
+    entry: Entry,
+    validation: bool,
+    features: &[ValidationFeature],
+    sink: Arc<dyn MessageSink>,
+) -> Result<VulkanInstance> {
     let application_info = vk::ApplicationInfo::builder()
         .application_name(b"Broth\0")
         .application_version(vk::make_version(1, 0, 0))
         .engine_name(b"No Engine\0")
         .engine_version(vk::make_version(1, 0, 0))
-        .api_version(vk::make_version(1, 0, 0));
+        .api_version(vk::make_version(1, 1, 0));
 
-    let layers = validated_layers(entry)?;
+    let layers = validated_layers(&entry, validation)?;
 
-    let mut extensions = validated_extensions(window)?;
+    let features = validated_features(&entry, features, validation)?;
+    let mut extensions =
+        validated_extensions(window, &features, validation)?;
+
+    // Exposes the HDR and extended sRGB surface color spaces.
+    if window.is_some()
+        && entry
+            .enumerate_instance_extension_properties(None)?
+            .iter()
+            .any(|e| {
+                e.extension_name
+                    == vk::EXT_SWAPCHAIN_COLORSPACE_EXTENSION.name
+            })
+    {
+        extensions.push(
+            vk::EXT_SWAPCHAIN_COLORSPACE_EXTENSION.name.as_ptr(),
+        );
+    }
 
     // Required by Vulkan SDK on macOS since 1.3.216.
     let flags = if cfg!(target_os = "macos")
@@ -44,15 +133,37 @@ pub unsafe fn create_instance(
         vk::InstanceCreateFlags::empty()
     };
 
-    let (info, _debug_info) = validated_info(
+    let mut sink = Box::new(SinkSlot::new(sink));
+    let debug_info = debug_messenger_info(&mut sink);
+    let mut chained_debug_info = debug_info;
+    let mut features_info = vk::ValidationFeaturesEXT::builder()
+        .enabled_validation_features(&features)
+        .build();
+    let info = validated_info(
         &application_info,
         &layers,
         &extensions,
         flags,
-    )?;
+        &mut chained_debug_info,
+        &mut features_info,
+        validation,
+    );
 
-    let instance = validated_instance(entry, &info, messenger)?;
-    Ok(instance)
+    let mut messenger = vk::DebugUtilsMessengerEXT::default();
+    let instance = validated_instance(
+        &entry,
+        &info,
+        &debug_info,
+        &mut messenger,
+        validation,
+    )?;
+    Ok(VulkanInstance {
+        instance,
+        messenger,
+        entry,
+        validation,
+        sink,
+    })
 }
 
 #[derive(Debug, Error, Clone)]
@@ -63,3 +174,12 @@ pub enum InstanceError {
     ValidationError(#[from] ValidationError),
 }
 type Result<T> = std::result::Result<T, InstanceError>;
+
+impl VkErrorSource for InstanceError {
+    fn vk_error_code(&self) -> Option<ErrorCode> {
+        match self {
+            Self::VkErrorCode(e) => Some(*e),
+            Self::ValidationError(e) => e.vk_error_code(),
+        }
+    }
+}
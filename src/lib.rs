@@ -0,0 +1,82 @@
+// Vulkan objects are created through `unsafe` functions that take
+// every handle they depend on, so long argument lists are expected here.
+#![allow(
+    clippy::missing_safety_doc,
+    clippy::too_many_arguments,
+    clippy::enum_variant_names
+)]
+
+use vulkanalia::{vk, Version};
+
+pub(crate) mod allocator;
+pub(crate) mod app;
+pub(crate) mod buffer;
+pub(crate) mod color;
+pub(crate) mod command;
+pub(crate) mod compute;
+pub(crate) mod config;
+pub(crate) mod debug_utils;
+pub(crate) mod descriptor;
+pub(crate) mod device;
+pub(crate) mod draw;
+pub(crate) mod frame_timing;
+pub(crate) mod image;
+pub(crate) mod image_view;
+pub(crate) mod instance;
+pub(crate) mod memory;
+pub(crate) mod offscreen;
+pub(crate) mod owned;
+pub(crate) mod pipeline;
+pub(crate) mod pipeline_cache;
+pub(crate) mod profiler;
+pub(crate) mod queue;
+pub(crate) mod reflect;
+pub(crate) mod render_pass;
+pub(crate) mod shader;
+pub(crate) mod swapchain;
+pub(crate) mod texture;
+pub(crate) mod upload;
+pub(crate) mod validation;
+pub(crate) mod vertex;
+
+pub use app::{App, AppError, DeviceContext};
+pub use buffer::Mat4;
+pub use compute::{ComputeOutput, Handoff};
+pub use config::{
+    ColorOutput, Config, ConfigError, Msaa, PresentMode,
+    RendererConfig, SceneConfig, ValidationFeature, ValidationSink,
+    WindowConfig,
+};
+pub use device::{DeviceReport, DeviceScore, DeviceSelector};
+pub use draw::{Draw, DrawId, DrawList, Material, Mesh};
+pub use frame_timing::{CpuTimings, FrameTimer};
+pub use pipeline::{
+    BlendMode, ComputePipeline, ComputePipelineBuilder,
+    GraphicsPipelineBuilder, Pipeline,
+};
+pub use profiler::{
+    DrawCounters, FrameStats, PipelineStatistics, RollingAverage,
+};
+pub use shader::compile_shader;
+pub use swapchain::{OutputEncoding, OutputFormat};
+pub use upload::UploadTicket;
+pub use validation::{
+    CollectingSink, MessageSink, ValidationMessage,
+};
+pub use vertex::{Vertex3, VertexLayout};
+
+/// Errors that may be caused by a Vulkan call, e.g. to recover from
+/// a lost device however deep in a helper it was reported.
+pub trait VkErrorSource {
+    /// The Vulkan error code behind this error, if there is one.
+    fn vk_error_code(&self) -> Option<vk::ErrorCode>;
+}
+
+/// Frames the CPU may record ahead of the GPU, see
+/// `App::set_frames_in_flight`.
+pub const DEFAULT_FRAMES_IN_FLIGHT: usize = 2;
+pub(crate) const IS_MACOS: bool = cfg!(target_os = "macos");
+pub(crate) const PORTABILITY_MACOS_VERSION: Version =
+    Version::new(1, 3, 216);
+pub(crate) const DEVICE_EXTENSIONS: &[vk::ExtensionName] =
+    &[vk::KHR_SWAPCHAIN_EXTENSION.name];
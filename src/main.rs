@@ -1,25 +1,13 @@
-mod app;
-mod buffer;
-mod color;
-mod command;
-mod descriptor;
-mod device;
-mod image;
-mod image_view;
-mod instance;
-mod memory;
-mod pipeline;
-mod queue;
-mod render_pass;
-mod swapchain;
-mod texture;
-mod validation;
-mod vertex;
-
-use app::{App, AppError};
+use broth::{
+    App, AppError, Config, ConfigError, DeviceSelector, Msaa,
+    PresentMode, ValidationFeature, ValidationSink,
+};
 use cgmath::Deg;
+use clap::Parser;
+use std::path::{Path, PathBuf};
 use thiserror::Error;
-use vulkanalia::{vk, Version};
+use tracing_subscriber::layer::SubscriberExt;
+use vulkanalia::vk;
 use winit::{
     dpi::LogicalSize,
     error::{EventLoopError, OsError},
@@ -30,14 +18,8 @@ use winit::{
     window::WindowBuilder,
 };
 
-pub const MAX_FRAMES_IN_FLIGHT: usize = 2;
-pub const IS_MACOS: bool = cfg!(target_os = "macos");
-pub const PORTABILITY_MACOS_VERSION: Version =
-    Version::new(1, 3, 216);
-pub const DEVICE_EXTENSIONS: &[vk::ExtensionName] =
-    &[vk::KHR_SWAPCHAIN_EXTENSION.name];
-
 type Result<T> = std::result::Result<T, MainError>;
+#[allow(clippy::enum_variant_names)]
 #[derive(Error, Debug)]
 enum MainError {
     #[error(transparent)]
@@ -46,14 +28,157 @@ enum MainError {
     WinitOsError(#[from] OsError),
     #[error(transparent)]
     AppError(#[from] AppError),
+    #[error(transparent)]
+    ConfigError(#[from] ConfigError),
+}
+
+/// Renders the configured scene, flags override `broth.toml`.
+#[derive(Debug, Parser)]
+#[command(version)]
+struct Cli {
+    /// Config file, `broth.toml` is used if it exists.
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+    /// Overrides a config key, e.g. `renderer.msaa=4`.
+    #[arg(long = "set", value_name = "KEY=VALUE")]
+    overrides: Vec<String>,
+    /// OBJ file to render.
+    #[arg(long, value_name = "PATH")]
+    mesh: Option<PathBuf>,
+    /// Image applied to the mesh.
+    #[arg(long, value_name = "PATH")]
+    texture: Option<PathBuf>,
+    /// Window or headless frame size.
+    #[arg(long, value_name = "WIDTHxHEIGHT", value_parser = parse_size)]
+    size: Option<(u32, u32)>,
+    /// `max` or 1, 2, 4, 8, 16, 32, 64.
+    #[arg(long, value_name = "SAMPLES")]
+    msaa: Option<Msaa>,
+    /// Fraction of MSAA samples shaded per pixel, 0 to disable.
+    #[arg(long, value_name = "FRACTION")]
+    sample_shading: Option<f32>,
+    /// immediate, mailbox, fifo or fifo_relaxed.
+    #[arg(long, value_name = "MODE")]
+    present_mode: Option<PresentMode>,
+    /// GPU to use, a name substring, `#index` or UUID from
+    /// `--list-devices`.
+    #[arg(long, value_name = "DEVICE")]
+    device: Option<DeviceSelector>,
+    /// Enables the validation layer.
+    #[arg(long, value_name = "BOOL", num_args = 0..=1, default_missing_value = "true")]
+    validation: Option<bool>,
+    /// Extra validation checks, any of synchronization,
+    /// best_practices and gpu_assisted.
+    #[arg(long, value_name = "FEATURES", value_delimiter = ',')]
+    validation_features: Option<Vec<ValidationFeature>>,
+    /// Panics on the first validation error.
+    #[arg(long)]
+    validation_panic: bool,
+    /// Counts what the GPU processes each frame, logged with `t`.
+    #[arg(long)]
+    pipeline_statistics: bool,
+    /// Renders without a window and writes the last frame to `--output`.
+    #[arg(long)]
+    headless: bool,
+    /// Frames rendered by `--headless`.
+    #[arg(
+        long,
+        value_name = "COUNT",
+        default_value_t = 1,
+        requires = "headless"
+    )]
+    frames: u32,
+    /// PNG written by `--headless`.
+    #[arg(
+        long,
+        value_name = "PATH",
+        default_value = "broth.png",
+        requires = "headless"
+    )]
+    output: PathBuf,
+    /// Prints every physical device and whether it can be used.
+    #[arg(long)]
+    list_devices: bool,
+    /// Writes a Chrome trace of rendering, swapchain recreation and
+    /// asset loading, open it in Perfetto or `chrome://tracing`.
+    #[arg(long, value_name = "PATH")]
+    trace: Option<PathBuf>,
+}
+
+impl Cli {
+    fn load_config(
+        &self,
+    ) -> std::result::Result<Config, ConfigError> {
+        let mut config =
+            Config::load(self.config.as_deref(), &self.overrides)?;
+        if let Some(mesh) = &self.mesh {
+            config.scene.model = mesh.clone();
+        }
+        if let Some(texture) = &self.texture {
+            config.scene.texture = texture.clone();
+        }
+        if let Some((width, height)) = self.size {
+            config.window.width = width;
+            config.window.height = height;
+        }
+        if let Some(msaa) = self.msaa {
+            config.renderer.msaa = msaa;
+        }
+        if let Some(sample_shading) = self.sample_shading {
+            config.renderer.sample_shading = sample_shading;
+        }
+        if let Some(present_mode) = self.present_mode {
+            config.renderer.present_mode = present_mode;
+        }
+        if let Some(device) = &self.device {
+            config.renderer.device = Some(device.clone());
+        }
+        if let Some(validation) = self.validation {
+            config.renderer.validation = validation;
+        }
+        if let Some(features) = &self.validation_features {
+            config.renderer.validation_features = features.clone();
+        }
+        if self.validation_panic {
+            config.renderer.validation_sink = ValidationSink::Panic;
+        }
+        if self.pipeline_statistics {
+            config.renderer.pipeline_statistics = true;
+        }
+        config.validate()?;
+
+        Ok(config)
+    }
+}
+
+fn parse_size(s: &str) -> std::result::Result<(u32, u32), String> {
+    s.split_once('x')
+        .and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)))
+        .ok_or_else(|| format!("expected WIDTHxHEIGHT, got `{}`", s))
 }
 
 fn main() -> Result<()> {
     // env::set_var("RUST_BACKTRACE", "1");
 
-    env_logger::init();
+    let cli = Cli::parse();
+    let config = cli.load_config();
+    init_logger(config.as_ref().map_or("info", |c| &c.log));
+    // Flushes the trace when dropped at the end of `main`.
+    let _trace = cli.trace.as_deref().map(init_trace);
 
-    match main_f() {
+    let result = config.map_err(MainError::from).and_then(|config| {
+        if cli.list_devices {
+            return main_list_devices(&config);
+        }
+        config.scene.validate()?;
+        if cli.headless {
+            main_headless(&config, cli.frames, &cli.output)
+        } else {
+            main_f(&config)
+        }
+    });
+
+    match result {
         Ok(_) => Ok(()),
         Err(e) => {
             log::error!("{}", e);
@@ -62,24 +187,217 @@ fn main() -> Result<()> {
     }
 }
 
-fn main_f() -> Result<()> {
+fn init_logger(filter: &str) {
+    env_logger::Builder::from_env(
+        env_logger::Env::default().default_filter_or(filter),
+    )
+    .init();
+}
+
+fn init_trace(path: &Path) -> tracing_chrome::FlushGuard {
+    let (layer, guard) =
+        tracing_chrome::ChromeLayerBuilder::new().file(path).build();
+    // Not `init`, which would also route `log` to tracing and fail
+    // with the logger already set.
+    let subscriber = tracing_subscriber::registry().with(layer);
+    if let Err(e) =
+        tracing::subscriber::set_global_default(subscriber)
+    {
+        log::warn!("Failed to start tracing: {}", e);
+    }
+    log::info!("Tracing to `{}`.", path.display());
+
+    guard
+}
+
+/// Checks devices against a hidden window's surface, or for headless
+/// rendering if no window can be opened.
+fn main_list_devices(config: &Config) -> Result<()> {
+    let window = EventLoop::new().ok().and_then(|event_loop| {
+        WindowBuilder::new()
+            .with_visible(false)
+            .build(&event_loop)
+            .ok()
+            .map(|window| (event_loop, window))
+    });
+    let reports = unsafe {
+        App::list_devices(
+            window.as_ref().map(|(_, window)| window),
+            config.renderer.validation,
+        )
+    }?;
+
+    if window.is_none() {
+        println!(
+            "No window available, checked for headless rendering."
+        );
+    }
+    // Best first, the first suitable device is used by default.
+    for report in reports {
+        println!(
+            "#{} {} ({:?}, Vulkan {}, {} MiB, msaa x{}): {}",
+            report.index,
+            report.name,
+            report.device_type,
+            report.api_version,
+            report.score.memory / (1024 * 1024),
+            report.score.max_msaa_samples,
+            match &report.suitability {
+                Ok(()) => "suitable".to_string(),
+                Err(e) => format!("unsuitable, {}", e),
+            }
+        );
+        if let Some(uuid) = report.uuid_string() {
+            println!("   {}", uuid);
+        }
+    }
+
+    Ok(())
+}
+
+/// Renders `frames` frames without a window and writes the last one
+/// to `output`.
+fn main_headless(
+    config: &Config,
+    frames: u32,
+    output: &Path,
+) -> Result<()> {
+    unsafe {
+        let mut app = App::create_headless(config)?;
+        for _ in 0..frames.max(1) {
+            app.render_offscreen()?;
+        }
+        app.save_offscreen(output)?;
+        log_frame_stats(&app);
+    }
+    log::info!("Wrote headless frame to `{}`.", output.display());
+
+    Ok(())
+}
+
+/// Switches to the next supported present mode, e.g. to turn vsync
+/// off with IMMEDIATE.
+fn cycle_present_mode(app: &mut App) {
+    const MODES: [vk::PresentModeKHR; 4] = [
+        vk::PresentModeKHR::FIFO,
+        vk::PresentModeKHR::FIFO_RELAXED,
+        vk::PresentModeKHR::MAILBOX,
+        vk::PresentModeKHR::IMMEDIATE,
+    ];
+    let supported = match unsafe { app.supported_present_modes() } {
+        Ok(supported) => supported,
+        Err(e) => return log::error!("{}", e),
+    };
+    let current = MODES
+        .iter()
+        .position(|m| *m == app.present_mode())
+        .unwrap_or(0);
+    if let Some(next) = (1..MODES.len())
+        .map(|i| MODES[(current + i) % MODES.len()])
+        .find(|m| supported.contains(m))
+    {
+        log::info!("Switching to present mode {:?}.", next);
+        app.set_present_mode(next);
+    }
+}
+
+/// Logs frame times, where the CPU spent the last frame, the average
+/// GPU time of each pass and what the last frame drew.
+fn log_frame_stats(app: &App) {
+    let timer = app.frame_timer();
+    log::info!(
+        "{:.1} fps, frame time {:.3} ms average, {:.3} ms p50, \
+         {:.3} ms p99",
+        timer.fps(),
+        timer.frame_time().as_secs_f64() * 1000.0,
+        timer.percentile(50.0).as_secs_f64() * 1000.0,
+        timer.percentile(99.0).as_secs_f64() * 1000.0
+    );
+    let cpu = timer.cpu_timings();
+    log::info!(
+        "CPU fence wait {:.3} ms, acquire {:.3} ms, uniforms {:.3} \
+         ms, record {:.3} ms, submit {:.3} ms, present {:.3} ms",
+        cpu.fence_wait.as_secs_f64() * 1000.0,
+        cpu.acquire.as_secs_f64() * 1000.0,
+        cpu.uniform_update.as_secs_f64() * 1000.0,
+        cpu.record.as_secs_f64() * 1000.0,
+        cpu.submit.as_secs_f64() * 1000.0,
+        cpu.present.as_secs_f64() * 1000.0
+    );
+    for timing in app.gpu_timings() {
+        log::info!(
+            "GPU {}: {:.3} ms",
+            timing.name(),
+            timing.average().as_secs_f64() * 1000.0
+        );
+    }
+    let stats = app.stats();
+    log::info!(
+        "{} draws, {} triangles, {} pipeline and {} descriptor set \
+         binds",
+        stats.draws.draw_calls,
+        stats.draws.triangles,
+        stats.draws.pipeline_binds,
+        stats.draws.descriptor_set_binds
+    );
+    if let Some(pipeline) = stats.pipeline {
+        log::info!(
+            "{} input vertices, {} vertex and {} fragment shader \
+             invocations, {} clipped primitives",
+            pipeline.input_vertices,
+            pipeline.vertex_shader_invocations,
+            pipeline.fragment_shader_invocations,
+            pipeline.clipping_primitives
+        );
+    }
+}
+
+/// Steps to the next higher sample count the device supports, back
+/// to one after the maximum.
+fn cycle_msaa(app: &mut App) {
+    let samples = app.msaa_samples();
+    let supported = app.supported_msaa_samples();
+    let next = (samples.bits().trailing_zeros() + 1..7)
+        .map(|i| vk::SampleCountFlags::from_bits_truncate(1 << i))
+        .find(|c| supported.contains(*c))
+        .unwrap_or(vk::SampleCountFlags::_1);
+    if let Err(e) = unsafe { app.set_msaa(Msaa::Samples(next)) } {
+        log::error!("{}", e);
+    }
+}
+
+fn main_f(config: &Config) -> Result<()> {
     // Window
 
     let event_loop = EventLoop::new()?;
     let window = WindowBuilder::new()
-        .with_title("Broth")
-        .with_inner_size(LogicalSize::new(1024, 768))
+        .with_title(&config.window.title)
+        .with_inner_size(LogicalSize::new(
+            config.window.width,
+            config.window.height,
+        ))
         .build(&event_loop)?;
     // Root
-    let mut app = unsafe { App::create(&window) }?;
-    let mut destroying = false;
+    // Dropped on close so the surface goes before the window.
+    let mut app = Some(unsafe { App::create(&window, config) }?);
     let mut minimized = false;
+    // Lost surfaces and devices are recovered by `render`, anything
+    // else it returns ends the loop and is reported by `main`.
+    let mut fatal = None;
+    let fatal_ref = &mut fatal;
     event_loop.run(move |event, target| {
         target.set_control_flow(ControlFlow::Poll);
+        let Some(app_ref) = app.as_mut() else {
+            return;
+        };
         match event {
             // Render a frame if our Vulkan app is not being destroyed.
-            Event::AboutToWait if !destroying && !minimized => {
-                unsafe { app.render(&window) }.unwrap();
+            Event::AboutToWait if !minimized => {
+                if let Err(e) = unsafe { app_ref.render(&window) } {
+                    *fatal_ref = Some(e);
+                    app = None;
+                    target.exit();
+                }
             }
             Event::WindowEvent {
                 event: WindowEvent::KeyboardInput { event, .. },
@@ -88,18 +406,31 @@ fn main_f() -> Result<()> {
                 && !event.repeat =>
             {
                 match event.key_without_modifiers().as_ref() {
-                    Key::Named(NamedKey::ArrowLeft) => app
+                    Key::Named(NamedKey::ArrowLeft) => app_ref
                         .rotate_camera(
                             Deg(0.0),
                             Deg(0.0),
                             Deg(-30.0),
                         ),
-                    Key::Named(NamedKey::ArrowRight) => app
+                    Key::Named(NamedKey::ArrowRight) => app_ref
                         .rotate_camera(Deg(0.0), Deg(0.0), Deg(30.0)),
-                    Key::Character("w") => app.move_camera(1.0, 0.0),
-                    Key::Character("s") => app.move_camera(-1.0, 0.0),
-                    Key::Character("d") => app.move_camera(0.0, -1.0),
-                    Key::Character("a") => app.move_camera(0.0, 1.0),
+                    Key::Character("w") => {
+                        app_ref.move_camera(1.0, 0.0)
+                    }
+                    Key::Character("s") => {
+                        app_ref.move_camera(-1.0, 0.0)
+                    }
+                    Key::Character("d") => {
+                        app_ref.move_camera(0.0, -1.0)
+                    }
+                    Key::Character("a") => {
+                        app_ref.move_camera(0.0, 1.0)
+                    }
+                    Key::Character("v") => {
+                        cycle_present_mode(app_ref)
+                    }
+                    Key::Character("m") => cycle_msaa(app_ref),
+                    Key::Character("t") => log_frame_stats(app_ref),
                     _ => {}
                 }
             }
@@ -108,10 +439,7 @@ fn main_f() -> Result<()> {
                 event: WindowEvent::CloseRequested,
                 ..
             } => {
-                destroying = true;
-                unsafe {
-                    app.destroy();
-                }
+                app = None;
                 target.exit();
             }
             Event::WindowEvent {
@@ -122,12 +450,15 @@ fn main_f() -> Result<()> {
                     minimized = true;
                 } else {
                     minimized = false;
-                    app.resized = true;
+                    app_ref.resize();
                 }
             }
             _ => {}
         }
     })?;
 
-    Ok(())
+    match fatal {
+        Some(e) => Err(e.into()),
+        None => Ok(()),
+    }
 }
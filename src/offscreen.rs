@@ -0,0 +1,203 @@
+use std::{fs::File, io::BufWriter, path::Path, rc::Rc};
+
+use vulkanalia::vk::{self, DeviceV1_0, ErrorCode, HasBuilder};
+
+use crate::{
+    allocator::Allocator,
+    buffer::{create_buffer, BufferError},
+    command::{
+        begin_single_time_commands, end_single_time_commands,
+        CommandError,
+    },
+    debug_utils::Label,
+    image::{create_image, ImageError},
+    swapchain::{Swapchain, SwapchainError},
+    VkErrorSource,
+};
+
+// Byte order matches PNG RGBA so read back pixels can be written as is.
+pub const OFFSCREEN_FORMAT: vk::Format = vk::Format::R8G8B8A8_SRGB;
+
+/// Stand-in for `create_swapchain` when rendering without a window.
+/// The single offscreen image takes the place of the swapchain images
+/// so the rest of the scene setup is shared.
+pub unsafe fn create_offscreen_target(
+    allocator: &Rc<Allocator>,
+    width: u32,
+    height: u32,
+) -> Result<Swapchain> {
+    let image = create_image(
+        allocator,
+        width,
+        height,
+        1,
+        vk::SampleCountFlags::_1,
+        OFFSCREEN_FORMAT,
+        vk::ImageTiling::OPTIMAL,
+        vk::ImageUsageFlags::COLOR_ATTACHMENT
+            | vk::ImageUsageFlags::TRANSFER_SRC,
+        vk::MemoryPropertyFlags::DEVICE_LOCAL,
+        "Offscreen target",
+    )?;
+
+    Ok(Swapchain::headless(
+        allocator.device(),
+        image,
+        vk::Extent2D { width, height },
+    )?)
+}
+
+/// Copies the rendered offscreen image (left in
+/// `TRANSFER_SRC_OPTIMAL` by the render pass) to host memory and
+/// writes it to `path` as an RGBA PNG.
+pub unsafe fn save_offscreen_image(
+    allocator: &Rc<Allocator>,
+    command_pool: vk::CommandPool,
+    graphics_queue: vk::Queue,
+    image: vk::Image,
+    extent: vk::Extent2D,
+    path: &Path,
+) -> Result<()> {
+    let device = allocator.device();
+    let size = (extent.width * extent.height * 4) as u64;
+
+    let staging_buffer = create_buffer(
+        allocator,
+        size,
+        vk::BufferUsageFlags::TRANSFER_DST,
+        vk::MemoryPropertyFlags::HOST_COHERENT
+            | vk::MemoryPropertyFlags::HOST_VISIBLE,
+        "Offscreen readback",
+    )?;
+
+    let command_buffer =
+        begin_single_time_commands(device, command_pool)?;
+    let label =
+        Label::begin(device, command_buffer, "Offscreen readback");
+
+    let subresource_range = vk::ImageSubresourceRange::builder()
+        .aspect_mask(vk::ImageAspectFlags::COLOR)
+        .base_mip_level(0)
+        .level_count(1)
+        .base_array_layer(0)
+        .layer_count(1);
+
+    // Make the resolve writes of the render pass visible to the copy.
+    let barrier = vk::ImageMemoryBarrier::builder()
+        .old_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+        .new_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+        .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .image(image)
+        .subresource_range(subresource_range)
+        .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+        .dst_access_mask(vk::AccessFlags::TRANSFER_READ);
+
+    device.cmd_pipeline_barrier(
+        command_buffer,
+        vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+        vk::PipelineStageFlags::TRANSFER,
+        vk::DependencyFlags::empty(),
+        &[] as &[vk::MemoryBarrier],
+        &[] as &[vk::BufferMemoryBarrier],
+        &[barrier],
+    );
+
+    let subresource = vk::ImageSubresourceLayers::builder()
+        .aspect_mask(vk::ImageAspectFlags::COLOR)
+        .mip_level(0)
+        .base_array_layer(0)
+        .layer_count(1);
+
+    let region = vk::BufferImageCopy::builder()
+        .buffer_offset(0)
+        .buffer_row_length(0)
+        .buffer_image_height(0)
+        .image_subresource(subresource)
+        .image_offset(vk::Offset3D { x: 0, y: 0, z: 0 })
+        .image_extent(vk::Extent3D {
+            width: extent.width,
+            height: extent.height,
+            depth: 1,
+        });
+
+    device.cmd_copy_image_to_buffer(
+        command_buffer,
+        image,
+        vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+        staging_buffer.handle(),
+        &[region],
+    );
+    drop(label);
+
+    end_single_time_commands(
+        device,
+        graphics_queue,
+        command_pool,
+        command_buffer,
+    )?;
+
+    let pixels = staging_buffer.read(size as usize)?;
+
+    write_png(path, extent, &pixels)
+}
+
+fn write_png(
+    path: &Path,
+    extent: vk::Extent2D,
+    pixels: &[u8],
+) -> Result<()> {
+    let file = File::create(path).map_err(|e| {
+        OffscreenError::FileCreateError(
+            path.display().to_string(),
+            e.to_string(),
+        )
+    })?;
+
+    let mut encoder = png::Encoder::new(
+        BufWriter::new(file),
+        extent.width,
+        extent.height,
+    );
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(pixels)?;
+
+    Ok(())
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum OffscreenError {
+    #[error(transparent)]
+    VkErrorCode(#[from] ErrorCode),
+    #[error(transparent)]
+    EncodingError(#[from] png::EncodingError),
+    #[error(transparent)]
+    BufferError(#[from] BufferError),
+    #[error(transparent)]
+    CommandError(#[from] CommandError),
+    #[error(transparent)]
+    ImageError(#[from] ImageError),
+    #[error(transparent)]
+    SwapchainError(#[from] SwapchainError),
+    #[error("Failed to create image file {0} with error: {1}")]
+    FileCreateError(String, String),
+}
+type Result<T> = std::result::Result<T, OffscreenError>;
+
+impl VkErrorSource for OffscreenError {
+    fn vk_error_code(&self) -> Option<ErrorCode> {
+        match self {
+            Self::VkErrorCode(e) => Some(*e),
+            Self::BufferError(e) => e.vk_error_code(),
+            Self::CommandError(e) => e.vk_error_code(),
+            Self::ImageError(e) => e.vk_error_code(),
+            Self::SwapchainError(e) => e.vk_error_code(),
+            Self::EncodingError(_) | Self::FileCreateError(..) => {
+                None
+            }
+        }
+    }
+}
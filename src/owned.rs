@@ -0,0 +1,83 @@
+use std::{fmt, rc::Rc};
+
+use vulkanalia::{
+    vk::{self, DeviceV1_0, Handle},
+    Device,
+};
+
+use crate::{debug_utils::set_name, device::LogicalDevice};
+
+/// A Vulkan handle that is destroyed with a single device call.
+pub trait DeviceObject: Copy {
+    unsafe fn destroy(self, device: &Device);
+}
+
+macro_rules! device_object {
+    ($type:ty, $destroy:ident) => {
+        impl DeviceObject for $type {
+            unsafe fn destroy(self, device: &Device) {
+                device.$destroy(self, None);
+            }
+        }
+    };
+}
+
+device_object!(vk::CommandPool, destroy_command_pool);
+device_object!(vk::DescriptorPool, destroy_descriptor_pool);
+device_object!(
+    vk::DescriptorSetLayout,
+    destroy_descriptor_set_layout
+);
+device_object!(vk::Fence, destroy_fence);
+device_object!(vk::Framebuffer, destroy_framebuffer);
+device_object!(vk::ImageView, destroy_image_view);
+device_object!(vk::Pipeline, destroy_pipeline);
+device_object!(vk::PipelineCache, destroy_pipeline_cache);
+device_object!(vk::PipelineLayout, destroy_pipeline_layout);
+device_object!(vk::QueryPool, destroy_query_pool);
+device_object!(vk::RenderPass, destroy_render_pass);
+device_object!(vk::Sampler, destroy_sampler);
+device_object!(vk::Semaphore, destroy_semaphore);
+device_object!(vk::ShaderModule, destroy_shader_module);
+
+/// Owns a single device object and destroys it when dropped.
+pub struct Owned<T: DeviceObject> {
+    device: Rc<LogicalDevice>,
+    handle: T,
+}
+
+impl<T: DeviceObject> Owned<T> {
+    pub fn new(device: &Rc<LogicalDevice>, handle: T) -> Self {
+        Self {
+            device: device.clone(),
+            handle,
+        }
+    }
+
+    pub fn handle(&self) -> T {
+        self.handle
+    }
+
+    /// Names the object, see `debug_utils::set_name`.
+    pub unsafe fn named(self, name: &str) -> Self
+    where
+        T: Handle<Repr = u64>,
+    {
+        set_name(&self.device, self.handle, name);
+        self
+    }
+}
+
+impl<T: DeviceObject + fmt::Debug> fmt::Debug for Owned<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Owned").field(&self.handle).finish()
+    }
+}
+
+impl<T: DeviceObject> Drop for Owned<T> {
+    fn drop(&mut self) {
+        unsafe {
+            self.handle.destroy(&self.device);
+        }
+    }
+}
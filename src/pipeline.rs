@@ -1,296 +1,570 @@
+use std::rc::Rc;
+
 use vulkanalia::{
     bytecode::Bytecode,
     vk::{self, DeviceV1_0, ErrorCode, Handle, HasBuilder},
     Device,
 };
 
-use crate::vertex::{Vertex2, Vertex3};
+use crate::{
+    compute::group_count,
+    debug_utils::set_name,
+    device::LogicalDevice,
+    owned::Owned,
+    reflect::{ReflectError, ShaderReflection},
+    vertex::VertexLayout,
+    VkErrorSource,
+};
 
-// TODO: Look into creating an interface specifying wether
-// TODO: the pipeline is 2D or 3D. Will use two different
-// TODO: shaders and vertex structs.
-pub unsafe fn create_pipeline(
-    device: &Device,
-    pipeline: &mut vk::Pipeline,
-    pipeline_layout: &mut vk::PipelineLayout,
-    descriptor_set_layout: vk::DescriptorSetLayout,
-    render_pass: vk::RenderPass,
-    swapchain_extent: vk::Extent2D,
-    msaa_samples: vk::SampleCountFlags,
-) -> Result<()> {
-    let vert = include_bytes!("../shaders/vert.spv");
-    let frag = include_bytes!("../shaders/frag.spv");
-
-    let vert_shader_module = create_shader_module(device, &vert[..])?;
-    let frag_shader_module = create_shader_module(device, &frag[..])?;
-
-    let vert_stage = vk::PipelineShaderStageCreateInfo::builder()
-        .stage(vk::ShaderStageFlags::VERTEX)
-        .module(vert_shader_module)
-        .name(b"main\0");
-    let frag_stage = vk::PipelineShaderStageCreateInfo::builder()
-        .stage(vk::ShaderStageFlags::FRAGMENT)
-        .module(frag_shader_module)
-        .name(b"main\0");
-
-    let binding_descriptions = &[Vertex3::binding_description()];
-    let attribute_descriptions = Vertex3::attribute_descriptions();
-    let vertex_input_state =
-        vk::PipelineVertexInputStateCreateInfo::builder()
-            .vertex_binding_descriptions(binding_descriptions)
-            .vertex_attribute_descriptions(&attribute_descriptions);
-
-    let input_assembly_state =
-        vk::PipelineInputAssemblyStateCreateInfo::builder()
-            .topology(vk::PrimitiveTopology::TRIANGLE_LIST)
-            .primitive_restart_enable(false);
-
-    let viewport = vk::Viewport::builder()
-        .x(0.0)
-        .y(0.0)
-        .width(swapchain_extent.width as f32)
-        .height(swapchain_extent.height as f32)
-        .min_depth(0.0)
-        .max_depth(1.0);
-
-    // TODO: Wtf is a scissor?
-    let scissor = vk::Rect2D::builder()
-        .offset(vk::Offset2D { x: 0, y: 0 })
-        .extent(swapchain_extent);
-
-    let viewports = &[viewport];
-    let scissors = &[scissor];
-    let viewport_state =
-        vk::PipelineViewportStateCreateInfo::builder()
-            .viewports(viewports)
-            .scissors(scissors);
-
-    let rasterization_state =
-        vk::PipelineRasterizationStateCreateInfo::builder()
-            .depth_clamp_enable(false)
-            .rasterizer_discard_enable(false)
-            .polygon_mode(vk::PolygonMode::FILL)
-            .polygon_mode(vk::PolygonMode::FILL)
-            .line_width(1.0)
-            .cull_mode(vk::CullModeFlags::BACK)
-            .front_face(vk::FrontFace::COUNTER_CLOCKWISE)
-            .depth_bias_enable(false);
-
-    let multisample_state =
-        vk::PipelineMultisampleStateCreateInfo::builder()
-            .sample_shading_enable(true)
-            .min_sample_shading(0.2)
-            .rasterization_samples(msaa_samples);
-
-    let depth_stencil_state =
-        vk::PipelineDepthStencilStateCreateInfo::builder()
-            .depth_test_enable(true)
-            .depth_write_enable(true)
-            .depth_compare_op(vk::CompareOp::LESS)
-            .depth_bounds_test_enable(false)
-            .min_depth_bounds(0.0) // Optional.
-            .max_depth_bounds(1.0) // Optional.
-            .stencil_test_enable(false);
-    // .front(/* vk::StencilOpState */) // Optional.
-    // .back(/* vk::StencilOpState */); // Optional.
-
-    // * More parameters vk::BlendFactor vk::BlendOp + documentation
-    let attachment = vk::PipelineColorBlendAttachmentState::builder()
-        .color_write_mask(vk::ColorComponentFlags::all())
-        .blend_enable(false)
-        .src_color_blend_factor(vk::BlendFactor::SRC_ALPHA)
-        .dst_color_blend_factor(vk::BlendFactor::ONE_MINUS_SRC_ALPHA)
-        .color_blend_op(vk::BlendOp::ADD)
-        .src_alpha_blend_factor(vk::BlendFactor::ONE)
-        .dst_alpha_blend_factor(vk::BlendFactor::ZERO)
-        .alpha_blend_op(vk::BlendOp::ADD);
-
-    let attachments = &[attachment];
-    let color_blend_state =
-        vk::PipelineColorBlendStateCreateInfo::builder()
-            .logic_op_enable(false)
-            .logic_op(vk::LogicOp::COPY)
-            .attachments(attachments)
-            .blend_constants([0.0, 0.0, 0.0, 0.0]);
-
-    let set_layouts = &[descriptor_set_layout];
-    let layout_info = vk::PipelineLayoutCreateInfo::builder()
-        .set_layouts(set_layouts);
-    *pipeline_layout =
-        device.create_pipeline_layout(&layout_info, None)?;
-
-    let stages = &[vert_stage, frag_stage];
-    let info = vk::GraphicsPipelineCreateInfo::builder()
-        .stages(stages)
-        .vertex_input_state(&vertex_input_state)
-        .input_assembly_state(&input_assembly_state)
-        .viewport_state(&viewport_state)
-        .rasterization_state(&rasterization_state)
-        .multisample_state(&multisample_state)
-        .depth_stencil_state(&depth_stencil_state)
-        .color_blend_state(&color_blend_state)
-        .layout(*pipeline_layout)
-        .render_pass(render_pass)
-        .subpass(0)
-        .base_pipeline_handle(vk::Pipeline::null()) // Optional.
-        .base_pipeline_index(-1); // Optional.
-
-    *pipeline = device
-        .create_graphics_pipelines(
-            vk::PipelineCache::null(),
-            &[info],
-            None,
-        )?
-        .0
-        .get(0)
-        .unwrap()
-        .to_owned();
-
-    device.destroy_shader_module(vert_shader_module, None);
-    device.destroy_shader_module(frag_shader_module, None);
-
-    Ok(())
+/// A graphics pipeline together with its layout, both destroyed when
+/// dropped.
+#[derive(Debug)]
+pub struct Pipeline {
+    device: Rc<LogicalDevice>,
+    pipeline: vk::Pipeline,
+    layout: vk::PipelineLayout,
+    set_layouts: Vec<vk::DescriptorSetLayout>,
+    push_constant_ranges: Vec<vk::PushConstantRange>,
 }
-pub unsafe fn create_pipeline_2d(
-    device: &Device,
-    pipeline: &mut vk::Pipeline,
-    pipeline_layout: &mut vk::PipelineLayout,
-    descriptor_set_layout: vk::DescriptorSetLayout,
-    render_pass: vk::RenderPass,
-    swapchain_extent: vk::Extent2D,
-    msaa_samples: vk::SampleCountFlags,
-) -> Result<()> {
-    let vert = include_bytes!("../shaders/2d_vert.spv");
-    let frag = include_bytes!("../shaders/frag.spv");
-
-    let vert_shader_module = create_shader_module(device, &vert[..])?;
-    let frag_shader_module = create_shader_module(device, &frag[..])?;
-
-    let vert_stage = vk::PipelineShaderStageCreateInfo::builder()
-        .stage(vk::ShaderStageFlags::VERTEX)
-        .module(vert_shader_module)
-        .name(b"main\0");
-    let frag_stage = vk::PipelineShaderStageCreateInfo::builder()
-        .stage(vk::ShaderStageFlags::FRAGMENT)
-        .module(frag_shader_module)
-        .name(b"main\0");
-
-    let binding_descriptions = &[Vertex2::binding_description()];
-    let attribute_descriptions = Vertex2::attribute_descriptions();
-    let vertex_input_state =
-        vk::PipelineVertexInputStateCreateInfo::builder()
-            .vertex_binding_descriptions(binding_descriptions)
-            .vertex_attribute_descriptions(&attribute_descriptions);
-
-    let input_assembly_state =
-        vk::PipelineInputAssemblyStateCreateInfo::builder()
-            .topology(vk::PrimitiveTopology::TRIANGLE_LIST)
-            .primitive_restart_enable(false);
-
-    let viewport = vk::Viewport::builder()
-        .x(0.0)
-        .y(0.0)
-        .width(swapchain_extent.width as f32)
-        .height(swapchain_extent.height as f32)
-        .min_depth(0.0)
-        .max_depth(1.0);
-
-    // TODO: Wtf is a scissor?
-    let scissor = vk::Rect2D::builder()
-        .offset(vk::Offset2D { x: 0, y: 0 })
-        .extent(swapchain_extent);
-
-    let viewports = &[viewport];
-    let scissors = &[scissor];
-    let viewport_state =
-        vk::PipelineViewportStateCreateInfo::builder()
-            .viewports(viewports)
-            .scissors(scissors);
-
-    let rasterization_state =
-        vk::PipelineRasterizationStateCreateInfo::builder()
-            .depth_clamp_enable(false)
-            .rasterizer_discard_enable(false)
-            .polygon_mode(vk::PolygonMode::FILL)
-            .polygon_mode(vk::PolygonMode::FILL)
-            .line_width(1.0)
-            .cull_mode(vk::CullModeFlags::BACK)
-            .front_face(vk::FrontFace::COUNTER_CLOCKWISE)
-            .depth_bias_enable(false);
-
-    let multisample_state =
-        vk::PipelineMultisampleStateCreateInfo::builder()
-            .sample_shading_enable(true)
-            .min_sample_shading(0.2)
-            .rasterization_samples(msaa_samples);
-
-    // let depth_stencil_state =
-    //     vk::PipelineDepthStencilStateCreateInfo::builder()
-    //         .depth_test_enable(true)
-    //         .depth_write_enable(true)
-    //         .depth_compare_op(vk::CompareOp::LESS)
-    //         .depth_bounds_test_enable(false)
-    //         .min_depth_bounds(0.0) // Optional.
-    //         .max_depth_bounds(1.0) // Optional.
-    //         .stencil_test_enable(false);
-    // .front(/* vk::StencilOpState */) // Optional.
-    // .back(/* vk::StencilOpState */); // Optional.
-
-    // * More parameters vk::BlendFactor vk::BlendOp + documentation
-    let attachment = vk::PipelineColorBlendAttachmentState::builder()
-        .color_write_mask(vk::ColorComponentFlags::all())
-        .blend_enable(false)
-        .src_color_blend_factor(vk::BlendFactor::SRC_ALPHA)
-        .dst_color_blend_factor(vk::BlendFactor::ONE_MINUS_SRC_ALPHA)
-        .color_blend_op(vk::BlendOp::ADD)
-        .src_alpha_blend_factor(vk::BlendFactor::ONE)
-        .dst_alpha_blend_factor(vk::BlendFactor::ZERO)
-        .alpha_blend_op(vk::BlendOp::ADD);
-
-    let attachments = &[attachment];
-    let color_blend_state =
-        vk::PipelineColorBlendStateCreateInfo::builder()
-            .logic_op_enable(false)
-            .logic_op(vk::LogicOp::COPY)
-            .attachments(attachments)
-            .blend_constants([0.0, 0.0, 0.0, 0.0]);
-
-    let set_layouts = &[descriptor_set_layout];
-    let layout_info = vk::PipelineLayoutCreateInfo::builder()
-        .set_layouts(set_layouts);
-    *pipeline_layout =
-        device.create_pipeline_layout(&layout_info, None)?;
-
-    let stages = &[vert_stage, frag_stage];
-    let info = vk::GraphicsPipelineCreateInfo::builder()
-        .stages(stages)
-        .vertex_input_state(&vertex_input_state)
-        .input_assembly_state(&input_assembly_state)
-        .viewport_state(&viewport_state)
-        .rasterization_state(&rasterization_state)
-        .multisample_state(&multisample_state)
-        .color_blend_state(&color_blend_state)
-        .layout(*pipeline_layout)
-        .render_pass(render_pass)
-        .subpass(0)
-        .base_pipeline_handle(vk::Pipeline::null()) // Optional.
-        .base_pipeline_index(-1); // Optional.
-
-    *pipeline = device
-        .create_graphics_pipelines(
-            vk::PipelineCache::null(),
-            &[info],
-            None,
-        )?
-        .0
-        .get(0)
-        .unwrap()
-        .to_owned();
-
-    device.destroy_shader_module(vert_shader_module, None);
-    device.destroy_shader_module(frag_shader_module, None);
-
-    Ok(())
+
+impl Pipeline {
+    pub fn handle(&self) -> vk::Pipeline {
+        self.pipeline
+    }
+
+    pub fn layout(&self) -> vk::PipelineLayout {
+        self.layout
+    }
+
+    /// The descriptor set layouts of `layout`, by set number.
+    pub fn set_layouts(&self) -> &[vk::DescriptorSetLayout] {
+        &self.set_layouts
+    }
+
+    pub fn push_constant_ranges(&self) -> &[vk::PushConstantRange] {
+        &self.push_constant_ranges
+    }
+
+    unsafe fn set_name(&self, name: &str) {
+        set_name(&self.device, self.pipeline, name);
+        set_name(
+            &self.device,
+            self.layout,
+            &format!("{} layout", name),
+        );
+    }
+}
+
+impl Drop for Pipeline {
+    fn drop(&mut self) {
+        unsafe {
+            self.device.destroy_pipeline(self.pipeline, None);
+            self.device.destroy_pipeline_layout(self.layout, None);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BlendMode {
+    #[default]
+    Opaque,
+    /// Source over destination using the source alpha.
+    Alpha,
+    Additive,
+}
+
+impl BlendMode {
+    fn attachment(self) -> vk::PipelineColorBlendAttachmentState {
+        // * More parameters vk::BlendFactor vk::BlendOp + documentation
+        let attachment =
+            vk::PipelineColorBlendAttachmentState::builder()
+                .color_write_mask(vk::ColorComponentFlags::all())
+                .color_blend_op(vk::BlendOp::ADD)
+                .src_alpha_blend_factor(vk::BlendFactor::ONE)
+                .dst_alpha_blend_factor(vk::BlendFactor::ZERO)
+                .alpha_blend_op(vk::BlendOp::ADD);
+
+        match self {
+            BlendMode::Opaque => attachment
+                .blend_enable(false)
+                .src_color_blend_factor(vk::BlendFactor::SRC_ALPHA)
+                .dst_color_blend_factor(
+                    vk::BlendFactor::ONE_MINUS_SRC_ALPHA,
+                ),
+            BlendMode::Alpha => attachment
+                .blend_enable(true)
+                .src_color_blend_factor(vk::BlendFactor::SRC_ALPHA)
+                .dst_color_blend_factor(
+                    vk::BlendFactor::ONE_MINUS_SRC_ALPHA,
+                ),
+            BlendMode::Additive => attachment
+                .blend_enable(true)
+                .src_color_blend_factor(vk::BlendFactor::SRC_ALPHA)
+                .dst_color_blend_factor(vk::BlendFactor::ONE),
+        }
+        .build()
+    }
+}
+
+/// Describes a graphics pipeline variant. Defaults to an opaque,
+/// back face culled triangle list with depth test and write enabled
+/// and a single sample.
+#[derive(Debug, Clone)]
+pub struct GraphicsPipelineBuilder<'a> {
+    stages: Vec<(vk::ShaderStageFlags, &'a [u8])>,
+    vertex_name: &'static str,
+    binding_descriptions: Vec<vk::VertexInputBindingDescription>,
+    attribute_descriptions: Vec<vk::VertexInputAttributeDescription>,
+    topology: vk::PrimitiveTopology,
+    polygon_mode: vk::PolygonMode,
+    cull_mode: vk::CullModeFlags,
+    front_face: vk::FrontFace,
+    depth_test: bool,
+    depth_write: bool,
+    blend_mode: BlendMode,
+    samples: vk::SampleCountFlags,
+    min_sample_shading: Option<f32>,
+    set_layouts: Vec<vk::DescriptorSetLayout>,
+    push_constant_ranges: Vec<vk::PushConstantRange>,
+    cache: vk::PipelineCache,
+    name: &'a str,
+}
+
+impl Default for GraphicsPipelineBuilder<'_> {
+    fn default() -> Self {
+        Self {
+            stages: vec![],
+            vertex_name: "no vertex layout",
+            binding_descriptions: vec![],
+            attribute_descriptions: vec![],
+            topology: vk::PrimitiveTopology::TRIANGLE_LIST,
+            polygon_mode: vk::PolygonMode::FILL,
+            cull_mode: vk::CullModeFlags::BACK,
+            front_face: vk::FrontFace::COUNTER_CLOCKWISE,
+            depth_test: true,
+            depth_write: true,
+            blend_mode: BlendMode::Opaque,
+            samples: vk::SampleCountFlags::_1,
+            min_sample_shading: None,
+            set_layouts: vec![],
+            push_constant_ranges: vec![],
+            cache: vk::PipelineCache::null(),
+            name: "Graphics pipeline",
+        }
+    }
+}
+
+impl<'a> GraphicsPipelineBuilder<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a SPIR-V shader stage with a `main` entry point.
+    pub fn stage(
+        mut self,
+        stage: vk::ShaderStageFlags,
+        code: &'a [u8],
+    ) -> Self {
+        self.stages.push((stage, code));
+        self
+    }
+
+    pub fn vertex_layout<V: VertexLayout>(mut self) -> Self {
+        self.vertex_name = std::any::type_name::<V>();
+        self.binding_descriptions = vec![V::binding_description()];
+        self.attribute_descriptions = V::attribute_descriptions();
+        self
+    }
+
+    pub fn topology(
+        mut self,
+        topology: vk::PrimitiveTopology,
+    ) -> Self {
+        self.topology = topology;
+        self
+    }
+
+    pub fn polygon_mode(
+        mut self,
+        polygon_mode: vk::PolygonMode,
+    ) -> Self {
+        self.polygon_mode = polygon_mode;
+        self
+    }
+
+    pub fn cull_mode(mut self, cull_mode: vk::CullModeFlags) -> Self {
+        self.cull_mode = cull_mode;
+        self
+    }
+
+    pub fn front_face(mut self, front_face: vk::FrontFace) -> Self {
+        self.front_face = front_face;
+        self
+    }
+
+    pub fn depth(mut self, test: bool, write: bool) -> Self {
+        self.depth_test = test;
+        self.depth_write = write;
+        self
+    }
+
+    pub fn blend_mode(mut self, blend_mode: BlendMode) -> Self {
+        self.blend_mode = blend_mode;
+        self
+    }
+
+    pub fn samples(mut self, samples: vk::SampleCountFlags) -> Self {
+        self.samples = samples;
+        self
+    }
+
+    /// Enables sample shading with the given minimum fraction.
+    pub fn min_sample_shading(
+        mut self,
+        min_sample_shading: f32,
+    ) -> Self {
+        self.min_sample_shading = Some(min_sample_shading);
+        self
+    }
+
+    pub fn set_layout(
+        mut self,
+        layout: vk::DescriptorSetLayout,
+    ) -> Self {
+        self.set_layouts.push(layout);
+        self
+    }
+
+    /// Overrides the push constant ranges reflected from the stages.
+    pub fn push_constant_range(
+        mut self,
+        range: vk::PushConstantRange,
+    ) -> Self {
+        self.push_constant_ranges.push(range);
+        self
+    }
+
+    /// Looks up and stores the compiled pipeline in `cache`.
+    pub fn cache(mut self, cache: vk::PipelineCache) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    /// Names the pipeline and its layout in validation messages.
+    pub fn name(mut self, name: &'a str) -> Self {
+        self.name = name;
+        self
+    }
+
+    pub(crate) unsafe fn build(
+        &self,
+        device: &Rc<LogicalDevice>,
+        render_pass: vk::RenderPass,
+    ) -> Result<Pipeline> {
+        let reflection = ShaderReflection::from_stages(
+            &self
+                .stages
+                .iter()
+                .map(|(_, code)| *code)
+                .collect::<Vec<_>>(),
+        )?;
+        reflection.validate_vertex_attributes(
+            self.vertex_name,
+            &self.attribute_descriptions,
+        )?;
+        let push_constant_ranges =
+            if self.push_constant_ranges.is_empty() {
+                &reflection.push_constant_ranges
+            } else {
+                &self.push_constant_ranges
+            };
+
+        let shader_modules = self
+            .stages
+            .iter()
+            .map(|(_, code)| {
+                Ok(Owned::new(
+                    device,
+                    create_shader_module(device, code)?,
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let stages = self
+            .stages
+            .iter()
+            .zip(&shader_modules)
+            .map(|((stage, _), module)| {
+                vk::PipelineShaderStageCreateInfo::builder()
+                    .stage(*stage)
+                    .module(module.handle())
+                    .name(b"main\0")
+            })
+            .collect::<Vec<_>>();
+
+        let vertex_input_state =
+            vk::PipelineVertexInputStateCreateInfo::builder()
+                .vertex_binding_descriptions(
+                    &self.binding_descriptions,
+                )
+                .vertex_attribute_descriptions(
+                    &self.attribute_descriptions,
+                );
+
+        let input_assembly_state =
+            vk::PipelineInputAssemblyStateCreateInfo::builder()
+                .topology(self.topology)
+                .primitive_restart_enable(false);
+
+        // Viewport and scissor are set when recording, so resizing
+        // doesn't require a new pipeline.
+        let viewport_state =
+            vk::PipelineViewportStateCreateInfo::builder()
+                .viewport_count(1)
+                .scissor_count(1);
+
+        let dynamic_states =
+            &[vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+        let dynamic_state =
+            vk::PipelineDynamicStateCreateInfo::builder()
+                .dynamic_states(dynamic_states);
+
+        let rasterization_state =
+            vk::PipelineRasterizationStateCreateInfo::builder()
+                .depth_clamp_enable(false)
+                .rasterizer_discard_enable(false)
+                .polygon_mode(self.polygon_mode)
+                .line_width(1.0)
+                .cull_mode(self.cull_mode)
+                .front_face(self.front_face)
+                .depth_bias_enable(false);
+
+        let multisample_state =
+            vk::PipelineMultisampleStateCreateInfo::builder()
+                .sample_shading_enable(
+                    self.min_sample_shading.is_some(),
+                )
+                .min_sample_shading(
+                    self.min_sample_shading.unwrap_or(0.0),
+                )
+                .rasterization_samples(self.samples);
+
+        // Ignored by render passes without a depth attachment.
+        let depth_stencil_state =
+            vk::PipelineDepthStencilStateCreateInfo::builder()
+                .depth_test_enable(self.depth_test)
+                .depth_write_enable(self.depth_write)
+                .depth_compare_op(vk::CompareOp::LESS)
+                .depth_bounds_test_enable(false)
+                .min_depth_bounds(0.0) // Optional.
+                .max_depth_bounds(1.0) // Optional.
+                .stencil_test_enable(false);
+
+        let attachments = &[self.blend_mode.attachment()];
+        let color_blend_state =
+            vk::PipelineColorBlendStateCreateInfo::builder()
+                .logic_op_enable(false)
+                .logic_op(vk::LogicOp::COPY)
+                .attachments(attachments)
+                .blend_constants([0.0, 0.0, 0.0, 0.0]);
+
+        let layout_info = vk::PipelineLayoutCreateInfo::builder()
+            .set_layouts(&self.set_layouts)
+            .push_constant_ranges(push_constant_ranges);
+
+        // Released by `Drop` if creating the pipeline fails.
+        let mut pipeline = Pipeline {
+            device: device.clone(),
+            pipeline: vk::Pipeline::null(),
+            layout: device
+                .create_pipeline_layout(&layout_info, None)?,
+            set_layouts: self.set_layouts.clone(),
+            push_constant_ranges: push_constant_ranges.clone(),
+        };
+
+        let info = vk::GraphicsPipelineCreateInfo::builder()
+            .stages(&stages)
+            .vertex_input_state(&vertex_input_state)
+            .input_assembly_state(&input_assembly_state)
+            .viewport_state(&viewport_state)
+            .rasterization_state(&rasterization_state)
+            .multisample_state(&multisample_state)
+            .depth_stencil_state(&depth_stencil_state)
+            .color_blend_state(&color_blend_state)
+            .dynamic_state(&dynamic_state)
+            .layout(pipeline.layout)
+            .render_pass(render_pass)
+            .subpass(0)
+            .base_pipeline_handle(vk::Pipeline::null()) // Optional.
+            .base_pipeline_index(-1); // Optional.
+
+        pipeline.pipeline = device
+            .create_graphics_pipelines(self.cache, &[info], None)?
+            .0
+            .first()
+            .unwrap()
+            .to_owned();
+        pipeline.set_name(self.name);
+
+        Ok(pipeline)
+    }
+}
+
+/// A compute pipeline together with the local workgroup size of its
+/// shader, see `dispatch`.
+#[derive(Debug)]
+pub struct ComputePipeline {
+    pipeline: Pipeline,
+    workgroup_size: [u32; 3],
+}
+
+impl ComputePipeline {
+    pub fn handle(&self) -> vk::Pipeline {
+        self.pipeline.handle()
+    }
+
+    pub fn layout(&self) -> vk::PipelineLayout {
+        self.pipeline.layout()
+    }
+
+    pub fn workgroup_size(&self) -> [u32; 3] {
+        self.workgroup_size
+    }
+
+    /// Binds the pipeline and `descriptor_sets` (from set 0) and
+    /// dispatches enough workgroups for `invocations` shader
+    /// invocations.
+    pub unsafe fn dispatch(
+        &self,
+        device: &Device,
+        command_buffer: vk::CommandBuffer,
+        descriptor_sets: &[vk::DescriptorSet],
+        invocations: [u32; 3],
+    ) {
+        device.cmd_bind_pipeline(
+            command_buffer,
+            vk::PipelineBindPoint::COMPUTE,
+            self.handle(),
+        );
+        if !descriptor_sets.is_empty() {
+            device.cmd_bind_descriptor_sets(
+                command_buffer,
+                vk::PipelineBindPoint::COMPUTE,
+                self.layout(),
+                0,
+                descriptor_sets,
+                &[],
+            );
+        }
+        let [x, y, z] = group_count(invocations, self.workgroup_size);
+        device.cmd_dispatch(command_buffer, x, y, z);
+    }
+}
+
+/// Describes a compute pipeline from a single SPIR-V compute shader
+/// with a `main` entry point.
+#[derive(Debug, Clone)]
+pub struct ComputePipelineBuilder<'a> {
+    code: &'a [u8],
+    set_layouts: Vec<vk::DescriptorSetLayout>,
+    push_constant_ranges: Vec<vk::PushConstantRange>,
+    cache: vk::PipelineCache,
+    name: &'a str,
+}
+
+impl<'a> ComputePipelineBuilder<'a> {
+    pub fn new(code: &'a [u8]) -> Self {
+        Self {
+            code,
+            set_layouts: vec![],
+            push_constant_ranges: vec![],
+            cache: vk::PipelineCache::null(),
+            name: "Compute pipeline",
+        }
+    }
+
+    pub fn set_layout(
+        mut self,
+        layout: vk::DescriptorSetLayout,
+    ) -> Self {
+        self.set_layouts.push(layout);
+        self
+    }
+
+    /// Overrides the push constant ranges reflected from the shader.
+    pub fn push_constant_range(
+        mut self,
+        range: vk::PushConstantRange,
+    ) -> Self {
+        self.push_constant_ranges.push(range);
+        self
+    }
+
+    /// Looks up and stores the compiled pipeline in `cache`.
+    pub fn cache(mut self, cache: vk::PipelineCache) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    /// Names the pipeline and its layout in validation messages.
+    pub fn name(mut self, name: &'a str) -> Self {
+        self.name = name;
+        self
+    }
+
+    pub(crate) unsafe fn build(
+        &self,
+        device: &Rc<LogicalDevice>,
+    ) -> Result<ComputePipeline> {
+        let reflection = ShaderReflection::from_stages(&[self.code])?;
+        let Some(workgroup_size) = reflection.workgroup_size else {
+            return Err(PipelineError::StageError(
+                "expected a compute shader".into(),
+            ));
+        };
+        let push_constant_ranges =
+            if self.push_constant_ranges.is_empty() {
+                &reflection.push_constant_ranges
+            } else {
+                &self.push_constant_ranges
+            };
+
+        let module = Owned::new(
+            device,
+            create_shader_module(device, self.code)?,
+        );
+        let stage = vk::PipelineShaderStageCreateInfo::builder()
+            .stage(vk::ShaderStageFlags::COMPUTE)
+            .module(module.handle())
+            .name(b"main\0");
+
+        let layout_info = vk::PipelineLayoutCreateInfo::builder()
+            .set_layouts(&self.set_layouts)
+            .push_constant_ranges(push_constant_ranges);
+
+        // Released by `Drop` if creating the pipeline fails.
+        let mut pipeline = Pipeline {
+            device: device.clone(),
+            pipeline: vk::Pipeline::null(),
+            layout: device
+                .create_pipeline_layout(&layout_info, None)?,
+            set_layouts: self.set_layouts.clone(),
+            push_constant_ranges: push_constant_ranges.clone(),
+        };
+
+        let info = vk::ComputePipelineCreateInfo::builder()
+            .stage(stage)
+            .layout(pipeline.layout);
+
+        pipeline.pipeline = device
+            .create_compute_pipelines(self.cache, &[info], None)?
+            .0[0];
+        pipeline.set_name(self.name);
+
+        Ok(ComputePipeline {
+            pipeline,
+            workgroup_size,
+        })
+    }
 }
 
 pub unsafe fn create_shader_module(
@@ -314,5 +588,18 @@ pub unsafe fn create_shader_module(
 pub enum PipelineError {
     #[error(transparent)]
     VkErrorCode(#[from] ErrorCode),
+    #[error(transparent)]
+    ReflectError(#[from] ReflectError),
+    #[error("Invalid shader stage: {0}")]
+    StageError(String),
 }
 type Result<T> = std::result::Result<T, PipelineError>;
+
+impl VkErrorSource for PipelineError {
+    fn vk_error_code(&self) -> Option<ErrorCode> {
+        match self {
+            Self::VkErrorCode(e) => Some(*e),
+            Self::ReflectError(_) | Self::StageError(_) => None,
+        }
+    }
+}
@@ -0,0 +1,128 @@
+use std::{fs, path::Path, rc::Rc};
+
+use vulkanalia::{
+    vk::{self, DeviceV1_0, ErrorCode, HasBuilder, InstanceV1_0},
+    Device, Instance,
+};
+
+use crate::{device::LogicalDevice, owned::Owned, VkErrorSource};
+
+/// Where the pipeline cache is kept between runs, relative to the
+/// working directory.
+pub const PIPELINE_CACHE_PATH: &str = "pipeline_cache.bin";
+
+/// Size of a `VK_PIPELINE_CACHE_HEADER_VERSION_ONE` header.
+const HEADER_SIZE: usize = 16 + vk::UUID_SIZE;
+
+/// Creates a pipeline cache seeded from the file at `path`. A missing
+/// file or one written by another driver or device starts an empty
+/// cache.
+pub unsafe fn create_pipeline_cache(
+    instance: &Instance,
+    device: &Rc<LogicalDevice>,
+    physical_device: vk::PhysicalDevice,
+    path: &Path,
+) -> Result<Owned<vk::PipelineCache>> {
+    let properties =
+        instance.get_physical_device_properties(physical_device);
+    let data = match fs::read(path) {
+        Ok(data) if is_compatible(&data, &properties) => {
+            log::debug!(
+                "Loaded pipeline cache {} ({} bytes).",
+                path.display(),
+                data.len()
+            );
+            data
+        }
+        Ok(_) => {
+            log::info!(
+                "Ignoring pipeline cache {}, it was written for another device or driver.",
+                path.display()
+            );
+            vec![]
+        }
+        Err(_) => vec![],
+    };
+
+    let info =
+        vk::PipelineCacheCreateInfo::builder().initial_data(&data);
+    let cache = match device.create_pipeline_cache(&info, None) {
+        Ok(cache) => cache,
+        // Drivers may still reject data that passed the header check.
+        Err(e) if !data.is_empty() => {
+            log::warn!("Discarding pipeline cache: {}", e);
+            device.create_pipeline_cache(
+                &vk::PipelineCacheCreateInfo::builder(),
+                None,
+            )?
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    Ok(Owned::new(device, cache).named("Pipeline cache"))
+}
+
+/// Writes the contents of `cache` to `path`, replacing the file only
+/// once the new one is complete.
+pub unsafe fn save_pipeline_cache(
+    device: &Device,
+    cache: vk::PipelineCache,
+    path: &Path,
+) -> Result<()> {
+    let data = device.get_pipeline_cache_data(cache)?;
+    let temporary = path.with_extension("tmp");
+    fs::write(&temporary, &data)
+        .and_then(|_| fs::rename(&temporary, path))
+        .map_err(|e| {
+            PipelineCacheError::FileWriteError(
+                path.display().to_string(),
+                e.to_string(),
+            )
+        })?;
+    log::debug!(
+        "Saved pipeline cache {} ({} bytes).",
+        path.display(),
+        data.len()
+    );
+
+    Ok(())
+}
+
+/// Checks the cache header against the device, drivers are supposed
+/// to do this as well but not all of them do.
+fn is_compatible(
+    data: &[u8],
+    properties: &vk::PhysicalDeviceProperties,
+) -> bool {
+    if data.len() < HEADER_SIZE {
+        return false;
+    }
+    let word = |i: usize| {
+        u32::from_ne_bytes(data[i * 4..i * 4 + 4].try_into().unwrap())
+    };
+
+    word(0) as usize >= HEADER_SIZE
+        && word(1)
+            == vk::PipelineCacheHeaderVersion::ONE.as_raw() as u32
+        && word(2) == properties.vendor_id
+        && word(3) == properties.device_id
+        && data[16..HEADER_SIZE] == properties.pipeline_cache_uuid[..]
+}
+
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum PipelineCacheError {
+    #[error(transparent)]
+    VkErrorCode(#[from] ErrorCode),
+    #[error("Failed to write pipeline cache {0} with error: {1}")]
+    FileWriteError(String, String),
+}
+type Result<T> = std::result::Result<T, PipelineCacheError>;
+
+impl VkErrorSource for PipelineCacheError {
+    fn vk_error_code(&self) -> Option<ErrorCode> {
+        match self {
+            Self::VkErrorCode(e) => Some(*e),
+            Self::FileWriteError(..) => None,
+        }
+    }
+}
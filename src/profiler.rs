@@ -0,0 +1,513 @@
+use std::{collections::VecDeque, rc::Rc, time::Duration};
+
+use vulkanalia::{
+    vk::{self, DeviceV1_0, ErrorCode, HasBuilder, InstanceV1_0},
+    Instance,
+};
+
+use crate::{device::LogicalDevice, owned::Owned, VkErrorSource};
+
+/// Scopes a frame in flight can time, each takes two timestamps.
+pub const MAX_GPU_SCOPES: u32 = 32;
+/// Samples the rolling averages are taken over.
+pub const AVERAGE_WINDOW: usize = 120;
+
+/// Converts the timestamps written on a queue family to durations.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimestampClock {
+    /// Nanoseconds per tick.
+    period: f64,
+    mask: u64,
+}
+
+impl TimestampClock {
+    /// `None` if queues of `family` can't write timestamps.
+    pub unsafe fn get(
+        instance: &Instance,
+        physical_device: vk::PhysicalDevice,
+        family: u32,
+    ) -> Option<Self> {
+        let period = instance
+            .get_physical_device_properties(physical_device)
+            .limits
+            .timestamp_period;
+        let valid_bits = instance
+            .get_physical_device_queue_family_properties(
+                physical_device,
+            )
+            .get(family as usize)?
+            .timestamp_valid_bits;
+
+        (valid_bits > 0 && period > 0.0).then(|| Self {
+            period: period as f64,
+            mask: u64::MAX >> (64 - valid_bits.min(64)),
+        })
+    }
+
+    pub fn duration(&self, begin: u64, end: u64) -> Duration {
+        let ticks = end.wrapping_sub(begin) & self.mask;
+        Duration::from_nanos((ticks as f64 * self.period) as u64)
+    }
+}
+
+/// Returned by `GpuProfiler::begin`, ends the scope.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GpuScope(Option<u32>);
+
+/// What the GPU processed in the main pass of a frame.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PipelineStatistics {
+    pub input_vertices: u64,
+    pub vertex_shader_invocations: u64,
+    /// Primitives that reached the clipping stage.
+    pub clipping_primitives: u64,
+    pub fragment_shader_invocations: u64,
+}
+
+// Results are written in bit order, which matches the fields above.
+const PIPELINE_STATISTICS: vk::QueryPipelineStatisticFlags =
+    vk::QueryPipelineStatisticFlags::from_bits_truncate(
+        vk::QueryPipelineStatisticFlags::INPUT_ASSEMBLY_VERTICES.bits()
+            | vk::QueryPipelineStatisticFlags::VERTEX_SHADER_INVOCATIONS
+                .bits()
+            | vk::QueryPipelineStatisticFlags::CLIPPING_PRIMITIVES.bits()
+            | vk::QueryPipelineStatisticFlags::FRAGMENT_SHADER_INVOCATIONS
+                .bits(),
+    );
+
+/// Counted on the CPU while recording a frame.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DrawCounters {
+    pub draw_calls: u32,
+    /// Assuming triangle lists.
+    pub triangles: u64,
+    pub pipeline_binds: u32,
+    pub descriptor_set_binds: u32,
+}
+
+/// See `App::stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FrameStats {
+    /// Of the last recorded frame.
+    pub draws: DrawCounters,
+    /// Of the last frame the GPU finished, `None` unless enabled.
+    pub pipeline: Option<PipelineStatistics>,
+}
+
+/// The queries written by one frame in flight.
+#[derive(Debug)]
+struct FrameQueries {
+    timestamps: Option<Owned<vk::QueryPool>>,
+    /// Names and first queries of the scopes begun this frame.
+    scopes: Vec<(&'static str, u32)>,
+    statistics: Option<Owned<vk::QueryPool>>,
+    statistics_begun: bool,
+}
+
+/// Times regions of the recorded commands with timestamp queries and
+/// optionally counts what the main pass processed with a pipeline
+/// statistics query, with pools per frame in flight. A frame's
+/// queries are read back once its fence is signaled, so nothing
+/// waits for the GPU.
+#[derive(Debug)]
+pub struct GpuProfiler {
+    device: Rc<LogicalDevice>,
+    clock: Option<TimestampClock>,
+    statistics_supported: bool,
+    statistics_enabled: bool,
+    frames: Vec<FrameQueries>,
+    frame: usize,
+    averages: Vec<RollingAverage>,
+    statistics: Option<PipelineStatistics>,
+}
+
+impl GpuProfiler {
+    /// Reads back the queries of `frame`'s previous use and makes it
+    /// the frame queries are recorded for. The frame's fence has to
+    /// be signaled.
+    pub unsafe fn begin_frame(&mut self, frame: usize) -> Result<()> {
+        self.frame = frame;
+        self.read_timestamps()?;
+        self.read_statistics()
+    }
+
+    unsafe fn read_timestamps(&mut self) -> Result<()> {
+        let queries = &mut self.frames[self.frame];
+        let (Some(clock), Some(pool)) =
+            (self.clock, &queries.timestamps)
+        else {
+            return Ok(());
+        };
+        let count = queries.scopes.len() as u32 * 2;
+        if count == 0 {
+            return Ok(());
+        }
+
+        // A value and an availability word per query.
+        let mut data = vec![0u8; count as usize * 16];
+        self.device.get_query_pool_results(
+            pool.handle(),
+            0,
+            count,
+            &mut data,
+            16,
+            vk::QueryResultFlags::_64
+                | vk::QueryResultFlags::WITH_AVAILABILITY,
+        )?;
+        let word = |i: usize| read_word(&data, i);
+
+        let mut totals: Vec<(&'static str, Duration)> = vec![];
+        for (name, query) in queries.scopes.drain(..) {
+            let (begin, end) =
+                (query as usize * 2, query as usize * 2 + 2);
+            if word(begin + 1) == 0 || word(end + 1) == 0 {
+                continue;
+            }
+            let duration = clock.duration(word(begin), word(end));
+            match totals.iter_mut().find(|(n, _)| *n == name) {
+                Some((_, total)) => *total += duration,
+                None => totals.push((name, duration)),
+            }
+        }
+        for (name, duration) in totals {
+            self.record(name, duration);
+        }
+
+        Ok(())
+    }
+
+    unsafe fn read_statistics(&mut self) -> Result<()> {
+        let queries = &mut self.frames[self.frame];
+        let Some(pool) = &queries.statistics else {
+            return Ok(());
+        };
+        if !std::mem::take(&mut queries.statistics_begun) {
+            return Ok(());
+        }
+
+        // Four counters and the availability word.
+        let mut data = [0u8; 40];
+        self.device.get_query_pool_results(
+            pool.handle(),
+            0,
+            1,
+            &mut data,
+            40,
+            vk::QueryResultFlags::_64
+                | vk::QueryResultFlags::WITH_AVAILABILITY,
+        )?;
+        let word = |i: usize| read_word(&data, i);
+        if word(4) != 0 {
+            self.statistics = Some(PipelineStatistics {
+                input_vertices: word(0),
+                vertex_shader_invocations: word(1),
+                clipping_primitives: word(2),
+                fragment_shader_invocations: word(3),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Resets the current frame's queries, recorded at the start of
+    /// its command buffer before any scope.
+    pub unsafe fn reset(
+        &mut self,
+        command_buffer: vk::CommandBuffer,
+    ) {
+        let queries = &mut self.frames[self.frame];
+        queries.scopes.clear();
+        queries.statistics_begun = false;
+        if let Some(pool) = &queries.timestamps {
+            self.device.cmd_reset_query_pool(
+                command_buffer,
+                pool.handle(),
+                0,
+                MAX_GPU_SCOPES * 2,
+            );
+        }
+        if let Some(pool) = &queries.statistics {
+            if self.statistics_enabled {
+                self.device.cmd_reset_query_pool(
+                    command_buffer,
+                    pool.handle(),
+                    0,
+                    1,
+                );
+            }
+        }
+    }
+
+    /// Starts timing the commands recorded after this into
+    /// `command_buffer`, which has to be submitted with the frame.
+    pub unsafe fn begin(
+        &mut self,
+        command_buffer: vk::CommandBuffer,
+        name: &'static str,
+    ) -> GpuScope {
+        let queries = &mut self.frames[self.frame];
+        let Some(pool) = &queries.timestamps else {
+            return GpuScope(None);
+        };
+        if queries.scopes.len() as u32 == MAX_GPU_SCOPES {
+            log::debug!("Out of GPU timer scopes for `{}`.", name);
+            return GpuScope(None);
+        }
+        let query = queries.scopes.len() as u32 * 2;
+        queries.scopes.push((name, query));
+        self.device.cmd_write_timestamp(
+            command_buffer,
+            vk::PipelineStageFlags::TOP_OF_PIPE,
+            pool.handle(),
+            query,
+        );
+
+        GpuScope(Some(query))
+    }
+
+    pub unsafe fn end(
+        &mut self,
+        command_buffer: vk::CommandBuffer,
+        scope: GpuScope,
+    ) {
+        let (Some(query), Some(pool)) =
+            (scope.0, &self.frames[self.frame].timestamps)
+        else {
+            return;
+        };
+        self.device.cmd_write_timestamp(
+            command_buffer,
+            vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+            pool.handle(),
+            query + 1,
+        );
+    }
+
+    /// Starts counting what the GPU processes, once per frame and
+    /// outside of a render pass. Does nothing unless enabled.
+    pub unsafe fn begin_statistics(
+        &mut self,
+        command_buffer: vk::CommandBuffer,
+    ) {
+        let queries = &mut self.frames[self.frame];
+        let Some(pool) = &queries.statistics else {
+            return;
+        };
+        if !self.statistics_enabled || queries.statistics_begun {
+            return;
+        }
+        queries.statistics_begun = true;
+        self.device.cmd_begin_query(
+            command_buffer,
+            pool.handle(),
+            0,
+            vk::QueryControlFlags::empty(),
+        );
+    }
+
+    pub unsafe fn end_statistics(
+        &mut self,
+        command_buffer: vk::CommandBuffer,
+    ) {
+        let queries = &self.frames[self.frame];
+        if let (Some(pool), true) =
+            (&queries.statistics, queries.statistics_begun)
+        {
+            self.device.cmd_end_query(
+                command_buffer,
+                pool.handle(),
+                0,
+            );
+        }
+    }
+
+    /// Turns the pipeline statistics query on or off from the next
+    /// recorded frame, they cost some GPU time.
+    pub fn set_statistics(&mut self, enabled: bool) {
+        if enabled && !self.statistics_supported {
+            log::warn!("Pipeline statistics queries not supported.");
+        }
+        self.statistics_enabled =
+            enabled && self.statistics_supported;
+        if !self.statistics_enabled {
+            self.statistics = None;
+        }
+    }
+
+    /// Of the last frame the GPU finished with statistics enabled.
+    pub fn statistics(&self) -> Option<PipelineStatistics> {
+        self.statistics
+    }
+
+    /// Recreates the per frame queries, the averages are kept.
+    pub unsafe fn set_frames_in_flight(
+        &mut self,
+        count: usize,
+    ) -> Result<()> {
+        self.frame = 0;
+        self.frames = (0..count)
+            .map(|i| {
+                let timestamps = match self.clock {
+                    Some(_) => Some(
+                        create_timestamp_pool(
+                            &self.device,
+                            MAX_GPU_SCOPES * 2,
+                        )?
+                        .named(&format!("Timestamps {}", i)),
+                    ),
+                    None => None,
+                };
+                let statistics = match self.statistics_supported {
+                    true => Some(
+                        create_statistics_pool(&self.device)?
+                            .named(&format!("Statistics {}", i)),
+                    ),
+                    false => None,
+                };
+                Ok(FrameQueries {
+                    timestamps,
+                    scopes: vec![],
+                    statistics,
+                    statistics_begun: false,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(())
+    }
+
+    /// Adds a duration measured elsewhere, e.g. by the uploader.
+    pub fn record(&mut self, name: &'static str, duration: Duration) {
+        let average = match self
+            .averages
+            .iter_mut()
+            .position(|a| a.name == name)
+        {
+            Some(i) => &mut self.averages[i],
+            None => {
+                self.averages.push(RollingAverage::new(name));
+                self.averages.last_mut().unwrap()
+            }
+        };
+        average.push(duration);
+    }
+
+    /// The rolling average of every scope, in the order they were
+    /// first seen.
+    pub fn averages(&self) -> &[RollingAverage] {
+        &self.averages
+    }
+}
+
+fn read_word(data: &[u8], i: usize) -> u64 {
+    u64::from_ne_bytes(data[i * 8..i * 8 + 8].try_into().unwrap())
+}
+
+/// The average of the last `AVERAGE_WINDOW` samples of a scope.
+#[derive(Debug, Clone)]
+pub struct RollingAverage {
+    name: &'static str,
+    samples: VecDeque<Duration>,
+    sum: Duration,
+}
+
+impl RollingAverage {
+    fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            samples: VecDeque::with_capacity(AVERAGE_WINDOW),
+            sum: Duration::ZERO,
+        }
+    }
+
+    fn push(&mut self, sample: Duration) {
+        if self.samples.len() == AVERAGE_WINDOW {
+            self.sum -= self.samples.pop_front().unwrap();
+        }
+        self.samples.push_back(sample);
+        self.sum += sample;
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn average(&self) -> Duration {
+        self.sum / self.samples.len().max(1) as u32
+    }
+
+    pub fn last(&self) -> Duration {
+        self.samples.back().copied().unwrap_or_default()
+    }
+}
+
+/// A query pool of `count` timestamps.
+pub unsafe fn create_timestamp_pool(
+    device: &Rc<LogicalDevice>,
+    count: u32,
+) -> Result<Owned<vk::QueryPool>> {
+    let info = vk::QueryPoolCreateInfo::builder()
+        .query_type(vk::QueryType::TIMESTAMP)
+        .query_count(count);
+
+    Ok(Owned::new(device, device.create_query_pool(&info, None)?))
+}
+
+/// A query pool with a single `PIPELINE_STATISTICS` query.
+unsafe fn create_statistics_pool(
+    device: &Rc<LogicalDevice>,
+) -> Result<Owned<vk::QueryPool>> {
+    let info = vk::QueryPoolCreateInfo::builder()
+        .query_type(vk::QueryType::PIPELINE_STATISTICS)
+        .query_count(1)
+        .pipeline_statistics(PIPELINE_STATISTICS);
+
+    Ok(Owned::new(device, device.create_query_pool(&info, None)?))
+}
+
+pub unsafe fn create_gpu_profiler(
+    instance: &Instance,
+    device: &Rc<LogicalDevice>,
+    physical_device: vk::PhysicalDevice,
+    graphics_family: u32,
+    frames_in_flight: usize,
+    statistics: bool,
+) -> Result<GpuProfiler> {
+    let clock = TimestampClock::get(
+        instance,
+        physical_device,
+        graphics_family,
+    );
+    if clock.is_none() {
+        log::warn!("Timestamps not supported, GPU timings disabled.");
+    }
+    let mut profiler = GpuProfiler {
+        device: device.clone(),
+        clock,
+        statistics_supported: device.pipeline_statistics(),
+        statistics_enabled: false,
+        frames: vec![],
+        frame: 0,
+        averages: vec![],
+        statistics: None,
+    };
+    profiler.set_frames_in_flight(frames_in_flight)?;
+    profiler.set_statistics(statistics);
+
+    Ok(profiler)
+}
+
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum ProfilerError {
+    #[error(transparent)]
+    VkErrorCode(#[from] ErrorCode),
+}
+type Result<T> = std::result::Result<T, ProfilerError>;
+
+impl VkErrorSource for ProfilerError {
+    fn vk_error_code(&self) -> Option<ErrorCode> {
+        match self {
+            Self::VkErrorCode(e) => Some(*e),
+        }
+    }
+}
@@ -1,12 +1,22 @@
 use vulkanalia::{
-    vk::{self, ErrorCode, InstanceV1_0, KhrSurfaceExtension},
+    vk::{
+        self, ErrorCode, Handle, InstanceV1_0, KhrSurfaceExtension,
+    },
     Instance,
 };
 
+use crate::VkErrorSource;
+
 #[derive(Copy, Clone, Debug)]
 pub struct QueueFamilyIndices {
     pub graphics: u32,
     pub present: u32,
+    /// A family without graphics support used for uploads if the
+    /// device has one, the graphics family otherwise.
+    pub transfer: u32,
+    /// A compute family without graphics support (async compute) if
+    /// the device has one, a family supporting both otherwise.
+    pub compute: u32,
 }
 
 impl QueueFamilyIndices {
@@ -27,8 +37,14 @@ impl QueueFamilyIndices {
             })
             .map(|i| i as u32);
 
+        // Without a surface (headless rendering) nothing is presented,
+        // so the graphics family stands in for the present family.
         let mut present = None;
         for (index, _properties) in properties.iter().enumerate() {
+            if surface.is_null() {
+                present = graphics;
+                break;
+            }
             if instance.get_physical_device_surface_support_khr(
                 physical_device,
                 index as u32,
@@ -39,12 +55,70 @@ impl QueueFamilyIndices {
             }
         }
 
-        if let (Some(graphics), Some(present)) = (graphics, present) {
-            Ok(Self { graphics, present })
+        // Transfer only families are usually backed by a DMA engine,
+        // prefer those over async compute families.
+        let transfer = properties
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| {
+                p.queue_flags.contains(vk::QueueFlags::TRANSFER)
+                    && !p
+                        .queue_flags
+                        .contains(vk::QueueFlags::GRAPHICS)
+            })
+            .min_by_key(|(_, p)| {
+                p.queue_flags.contains(vk::QueueFlags::COMPUTE)
+            })
+            .map(|(i, _)| i as u32)
+            .or(graphics);
+
+        let compute = properties
+            .iter()
+            .position(|p| {
+                p.queue_flags.contains(vk::QueueFlags::COMPUTE)
+                    && !p
+                        .queue_flags
+                        .contains(vk::QueueFlags::GRAPHICS)
+            })
+            .or_else(|| {
+                properties.iter().position(|p| {
+                    p.queue_flags.contains(
+                        vk::QueueFlags::COMPUTE
+                            | vk::QueueFlags::GRAPHICS,
+                    )
+                })
+            })
+            .map(|i| i as u32);
+
+        if let (
+            Some(graphics),
+            Some(present),
+            Some(transfer),
+            Some(compute),
+        ) = (graphics, present, transfer, compute)
+        {
+            Ok(Self {
+                graphics,
+                present,
+                transfer,
+                compute,
+            })
         } else {
             Err(QueueError::SuitabilityError)
         }
     }
+
+    /// Whether uploads run on their own queue family, in which case
+    /// uploaded resources change ownership to the graphics family.
+    pub fn has_dedicated_transfer(&self) -> bool {
+        self.transfer != self.graphics
+    }
+
+    /// Whether compute work runs on its own queue family, in which
+    /// case its results change ownership to the graphics family.
+    pub fn has_dedicated_compute(&self) -> bool {
+        self.compute != self.graphics
+    }
 }
 
 #[derive(Debug, Clone, thiserror::Error)]
@@ -55,3 +129,12 @@ pub enum QueueError {
     SuitabilityError,
 }
 type Result<T> = std::result::Result<T, QueueError>;
+
+impl VkErrorSource for QueueError {
+    fn vk_error_code(&self) -> Option<ErrorCode> {
+        match self {
+            Self::VkErrorCode(e) => Some(*e),
+            Self::SuitabilityError => None,
+        }
+    }
+}
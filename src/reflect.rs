@@ -0,0 +1,551 @@
+use naga::{
+    front::spv, AddressSpace, ArraySize, Binding, ImageClass, Module,
+    ScalarKind, ShaderStage, TypeInner, VectorSize,
+};
+use vulkanalia::vk::{self, HasBuilder};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DescriptorBinding {
+    /// Name of the variable, e.g. `camera` for a uniform block
+    /// declared as `uniform Camera { .. } camera`. `None` without
+    /// debug names in the SPIR-V.
+    pub name: Option<String>,
+    pub set: u32,
+    pub binding: u32,
+    pub descriptor_type: vk::DescriptorType,
+    pub count: u32,
+    pub stages: vk::ShaderStageFlags,
+    /// Block size of uniform and storage buffers, in bytes.
+    pub size: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VertexInput {
+    pub location: u32,
+    pub format: vk::Format,
+}
+
+/// The resources and vertex inputs one or more shader stages expect.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ShaderReflection {
+    /// Sorted by set and binding.
+    pub bindings: Vec<DescriptorBinding>,
+    pub push_constant_ranges: Vec<vk::PushConstantRange>,
+    /// Inputs of the vertex stage, sorted by location.
+    pub vertex_inputs: Vec<VertexInput>,
+    /// Local workgroup size of the compute stage.
+    pub workgroup_size: Option<[u32; 3]>,
+}
+
+impl ShaderReflection {
+    /// Reflects every stage in `bytecodes` and merges the results.
+    pub fn from_stages(bytecodes: &[&[u8]]) -> Result<Self> {
+        bytecodes.iter().try_fold(Self::default(), |r, b| {
+            r.merge(reflect_shader(b)?)
+        })
+    }
+
+    /// Combines the reflection of two stages, a binding used by both
+    /// has to agree on its type and count.
+    pub fn merge(mut self, other: Self) -> Result<Self> {
+        for b in other.bindings {
+            match self
+                .bindings
+                .iter_mut()
+                .find(|e| e.set == b.set && e.binding == b.binding)
+            {
+                Some(e)
+                    if e.descriptor_type == b.descriptor_type
+                        && e.count == b.count =>
+                {
+                    e.stages |= b.stages;
+                    e.size = e.size.max(b.size);
+                    if e.name.is_none() {
+                        e.name = b.name;
+                    }
+                }
+                Some(e) => {
+                    return Err(ReflectError::BindingMismatchError(format!(
+                        "set {} binding {} is {:?} x{} in {:?} but {:?} x{} in {:?}",
+                        b.set,
+                        b.binding,
+                        e.descriptor_type,
+                        e.count,
+                        e.stages,
+                        b.descriptor_type,
+                        b.count,
+                        b.stages,
+                    )))
+                }
+                None => self.bindings.push(b),
+            }
+        }
+        self.bindings.sort_by_key(|b| (b.set, b.binding));
+        self.push_constant_ranges.extend(other.push_constant_ranges);
+        self.vertex_inputs.extend(other.vertex_inputs);
+        self.vertex_inputs.sort_by_key(|i| i.location);
+        self.workgroup_size =
+            self.workgroup_size.or(other.workgroup_size);
+
+        Ok(self)
+    }
+
+    pub fn set_bindings(
+        &self,
+        set: u32,
+    ) -> impl Iterator<Item = &DescriptorBinding> {
+        self.bindings.iter().filter(move |b| b.set == set)
+    }
+
+    /// Pool sizes for allocating `sets` copies of descriptor set `set`.
+    pub fn pool_sizes(
+        &self,
+        set: u32,
+        sets: u32,
+    ) -> Vec<vk::DescriptorPoolSize> {
+        let mut sizes: Vec<vk::DescriptorPoolSize> = vec![];
+        for b in self.set_bindings(set) {
+            match sizes
+                .iter_mut()
+                .find(|s| s.type_ == b.descriptor_type)
+            {
+                Some(s) => s.descriptor_count += b.count * sets,
+                None => sizes.push(
+                    vk::DescriptorPoolSize::builder()
+                        .type_(b.descriptor_type)
+                        .descriptor_count(b.count * sets)
+                        .build(),
+                ),
+            }
+        }
+        sizes
+    }
+
+    /// Checks that `attributes` provide every input of the vertex
+    /// stage with a format of the same numeric type and component
+    /// count, e.g. `R8G8B8A8_UNORM` for a `vec4`. `vertex` names the
+    /// vertex type in the error.
+    pub fn validate_vertex_attributes(
+        &self,
+        vertex: &str,
+        attributes: &[vk::VertexInputAttributeDescription],
+    ) -> Result<()> {
+        for input in &self.vertex_inputs {
+            match attributes.iter().find(|a| a.location == input.location)
+            {
+                Some(a)
+                    if vertex_format_class(a.format).is_some()
+                        && vertex_format_class(a.format)
+                            == vertex_format_class(input.format) => {}
+                Some(a) => {
+                    return Err(ReflectError::VertexInputMismatchError(format!(
+                        "{} provides location {} as {:?}, the shader expects {:?}",
+                        vertex, input.location, a.format, input.format
+                    )))
+                }
+                None => {
+                    return Err(ReflectError::VertexInputMismatchError(format!(
+                        "{} has no attribute at location {}, the shader expects {:?}",
+                        vertex, input.location, input.format
+                    )))
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub fn reflect_shader(bytecode: &[u8]) -> Result<ShaderReflection> {
+    let module =
+        spv::parse_u8_slice(bytecode, &spv::Options::default())
+            .map_err(|e| ReflectError::ParseError(e.to_string()))?;
+
+    let mut reflection = ShaderReflection::default();
+    for entry_point in &module.entry_points {
+        let stage = stage_flags(entry_point.stage);
+        for (_, global) in module.global_variables.iter() {
+            match (global.space, global.binding.as_ref()) {
+                (AddressSpace::PushConstant, _) => {
+                    let size = module.types[global.ty]
+                        .inner
+                        .size(module.to_ctx());
+                    reflection.push_constant_ranges.push(
+                        vk::PushConstantRange::builder()
+                            .stage_flags(stage)
+                            .offset(0)
+                            .size(size)
+                            .build(),
+                    );
+                }
+                (space, Some(binding)) => {
+                    let (descriptor_type, count) =
+                        descriptor_type(&module, space, global.ty)?;
+                    let size = matches!(
+                        space,
+                        AddressSpace::Uniform
+                            | AddressSpace::Storage { .. }
+                    )
+                    .then(|| {
+                        module.types[global.ty]
+                            .inner
+                            .size(module.to_ctx())
+                            as u64
+                    });
+                    reflection =
+                        reflection.merge(ShaderReflection {
+                            bindings: vec![DescriptorBinding {
+                                name: global.name.clone(),
+                                set: binding.group,
+                                binding: binding.binding,
+                                descriptor_type,
+                                count,
+                                stages: stage,
+                                size,
+                            }],
+                            ..Default::default()
+                        })?;
+                }
+                _ => {}
+            }
+        }
+
+        if entry_point.stage == ShaderStage::Vertex {
+            for argument in &entry_point.function.arguments {
+                match &module.types[argument.ty].inner {
+                    TypeInner::Struct { members, .. } => {
+                        for m in members {
+                            push_input(
+                                &module,
+                                &mut reflection,
+                                &m.binding,
+                                m.ty,
+                            )?
+                        }
+                    }
+                    _ => push_input(
+                        &module,
+                        &mut reflection,
+                        &argument.binding,
+                        argument.ty,
+                    )?,
+                }
+            }
+            reflection.vertex_inputs.sort_by_key(|i| i.location);
+        }
+
+        if entry_point.stage == ShaderStage::Compute {
+            reflection.workgroup_size =
+                Some(entry_point.workgroup_size);
+        }
+    }
+
+    Ok(reflection)
+}
+
+fn push_input(
+    module: &Module,
+    reflection: &mut ShaderReflection,
+    binding: &Option<Binding>,
+    ty: naga::Handle<naga::Type>,
+) -> Result<()> {
+    if let Some(Binding::Location { location, .. }) = binding {
+        reflection.vertex_inputs.push(VertexInput {
+            location: *location,
+            format: vertex_format(&module.types[ty].inner)?,
+        });
+    }
+
+    Ok(())
+}
+
+fn stage_flags(stage: ShaderStage) -> vk::ShaderStageFlags {
+    match stage {
+        ShaderStage::Vertex => vk::ShaderStageFlags::VERTEX,
+        ShaderStage::Fragment => vk::ShaderStageFlags::FRAGMENT,
+        ShaderStage::Compute => vk::ShaderStageFlags::COMPUTE,
+        ShaderStage::Task => vk::ShaderStageFlags::TASK_EXT,
+        ShaderStage::Mesh => vk::ShaderStageFlags::MESH_EXT,
+    }
+}
+
+fn descriptor_type(
+    module: &Module,
+    space: AddressSpace,
+    ty: naga::Handle<naga::Type>,
+) -> Result<(vk::DescriptorType, u32)> {
+    let (inner, count) = match &module.types[ty].inner {
+        TypeInner::BindingArray {
+            base,
+            size: ArraySize::Constant(size),
+        } => (&module.types[*base].inner, size.get()),
+        inner => (inner, 1),
+    };
+    let descriptor_type = match (space, inner) {
+        (AddressSpace::Uniform, _) => {
+            vk::DescriptorType::UNIFORM_BUFFER
+        }
+        (AddressSpace::Storage { .. }, _) => {
+            vk::DescriptorType::STORAGE_BUFFER
+        }
+        (
+            AddressSpace::Handle,
+            TypeInner::Image {
+                class: ImageClass::Storage { .. },
+                ..
+            },
+        ) => vk::DescriptorType::STORAGE_IMAGE,
+        (AddressSpace::Handle, TypeInner::Image { .. }) => {
+            vk::DescriptorType::SAMPLED_IMAGE
+        }
+        (AddressSpace::Handle, TypeInner::Sampler { .. }) => {
+            vk::DescriptorType::SAMPLER
+        }
+        _ => {
+            return Err(ReflectError::UnsupportedTypeError(format!(
+                "{:?} in {:?}",
+                inner, space
+            )))
+        }
+    };
+
+    Ok((descriptor_type, count))
+}
+
+fn vertex_format(inner: &TypeInner) -> Result<vk::Format> {
+    let (size, scalar) = match inner {
+        TypeInner::Scalar(scalar) => (1, scalar),
+        TypeInner::Vector { size, scalar } => (
+            match size {
+                VectorSize::Bi => 2,
+                VectorSize::Tri => 3,
+                VectorSize::Quad => 4,
+            },
+            scalar,
+        ),
+        _ => {
+            return Err(ReflectError::UnsupportedTypeError(format!(
+                "vertex input {:?}",
+                inner
+            )))
+        }
+    };
+
+    let formats = match (scalar.kind, scalar.width) {
+        (ScalarKind::Float, 4) => [
+            vk::Format::R32_SFLOAT,
+            vk::Format::R32G32_SFLOAT,
+            vk::Format::R32G32B32_SFLOAT,
+            vk::Format::R32G32B32A32_SFLOAT,
+        ],
+        (ScalarKind::Sint, 4) => [
+            vk::Format::R32_SINT,
+            vk::Format::R32G32_SINT,
+            vk::Format::R32G32B32_SINT,
+            vk::Format::R32G32B32A32_SINT,
+        ],
+        (ScalarKind::Uint, 4) => [
+            vk::Format::R32_UINT,
+            vk::Format::R32G32_UINT,
+            vk::Format::R32G32B32_UINT,
+            vk::Format::R32G32B32A32_UINT,
+        ],
+        _ => {
+            return Err(ReflectError::UnsupportedTypeError(format!(
+                "vertex input {:?}",
+                inner
+            )))
+        }
+    };
+
+    Ok(formats[size - 1])
+}
+
+/// The numeric type a vertex input reads `format` as and its
+/// component count, `None` for formats not supported here.
+fn vertex_format_class(
+    format: vk::Format,
+) -> Option<(ScalarKind, u32)> {
+    use vk::Format as F;
+    use ScalarKind::{Float, Sint, Uint};
+
+    Some(match format {
+        F::R8_UNORM
+        | F::R8_SNORM
+        | F::R8_USCALED
+        | F::R8_SSCALED
+        | F::R8_SRGB
+        | F::R16_UNORM
+        | F::R16_SNORM
+        | F::R16_USCALED
+        | F::R16_SSCALED
+        | F::R16_SFLOAT
+        | F::R32_SFLOAT => (Float, 1),
+        F::R8G8_UNORM
+        | F::R8G8_SNORM
+        | F::R8G8_USCALED
+        | F::R8G8_SSCALED
+        | F::R8G8_SRGB
+        | F::R16G16_UNORM
+        | F::R16G16_SNORM
+        | F::R16G16_USCALED
+        | F::R16G16_SSCALED
+        | F::R16G16_SFLOAT
+        | F::R32G32_SFLOAT => (Float, 2),
+        F::R8G8B8_UNORM
+        | F::R8G8B8_SNORM
+        | F::R8G8B8_USCALED
+        | F::R8G8B8_SSCALED
+        | F::R8G8B8_SRGB
+        | F::B8G8R8_UNORM
+        | F::B8G8R8_SNORM
+        | F::B8G8R8_SRGB
+        | F::R16G16B16_UNORM
+        | F::R16G16B16_SNORM
+        | F::R16G16B16_USCALED
+        | F::R16G16B16_SSCALED
+        | F::R16G16B16_SFLOAT
+        | F::R32G32B32_SFLOAT
+        | F::B10G11R11_UFLOAT_PACK32 => (Float, 3),
+        F::R8G8B8A8_UNORM
+        | F::R8G8B8A8_SNORM
+        | F::R8G8B8A8_USCALED
+        | F::R8G8B8A8_SSCALED
+        | F::R8G8B8A8_SRGB
+        | F::B8G8R8A8_UNORM
+        | F::B8G8R8A8_SNORM
+        | F::B8G8R8A8_SRGB
+        | F::A8B8G8R8_UNORM_PACK32
+        | F::A8B8G8R8_SNORM_PACK32
+        | F::A8B8G8R8_SRGB_PACK32
+        | F::A2R10G10B10_UNORM_PACK32
+        | F::A2R10G10B10_SNORM_PACK32
+        | F::A2B10G10R10_UNORM_PACK32
+        | F::A2B10G10R10_SNORM_PACK32
+        | F::R16G16B16A16_UNORM
+        | F::R16G16B16A16_SNORM
+        | F::R16G16B16A16_USCALED
+        | F::R16G16B16A16_SSCALED
+        | F::R16G16B16A16_SFLOAT
+        | F::R32G32B32A32_SFLOAT => (Float, 4),
+        F::R8_UINT | F::R16_UINT | F::R32_UINT => (Uint, 1),
+        F::R8G8_UINT | F::R16G16_UINT | F::R32G32_UINT => (Uint, 2),
+        F::R8G8B8_UINT | F::R16G16B16_UINT | F::R32G32B32_UINT => {
+            (Uint, 3)
+        }
+        F::R8G8B8A8_UINT
+        | F::B8G8R8A8_UINT
+        | F::A8B8G8R8_UINT_PACK32
+        | F::A2B10G10R10_UINT_PACK32
+        | F::R16G16B16A16_UINT
+        | F::R32G32B32A32_UINT => (Uint, 4),
+        F::R8_SINT | F::R16_SINT | F::R32_SINT => (Sint, 1),
+        F::R8G8_SINT | F::R16G16_SINT | F::R32G32_SINT => (Sint, 2),
+        F::R8G8B8_SINT | F::R16G16B16_SINT | F::R32G32B32_SINT => {
+            (Sint, 3)
+        }
+        F::R8G8B8A8_SINT
+        | F::B8G8R8A8_SINT
+        | F::A8B8G8R8_SINT_PACK32
+        | F::A2B10G10R10_SINT_PACK32
+        | F::R16G16B16A16_SINT
+        | F::R32G32B32A32_SINT => (Sint, 4),
+        _ => return None,
+    })
+}
+
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum ReflectError {
+    #[error("Failed to parse SPIR-V: {0}")]
+    ParseError(String),
+    #[error("Unsupported shader resource: {0}")]
+    UnsupportedTypeError(String),
+    #[error("Descriptor binding mismatch between stages: {0}")]
+    BindingMismatchError(String),
+    #[error("Vertex layout doesn't match the shader: {0}")]
+    VertexInputMismatchError(String),
+    #[error("Descriptors don't match the shader: {0}")]
+    DescriptorMismatchError(String),
+}
+type Result<T> = std::result::Result<T, ReflectError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reflection(format: vk::Format) -> ShaderReflection {
+        ShaderReflection {
+            vertex_inputs: vec![VertexInput {
+                location: 0,
+                format,
+            }],
+            ..Default::default()
+        }
+    }
+
+    fn attribute(
+        format: vk::Format,
+    ) -> vk::VertexInputAttributeDescription {
+        vk::VertexInputAttributeDescription::builder()
+            .location(0)
+            .format(format)
+            .build()
+    }
+
+    #[test]
+    fn normalized_attributes_feed_float_inputs() {
+        let vec4 = reflection(vk::Format::R32G32B32A32_SFLOAT);
+        for format in [
+            vk::Format::R32G32B32A32_SFLOAT,
+            vk::Format::R8G8B8A8_UNORM,
+            vk::Format::R8G8B8A8_SNORM,
+            vk::Format::R16G16B16A16_SFLOAT,
+        ] {
+            assert!(vec4
+                .validate_vertex_attributes(
+                    "Vertex",
+                    &[attribute(format)]
+                )
+                .is_ok());
+        }
+    }
+
+    #[test]
+    fn numeric_type_and_components_have_to_match() {
+        let vec4 = reflection(vk::Format::R32G32B32A32_SFLOAT);
+        for format in [
+            vk::Format::R8G8B8A8_UINT,
+            vk::Format::R32G32B32_SFLOAT,
+            vk::Format::D32_SFLOAT,
+        ] {
+            assert!(vec4
+                .validate_vertex_attributes(
+                    "Vertex",
+                    &[attribute(format)]
+                )
+                .is_err());
+        }
+
+        let uvec2 = reflection(vk::Format::R32G32_UINT);
+        assert!(uvec2
+            .validate_vertex_attributes(
+                "Vertex",
+                &[attribute(vk::Format::R16G16_UINT)]
+            )
+            .is_ok());
+        assert!(uvec2
+            .validate_vertex_attributes(
+                "Vertex",
+                &[attribute(vk::Format::R16G16_SINT)]
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn missing_attributes_are_rejected() {
+        let vec4 = reflection(vk::Format::R32G32B32A32_SFLOAT);
+        assert!(vec4
+            .validate_vertex_attributes("Vertex", &[])
+            .is_err());
+    }
+}
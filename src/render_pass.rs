@@ -1,21 +1,34 @@
+use std::rc::Rc;
+
 use vulkanalia::{
     vk::{self, DeviceV1_0, ErrorCode, HasBuilder, InstanceV1_0},
-    Device, Instance,
+    Instance,
 };
 
 use crate::{
-    image::{create_image, ImageError},
-    image_view::{create_image_view, ImageViewError},
+    allocator::Allocator,
+    device::LogicalDevice,
+    image::{create_image, Image, ImageError},
+    image_view::ImageViewError,
+    owned::Owned,
+    VkErrorSource,
 };
 
+pub type RenderPass = Owned<vk::RenderPass>;
+
+/// `final_layout` is the layout the resolved image is left in,
+/// `PRESENT_SRC_KHR` for a swapchain or `TRANSFER_SRC_OPTIMAL` when
+/// the image is read back for headless rendering. With one sample
+/// the swapchain image is rendered to directly, without a resolve
+/// attachment, see `create_framebuffers`.
 pub unsafe fn create_render_pass(
     instance: &Instance,
-    device: &Device,
+    device: &Rc<LogicalDevice>,
     physical_device: vk::PhysicalDevice,
     swapchain_format: vk::Format,
     msaa_samples: vk::SampleCountFlags,
-    render_pass: &mut vk::RenderPass,
-) -> Result<()> {
+    final_layout: vk::ImageLayout,
+) -> Result<RenderPass> {
     let dependency = vk::SubpassDependency::builder()
         .src_subpass(vk::SUBPASS_EXTERNAL)
         .dst_subpass(0)
@@ -33,6 +46,7 @@ pub unsafe fn create_render_pass(
                 | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
         );
 
+    let multisampled = msaa_samples != vk::SampleCountFlags::_1;
     let color_attachment = vk::AttachmentDescription::builder()
         .format(swapchain_format)
         .samples(msaa_samples)
@@ -41,7 +55,11 @@ pub unsafe fn create_render_pass(
         .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
         .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
         .initial_layout(vk::ImageLayout::UNDEFINED)
-        .final_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL);
+        .final_layout(if multisampled {
+            vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL
+        } else {
+            final_layout
+        });
 
     let color_attachment_ref = vk::AttachmentReference::builder()
         .attachment(0)
@@ -56,7 +74,7 @@ pub unsafe fn create_render_pass(
             .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
             .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
             .initial_layout(vk::ImageLayout::UNDEFINED)
-            .final_layout(vk::ImageLayout::PRESENT_SRC_KHR);
+            .final_layout(final_layout);
 
     let color_resolve_attachment_ref =
         vk::AttachmentReference::builder()
@@ -83,27 +101,27 @@ pub unsafe fn create_render_pass(
             vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
         );
 
-    let subpass = vk::SubpassDescription::builder()
+    let mut subpass = vk::SubpassDescription::builder()
         .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
         .color_attachments(color_attachments)
-        .depth_stencil_attachment(&depth_stencil_attachment_ref)
-        .resolve_attachments(resolve_attachments);
+        .depth_stencil_attachment(&depth_stencil_attachment_ref);
+    let mut attachments =
+        vec![color_attachment, depth_stencil_attachment];
+    if multisampled {
+        subpass = subpass.resolve_attachments(resolve_attachments);
+        attachments.push(color_resolve_attachment);
+    }
 
-    let attachments = &[
-        color_attachment,
-        depth_stencil_attachment,
-        color_resolve_attachment,
-    ];
     let subpasses = &[subpass];
     let dependencies = &[dependency];
     let info = vk::RenderPassCreateInfo::builder()
-        .attachments(attachments)
+        .attachments(&attachments)
         .subpasses(subpasses)
         .dependencies(dependencies);
 
-    *render_pass = device.create_render_pass(&info, None)?;
+    let render_pass = device.create_render_pass(&info, None)?;
 
-    Ok(())
+    Ok(Owned::new(device, render_pass).named("Render pass"))
 }
 
 pub unsafe fn get_depth_format(
@@ -125,78 +143,6 @@ pub unsafe fn get_depth_format(
     )
 }
 
-pub unsafe fn create_render_pass_2d(
-    instance: &Instance,
-    device: &Device,
-    swapchain_format: vk::Format,
-    msaa_samples: vk::SampleCountFlags,
-    render_pass: &mut vk::RenderPass,
-) -> Result<()> {
-    let dependency = vk::SubpassDependency::builder()
-        .src_subpass(vk::SUBPASS_EXTERNAL)
-        .dst_subpass(0)
-        .src_stage_mask(
-            vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
-                | vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS,
-        )
-        .src_access_mask(vk::AccessFlags::empty())
-        .dst_stage_mask(
-            vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
-                | vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS,
-        )
-        .dst_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE);
-
-    let color_attachment = vk::AttachmentDescription::builder()
-        .format(swapchain_format)
-        .samples(msaa_samples)
-        .load_op(vk::AttachmentLoadOp::CLEAR)
-        .store_op(vk::AttachmentStoreOp::STORE)
-        .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
-        .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
-        .initial_layout(vk::ImageLayout::UNDEFINED)
-        .final_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL);
-
-    let color_attachment_ref = vk::AttachmentReference::builder()
-        .attachment(0)
-        .layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL);
-
-    let color_resolve_attachment =
-        vk::AttachmentDescription::builder()
-            .format(swapchain_format)
-            .samples(vk::SampleCountFlags::_1)
-            .load_op(vk::AttachmentLoadOp::DONT_CARE)
-            .store_op(vk::AttachmentStoreOp::STORE)
-            .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
-            .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
-            .initial_layout(vk::ImageLayout::UNDEFINED)
-            .final_layout(vk::ImageLayout::PRESENT_SRC_KHR);
-
-    let color_resolve_attachment_ref =
-        vk::AttachmentReference::builder()
-            .attachment(1)
-            .layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL);
-
-    let color_attachments = &[color_attachment_ref];
-    let resolve_attachments = &[color_resolve_attachment_ref];
-
-    let subpass = vk::SubpassDescription::builder()
-        .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
-        .color_attachments(color_attachments)
-        .resolve_attachments(resolve_attachments);
-
-    let attachments = &[color_attachment, color_resolve_attachment];
-    let subpasses = &[subpass];
-    let dependencies = &[dependency];
-    let info = vk::RenderPassCreateInfo::builder()
-        .attachments(attachments)
-        .subpasses(subpasses)
-        .dependencies(dependencies);
-
-    *render_pass = device.create_render_pass(&info, None)?;
-
-    Ok(())
-}
-
 pub unsafe fn get_supported_format(
     instance: &Instance,
     physical_device: vk::PhysicalDevice,
@@ -228,19 +174,14 @@ pub unsafe fn get_supported_format(
 
 pub unsafe fn create_depth_objects(
     instance: &Instance,
-    device: &Device,
+    allocator: &Rc<Allocator>,
     physical_device: vk::PhysicalDevice,
     swapchain_extent: vk::Extent2D,
     msaa_samples: vk::SampleCountFlags,
-    depth_image: &mut vk::Image,
-    depth_image_memory: &mut vk::DeviceMemory,
-    depth_image_view: &mut vk::ImageView,
-) -> Result<()> {
+) -> Result<Image> {
     let format = get_depth_format(instance, physical_device)?;
-    (*depth_image, *depth_image_memory) = create_image(
-        instance,
-        device,
-        physical_device,
+    let mut depth_image = create_image(
+        allocator,
         swapchain_extent.width,
         swapchain_extent.height,
         1,
@@ -249,19 +190,14 @@ pub unsafe fn create_depth_objects(
         vk::ImageTiling::OPTIMAL,
         vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT,
         vk::MemoryPropertyFlags::DEVICE_LOCAL,
+        "Depth target",
     )?;
 
     // Image View
 
-    *depth_image_view = create_image_view(
-        device,
-        *depth_image,
-        format,
-        vk::ImageAspectFlags::DEPTH,
-        1,
-    )?;
+    depth_image.create_view(vk::ImageAspectFlags::DEPTH)?;
 
-    Ok(())
+    Ok(depth_image)
 }
 
 #[derive(Debug, Clone, thiserror::Error)]
@@ -276,3 +212,14 @@ pub enum RenderPassError {
     SupportError,
 }
 type Result<T> = std::result::Result<T, RenderPassError>;
+
+impl VkErrorSource for RenderPassError {
+    fn vk_error_code(&self) -> Option<ErrorCode> {
+        match self {
+            Self::VkErrorCode(e) => Some(*e),
+            Self::ImageViewError(e) => e.vk_error_code(),
+            Self::ImageError(e) => e.vk_error_code(),
+            Self::SupportError => None,
+        }
+    }
+}
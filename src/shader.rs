@@ -0,0 +1,207 @@
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+    sync::mpsc::{channel, Receiver},
+};
+
+use naga::{
+    back::spv,
+    front::glsl,
+    valid::{Capabilities, ValidationFlags, Validator},
+    ShaderStage, SourceLocation,
+};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+
+use crate::reflect::ReflectError;
+
+/// Directory hot reloaded GLSL sources are loaded from, relative to
+/// the working directory like the default model and texture.
+pub const SHADER_DIR: &str = "shaders";
+
+/// The sources in `SHADER_DIR` as they were when the crate was built,
+/// compiled unless hot reload watches the directory so the app runs
+/// from any working directory.
+const EMBEDDED_SHADERS: &[(&str, &str)] = &[
+    ("shader.vert", include_str!("../shaders/shader.vert")),
+    ("shader.frag", include_str!("../shaders/shader.frag")),
+];
+
+/// Compiles a GLSL shader to SPIR-V. The stage is taken from the
+/// extension (`.vert`, `.frag` or `.comp`).
+pub fn compile_shader(path: &Path) -> Result<Vec<u8>> {
+    let source = fs::read_to_string(path).map_err(|e| {
+        ShaderError::FileOpenError(
+            path.display().to_string(),
+            e.to_string(),
+        )
+    })?;
+
+    compile_shader_source(path, &source)
+}
+
+/// Compiles the embedded copy of `name` in `SHADER_DIR`.
+pub(crate) fn compile_embedded_shader(name: &str) -> Result<Vec<u8>> {
+    let (_, source) = EMBEDDED_SHADERS
+        .iter()
+        .find(|(n, _)| *n == name)
+        .ok_or_else(|| {
+            ShaderError::FileOpenError(
+                name.to_string(),
+                "not embedded in the crate".to_string(),
+            )
+        })?;
+
+    compile_shader_source(Path::new(name), source)
+}
+
+/// Compiles `source`, errors are reported against `path`, whose
+/// extension gives the stage.
+fn compile_shader_source(
+    path: &Path,
+    source: &str,
+) -> Result<Vec<u8>> {
+    let display = path.display().to_string();
+    let stage = match path.extension().and_then(|e| e.to_str()) {
+        Some("vert") => ShaderStage::Vertex,
+        Some("frag") => ShaderStage::Fragment,
+        Some("comp") => ShaderStage::Compute,
+        _ => return Err(ShaderError::UnsupportedStageError(display)),
+    };
+
+    let module = glsl::Frontend::default()
+        .parse(&glsl::Options::from(stage), source)
+        .map_err(|e| {
+            let messages = e
+                .errors
+                .iter()
+                .map(|e| {
+                    format_error(
+                        &display,
+                        e.location(source),
+                        &e.kind,
+                    )
+                })
+                .collect::<Vec<_>>();
+            ShaderError::CompileError(messages.join("\n"))
+        })?;
+    let info = Validator::new(
+        ValidationFlags::all(),
+        Capabilities::default() | Capabilities::PUSH_CONSTANT,
+    )
+    .validate(&module)
+    .map_err(|e| {
+        ShaderError::CompileError(format_error(
+            &display,
+            e.location(source),
+            e.as_inner(),
+        ))
+    })?;
+
+    // The sources are written for Vulkan, don't flip y. Debug names
+    // are kept, descriptors are matched to bindings by them.
+    let options = spv::Options {
+        flags: spv::WriterFlags::DEBUG
+            | spv::WriterFlags::LABEL_VARYINGS
+            | spv::WriterFlags::CLAMP_FRAG_DEPTH,
+        ..Default::default()
+    };
+    let words = spv::write_vec(&module, &info, &options, None)
+        .map_err(|e| {
+            ShaderError::CompileError(format!("{}: {}", display, e))
+        })?;
+
+    Ok(words.iter().flat_map(|w| w.to_ne_bytes()).collect())
+}
+
+fn format_error(
+    path: &str,
+    location: Option<SourceLocation>,
+    error: &dyn std::fmt::Display,
+) -> String {
+    match location {
+        Some(l) => format!(
+            "{}:{}:{}: {}",
+            path, l.line_number, l.line_position, error
+        ),
+        None => format!("{}: {}", path, error),
+    }
+}
+
+/// Watches a shader directory for changes, events are collected on a
+/// background thread and drained with `changed`.
+pub struct ShaderWatcher {
+    _watcher: RecommendedWatcher,
+    receiver: Receiver<PathBuf>,
+    dir: PathBuf,
+}
+
+impl ShaderWatcher {
+    pub fn new(dir: &Path) -> Result<Self> {
+        let (sender, receiver) = channel();
+        let mut watcher = notify::recommended_watcher(
+            move |event: notify::Result<notify::Event>| match event {
+                Ok(event)
+                    if event.kind.is_create()
+                        || event.kind.is_modify() =>
+                {
+                    for path in event.paths {
+                        let _ = sender.send(path);
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => log::warn!("Shader watcher error: {}", e),
+            },
+        )?;
+        // Editors often save by replacing the file, so watch the
+        // directory rather than the files.
+        watcher.watch(dir, RecursiveMode::NonRecursive)?;
+
+        Ok(Self {
+            _watcher: watcher,
+            receiver,
+            dir: dir.to_path_buf(),
+        })
+    }
+
+    /// The watched directory, shaders are compiled from it.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// File names of the shaders changed since the last call.
+    pub fn changed(&self) -> HashSet<String> {
+        self.receiver
+            .try_iter()
+            .filter_map(|p| {
+                p.file_name()
+                    .and_then(|n| n.to_str())
+                    .map(String::from)
+            })
+            .collect()
+    }
+}
+
+impl std::fmt::Debug for ShaderWatcher {
+    fn fmt(
+        &self,
+        f: &mut std::fmt::Formatter<'_>,
+    ) -> std::fmt::Result {
+        f.debug_struct("ShaderWatcher").finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ShaderError {
+    #[error(transparent)]
+    ReflectError(#[from] ReflectError),
+    #[error(transparent)]
+    NotifyError(#[from] notify::Error),
+    #[error("Failed to open shader {0} with error: {1}")]
+    FileOpenError(String, String),
+    #[error("Unsupported shader stage for {0}, expected .vert, .frag or .comp.")]
+    UnsupportedStageError(String),
+    #[error("Failed to compile shader:\n{0}")]
+    CompileError(String),
+}
+type Result<T> = std::result::Result<T, ShaderError>;
@@ -1,38 +1,181 @@
+use std::rc::Rc;
+
 use vulkanalia::{
     vk::{
-        self, DeviceV1_0, ErrorCode, Handle, HasBuilder, ImageView,
+        self, DeviceV1_0, ErrorCode, Handle, HasBuilder,
         KhrSurfaceExtension, KhrSwapchainExtension,
     },
-    Device, Instance, VkResult,
+    Instance,
 };
 use winit::window::Window;
 
 use crate::{
+    config::ColorOutput,
+    debug_utils::set_name,
+    device::LogicalDevice,
+    image::Image,
     image_view::{create_image_view, ImageViewError},
+    instance::VulkanInstance,
+    owned::Owned,
     queue::{QueueError, QueueFamilyIndices},
-    MAX_FRAMES_IN_FLIGHT,
+    VkErrorSource,
 };
 
+/// A window surface, destroyed when dropped. The window has to
+/// outlive it.
+#[derive(Debug)]
+pub struct Surface {
+    instance: Rc<VulkanInstance>,
+    surface: vk::SurfaceKHR,
+}
+
+impl Surface {
+    pub fn handle(&self) -> vk::SurfaceKHR {
+        self.surface
+    }
+}
+
+impl Drop for Surface {
+    fn drop(&mut self) {
+        unsafe {
+            self.instance.destroy_surface_khr(self.surface, None);
+        }
+    }
+}
+
+pub unsafe fn create_surface(
+    instance: &Rc<VulkanInstance>,
+    window: &Window,
+) -> Result<Surface> {
+    Ok(Surface {
+        instance: instance.clone(),
+        surface: vulkanalia::window::create_surface(
+            instance, window, window,
+        )?,
+    })
+}
+
+/// The images presented to, together with their views. A headless
+/// swapchain has a null handle and owns its single offscreen image
+/// instead.
+#[derive(Debug)]
+pub struct Swapchain {
+    device: Rc<LogicalDevice>,
+    swapchain: vk::SwapchainKHR,
+    images: Vec<vk::Image>,
+    image_views: Vec<Owned<vk::ImageView>>,
+    output: OutputFormat,
+    extent: vk::Extent2D,
+    present_mode: vk::PresentModeKHR,
+    _offscreen_image: Option<Image>,
+}
+
+impl Swapchain {
+    /// Wraps `image` so it can be rendered to in place of swapchain
+    /// images.
+    pub unsafe fn headless(
+        device: &Rc<LogicalDevice>,
+        image: Image,
+        extent: vk::Extent2D,
+    ) -> Result<Self> {
+        let images = vec![image.handle()];
+        let image_views = create_swapchain_image_views(
+            device,
+            &images,
+            image.format(),
+        )?;
+
+        Ok(Self {
+            device: device.clone(),
+            swapchain: vk::SwapchainKHR::null(),
+            images,
+            image_views,
+            output: OutputFormat::new(
+                image.format(),
+                vk::ColorSpaceKHR::SRGB_NONLINEAR,
+            ),
+            extent,
+            present_mode: vk::PresentModeKHR::FIFO,
+            _offscreen_image: Some(image),
+        })
+    }
+
+    pub fn handle(&self) -> vk::SwapchainKHR {
+        self.swapchain
+    }
+
+    pub fn images(&self) -> &[vk::Image] {
+        &self.images
+    }
+
+    pub fn image_views(&self) -> &[Owned<vk::ImageView>] {
+        &self.image_views
+    }
+
+    pub fn format(&self) -> vk::Format {
+        self.output.format
+    }
+
+    /// What is actually presented, which may not be the preferred
+    /// output.
+    pub fn output(&self) -> OutputFormat {
+        self.output
+    }
+
+    pub fn extent(&self) -> vk::Extent2D {
+        self.extent
+    }
+
+    /// The mode actually used, FIFO if the requested one isn't
+    /// supported.
+    pub fn present_mode(&self) -> vk::PresentModeKHR {
+        self.present_mode
+    }
+}
+
+impl Swapchain {
+    /// Destroys the swapchain before it is dropped, e.g. ahead of a
+    /// lost surface. Nothing may use its images afterwards.
+    pub unsafe fn destroy(&mut self) {
+        self.image_views.clear();
+        self.images.clear();
+        if !self.swapchain.is_null() {
+            self.device.destroy_swapchain_khr(self.swapchain, None);
+            self.swapchain = vk::SwapchainKHR::null();
+        }
+    }
+}
+
+impl Drop for Swapchain {
+    fn drop(&mut self) {
+        unsafe { self.destroy() };
+    }
+}
+
+/// Creates a swapchain for `surface`, `old_swapchain` may be null or
+/// the swapchain being replaced. Falls back to FIFO if `present_mode`
+/// isn't supported.
 pub unsafe fn create_swapchain(
     window: &Window,
     instance: &Instance,
-    device: &Device,
+    device: &Rc<LogicalDevice>,
     surface: vk::SurfaceKHR,
     physical_device: vk::PhysicalDevice,
-    swapchain: &mut vk::SwapchainKHR,
-    swapchain_images: &mut Vec<vk::Image>,
-    swapchain_format: &mut vk::Format,
-    swapchain_extent: &mut vk::Extent2D,
-) -> Result<()> {
+    outputs: &[ColorOutput],
+    present_mode: vk::PresentModeKHR,
+    old_swapchain: vk::SwapchainKHR,
+) -> Result<Swapchain> {
     let indices =
         QueueFamilyIndices::get(instance, surface, physical_device)?;
     let support =
         SwapchainSupport::get(instance, surface, physical_device)?;
 
-    let surface_format =
-        get_swapchain_surface_format(&support.formats);
-    let present_mode =
-        get_swapchain_present_mode(&support.present_modes);
+    let output =
+        get_swapchain_surface_format(&support.formats, outputs);
+    let present_mode = get_swapchain_present_mode(
+        &support.present_modes,
+        present_mode,
+    );
     let extent = get_swapchain_extent(window, support.capabilities);
     let mut image_count = support.capabilities.min_image_count + 1;
 
@@ -54,8 +197,8 @@ pub unsafe fn create_swapchain(
     let info = vk::SwapchainCreateInfoKHR::builder()
         .surface(surface)
         .min_image_count(image_count)
-        .image_format(surface_format.format)
-        .image_color_space(surface_format.color_space)
+        .image_format(output.format)
+        .image_color_space(output.color_space)
         .image_extent(extent)
         .image_array_layers(1)
         .image_usage(vk::ImageUsageFlags::COLOR_ATTACHMENT)
@@ -65,60 +208,205 @@ pub unsafe fn create_swapchain(
         .composite_alpha(vk::CompositeAlphaFlagsKHR::OPAQUE)
         .present_mode(present_mode)
         .clipped(true)
-        .old_swapchain(vk::SwapchainKHR::null());
+        .old_swapchain(old_swapchain);
 
-    *swapchain = device.create_swapchain_khr(&info, None)?;
-    *swapchain_images =
-        device.get_swapchain_images_khr(*swapchain)?;
-    *swapchain_format = surface_format.format;
-    *swapchain_extent = extent;
-    Ok(())
+    // Released by `Drop` if creating the image views fails.
+    let mut swapchain = Swapchain {
+        device: device.clone(),
+        swapchain: device.create_swapchain_khr(&info, None)?,
+        images: vec![],
+        image_views: vec![],
+        output,
+        extent,
+        present_mode,
+        _offscreen_image: None,
+    };
+    swapchain.images =
+        device.get_swapchain_images_khr(swapchain.swapchain)?;
+    for (i, image) in swapchain.images.iter().enumerate() {
+        set_name(device, *image, &format!("Swapchain image {}", i));
+    }
+    swapchain.image_views = create_swapchain_image_views(
+        device,
+        &swapchain.images,
+        swapchain.output.format,
+    )?;
+
+    Ok(swapchain)
 }
 
 pub unsafe fn create_swapchain_image_views(
-    device: &Device,
+    device: &Rc<LogicalDevice>,
     swapchain_images: &[vk::Image],
     swapchain_format: vk::Format,
-    swapchain_image_views: &mut Vec<ImageView>,
-) -> Result<()> {
-    *swapchain_image_views = swapchain_images
+) -> Result<Vec<Owned<vk::ImageView>>> {
+    swapchain_images
         .iter()
-        .map(|i| {
-            create_image_view(
+        .enumerate()
+        .map(|(i, image)| {
+            let view = create_image_view(
                 device,
-                *i,
+                *image,
                 swapchain_format,
                 vk::ImageAspectFlags::COLOR,
                 1,
-            )
-            .map_err(|e| e.into())
+            )?;
+            Ok(Owned::new(device, view)
+                .named(&format!("Swapchain image view {}", i)))
         })
-        .collect::<Result<Vec<ImageView>>>()?;
+        .collect()
+}
 
-    Ok(())
+/// How the fragment shaders encode linear color for the output, the
+/// values match `outputEncoding` in the camera uniform.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputEncoding {
+    /// Written as is, an `_SRGB` format encodes on store.
+    Linear = 0,
+    /// sRGB transfer function in the shader, for `UNORM` formats.
+    Srgb = 1,
+    /// Rec. 2020 primaries and the PQ transfer function.
+    Pq = 2,
+    /// Linear scRGB, 1.0 is 80 nits.
+    ExtendedLinear = 3,
+}
+
+/// The swapchain format and color space, see `App::output_format`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutputFormat {
+    pub format: vk::Format,
+    pub color_space: vk::ColorSpaceKHR,
+    pub encoding: OutputEncoding,
+}
+
+impl OutputFormat {
+    pub fn new(
+        format: vk::Format,
+        color_space: vk::ColorSpaceKHR,
+    ) -> Self {
+        let encoding = match color_space {
+            vk::ColorSpaceKHR::HDR10_ST2084_EXT => OutputEncoding::Pq,
+            vk::ColorSpaceKHR::EXTENDED_SRGB_LINEAR_EXT => {
+                OutputEncoding::ExtendedLinear
+            }
+            _ if matches!(
+                format,
+                vk::Format::B8G8R8A8_SRGB
+                    | vk::Format::R8G8B8A8_SRGB
+                    | vk::Format::A8B8G8R8_SRGB_PACK32
+            ) =>
+            {
+                OutputEncoding::Linear
+            }
+            _ => OutputEncoding::Srgb,
+        };
+        Self {
+            format,
+            color_space,
+            encoding,
+        }
+    }
+
+    pub fn is_hdr(&self) -> bool {
+        matches!(
+            self.encoding,
+            OutputEncoding::Pq | OutputEncoding::ExtendedLinear
+        )
+    }
+}
+
+impl ColorOutput {
+    /// Surface formats of the output, best first.
+    fn surface_formats(
+        self,
+    ) -> &'static [(vk::Format, vk::ColorSpaceKHR)] {
+        match self {
+            ColorOutput::Srgb => &[
+                (
+                    vk::Format::B8G8R8A8_SRGB,
+                    vk::ColorSpaceKHR::SRGB_NONLINEAR,
+                ),
+                (
+                    vk::Format::R8G8B8A8_SRGB,
+                    vk::ColorSpaceKHR::SRGB_NONLINEAR,
+                ),
+            ],
+            ColorOutput::Hdr10 => &[
+                (
+                    vk::Format::A2B10G10R10_UNORM_PACK32,
+                    vk::ColorSpaceKHR::HDR10_ST2084_EXT,
+                ),
+                (
+                    vk::Format::A2R10G10B10_UNORM_PACK32,
+                    vk::ColorSpaceKHR::HDR10_ST2084_EXT,
+                ),
+            ],
+            ColorOutput::ExtendedSrgb => &[(
+                vk::Format::R16G16B16A16_SFLOAT,
+                vk::ColorSpaceKHR::EXTENDED_SRGB_LINEAR_EXT,
+            )],
+        }
+    }
 }
 
+/// Picks the first of `outputs` the surface supports. Otherwise any
+/// sRGB format is used, encoded in the shader if it's `UNORM`.
 pub fn get_swapchain_surface_format(
     formats: &[vk::SurfaceFormatKHR],
-) -> vk::SurfaceFormatKHR {
-    formats
+    outputs: &[ColorOutput],
+) -> OutputFormat {
+    let supported = |(format, color_space): &(_, _)| {
+        formats.iter().any(|f| {
+            f.format == *format && f.color_space == *color_space
+        })
+    };
+    let output = outputs
         .iter()
-        .cloned()
-        .find(|f| {
-            f.format == vk::Format::B8G8R8A8_SRGB
-                && f.color_space == vk::ColorSpaceKHR::SRGB_NONLINEAR
+        .flat_map(|o| o.surface_formats())
+        .find(|f| supported(f))
+        .map(|&(format, color_space)| {
+            OutputFormat::new(format, color_space)
         })
-        .unwrap_or_else(|| formats[0])
+        .unwrap_or_else(|| {
+            let f = formats
+                .iter()
+                .find(|f| {
+                    f.color_space == vk::ColorSpaceKHR::SRGB_NONLINEAR
+                })
+                .unwrap_or(&formats[0]);
+            log::warn!(
+                "No preferred output {:?} is supported, using {:?} {:?}.",
+                outputs,
+                f.format,
+                f.color_space
+            );
+            OutputFormat::new(f.format, f.color_space)
+        });
+    log::info!(
+        "Presenting {:?} in {:?}.",
+        output.format,
+        output.color_space
+    );
+
+    output
 }
 
 pub fn get_swapchain_present_mode(
     present_modes: &[vk::PresentModeKHR],
+    preferred: vk::PresentModeKHR,
 ) -> vk::PresentModeKHR {
     present_modes
         .iter()
         .cloned()
-        .find(|m| *m == vk::PresentModeKHR::MAILBOX)
-        .unwrap_or(vk::PresentModeKHR::FIFO)
+        .find(|m| *m == preferred)
+        .unwrap_or_else(|| {
+            log::warn!(
+                "Present mode {:?} is not supported, using FIFO.",
+                preferred
+            );
+            vk::PresentModeKHR::FIFO
+        })
 }
 
 pub fn get_swapchain_extent(
@@ -178,20 +466,26 @@ impl SwapchainSupport {
     }
 }
 
+/// `color_image_view` is the multisampled target resolved into the
+/// swapchain images, `None` without MSAA.
 pub unsafe fn create_framebuffers(
-    device: &Device,
-    swapchain_image_views: &[vk::ImageView],
-    color_image_view: vk::ImageView,
+    device: &Rc<LogicalDevice>,
+    swapchain_image_views: &[Owned<vk::ImageView>],
+    color_image_view: Option<vk::ImageView>,
     depth_image_view: vk::ImageView,
     swapchain_extent: vk::Extent2D,
     render_pass: vk::RenderPass,
-    framebuffers: &mut Vec<vk::Framebuffer>,
-) -> Result<()> {
-    *framebuffers = swapchain_image_views
+) -> Result<Vec<Owned<vk::Framebuffer>>> {
+    swapchain_image_views
         .iter()
-        .map(|i| {
-            let attachments =
-                &[color_image_view, depth_image_view, *i];
+        .enumerate()
+        .map(|(n, i)| {
+            let attachments = &match color_image_view {
+                Some(color) => {
+                    vec![color, depth_image_view, i.handle()]
+                }
+                None => vec![i.handle(), depth_image_view],
+            };
             let create_info = vk::FramebufferCreateInfo::builder()
                 .render_pass(render_pass)
                 .attachments(attachments)
@@ -199,45 +493,21 @@ pub unsafe fn create_framebuffers(
                 .height(swapchain_extent.height)
                 .layers(1);
 
-            device.create_framebuffer(&create_info, None)
+            let framebuffer =
+                device.create_framebuffer(&create_info, None)?;
+            Ok(Owned::new(device, framebuffer)
+                .named(&format!("Framebuffer {}", n)))
         })
-        .collect::<VkResult<Vec<_>>>()?;
-
-    Ok(())
-}
-
-pub unsafe fn create_framebuffers_2d(
-    device: &Device,
-    swapchain_image_views: &[vk::ImageView],
-    color_image_view: vk::ImageView,
-    swapchain_extent: vk::Extent2D,
-    render_pass: vk::RenderPass,
-    framebuffers: &mut Vec<vk::Framebuffer>,
-) -> Result<()> {
-    *framebuffers = swapchain_image_views
-        .iter()
-        .map(|i| {
-            let attachments = &[color_image_view, *i];
-            let create_info = vk::FramebufferCreateInfo::builder()
-                .render_pass(render_pass)
-                .attachments(attachments)
-                .width(swapchain_extent.width)
-                .height(swapchain_extent.height)
-                .layers(1);
-
-            device.create_framebuffer(&create_info, None)
-        })
-        .collect::<VkResult<Vec<_>>>()?;
-
-    Ok(())
+        .collect()
 }
 
 pub unsafe fn create_sync_objects(
-    device: &Device,
+    device: &Rc<LogicalDevice>,
     swapchain_images: &[vk::Image],
-    image_available_semaphores: &mut Vec<vk::Semaphore>,
-    render_finished_semaphores: &mut Vec<vk::Semaphore>,
-    in_flight_fences: &mut Vec<vk::Fence>,
+    frames_in_flight: usize,
+    image_available_semaphores: &mut Vec<Owned<vk::Semaphore>>,
+    render_finished_semaphores: &mut Vec<Owned<vk::Semaphore>>,
+    in_flight_fences: &mut Vec<Owned<vk::Fence>>,
     images_in_flight: &mut Vec<vk::Fence>,
 ) -> Result<()> {
     let semaphore_info = vk::SemaphoreCreateInfo::builder();
@@ -246,14 +516,32 @@ pub unsafe fn create_sync_objects(
 
     *images_in_flight =
         swapchain_images.iter().map(|_| vk::Fence::null()).collect();
+    image_available_semaphores.clear();
+    render_finished_semaphores.clear();
+    in_flight_fences.clear();
 
-    for _ in 0..MAX_FRAMES_IN_FLIGHT {
-        image_available_semaphores
-            .push(device.create_semaphore(&semaphore_info, None)?);
-        render_finished_semaphores
-            .push(device.create_semaphore(&semaphore_info, None)?);
-        in_flight_fences
-            .push(device.create_fence(&fence_info, None)?);
+    for i in 0..frames_in_flight {
+        image_available_semaphores.push(
+            Owned::new(
+                device,
+                device.create_semaphore(&semaphore_info, None)?,
+            )
+            .named(&format!("Image available {}", i)),
+        );
+        render_finished_semaphores.push(
+            Owned::new(
+                device,
+                device.create_semaphore(&semaphore_info, None)?,
+            )
+            .named(&format!("Render finished {}", i)),
+        );
+        in_flight_fences.push(
+            Owned::new(
+                device,
+                device.create_fence(&fence_info, None)?,
+            )
+            .named(&format!("In flight {}", i)),
+        );
     }
 
     Ok(())
@@ -269,3 +557,13 @@ pub enum SwapchainError {
     ImageViewError(#[from] ImageViewError),
 }
 type Result<T> = std::result::Result<T, SwapchainError>;
+
+impl VkErrorSource for SwapchainError {
+    fn vk_error_code(&self) -> Option<ErrorCode> {
+        match self {
+            Self::QueueError(e) => e.vk_error_code(),
+            Self::VkErrorCode(e) => Some(*e),
+            Self::ImageViewError(e) => e.vk_error_code(),
+        }
+    }
+}
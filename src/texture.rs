@@ -1,170 +1,131 @@
-use std::{fs::File, ptr::copy_nonoverlapping as memcpy};
+use std::{fs::File, path::Path, rc::Rc};
 
 use png::DecodingError;
 use vulkanalia::{
     vk::{self, DeviceV1_0, ErrorCode, HasBuilder},
-    Device, Instance,
+    Instance,
 };
 
 use crate::{
-    buffer::{create_buffer, BufferError},
-    image::{
-        copy_buffer_to_image, create_image, generate_mipmaps,
-        transition_image_layout, ImageError,
-    },
-    image_view::{create_image_view, ImageViewError},
+    device::LogicalDevice,
+    image::{check_mipmap_support, create_image, Image, ImageError},
+    image_view::ImageViewError,
+    owned::Owned,
+    upload::{UploadError, Uploader},
+    VkErrorSource,
 };
 
-pub unsafe fn create_texture_image(
-    instance: &Instance,
-    device: &Device,
-    physical_device: vk::PhysicalDevice,
-    command_pool: vk::CommandPool,
-    graphics_queue: vk::Queue,
-    mip_levels: &mut u32,
-    texture_image: &mut vk::Image,
-    texture_image_memory: &mut vk::DeviceMemory,
-) -> Result<()> {
-    let image =
-        File::open("resources/fish_albedo.png").map_err(|e| {
+/// The decoded RGBA pixels of a texture, kept to upload it again
+/// after a device loss.
+#[derive(Debug, Clone)]
+pub struct TextureData {
+    pub name: String,
+    pub pixels: Vec<u8>,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl TextureData {
+    /// Decodes the PNG at `path`, RGB images are converted to RGBA.
+    pub fn load(path: &Path) -> Result<Self> {
+        let _span =
+            tracing::info_span!("load_image", path = %path.display())
+                .entered();
+        let image = File::open(path).map_err(|e| {
             TextureError::FileOpenError(
-                "resources/viking_room.png".into(),
+                path.display().to_string(),
                 e.to_string(),
             )
         })?;
 
-    let decoder = png::Decoder::new(image);
-    let mut reader = decoder.read_info()?;
-
-    let mut pixels = vec![0; reader.info().raw_bytes()];
-    reader.next_frame(&mut pixels)?;
-
-    let mut size = reader.info().raw_bytes() as u64;
-    let (width, height) = reader.info().size();
-    *mip_levels =
-        (width.max(height) as f32).log2().floor() as u32 + 1;
-    match reader.info().color_type {
-        png::ColorType::Rgb => {
-            log::warn!("Have to convert RGB texture image to RGBA.");
-            let mut t_pixels =
-                Vec::with_capacity(pixels.len() * 4 / 3);
-            for i in 0..(size as usize / 3) {
-                t_pixels.push(pixels[i * 3]);
-                t_pixels.push(pixels[i * 3 + 1]);
-                t_pixels.push(pixels[i * 3 + 2]);
-                t_pixels.push(u8::MAX);
+        let decoder = png::Decoder::new(image);
+        let mut reader = decoder.read_info()?;
+
+        let mut pixels = vec![0; reader.info().raw_bytes()];
+        reader.next_frame(&mut pixels)?;
+
+        let size = reader.info().raw_bytes();
+        let (width, height) = reader.info().size();
+        match reader.info().color_type {
+            png::ColorType::Rgb => {
+                log::warn!(
+                    "Have to convert RGB texture image to RGBA."
+                );
+                let mut t_pixels =
+                    Vec::with_capacity(pixels.len() * 4 / 3);
+                for i in 0..(size / 3) {
+                    t_pixels.push(pixels[i * 3]);
+                    t_pixels.push(pixels[i * 3 + 1]);
+                    t_pixels.push(pixels[i * 3 + 2]);
+                    t_pixels.push(u8::MAX);
+                }
+                pixels = t_pixels;
             }
-            pixels = t_pixels;
-            size = pixels.len() as u64;
-        }
-        png::ColorType::Rgba => (),
-        _ => {
-            return Err(TextureError::UnsupportedTextureError(
-                format!("{:?}", reader.info().color_type),
-            ));
-        }
-    };
-
-    let (staging_buffer, staging_buffer_memory) = create_buffer(
-        instance,
-        device,
-        physical_device,
-        size,
-        vk::BufferUsageFlags::TRANSFER_SRC,
-        vk::MemoryPropertyFlags::HOST_COHERENT
-            | vk::MemoryPropertyFlags::HOST_VISIBLE,
-    )?;
-
-    let memory = device.map_memory(
-        staging_buffer_memory,
-        0,
-        size,
-        vk::MemoryMapFlags::empty(),
-    )?;
+            png::ColorType::Rgba => (),
+            _ => {
+                return Err(TextureError::UnsupportedTextureError(
+                    format!("{:?}", reader.info().color_type),
+                ));
+            }
+        };
+
+        Ok(Self {
+            name: path.display().to_string(),
+            pixels,
+            width,
+            height,
+        })
+    }
+}
 
-    memcpy(pixels.as_ptr(), memory.cast(), pixels.len());
+/// Creates a mipmapped, sampled image of `texture` with a view of
+/// all its mip levels. The upload is recorded into `uploader` and
+/// the image is ready once the next flush completes.
+pub unsafe fn create_texture_image(
+    instance: &Instance,
+    uploader: &mut Uploader,
+    physical_device: vk::PhysicalDevice,
+    texture: &TextureData,
+) -> Result<Image> {
+    let (width, height) = (texture.width, texture.height);
+    let mip_levels =
+        (width.max(height) as f32).log2().floor() as u32 + 1;
 
-    device.unmap_memory(staging_buffer_memory);
+    // ! SRGB is not necessarily supported
+    let format = vk::Format::R8G8B8A8_SRGB;
+    check_mipmap_support(instance, physical_device, format)?;
 
-    (*texture_image, *texture_image_memory) = create_image(
-        instance,
-        device,
-        physical_device,
+    let mut texture_image = create_image(
+        uploader.allocator(),
         width,
         height,
-        *mip_levels,
+        mip_levels,
         vk::SampleCountFlags::_1,
-        // ! SRGB is not necessarily supported
-        vk::Format::R8G8B8A8_SRGB,
+        format,
         vk::ImageTiling::OPTIMAL,
         vk::ImageUsageFlags::SAMPLED
             | vk::ImageUsageFlags::TRANSFER_DST
             | vk::ImageUsageFlags::TRANSFER_SRC,
         vk::MemoryPropertyFlags::DEVICE_LOCAL,
+        &texture.name,
     )?;
 
-    transition_image_layout(
-        device,
-        command_pool,
-        graphics_queue,
-        *texture_image,
-        vk::Format::R8G8B8A8_SRGB,
-        vk::ImageLayout::UNDEFINED,
-        vk::ImageLayout::TRANSFER_DST_OPTIMAL,
-        *mip_levels,
-    )?;
-
-    copy_buffer_to_image(
-        device,
-        command_pool,
-        graphics_queue,
-        staging_buffer,
-        *texture_image,
+    uploader.upload_image(
+        &texture.pixels,
+        &texture_image,
         width,
         height,
     )?;
 
-    device.destroy_buffer(staging_buffer, None);
-    device.free_memory(staging_buffer_memory, None);
-
-    generate_mipmaps(
-        instance,
-        device,
-        physical_device,
-        command_pool,
-        graphics_queue,
-        *texture_image,
-        vk::Format::R8G8B8A8_SRGB,
-        width,
-        height,
-        *mip_levels,
-    )?;
+    texture_image.create_view(vk::ImageAspectFlags::COLOR)?;
 
-    Ok(())
-}
-
-pub unsafe fn create_texture_image_view(
-    device: &Device,
-    texture_image: &vk::Image,
-    mip_levels: &u32,
-    texture_image_view: &mut vk::ImageView,
-) -> Result<()> {
-    *texture_image_view = create_image_view(
-        device,
-        *texture_image,
-        vk::Format::R8G8B8A8_SRGB,
-        vk::ImageAspectFlags::COLOR,
-        *mip_levels,
-    )?;
-
-    Ok(())
+    Ok(texture_image)
 }
 
 pub unsafe fn create_texture_sampler(
-    device: &Device,
-    mip_levels: &u32,
-    texture_sampler: &mut vk::Sampler,
-) -> Result<()> {
+    device: &Rc<LogicalDevice>,
+    mip_levels: u32,
+) -> Result<Owned<vk::Sampler>> {
     let info = vk::SamplerCreateInfo::builder()
         .mag_filter(vk::Filter::LINEAR)
         .min_filter(vk::Filter::LINEAR)
@@ -181,12 +142,12 @@ pub unsafe fn create_texture_sampler(
         .compare_op(vk::CompareOp::ALWAYS)
         .mipmap_mode(vk::SamplerMipmapMode::LINEAR)
         .min_lod(0.0) // Optional.
-        .max_lod(*mip_levels as f32)
+        .max_lod(mip_levels as f32)
         .mip_lod_bias(0.0); // Optional.
 
-    *texture_sampler = device.create_sampler(&info, None)?;
+    let texture_sampler = device.create_sampler(&info, None)?;
 
-    Ok(())
+    Ok(Owned::new(device, texture_sampler).named("Texture sampler"))
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -200,7 +161,7 @@ pub enum TextureError {
     #[error(transparent)]
     ImageViewError(#[from] ImageViewError),
     #[error(transparent)]
-    BufferError(#[from] BufferError),
+    UploadError(#[from] UploadError),
     #[error("Failed to open texture image {0} with error: {1}")]
     FileOpenError(String, String),
     #[error(
@@ -209,3 +170,17 @@ pub enum TextureError {
     UnsupportedTextureError(String),
 }
 type Result<T> = std::result::Result<T, TextureError>;
+
+impl VkErrorSource for TextureError {
+    fn vk_error_code(&self) -> Option<ErrorCode> {
+        match self {
+            Self::VkErrorCode(e) => Some(*e),
+            Self::ImageError(e) => e.vk_error_code(),
+            Self::ImageViewError(e) => e.vk_error_code(),
+            Self::UploadError(e) => e.vk_error_code(),
+            Self::DecodingError(_)
+            | Self::FileOpenError(..)
+            | Self::UnsupportedTextureError(_) => None,
+        }
+    }
+}
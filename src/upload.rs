@@ -0,0 +1,651 @@
+use std::{mem::size_of_val, rc::Rc, time::Duration};
+
+use vulkanalia::{
+    vk::{self, DeviceV1_0, ErrorCode, Handle, HasBuilder},
+    Instance,
+};
+
+use crate::{
+    allocator::Allocator,
+    buffer::{copy_buffer, create_buffer, Buffer, BufferError},
+    command::{begin_single_time_commands, CommandError},
+    debug_utils::Label,
+    image::{
+        copy_buffer_to_image, generate_mipmaps,
+        transition_image_layout, Image, ImageError,
+    },
+    owned::Owned,
+    profiler::{
+        create_timestamp_pool, ProfilerError, TimestampClock,
+    },
+    queue::{QueueError, QueueFamilyIndices},
+    VkErrorSource,
+};
+
+/// Identifies a flushed batch of uploads.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+pub struct UploadTicket(u64);
+
+/// Batches copies from staging buffers into device local resources
+/// and submits them without waiting for the queue.
+///
+/// With a dedicated transfer family the copies run on the transfer
+/// queue and release the resources to the graphics family, which
+/// acquires them (and generates mipmaps) in a second submission that
+/// waits on a semaphore. Otherwise everything is recorded into one
+/// command buffer for the graphics queue.
+///
+/// Resources returned by the `upload_*` calls must not be used before
+/// the ticket returned by the next `flush` is complete.
+#[derive(Debug)]
+pub struct Uploader {
+    allocator: Rc<Allocator>,
+    indices: QueueFamilyIndices,
+    transfer_queue: vk::Queue,
+    graphics_queue: vk::Queue,
+    batch: Option<Batch>,
+    submitted: Vec<Submission>,
+    next_ticket: u64,
+    transfer_pool: Owned<vk::CommandPool>,
+    graphics_pool: Owned<vk::CommandPool>,
+    /// `None` if the transfer family can't write timestamps.
+    clock: Option<TimestampClock>,
+    /// GPU time of the transfer commands of finished submissions.
+    timings: Vec<Duration>,
+}
+
+/// The commands recorded since the last flush.
+#[derive(Debug)]
+struct Batch {
+    transfer: vk::CommandBuffer,
+    /// Acquires ownership on the graphics queue, only with a
+    /// dedicated transfer family.
+    acquire: Option<vk::CommandBuffer>,
+    staging: Vec<Buffer>,
+    /// Written at the start and end of `transfer`.
+    timestamps: Option<Owned<vk::QueryPool>>,
+}
+
+#[derive(Debug)]
+struct Submission {
+    ticket: UploadTicket,
+    fence: Owned<vk::Fence>,
+    _semaphore: Option<Owned<vk::Semaphore>>,
+    command_buffers: Vec<vk::CommandBuffer>,
+    _staging: Vec<Buffer>,
+    timestamps: Option<Owned<vk::QueryPool>>,
+}
+
+pub unsafe fn create_uploader(
+    instance: &Instance,
+    allocator: &Rc<Allocator>,
+    surface: vk::SurfaceKHR,
+    physical_device: vk::PhysicalDevice,
+    graphics_queue: vk::Queue,
+    transfer_queue: vk::Queue,
+) -> Result<Uploader> {
+    let device = allocator.device();
+    let indices =
+        QueueFamilyIndices::get(instance, surface, physical_device)?;
+    let create_pool =
+        |family, name| -> Result<Owned<vk::CommandPool>> {
+            let info = vk::CommandPoolCreateInfo::builder()
+                .flags(vk::CommandPoolCreateFlags::TRANSIENT)
+                .queue_family_index(family);
+            Ok(Owned::new(
+                device,
+                device.create_command_pool(&info, None)?,
+            )
+            .named(name))
+        };
+
+    if indices.has_dedicated_transfer() {
+        log::info!(
+            "Uploading on dedicated transfer queue family {}.",
+            indices.transfer
+        );
+    }
+
+    Ok(Uploader {
+        allocator: allocator.clone(),
+        indices,
+        transfer_queue,
+        graphics_queue,
+        batch: None,
+        submitted: vec![],
+        // Ticket 0 stands for "nothing uploaded yet".
+        next_ticket: 1,
+        transfer_pool: create_pool(
+            indices.transfer,
+            "Upload transfer command pool",
+        )?,
+        graphics_pool: create_pool(
+            indices.graphics,
+            "Upload graphics command pool",
+        )?,
+        clock: TimestampClock::get(
+            instance,
+            physical_device,
+            indices.transfer,
+        ),
+        timings: vec![],
+    })
+}
+
+impl Uploader {
+    pub fn allocator(&self) -> &Rc<Allocator> {
+        &self.allocator
+    }
+
+    /// Creates a device local buffer with `usage` and records a copy
+    /// of `data` into it. `data` can't be empty, Vulkan has no empty
+    /// buffers.
+    pub unsafe fn upload_buffer<T>(
+        &mut self,
+        data: &[T],
+        usage: vk::BufferUsageFlags,
+        name: &str,
+    ) -> Result<Buffer> {
+        let size = size_of_val(data) as u64;
+        if size == 0 {
+            return Err(UploadError::EmptyBufferError(
+                name.to_string(),
+            ));
+        }
+        let staging_buffer = create_buffer(
+            &self.allocator,
+            size,
+            vk::BufferUsageFlags::TRANSFER_SRC,
+            vk::MemoryPropertyFlags::HOST_COHERENT
+                | vk::MemoryPropertyFlags::HOST_VISIBLE,
+            &format!("{} staging", name),
+        )?;
+        staging_buffer.write(data)?;
+
+        let buffer = create_buffer(
+            &self.allocator,
+            size,
+            vk::BufferUsageFlags::TRANSFER_DST | usage,
+            vk::MemoryPropertyFlags::DEVICE_LOCAL,
+            name,
+        )?;
+
+        let (graphics, transfer) =
+            (self.indices.graphics, self.indices.transfer);
+        let device = self.allocator.device().clone();
+        let batch = self.batch()?;
+        let _label = Label::begin(
+            &device,
+            batch.transfer,
+            &format!("Upload {}", name),
+        );
+        copy_buffer(
+            &device,
+            batch.transfer,
+            staging_buffer.handle(),
+            buffer.handle(),
+            size,
+        );
+
+        // Uploads complete before the buffer is used, so a coarse
+        // barrier costs nothing here.
+        let barrier = vk::BufferMemoryBarrier::builder()
+            .buffer(buffer.handle())
+            .offset(0)
+            .size(vk::WHOLE_SIZE as vk::DeviceSize);
+        if let Some(acquire) = batch.acquire {
+            let barrier = barrier
+                .src_queue_family_index(transfer)
+                .dst_queue_family_index(graphics);
+            device.cmd_pipeline_barrier(
+                batch.transfer,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                vk::DependencyFlags::empty(),
+                &[] as &[vk::MemoryBarrier],
+                &[barrier.src_access_mask(
+                    vk::AccessFlags::TRANSFER_WRITE,
+                )],
+                &[] as &[vk::ImageMemoryBarrier],
+            );
+            device.cmd_pipeline_barrier(
+                acquire,
+                vk::PipelineStageFlags::TOP_OF_PIPE,
+                vk::PipelineStageFlags::ALL_COMMANDS,
+                vk::DependencyFlags::empty(),
+                &[] as &[vk::MemoryBarrier],
+                &[barrier
+                    .dst_access_mask(vk::AccessFlags::MEMORY_READ)],
+                &[] as &[vk::ImageMemoryBarrier],
+            );
+        } else {
+            device.cmd_pipeline_barrier(
+                batch.transfer,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::ALL_COMMANDS,
+                vk::DependencyFlags::empty(),
+                &[] as &[vk::MemoryBarrier],
+                &[barrier
+                    .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+                    .dst_access_mask(vk::AccessFlags::MEMORY_READ)],
+                &[] as &[vk::ImageMemoryBarrier],
+            );
+        }
+        batch.staging.push(staging_buffer);
+
+        Ok(buffer)
+    }
+
+    /// Records a copy of `pixels` into mip level 0 of `image` and the
+    /// generation of the remaining levels. `image` has to be a color
+    /// image in `UNDEFINED` layout, it ends up in
+    /// `SHADER_READ_ONLY_OPTIMAL`.
+    pub unsafe fn upload_image(
+        &mut self,
+        pixels: &[u8],
+        image: &Image,
+        width: u32,
+        height: u32,
+    ) -> Result<()> {
+        let staging_buffer = create_buffer(
+            &self.allocator,
+            pixels.len() as u64,
+            vk::BufferUsageFlags::TRANSFER_SRC,
+            vk::MemoryPropertyFlags::HOST_COHERENT
+                | vk::MemoryPropertyFlags::HOST_VISIBLE,
+            &format!("{} staging", image.name()),
+        )?;
+        staging_buffer.write(pixels)?;
+
+        let (graphics, transfer) =
+            (self.indices.graphics, self.indices.transfer);
+        let device = self.allocator.device().clone();
+        let batch = self.batch()?;
+        let label = Label::begin(
+            &device,
+            batch.transfer,
+            &format!("Upload {}", image.name()),
+        );
+        transition_image_layout(
+            &device,
+            batch.transfer,
+            image.handle(),
+            image.format(),
+            vk::ImageLayout::UNDEFINED,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            image.mip_levels(),
+        )?;
+        copy_buffer_to_image(
+            &device,
+            batch.transfer,
+            staging_buffer.handle(),
+            image.handle(),
+            width,
+            height,
+        );
+
+        // Blitting needs a graphics queue, so the mipmaps are
+        // generated after the image changed ownership.
+        let mipmap_commands = match batch.acquire {
+            Some(acquire) => {
+                let subresource =
+                    vk::ImageSubresourceRange::builder()
+                        .aspect_mask(vk::ImageAspectFlags::COLOR)
+                        .base_mip_level(0)
+                        .level_count(image.mip_levels())
+                        .base_array_layer(0)
+                        .layer_count(1);
+                let barrier = vk::ImageMemoryBarrier::builder()
+                    .image(image.handle())
+                    .old_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+                    .new_layout(vk::ImageLayout::TRANSFER_DST_OPTIMAL)
+                    .src_queue_family_index(transfer)
+                    .dst_queue_family_index(graphics)
+                    .subresource_range(subresource);
+                device.cmd_pipeline_barrier(
+                    batch.transfer,
+                    vk::PipelineStageFlags::TRANSFER,
+                    vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                    vk::DependencyFlags::empty(),
+                    &[] as &[vk::MemoryBarrier],
+                    &[] as &[vk::BufferMemoryBarrier],
+                    &[barrier.src_access_mask(
+                        vk::AccessFlags::TRANSFER_WRITE,
+                    )],
+                );
+                device.cmd_pipeline_barrier(
+                    acquire,
+                    vk::PipelineStageFlags::TOP_OF_PIPE,
+                    vk::PipelineStageFlags::TRANSFER,
+                    vk::DependencyFlags::empty(),
+                    &[] as &[vk::MemoryBarrier],
+                    &[] as &[vk::BufferMemoryBarrier],
+                    &[barrier.dst_access_mask(
+                        vk::AccessFlags::TRANSFER_READ
+                            | vk::AccessFlags::TRANSFER_WRITE,
+                    )],
+                );
+                acquire
+            }
+            None => batch.transfer,
+        };
+        drop(label);
+        let _label = Label::begin(
+            &device,
+            mipmap_commands,
+            &format!("Mipmaps {}", image.name()),
+        );
+        generate_mipmaps(
+            &device,
+            mipmap_commands,
+            image.handle(),
+            width,
+            height,
+            image.mip_levels(),
+        );
+        batch.staging.push(staging_buffer);
+
+        Ok(())
+    }
+
+    /// Submits the uploads recorded since the last flush. Returns the
+    /// ticket of the previous flush if nothing was recorded.
+    pub unsafe fn flush(&mut self) -> Result<UploadTicket> {
+        let Some(batch) = self.batch.take() else {
+            return Ok(UploadTicket(self.next_ticket - 1));
+        };
+        let device = self.allocator.device();
+
+        let fence = Owned::new(
+            device,
+            device.create_fence(
+                &vk::FenceCreateInfo::builder(),
+                None,
+            )?,
+        )
+        .named("Upload fence");
+        let mut command_buffers = vec![batch.transfer];
+        if let Some(timestamps) = &batch.timestamps {
+            device.cmd_write_timestamp(
+                batch.transfer,
+                vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                timestamps.handle(),
+                1,
+            );
+        }
+        device.end_command_buffer(batch.transfer)?;
+
+        let semaphore = match batch.acquire {
+            Some(acquire) => {
+                device.end_command_buffer(acquire)?;
+                command_buffers.push(acquire);
+                let semaphore = Owned::new(
+                    device,
+                    device.create_semaphore(
+                        &vk::SemaphoreCreateInfo::builder(),
+                        None,
+                    )?,
+                )
+                .named("Upload semaphore");
+
+                let transfer_buffers = &[batch.transfer];
+                let signal_semaphores = &[semaphore.handle()];
+                let transfer_info = vk::SubmitInfo::builder()
+                    .command_buffers(transfer_buffers)
+                    .signal_semaphores(signal_semaphores);
+                device.queue_submit(
+                    self.transfer_queue,
+                    &[transfer_info],
+                    vk::Fence::null(),
+                )?;
+
+                let acquire_buffers = &[acquire];
+                let wait_stages =
+                    &[vk::PipelineStageFlags::ALL_COMMANDS];
+                let acquire_info = vk::SubmitInfo::builder()
+                    .wait_semaphores(signal_semaphores)
+                    .wait_dst_stage_mask(wait_stages)
+                    .command_buffers(acquire_buffers);
+                device.queue_submit(
+                    self.graphics_queue,
+                    &[acquire_info],
+                    fence.handle(),
+                )?;
+
+                Some(semaphore)
+            }
+            None => {
+                let transfer_buffers = &[batch.transfer];
+                let info = vk::SubmitInfo::builder()
+                    .command_buffers(transfer_buffers);
+                device.queue_submit(
+                    self.transfer_queue,
+                    &[info],
+                    fence.handle(),
+                )?;
+
+                None
+            }
+        };
+
+        let ticket = UploadTicket(self.next_ticket);
+        self.next_ticket += 1;
+        self.submitted.push(Submission {
+            ticket,
+            fence,
+            _semaphore: semaphore,
+            command_buffers,
+            _staging: batch.staging,
+            timestamps: batch.timestamps,
+        });
+
+        Ok(ticket)
+    }
+
+    /// Releases the staging memory of finished uploads.
+    pub unsafe fn poll(&mut self) -> Result<()> {
+        let device = self.allocator.device().clone();
+        let mut finished = vec![];
+        for (i, submission) in self.submitted.iter().enumerate() {
+            if device.get_fence_status(submission.fence.handle())?
+                == vk::SuccessCode::SUCCESS
+            {
+                finished.push(i);
+            }
+        }
+        for i in finished.into_iter().rev() {
+            self.release(i);
+        }
+
+        Ok(())
+    }
+
+    /// Whether the uploads of `ticket` finished, as of the last
+    /// `poll` or `wait`.
+    pub fn is_complete(&self, ticket: UploadTicket) -> bool {
+        ticket.0 < self.next_ticket
+            && !self.submitted.iter().any(|s| s.ticket == ticket)
+    }
+
+    /// Blocks until the uploads of `ticket` finished.
+    pub unsafe fn wait(
+        &mut self,
+        ticket: UploadTicket,
+    ) -> Result<()> {
+        if let Some(i) =
+            self.submitted.iter().position(|s| s.ticket == ticket)
+        {
+            self.allocator.device().wait_for_fences(
+                &[self.submitted[i].fence.handle()],
+                true,
+                u64::MAX,
+            )?;
+            self.release(i);
+        }
+
+        Ok(())
+    }
+
+    /// Flushes and blocks until every upload finished.
+    pub unsafe fn wait_all(&mut self) -> Result<()> {
+        self.flush()?;
+        while let Some(submission) = self.submitted.last() {
+            self.wait(submission.ticket)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the GPU time of the transfer commands of each batch
+    /// finished since the last call, see `poll`.
+    pub fn take_timings(&mut self) -> Vec<Duration> {
+        std::mem::take(&mut self.timings)
+    }
+
+    /// The current batch, begun on first use.
+    unsafe fn batch(&mut self) -> Result<&mut Batch> {
+        if self.batch.is_none() {
+            let transfer = begin_single_time_commands(
+                self.allocator.device(),
+                self.transfer_pool.handle(),
+            )?;
+            let acquire = if self.indices.has_dedicated_transfer() {
+                Some(begin_single_time_commands(
+                    self.allocator.device(),
+                    self.graphics_pool.handle(),
+                )?)
+            } else {
+                None
+            };
+            let timestamps = match self.clock {
+                Some(_) => {
+                    let device = self.allocator.device();
+                    let pool = create_timestamp_pool(device, 2)?
+                        .named("Upload timestamps");
+                    device.cmd_reset_query_pool(
+                        transfer,
+                        pool.handle(),
+                        0,
+                        2,
+                    );
+                    device.cmd_write_timestamp(
+                        transfer,
+                        vk::PipelineStageFlags::TOP_OF_PIPE,
+                        pool.handle(),
+                        0,
+                    );
+                    Some(pool)
+                }
+                None => None,
+            };
+            self.batch = Some(Batch {
+                transfer,
+                acquire,
+                staging: vec![],
+                timestamps,
+            });
+        }
+
+        Ok(self.batch.as_mut().unwrap())
+    }
+
+    unsafe fn release(&mut self, index: usize) {
+        let submission = self.submitted.remove(index);
+        let device = self.allocator.device();
+        if let (Some(clock), Some(timestamps)) =
+            (self.clock, &submission.timestamps)
+        {
+            let mut data = [0u8; 16];
+            match device.get_query_pool_results(
+                timestamps.handle(),
+                0,
+                2,
+                &mut data,
+                8,
+                vk::QueryResultFlags::_64,
+            ) {
+                Ok(vk::SuccessCode::SUCCESS) => {
+                    let [begin, end] = [0, 8].map(|i| {
+                        u64::from_ne_bytes(
+                            data[i..i + 8].try_into().unwrap(),
+                        )
+                    });
+                    self.timings.push(clock.duration(begin, end));
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    log::warn!("Failed to read upload time: {}", e)
+                }
+            }
+        }
+        device.free_command_buffers(
+            self.transfer_pool.handle(),
+            &submission.command_buffers[..1],
+        );
+        if submission.command_buffers.len() > 1 {
+            device.free_command_buffers(
+                self.graphics_pool.handle(),
+                &submission.command_buffers[1..],
+            );
+        }
+    }
+}
+
+impl Drop for Uploader {
+    fn drop(&mut self) {
+        // Staging buffers and command buffers may still be read.
+        let fences = self
+            .submitted
+            .iter()
+            .map(|s| s.fence.handle())
+            .collect::<Vec<_>>();
+        if !fences.is_empty() {
+            if let Err(e) = unsafe {
+                self.allocator.device().wait_for_fences(
+                    &fences,
+                    true,
+                    u64::MAX,
+                )
+            } {
+                log::error!("Failed to wait for uploads: {}", e);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, thiserror::Error)]
+pub enum UploadError {
+    #[error(transparent)]
+    VkErrorCode(#[from] ErrorCode),
+    #[error(transparent)]
+    QueueError(#[from] QueueError),
+    #[error(transparent)]
+    CommandError(#[from] CommandError),
+    #[error(transparent)]
+    BufferError(#[from] BufferError),
+    #[error(transparent)]
+    ImageError(#[from] ImageError),
+    #[error(transparent)]
+    ProfilerError(#[from] ProfilerError),
+    #[error("Failed to upload {0}, it is empty.")]
+    EmptyBufferError(String),
+}
+type Result<T> = std::result::Result<T, UploadError>;
+
+impl VkErrorSource for UploadError {
+    fn vk_error_code(&self) -> Option<ErrorCode> {
+        match self {
+            Self::VkErrorCode(e) => Some(*e),
+            Self::QueueError(e) => e.vk_error_code(),
+            Self::CommandError(e) => e.vk_error_code(),
+            Self::BufferError(e) => e.vk_error_code(),
+            Self::ImageError(e) => e.vk_error_code(),
+            Self::ProfilerError(e) => e.vk_error_code(),
+            Self::EmptyBufferError(_) => None,
+        }
+    }
+}
@@ -1,22 +1,215 @@
+use std::backtrace::Backtrace;
 use std::collections::HashSet;
 use std::ffi::CStr;
+use std::fmt;
 use std::os::raw::c_void;
+use std::sync::{Arc, Mutex, RwLock};
 use thiserror::Error;
 
 use vulkanalia::vk::{
-    self, DebugUtilsMessengerCreateInfoEXTBuilder, EntryV1_0,
-    ErrorCode, ExtDebugUtilsExtension, HasBuilder,
+    self, EntryV1_0, ErrorCode, ExtDebugUtilsExtension, Handle,
+    HasBuilder,
 };
 use vulkanalia::Entry;
 use vulkanalia::{window as vk_window, Instance};
 use winit::window::Window;
 
-const VALIDATION_ENABLED: bool = cfg!(debug_assertions);
+use crate::config::{ValidationFeature, ValidationSink};
+use crate::VkErrorSource;
 
 const VALIDATION_LAYER: vk::ExtensionName =
     vk::ExtensionName::from_bytes(b"VK_LAYER_KHRONOS_validation");
+
+/// A message from the validation layer or the driver.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationMessage {
+    pub severity: vk::DebugUtilsMessageSeverityFlagsEXT,
+    pub types: vk::DebugUtilsMessageTypeFlagsEXT,
+    /// The VUID or check name, e.g.
+    /// `VUID-vkCmdDraw-None-02859`, empty if there is none.
+    pub id: String,
+    pub message: String,
+    /// Names of the objects involved, see `debug_utils::set_name`.
+    pub objects: Vec<String>,
+    /// Labels open in the command buffer, outermost first.
+    pub labels: Vec<String>,
+}
+
+impl ValidationMessage {
+    pub fn is_error(&self) -> bool {
+        self.severity >= vk::DebugUtilsMessageSeverityFlagsEXT::ERROR
+    }
+}
+
+impl fmt::Display for ValidationMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "({:?}) {}", self.types, self.message)?;
+        if !self.objects.is_empty() {
+            write!(f, " [objects: {}]", self.objects.join(", "))?;
+        }
+        if !self.labels.is_empty() {
+            write!(f, " [labels: {}]", self.labels.join(" > "))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Receives every message of the instance's debug messenger, see
+/// `VulkanInstance::set_sink`. Layers may call it from any thread.
+pub trait MessageSink: Send + Sync {
+    fn receive(&self, message: &ValidationMessage);
+
+    /// Whether the first error should panic, see `SinkSlot::check`.
+    fn panics_on_error(&self) -> bool {
+        false
+    }
+}
+
+/// Forwards messages to `log` by severity, the default sink.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LogSink;
+
+impl MessageSink for LogSink {
+    fn receive(&self, message: &ValidationMessage) {
+        let severity = message.severity;
+        if severity >= vk::DebugUtilsMessageSeverityFlagsEXT::ERROR {
+            log::error!("{}", message);
+        } else if severity
+            >= vk::DebugUtilsMessageSeverityFlagsEXT::WARNING
+        {
+            log::warn!("{}", message);
+        } else if severity
+            >= vk::DebugUtilsMessageSeverityFlagsEXT::INFO
+        {
+            log::debug!("{}", message);
+        } else {
+            log::trace!("{}", message);
+        }
+    }
+}
+
+/// Logs messages and keeps the warnings and errors, e.g. to check a
+/// frame didn't produce any.
+#[derive(Debug, Default)]
+pub struct CollectingSink {
+    messages: Mutex<Vec<ValidationMessage>>,
+}
+
+impl CollectingSink {
+    /// Returns the messages collected so far and clears them.
+    pub fn take(&self) -> Vec<ValidationMessage> {
+        std::mem::take(&mut *self.messages.lock().unwrap())
+    }
+
+    pub fn error_count(&self) -> usize {
+        self.messages
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|m| m.is_error())
+            .count()
+    }
+}
+
+impl MessageSink for CollectingSink {
+    fn receive(&self, message: &ValidationMessage) {
+        LogSink.receive(message);
+        if message.severity
+            >= vk::DebugUtilsMessageSeverityFlagsEXT::WARNING
+        {
+            self.messages.lock().unwrap().push(message.clone());
+        }
+    }
+}
+
+/// Logs messages and panics on the first error with a backtrace of
+/// the Vulkan call that caused it. A panic can't unwind out of the
+/// debug callback, so it is raised once the call returned, see
+/// `SinkSlot::check`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PanicSink;
+
+impl MessageSink for PanicSink {
+    fn receive(&self, message: &ValidationMessage) {
+        LogSink.receive(message);
+    }
+
+    fn panics_on_error(&self) -> bool {
+        true
+    }
+}
+
+impl From<ValidationSink> for Arc<dyn MessageSink> {
+    fn from(sink: ValidationSink) -> Self {
+        match sink {
+            ValidationSink::Log => Arc::new(LogSink),
+            ValidationSink::Panic => Arc::new(PanicSink),
+        }
+    }
+}
+
+/// The sink the debug messenger's callback forwards to, it has to
+/// stay at the same address while the instance exists.
+pub struct SinkSlot {
+    sink: RwLock<Arc<dyn MessageSink>>,
+    /// The first error of a sink that panics on it, with a backtrace.
+    first_error: Mutex<Option<String>>,
+}
+
+impl SinkSlot {
+    pub fn new(sink: Arc<dyn MessageSink>) -> Self {
+        Self {
+            sink: RwLock::new(sink),
+            first_error: Mutex::new(None),
+        }
+    }
+
+    pub fn get(&self) -> Arc<dyn MessageSink> {
+        self.sink.read().unwrap().clone()
+    }
+
+    pub fn set(&self, sink: Arc<dyn MessageSink>) {
+        *self.sink.write().unwrap() = sink;
+    }
+
+    /// Forwards `message` to the sink and keeps it if it is the
+    /// first error of a sink that panics on errors.
+    pub fn receive(&self, message: &ValidationMessage) {
+        let sink = self.get();
+        sink.receive(message);
+        if sink.panics_on_error() && message.is_error() {
+            self.first_error.lock().unwrap().get_or_insert_with(
+                || {
+                    format!(
+                        "Validation error {}: {}\n{}",
+                        message.id,
+                        message.message,
+                        Backtrace::force_capture()
+                    )
+                },
+            );
+        }
+    }
+
+    /// Panics with the first error kept by `receive`, called from
+    /// Rust code after the Vulkan calls that may have raised it.
+    pub fn check(&self) {
+        let error = self.first_error.lock().unwrap().take();
+        if let Some(error) = error {
+            panic!("{}", error);
+        }
+    }
+}
+
+impl fmt::Debug for SinkSlot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("SinkSlot").finish_non_exhaustive()
+    }
+}
 pub unsafe fn validated_layers(
     entry: &Entry,
+    enabled: bool,
 ) -> Result<Vec<*const i8>> {
     let available_layers = entry
         .enumerate_instance_layer_properties()?
@@ -24,13 +217,11 @@ pub unsafe fn validated_layers(
         .map(|l| l.layer_name)
         .collect::<HashSet<_>>();
 
-    if VALIDATION_ENABLED
-        && !available_layers.contains(&VALIDATION_LAYER)
-    {
+    if enabled && !available_layers.contains(&VALIDATION_LAYER) {
         return Err(ValidationError::NoSupport);
     }
 
-    let layers = if VALIDATION_ENABLED {
+    let layers = if enabled {
         vec![VALIDATION_LAYER.as_ptr()]
     } else {
         Vec::new()
@@ -39,77 +230,147 @@ pub unsafe fn validated_layers(
     Ok(layers)
 }
 
+/// The `features` the validation layer supports, none if it is
+/// disabled.
+pub unsafe fn validated_features(
+    entry: &Entry,
+    features: &[ValidationFeature],
+    enabled: bool,
+) -> Result<Vec<vk::ValidationFeatureEnableEXT>> {
+    if !enabled || features.is_empty() {
+        if !features.is_empty() {
+            log::warn!(
+                "Validation features {:?} need validation enabled.",
+                features
+            );
+        }
+        return Ok(Vec::new());
+    }
+    let supported = entry
+        .enumerate_instance_extension_properties(Some(
+            VALIDATION_LAYER.as_bytes(),
+        ))?
+        .iter()
+        .any(|e| {
+            e.extension_name
+                == vk::EXT_VALIDATION_FEATURES_EXTENSION.name
+        });
+    if !supported {
+        log::warn!(
+            "Validation features {:?} are not supported.",
+            features
+        );
+        return Ok(Vec::new());
+    }
+
+    let mut enables = Vec::new();
+    for feature in features {
+        match feature {
+            ValidationFeature::Synchronization => enables.push(
+                vk::ValidationFeatureEnableEXT::SYNCHRONIZATION_VALIDATION,
+            ),
+            ValidationFeature::BestPractices => enables
+                .push(vk::ValidationFeatureEnableEXT::BEST_PRACTICES),
+            ValidationFeature::GpuAssisted => enables.extend([
+                vk::ValidationFeatureEnableEXT::GPU_ASSISTED,
+                vk::ValidationFeatureEnableEXT::GPU_ASSISTED_RESERVE_BINDING_SLOT,
+            ]),
+        }
+    }
+
+    Ok(enables)
+}
+
 pub fn validated_extensions(
-    window: &Window,
+    window: Option<&Window>,
+    features: &[vk::ValidationFeatureEnableEXT],
+    enabled: bool,
 ) -> Result<Vec<*const i8>> {
-    let mut extensions =
-        vk_window::get_required_instance_extensions(window)
-            .iter()
-            .map(|e| e.as_ptr())
-            .collect::<Vec<_>>();
+    // Headless rendering does not need any surface extensions.
+    let mut extensions = match window {
+        Some(window) => {
+            vk_window::get_required_instance_extensions(window)
+                .iter()
+                .map(|e| e.as_ptr())
+                .collect::<Vec<_>>()
+        }
+        None => Vec::new(),
+    };
 
-    if VALIDATION_ENABLED {
+    if enabled {
         extensions.push(vk::EXT_DEBUG_UTILS_EXTENSION.name.as_ptr());
     }
+    if !features.is_empty() {
+        extensions.push(
+            vk::EXT_VALIDATION_FEATURES_EXTENSION.name.as_ptr(),
+        );
+    }
 
     Ok(extensions)
 }
 
+/// Messenger info forwarding every message to `sink`, which has to
+/// outlive the instance.
+pub fn debug_messenger_info(
+    sink: &mut SinkSlot,
+) -> vk::DebugUtilsMessengerCreateInfoEXT {
+    vk::DebugUtilsMessengerCreateInfoEXT::builder()
+        .message_severity(vk::DebugUtilsMessageSeverityFlagsEXT::all())
+        .message_type(vk::DebugUtilsMessageTypeFlagsEXT::all())
+        .user_callback(Some(debug_callback))
+        .user_data(sink)
+        .build()
+}
+
 pub unsafe fn validated_instance(
     entry: &Entry,
     info: &vk::InstanceCreateInfo,
+    debug_info: &vk::DebugUtilsMessengerCreateInfoEXT,
     messenger: &mut vk::DebugUtilsMessengerEXT,
+    enabled: bool,
 ) -> Result<Instance> {
-    let instance = entry.create_instance(&info, None)?;
-
-    if VALIDATION_ENABLED {
-        let debug_info = vk::DebugUtilsMessengerCreateInfoEXT::builder()
-            .message_severity(vk::DebugUtilsMessageSeverityFlagsEXT::all())
-            .message_type(vk::DebugUtilsMessageTypeFlagsEXT::all())
-            .user_callback(Some(debug_callback));
+    let instance = entry.create_instance(info, None)?;
 
+    if enabled {
         *messenger = instance
-            .create_debug_utils_messenger_ext(&debug_info, None)?;
+            .create_debug_utils_messenger_ext(debug_info, None)?;
     }
 
     Ok(instance)
 }
 
+/// Chains `debug_info`, to get messages from instance creation, and
+/// the enabled `features` when validation is.
 pub unsafe fn validated_info<'a>(
-    application_info: &vk::ApplicationInfo,
-    layers: &Vec<*const i8>,
-    extensions: &Vec<*const i8>,
+    application_info: &'a vk::ApplicationInfo,
+    layers: &'a [*const i8],
+    extensions: &'a [*const i8],
     flags: vk::InstanceCreateFlags,
-) -> Result<(
-    vk::InstanceCreateInfo,
-    DebugUtilsMessengerCreateInfoEXTBuilder<'a>,
-)> {
+    debug_info: &'a mut vk::DebugUtilsMessengerCreateInfoEXT,
+    features: &'a mut vk::ValidationFeaturesEXT,
+    enabled: bool,
+) -> vk::InstanceCreateInfoBuilder<'a> {
     let mut info = vk::InstanceCreateInfo::builder()
         .application_info(application_info)
         .enabled_layer_names(layers)
         .enabled_extension_names(extensions)
         .flags(flags);
 
-    let mut debug_info =
-        vk::DebugUtilsMessengerCreateInfoEXT::builder()
-            .message_severity(
-                vk::DebugUtilsMessageSeverityFlagsEXT::all(),
-            )
-            .message_type(vk::DebugUtilsMessageTypeFlagsEXT::all())
-            .user_callback(Some(debug_callback));
-
-    if VALIDATION_ENABLED {
-        info = info.push_next(&mut debug_info);
+    if enabled {
+        info = info.push_next(debug_info);
+        if features.enabled_validation_feature_count > 0 {
+            info = info.push_next(features);
+        }
     }
 
-    Ok((*info, debug_info))
+    info
 }
 
 pub unsafe fn destroy_debug_utils_messenger_ext(
     instance: &Instance,
     messenger: vk::DebugUtilsMessengerEXT,
 ) {
-    if VALIDATION_ENABLED {
+    if !messenger.is_null() {
         instance.destroy_debug_utils_messenger_ext(messenger, None);
     }
 }
@@ -118,28 +379,56 @@ extern "system" fn debug_callback(
     severity: vk::DebugUtilsMessageSeverityFlagsEXT,
     type_: vk::DebugUtilsMessageTypeFlagsEXT,
     data: *const vk::DebugUtilsMessengerCallbackDataEXT,
-    _: *mut c_void,
+    sink: *mut c_void,
 ) -> vk::Bool32 {
     let data = unsafe { *data };
-    let message =
-        unsafe { CStr::from_ptr(data.message) }.to_string_lossy();
-
-    if severity >= vk::DebugUtilsMessageSeverityFlagsEXT::ERROR {
-        log::error!("({:?}) {}", type_, message);
-    } else if severity
-        >= vk::DebugUtilsMessageSeverityFlagsEXT::WARNING
-    {
-        log::warn!("({:?}) {}", type_, message);
-    } else if severity >= vk::DebugUtilsMessageSeverityFlagsEXT::INFO
-    {
-        log::debug!("({:?}) {}", type_, message);
-    } else {
-        log::trace!("({:?}) {}", type_, message);
+    let message = ValidationMessage {
+        severity,
+        types: type_,
+        id: if data.message_id_name.is_null() {
+            String::new()
+        } else {
+            unsafe { CStr::from_ptr(data.message_id_name) }
+                .to_string_lossy()
+                .into_owned()
+        },
+        message: unsafe { CStr::from_ptr(data.message) }
+            .to_string_lossy()
+            .into_owned(),
+        objects: unsafe { c_array(data.objects, data.object_count) }
+            .iter()
+            .filter_map(|o| c_string(o.object_name))
+            .collect(),
+        labels: unsafe {
+            c_array(data.cmd_buf_labels, data.cmd_buf_label_count)
+        }
+        .iter()
+        .filter_map(|l| c_string(l.label_name))
+        .collect(),
+    };
+
+    match unsafe { (sink as *const SinkSlot).as_ref() } {
+        Some(sink) => sink.receive(&message),
+        None => LogSink.receive(&message),
     }
 
     vk::FALSE
 }
 
+unsafe fn c_array<'a, T>(data: *const T, len: u32) -> &'a [T] {
+    if data.is_null() {
+        &[]
+    } else {
+        std::slice::from_raw_parts(data, len as usize)
+    }
+}
+
+fn c_string(s: *const i8) -> Option<String> {
+    (!s.is_null()).then(|| {
+        unsafe { CStr::from_ptr(s) }.to_string_lossy().into_owned()
+    })
+}
+
 #[derive(Clone, Debug, Error)]
 pub enum ValidationError {
     #[error("Validation layer requested but not supported.")]
@@ -148,3 +437,90 @@ pub enum ValidationError {
     VkErrorCode(#[from] ErrorCode),
 }
 type Result<T> = std::result::Result<T, ValidationError>;
+
+impl VkErrorSource for ValidationError {
+    fn vk_error_code(&self) -> Option<ErrorCode> {
+        match self {
+            Self::VkErrorCode(e) => Some(*e),
+            Self::NoSupport => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+
+    use super::*;
+
+    type Severity = vk::DebugUtilsMessageSeverityFlagsEXT;
+
+    /// Sends a message through the debug callback like the layer.
+    fn send(slot: &SinkSlot, severity: Severity, message: &str) {
+        let id = CString::new("VUID-test").unwrap();
+        let message = CString::new(message).unwrap();
+        let data = vk::DebugUtilsMessengerCallbackDataEXT::builder()
+            .message_id_name(id.as_bytes_with_nul())
+            .message(message.as_bytes_with_nul())
+            .build();
+        debug_callback(
+            severity,
+            vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION,
+            &data,
+            slot as *const SinkSlot as *mut c_void,
+        );
+    }
+
+    #[test]
+    fn collecting_sink_keeps_warnings_and_errors() {
+        let sink = Arc::new(CollectingSink::default());
+        let slot = SinkSlot::new(sink.clone());
+        send(&slot, Severity::VERBOSE, "verbose");
+        send(&slot, Severity::INFO, "info");
+        send(&slot, Severity::WARNING, "warning");
+        send(&slot, Severity::ERROR, "error");
+
+        assert_eq!(sink.error_count(), 1);
+        let messages = sink.take();
+        assert_eq!(
+            messages.iter().map(|m| &*m.message).collect::<Vec<_>>(),
+            ["warning", "error"]
+        );
+        assert_eq!(messages[1].id, "VUID-test");
+        assert!(sink.take().is_empty());
+        assert_eq!(sink.error_count(), 0);
+    }
+
+    #[test]
+    fn sink_can_be_replaced() {
+        let first = Arc::new(CollectingSink::default());
+        let second = Arc::new(CollectingSink::default());
+        let slot = SinkSlot::new(first.clone());
+        send(&slot, Severity::ERROR, "first");
+        slot.set(second.clone());
+        send(&slot, Severity::ERROR, "second");
+
+        assert_eq!(first.take()[0].message, "first");
+        assert_eq!(second.take()[0].message, "second");
+    }
+
+    #[test]
+    fn panic_sink_panics_after_the_callback() {
+        let slot = SinkSlot::new(Arc::new(PanicSink));
+        send(&slot, Severity::WARNING, "warning");
+        slot.check();
+
+        send(&slot, Severity::ERROR, "first error");
+        send(&slot, Severity::ERROR, "second error");
+        let panic = std::panic::catch_unwind(|| slot.check())
+            .unwrap_err()
+            .downcast::<String>()
+            .unwrap();
+        assert!(
+            panic.starts_with("Validation error VUID-test: first")
+        );
+
+        // Raised once.
+        slot.check();
+    }
+}